@@ -0,0 +1,326 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Waiting for the processes of a chain without blocking a thread, for
+//! async runtimes like tokio (feature `async`). The futures don't depend
+//! on a runtime: a single watcher thread of the crate `poll()`s the pidfds
+//! of all pending futures and wakes them up when a process terminated.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use crate::pipe::new_cloexec_pipe;
+use crate::wait::{next_signal_at, signal_due};
+use crate::{spawn_piped_cmd_chain_handle, try_update_process_states, ChainResult, CmdChain, ExecOptions, PipeEnd,
+            PiperError, ProcessState};
+
+/// How often the watcher wakes up futures whose processes have no pidfd
+/// (kernels before 5.3, other systems).
+const FALLBACK_POLL_MS: u64 = 10;
+
+/// Runs a command chain and returns a future that resolves to the exit
+/// status of the chain when all processes are finished. The processes
+/// are started right away with pidfds (see `ExecOptions::set_pidfds()`),
+/// not when the future is polled first; a spawn error is returned by the
+/// future. Like `spawn_piped_cmd_chain_handle()` the chain is never waited
+/// for synchronously; its timeout counts from the call and is applied like
+/// in `execute_piped_cmd_chain()`. Dropping the future doesn't kill the
+/// processes.
+pub fn execute_piped_cmd_chain_async(cmds: &CmdChain) -> impl Future<Output=Result<ChainResult, PiperError>> {
+    let spawned = spawn_piped_cmd_chain_handle(cmds, &ExecOptions::new().set_pidfds(true));
+    let (pipefail, negate_status) = (cmds.pipefail(), cmds.negate_status());
+    async move {
+        let mut handle = spawned?;
+        let states = handle.wait_async().await?;
+        let result = ChainResult::from_states(states, pipefail);
+        Ok(if negate_status { result.negated() } else { result })
+    }
+}
+
+/// Future of `ChainHandle::wait_async()`.
+#[derive(Debug)]
+pub struct WaitFuture<'a> {
+    /// `None` after the future completed.
+    states: Option<&'a mut Vec<ProcessState>>,
+    /// When the chain runs into its timeout, see `ChainHandle`.
+    deadline: Option<Instant>,
+    /// The last signal that was sent because of the timeout, see `signal_due()`.
+    sent: Option<libc::c_int>,
+    /// Identifies the entry of the future in the watcher.
+    id: u64,
+    /// Whether the watcher may have an entry of the future.
+    watched: bool,
+}
+
+impl<'a> WaitFuture<'a> {
+    /// Constructor.
+    pub(crate) fn new(states: &'a mut Vec<ProcessState>, deadline: Option<Instant>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        Self { states: Some(states), deadline, sent: None, id, watched: false }
+    }
+}
+
+impl<'a> Future for WaitFuture<'a> {
+    type Output = Result<&'a Vec<ProcessState>, PiperError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let states = this.states.as_mut().expect("WaitFuture polled after completion");
+        signal_due(states, this.deadline, &mut this.sent);
+        match try_update_process_states(states, true) {
+            Err(e) => Poll::Ready(Err(e)),
+            Ok(true) => {
+                let states = this.states.take().unwrap();
+                if this.sent.is_some() {
+                    states.iter_mut().for_each(|state| state.set_timed_out());
+                }
+                Poll::Ready(Ok(states))
+            }
+            Ok(false) => {
+                // a pidfd stays readable until the process is reaped, hence
+                // an exit before the registration isn't missed
+                let pidfd = states.iter().find(|state| !state.finished()).and_then(|state| state.wait_fd());
+                watcher().watch(this.id, pidfd, next_signal_at(this.deadline, this.sent), cx.waker().clone());
+                this.watched = true;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for WaitFuture<'_> {
+    fn drop(&mut self) {
+        if self.watched {
+            watcher().unwatch(self.id);
+        }
+    }
+}
+
+/// A future that waits for a process, see `Watcher`.
+#[derive(Debug)]
+struct WatchEntry {
+    /// See `WaitFuture::id`.
+    id: u64,
+    /// A duplicate of the pidfd to wait for, so that it stays open even
+    /// if the states are dropped; `None`: wake up after `FALLBACK_POLL_MS`.
+    /// Shared with the thread while it `poll()`s it, so that replacing the
+    /// entry doesn't close an FD that is being polled.
+    pidfd: Option<Arc<OwnedFd>>,
+    /// When to wake up at the latest.
+    until: Option<Instant>,
+    waker: Waker,
+}
+
+/// The futures that wait for a process, see `watcher()`.
+#[derive(Debug)]
+struct Watcher {
+    /// At most one entry per future.
+    entries: Mutex<Vec<WatchEntry>>,
+    /// Write end of the self-pipe that interrupts the `poll()` of the thread.
+    wake_fd: libc::c_int,
+}
+
+impl Watcher {
+    /// Wakes up `waker` of the future `id` when the process of `pidfd`
+    /// terminated or at `until`, whatever comes first. Replaces the
+    /// previous entry of the future.
+    fn watch(&self, id: u64, pidfd: Option<libc::c_int>, until: Option<Instant>, waker: Waker) {
+        // without a duplicate the future is polled like without pidfd
+        let pidfd = pidfd.and_then(|fd| {
+            let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if dup == -1 { None } else { Some(Arc::new(unsafe { OwnedFd::from_raw_fd(dup) })) }
+        });
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.id != id);
+        entries.push(WatchEntry { id, pidfd, until, waker });
+        drop(entries);
+        // EAGAIN if the pipe is full; the thread wakes up anyway then
+        unsafe { libc::write(self.wake_fd, [0_u8].as_ptr() as *const libc::c_void, 1) };
+    }
+
+    /// Removes the entry of the future `id`, e.g. when it's dropped.
+    fn unwatch(&self, id: u64) {
+        self.entries.lock().unwrap().retain(|entry| entry.id != id);
+    }
+}
+
+/// The watcher; its thread is started on first use and runs as long as
+/// the process.
+fn watcher() -> &'static Watcher {
+    static WATCHER: OnceLock<Watcher> = OnceLock::new();
+    WATCHER.get_or_init(|| {
+        let fds = new_cloexec_pipe().unwrap_or_else(|e| panic!("Can't create the pipe of the watcher! {}", e));
+        for fd in fds.iter() {
+            unsafe { libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK) };
+        }
+        let read_fd = fds[PipeEnd::Read as usize];
+        std::thread::spawn(move || watch_loop(read_fd));
+        Watcher { entries: Mutex::new(vec![]), wake_fd: fds[PipeEnd::Write as usize] }
+    })
+}
+
+/// The watcher thread: `poll()`s the self-pipe and all pidfds and wakes up
+/// the futures of the readable (or closed) pidfds.
+fn watch_loop(read_fd: libc::c_int) {
+    let interval = Duration::from_millis(FALLBACK_POLL_MS);
+    let mut last_tick = Instant::now();
+    loop {
+        let mut poll_fds = vec![libc::pollfd { fd: read_fd, events: libc::POLLIN, revents: 0 }];
+        // the pidfds of poll_fds[1..]; an entry that was replaced meanwhile
+        // has another one
+        let mut pidfds = vec![];
+        let mut wake_ups = vec![];
+        for entry in watcher().entries.lock().unwrap().iter() {
+            match &entry.pidfd {
+                Some(fd) => {
+                    poll_fds.push(libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+                    pidfds.push(fd.clone());
+                }
+                None => wake_ups.push(interval),
+            }
+            if let Some(until) = entry.until {
+                wake_ups.push(until.saturating_duration_since(Instant::now()));
+            }
+        }
+        // round up, otherwise a sub millisecond timeout would spin
+        let timeout = wake_ups.into_iter().min()
+            .map_or(-1, |timeout| timeout.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int);
+        let res = unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, timeout) };
+        let ready: Vec<Arc<OwnedFd>> = if res == -1 {
+            if errno::errno().0 == libc::EINTR {
+                continue;
+            }
+            // e.g. ENOMEM: all futures poll their processes again, but
+            // not more often than the ones without pidfd
+            std::thread::sleep(interval);
+            pidfds
+        } else {
+            poll_fds[1..].iter().zip(pidfds)
+                .filter(|(poll_fd, _)| poll_fd.revents != 0)
+                .map(|(_, fd)| fd)
+                .collect()
+        };
+        let mut buf = [0_u8; 64];
+        while unsafe { libc::read(read_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+
+        // not on every wake-up of the self-pipe, otherwise the futures
+        // without pidfd would spin
+        let tick = last_tick.elapsed() >= interval;
+        if tick {
+            last_tick = Instant::now();
+        }
+        let now = Instant::now();
+        watcher().entries.lock().unwrap().retain(|entry| {
+            let wake = match &entry.pidfd {
+                Some(fd) => ready.iter().any(|ready| Arc::ptr_eq(ready, fd)),
+                None => tick,
+            } || entry.until.is_some_and(|until| now >= until);
+            if wake {
+                entry.waker.wake_by_ref();
+            }
+            !wake
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Wake;
+    use std::thread::Thread;
+    use crate::{parse_cmd_chain, BasicCmdBuilder, Builder, CmdChainBuilder};
+
+    /// Wakes up the thread of `block_on()`.
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Minimal executor: parks the thread until the future was woken up.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn test_execute_piped_cmd_chain_async() {
+        let begin = Instant::now();
+        let result = block_on(execute_piped_cmd_chain_async(&parse_cmd_chain("sleep 0.2 | false").unwrap())).unwrap();
+        assert_eq!(result.exit_code(), 1);
+        assert!(begin.elapsed() >= Duration::from_millis(200));
+
+        let result = block_on(execute_piped_cmd_chain_async(&parse_cmd_chain("does-not-exist").unwrap()));
+        assert_eq!(result.unwrap_err().errno(), libc::ENOENT);
+
+        // both futures in one thread
+        let first = execute_piped_cmd_chain_async(&parse_cmd_chain("sleep 0.1").unwrap());
+        let second = execute_piped_cmd_chain_async(&parse_cmd_chain("true").unwrap());
+        let (first, second) = block_on(async { (first.await, second.await) });
+        assert_eq!(first.unwrap().exit_code(), 0);
+        assert_eq!(second.unwrap().exit_code(), 0);
+
+        // the timeout of the chain applies
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("10"))
+            .set_timeout(Duration::from_millis(100))
+            .build();
+        let begin = Instant::now();
+        let result = block_on(execute_piped_cmd_chain_async(&cmd_chain)).unwrap();
+        assert_eq!(result.exit_code(), 128 + libc::SIGTERM);
+        assert!(begin.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_watcher_entries() {
+        let entries = |id: u64| watcher().entries.lock().unwrap().iter().filter(|entry| entry.id == id).count();
+        let mut handle = crate::spawn_piped_cmd_chain_handle(&parse_cmd_chain("sleep 10").unwrap(),
+                                                             &ExecOptions::new().set_pidfds(true)).unwrap();
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(handle.wait_async());
+        let id = future.id;
+        // one entry per future, however often it's polled
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(entries(id), 1);
+        drop(future);
+        assert_eq!(entries(id), 0);
+        handle.terminate(Duration::from_secs(1)).unwrap();
+    }
+}
@@ -22,7 +22,8 @@
     SOFTWARE.
 */
 
-use unix_exec_piper::{execute_piped_cmd_chain, CmdChainBuilder, BasicCmdBuilder, Builder, update_process_states};
+use std::time::Duration;
+use unix_exec_piper::{execute_piped_cmd_chain, CmdChainBuilder, BasicCmdBuilder, Builder, wait_background_poll};
 
 /// Please note: My experience showed that somehow output redirection into a file make
 /// problems when executed from the IDE (CLion), at least when the path is inside src. Don't
@@ -50,7 +51,9 @@ fn main() {
             .add_arg("cat")
             .set_output_redirect_path("foobar.txt")
     ).build();
-    execute_piped_cmd_chain(&cmd_chain);
+    if let Err(e) = execute_piped_cmd_chain(&cmd_chain) {
+        eprintln!("Example one failed: {}", e);
+    }
 
     println!();
     println!("############################################################");
@@ -74,13 +77,13 @@ fn main() {
         )
         .set_background(true)
         .build();
-    let mut state = execute_piped_cmd_chain(&cmd_chain);
+    let mut state = execute_piped_cmd_chain(&cmd_chain).expect("Example two failed");
     println!("Process states after dispatch: {:#?}", state);
-    while !update_process_states(&mut state, true) {
-        /*
-         * Example two wait non-blocking. This check could be done for example
-         * in a shell everytime the user presses 'enter'
-         */
-    }
+    /*
+     * Example two waits non-blocking. This check could be done for example
+     * in a shell everytime the user presses 'enter' via `update_process_states()`.
+     * Here we just poll with a backoff until everything is done.
+     */
+    wait_background_poll(&mut state, Duration::from_millis(1), Duration::from_millis(100));
     println!("Process states after finished: {:#?}", state);
 }
\ No newline at end of file
@@ -0,0 +1,158 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Execution of long command chains with a limited number of file
+//! descriptors. A chain of n commands needs n - 1 pipes, i.e. 2 * (n - 1)
+//! FDs at the same time. If that's too much, the chain is split into
+//! segments that run one after another; the output of a segment is
+//! materialized into a temp file that becomes the input of the next one:
+//!
+//! ```text
+//! a | b | c | d | e        =>       a | b > tmp1;  c | d < tmp1 > tmp2;  e < tmp2
+//! ```
+
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::time::Instant;
+use crate::wait::deadline_of;
+use crate::{ensure_foreground, execute_piped_cmd_chain, CmdChain, PiperError, ProcessState};
+
+/// Runs a command chain like `execute_piped_cmd_chain()` but uses at most
+/// `max_fds` FDs for the pipes between the commands. If the chain needs
+/// more, it's split into segments that run one after another with temp
+/// files in between (see module description). The segments don't run
+/// concurrently, i.e. a segment must finish before the next one starts,
+/// which doesn't work for commands that never end, and the temp files
+/// need as much disk space as the output of the segments.
+///
+/// The temp files are removed afterwards, also on errors. Returns the
+/// states of all processes in the order of the chain. If a segment can't
+/// be spawned, the following segments aren't executed. Waits for all
+/// processes, even if the chain is marked as background, but fails if
+/// it's required to run in background.
+///
+/// The timeout of the chain (see `CmdChainBuilder::set_timeout()`) applies
+/// to all segments together: each segment gets what is left of it. If a
+/// segment timed out, the following segments aren't executed either.
+pub fn execute_with_fd_budget(cmds: &CmdChain, max_fds: usize) -> Result<Vec<ProcessState>, PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    // a segment of k commands needs k - 1 pipes
+    let segment_len = max_fds / 2 + 1;
+    let len = cmds.length();
+
+    let mut temp_files: Vec<PathBuf> = vec![];
+    let mut states = vec![];
+    let res = (0..len).step_by(segment_len).try_for_each(|begin| {
+        if states.iter().any(|state: &ProcessState| state.timed_out()) {
+            return Ok(());
+        }
+        let end = (begin + segment_len).min(len);
+        let in_path = temp_files.last().cloned();
+        let out_path = if end < len {
+            let path = create_temp_file()?;
+            temp_files.push(path.clone());
+            Some(path)
+        } else {
+            None
+        };
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let segment = cmds.segment(begin..end, in_path.as_deref(), out_path.as_deref(), timeout);
+        states.extend(execute_piped_cmd_chain(&segment)?);
+        Ok(())
+    });
+
+    for path in temp_files {
+        let _ = std::fs::remove_file(path);
+    }
+    res.map(|_| states)
+}
+
+/// Creates an empty temp file for the output of a segment. It's created
+/// exclusively, so that an existing file (e.g. a symlink) is never used.
+fn create_temp_file() -> Result<PathBuf, PiperError> {
+    let path = std::env::temp_dir().join(format!("unix_exec_piper_fd_budget_{}_{}", std::process::id(), temp_file_id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| PiperError::RedirectOpenFailed {
+            path: path.to_string_lossy().into_owned(),
+            errno: e.raw_os_error().unwrap_or(libc::EIO),
+        })?;
+    Ok(path)
+}
+
+/// Unique id per call of `create_temp_file()` in this process, so that
+/// multiple threads can use `execute_with_fd_budget()` at the same time.
+fn temp_file_id() -> usize {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BasicCmdBuilder, Builder, CmdChainBuilder};
+
+    #[test]
+    fn test_execute_with_fd_budget() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_budget_out_{}.txt", std::process::id()));
+        let temp_files = || {
+            let prefix = format!("unix_exec_piper_fd_budget_{}_", std::process::id());
+            std::fs::read_dir(std::env::temp_dir()).unwrap()
+                .filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().starts_with(&prefix))
+                .count()
+        };
+
+        // 'seq 1 1000 | cat | ... | cat | wc -l > out_file' with 20 cats
+        let mut builder = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("seq").add_arg("seq").add_arg("1").add_arg("1000"));
+        for _ in 0..20 {
+            builder = builder.add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"));
+        }
+        let cmd_chain = builder
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("wc")
+                    .add_arg("wc")
+                    .add_arg("-l")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+
+        // segments of 3 commands, i.e. 8 segments and 7 temp files
+        let states = execute_with_fd_budget(&cmd_chain, 4).unwrap();
+        assert_eq!(states.len(), 22);
+        assert!(states.iter().all(|state| state.finished() && state.exit_code() == 0));
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap().trim(), "1000");
+        assert_eq!(temp_files(), 0);
+
+        // even no pipe at all
+        execute_with_fd_budget(&cmd_chain, 0).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap().trim(), "1000");
+        assert_eq!(temp_files(), 0);
+        std::fs::remove_file(&out_file).unwrap();
+    }
+}
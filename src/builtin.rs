@@ -0,0 +1,428 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Built-in commands like `cd` or `export` that change the state of the
+//! shell itself and therefore have to run in the parent process, see
+//! `BasicCmdBuilder::set_builtin()`.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::FromRawFd;
+use std::panic::RefUnwindSafe;
+use std::path::Path;
+use crate::{BasicCmd, ChainIo, ChainMode, CmdChain, ExecOptions, OutputRedirectMode, PiperError, ProcessState};
+
+/// A command that is implemented in Rust instead of by an executable.
+/// Like in bash a chain that consists of only one builtin runs it in the
+/// parent process, e.g. `cd` changes the working directory of the parent.
+/// In a pipeline (or in background) it runs in a forked child like a
+/// normal command, hence `cd /tmp | cat` has no effect on the parent.
+/// It's `RefUnwindSafe` so that a chain stays unwind safe.
+pub trait Builtin: fmt::Debug + Send + Sync + RefUnwindSafe {
+    /// Runs the builtin in the parent. `args` includes the name of the
+    /// builtin as args[0]. Returns the exit code.
+    fn run(&self, args: &[OsString], stdin: &mut dyn Read, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32;
+
+    /// Runs the builtin in a forked child, where its effects on the
+    /// process are lost anyway. It's called between `fork()` and
+    /// `_exit()`, i.e. like the child code of the crate it must not
+    /// allocate, format with `format!()` or take locks: another thread
+    /// of the parent may have held them during `fork()`. The streams are
+    /// unbuffered. `run()` by default.
+    fn run_in_child(&self, args: &[OsString], stdin: &mut dyn Read, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        self.run(args, stdin, stdout, stderr)
+    }
+
+    /// Whether the builtin asks the caller to exit, like `Exit`. If it
+    /// ran in the parent, its state says so, see
+    /// `ProcessState::exit_requested()`.
+    fn exits(&self) -> bool {
+        false
+    }
+}
+
+/// `cd [dir]`: changes the working directory, to `$HOME` without `dir`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Cd;
+
+impl Cd {
+    /// Changes into `dir`. Returns the exit code.
+    fn cd(dir: &OsStr, stderr: &mut dyn Write) -> i32 {
+        match std::env::set_current_dir(dir) {
+            Ok(()) => 0,
+            Err(e) => {
+                // errno::Errno formats without allocating, see run_in_child()
+                let errno = errno::Errno(e.raw_os_error().unwrap_or(libc::EIO));
+                let _ = writeln!(stderr, "cd: {}: {}", Path::new(dir).display(), errno);
+                1
+            }
+        }
+    }
+}
+
+impl Builtin for Cd {
+    fn run(&self, args: &[OsString], _stdin: &mut dyn Read, _stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        match args.get(1).cloned().or_else(|| std::env::var_os("HOME")) {
+            Some(dir) => Self::cd(&dir, stderr),
+            None => {
+                let _ = writeln!(stderr, "cd: HOME not set");
+                1
+            }
+        }
+    }
+
+    /// Doesn't look up `$HOME`, which allocates; the change of the
+    /// directory is lost anyway.
+    fn run_in_child(&self, args: &[OsString], _stdin: &mut dyn Read, _stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        args.get(1).map_or(0, |dir| Self::cd(dir, stderr))
+    }
+}
+
+/// `export NAME=value...`: sets environment variables of the process,
+/// i.e. of all commands that are started afterwards. A `NAME` without
+/// a value is ignored, every variable is exported anyway.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Export;
+
+impl Export {
+    /// Validates the args and hands every `NAME=value` to `set`. Returns
+    /// the exit code.
+    fn export<F: FnMut(&OsStr, &OsStr)>(args: &[OsString], stderr: &mut dyn Write, mut set: F) -> i32 {
+        let mut exit_code = 0;
+        for arg in args.iter().skip(1) {
+            let bytes = arg.as_bytes();
+            match bytes.iter().position(|b| *b == b'=') {
+                Some(0) => {
+                    let _ = stderr.write_all(b"export: '");
+                    let _ = stderr.write_all(bytes);
+                    let _ = stderr.write_all(b"': not a valid identifier\n");
+                    exit_code = 1;
+                }
+                Some(pos) => set(OsStr::from_bytes(&bytes[..pos]), OsStr::from_bytes(&bytes[pos + 1..])),
+                None => {}
+            }
+        }
+        exit_code
+    }
+}
+
+impl Builtin for Export {
+    fn run(&self, args: &[OsString], _stdin: &mut dyn Read, _stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        Self::export(args, stderr, |name, value| std::env::set_var(name, value))
+    }
+
+    /// Only validates the args: setting a variable allocates and takes
+    /// the lock of the environment.
+    fn run_in_child(&self, args: &[OsString], _stdin: &mut dyn Read, _stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        Self::export(args, stderr, |_, _| {})
+    }
+}
+
+/// `umask [mode]`: sets the file mode creation mask to the octal `mode`
+/// or prints the current one (e.g. `0022`).
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Umask;
+
+impl Umask {
+    /// Parses the octal `mode` byte by byte: `to_str()` and
+    /// `from_str_radix()` don't allocate either, but a non-UTF-8 `mode`
+    /// would need `to_string_lossy()` for the message.
+    fn parse(mode: &OsStr) -> Option<libc::mode_t> {
+        let digits = mode.as_bytes();
+        if digits.is_empty() {
+            return None;
+        }
+        digits.iter().try_fold(0 as libc::mode_t, |mask, &digit| match digit {
+            b'0'..=b'7' if mask <= 0o777 => Some(mask * 8 + libc::mode_t::from(digit - b'0')),
+            _ => None,
+        })
+    }
+
+    fn umask(args: &[OsString], stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        match args.get(1) {
+            None => {
+                // there is no way to read the mask without setting it
+                let mask = unsafe { libc::umask(0) };
+                unsafe { libc::umask(mask) };
+                match writeln!(stdout, "{:04o}", mask) {
+                    Ok(()) => 0,
+                    Err(_) => 1,
+                }
+            }
+            Some(mode) => match Self::parse(mode) {
+                Some(mask) if mask <= 0o777 => {
+                    unsafe { libc::umask(mask) };
+                    0
+                }
+                _ => {
+                    let _ = stderr.write_all(b"umask: ");
+                    let _ = stderr.write_all(mode.as_bytes());
+                    let _ = stderr.write_all(b": invalid octal number\n");
+                    1
+                }
+            },
+        }
+    }
+}
+
+impl Builtin for Umask {
+    fn run(&self, args: &[OsString], _stdin: &mut dyn Read, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        Self::umask(args, stdout, stderr)
+    }
+
+    /// Like `run()`: `umask()` is async-signal-safe and the output is
+    /// written to the unbuffered streams without allocating.
+    fn run_in_child(&self, args: &[OsString], _stdin: &mut dyn Read, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        Self::umask(args, stdout, stderr)
+    }
+}
+
+/// `exit [n]`: asks the caller to exit with the status `n` (modulo 256),
+/// 0 without `n`; a shell passes `$?` to get the behavior of bash. A
+/// library can't exit the process of the application, hence in the parent
+/// it only returns the status and marks its state, see
+/// `ProcessState::exit_requested()`; `execute_cmd_list()` stops the list
+/// then. In a pipeline it just ends its child, like in bash.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Exit;
+
+impl Builtin for Exit {
+    fn run(&self, args: &[OsString], _stdin: &mut dyn Read, _stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+        match args.get(1) {
+            None => 0,
+            Some(status) => match status.to_str().and_then(|status| status.parse::<i64>().ok()) {
+                Some(status) => (status & 0xff) as i32,
+                None => {
+                    let _ = stderr.write_all(b"exit: ");
+                    let _ = stderr.write_all(status.as_bytes());
+                    let _ = stderr.write_all(b": numeric argument required\n");
+                    2
+                }
+            },
+        }
+    }
+
+    fn exits(&self) -> bool {
+        true
+    }
+}
+
+/// Whether the chain is a single builtin that runs in the parent. A
+/// builtin with what the parent can't set up for it without affecting
+/// itself (pipes, FD duplications, a working directory, credentials or
+/// environment variables) runs in a child, as well as one whose streams
+/// the executor connects (`io`), e.g. to capture the output or in a
+/// subshell, like in bash.
+pub(crate) fn runs_in_parent(cmds: &CmdChain, io: &ChainIo) -> bool {
+    match cmds.cmds().as_slice() {
+        [cmd] => cmd.builtin().is_some()
+            && io.stdin.is_none()
+            && io.stdout.is_none()
+            && io.stderr.is_none()
+            && cmds.ensure_mode(ChainMode::Foreground).is_ok()
+            && !cmds.background()
+            && cmd.stdin_pipe_fd().is_none()
+            && cmd.stdout_pipe_fd().is_none()
+            && !cmd.merge_stderr()
+            && cmd.fd_dups().is_empty()
+            && cmd.work_dir().is_none()
+            && cmd.uid().is_none()
+            && cmd.gid().is_none()
+            && cmd.groups().is_none()
+            && cmd.env().is_empty()
+            && !cmd.clear_env(),
+        _ => false,
+    }
+}
+
+/// Runs the builtin of `cmd` in the parent with its redirects. The state
+/// has the pid 0 because there is no process; it's finished already.
+pub(crate) fn run_in_parent(cmd: &BasicCmd, opts: &ExecOptions) -> Result<ProcessState, PiperError> {
+    let mut state = ProcessState::new(cmd.executable().to_string_lossy().into_owned(), 0);
+    if cmd.builtin().unwrap().exits() {
+        state.set_exit_requested();
+    }
+    match run_with_redirects(cmd, opts) {
+        Ok(exit_code) => state.finish(exit_code),
+        Err(e) if opts.spawn_failures_as_exit_codes() => {
+            state.set_spawn_error(e);
+            state.finish(1);
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(state)
+}
+
+fn run_with_redirects(cmd: &BasicCmd, opts: &ExecOptions) -> Result<i32, PiperError> {
+    let builtin = cmd.builtin().unwrap();
+    let mut stdin: Box<dyn Read> = match (cmd.in_red_path(), cmd.stdin_contents()) {
+        (Some(path), _) => Box::new(open_redirect(path, OpenOptions::new().read(true))?),
+        (None, Some(contents)) => Box::new(contents.as_slice()),
+        (None, None) => Box::new(std::io::stdin()),
+    };
+    let mut stdout: Box<dyn Write> = match cmd.out_red_path() {
+        Some(path) => {
+            let mut options = OpenOptions::new();
+            options.write(true).create(true);
+            match cmd.out_red_mode() {
+                OutputRedirectMode::Truncate if opts.noclobber() => options.create_new(true),
+                OutputRedirectMode::Truncate | OutputRedirectMode::Clobber => options.truncate(true),
+                OutputRedirectMode::Append => options.append(true),
+            };
+            Box::new(open_redirect(path, options.mode(0o644))?)
+        }
+        None => Box::new(std::io::stdout()),
+    };
+    let mut stderr: Box<dyn Write> = match cmd.err_red_path() {
+        Some(path) => Box::new(open_redirect(path, OpenOptions::new().write(true).create(true).truncate(true))?),
+        None => Box::new(std::io::stderr()),
+    };
+    let exit_code = builtin.run(cmd.args(), &mut stdin, &mut stdout, &mut stderr);
+    let _ = stdout.flush();
+    Ok(exit_code)
+}
+
+/// Opens the file of a redirect.
+fn open_redirect(path: &Path, options: &OpenOptions) -> Result<File, PiperError> {
+    options.open(path).map_err(|e| PiperError::RedirectOpenFailed {
+        path: path.to_string_lossy().into_owned(),
+        errno: e.raw_os_error().unwrap_or(libc::EIO),
+    })
+}
+
+/// Child code: runs the builtin of the command with STDIN, STDOUT and
+/// STDERR after its FDs are connected, see `Builtin::run_in_child()`.
+/// Returns its exit code. Doesn't allocate: the streams are unbuffered.
+pub(crate) fn run_in_child(cmd: &BasicCmd) -> i32 {
+    // the FDs are never closed: `_exit()` follows
+    let mut stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDIN_FILENO) });
+    let mut stdout = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDOUT_FILENO) });
+    let mut stderr = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDERR_FILENO) });
+    cmd.builtin().unwrap().run_in_child(cmd.args(), &mut *stdin, &mut *stdout, &mut *stderr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute_cmd_list, execute_piped_cmd_chain, execute_piped_cmd_chain_capture_string, BasicCmdBuilder, Builder,
+                CmdChainBuilder, CmdListBuilder, Connector};
+
+    #[test]
+    fn test_builtins() {
+        let export = |arg: &str| BasicCmdBuilder::new().set_builtin(Export).add_arg("export").add_arg(arg);
+        let cat = || BasicCmdBuilder::new().set_executable("cat").add_arg("cat");
+
+        // 'export ... > file' runs in the parent
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_builtin_{}.txt", std::process::id()));
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(export("UNIX_EXEC_PIPER_BUILTIN_A=1").set_output_redirect_path(&out_file))
+            .build();
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].pid(), 0);
+        assert_eq!(states[0].exit_code(), 0);
+        assert_eq!(std::env::var("UNIX_EXEC_PIPER_BUILTIN_A").as_deref(), Ok("1"));
+        assert_eq!(std::fs::read(&out_file).unwrap(), b"");
+        std::fs::remove_file(&out_file).unwrap();
+
+        // 'export ... | cat' runs in a child
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(export("UNIX_EXEC_PIPER_BUILTIN_B=1"))
+            .add_cmd(cat())
+            .build();
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_ne!(states[0].pid(), 0);
+        assert_eq!(states[0].exit_code(), 0);
+        assert!(std::env::var("UNIX_EXEC_PIPER_BUILTIN_B").is_err());
+
+        // every executor runs it in the parent, e.g. with a handle
+        let cmd_chain = CmdChainBuilder::new().add_cmd(export("UNIX_EXEC_PIPER_BUILTIN_C=1")).build();
+        let mut handle = crate::spawn_piped_cmd_chain_handle(&cmd_chain, &crate::ExecOptions::default()).unwrap();
+        assert!(handle.states()[0].in_parent());
+        assert_eq!(handle.wait().unwrap()[0].exit_code(), 0);
+        assert_eq!(std::env::var("UNIX_EXEC_PIPER_BUILTIN_C").as_deref(), Ok("1"));
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_builtin(Umask).add_arg("umask"))
+            .add_cmd(cat())
+            .build();
+        let (_, stdout) = execute_piped_cmd_chain_capture_string(&cmd_chain).unwrap();
+        assert!(stdout.len() == 5 && stdout.starts_with('0') && stdout.ends_with('\n'), "{:?}", stdout);
+
+        // the output of a single builtin is captured in a child, like '$(umask)'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_builtin(Umask).add_arg("umask"))
+            .build();
+        let (states, stdout) = execute_piped_cmd_chain_capture_string(&cmd_chain).unwrap();
+        assert!(!states[0].in_parent());
+        assert!(stdout.len() == 5 && stdout.starts_with('0') && stdout.ends_with('\n'), "{:?}", stdout);
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_builtin(Umask).add_arg("umask").add_arg("9"))
+            .build();
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].exit_code(), 1);
+
+        // a mode that isn't UTF-8 is reported as is
+        let args = [OsString::from("umask"), OsString::from(OsStr::from_bytes(b"0\xff"))];
+        let mut stderr = Vec::new();
+        assert_eq!(Umask.run_in_child(&args, &mut std::io::empty(), &mut std::io::sink(), &mut stderr), 1);
+        assert_eq!(stderr, b"umask: 0\xff: invalid octal number\n");
+        assert_eq!(Umask::parse(OsStr::new("0022")), Some(0o22));
+        assert_eq!(Umask::parse(OsStr::new("")), None);
+        assert_eq!(Umask::parse(OsStr::new("1777")), Some(0o1777));
+    }
+
+    #[test]
+    fn test_exit() {
+        let exit = |status: &str| CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_builtin(Exit).add_arg("exit").add_arg(status));
+
+        // 'exit 258; echo unreachable' stops the list
+        let list = CmdListBuilder::new()
+            .add_chain(Connector::Always, exit("258").build())
+            .add_chain(Connector::Always, crate::parse_cmd_chain("echo unreachable").unwrap())
+            .build();
+        let res = execute_cmd_list(&list).unwrap();
+        assert_eq!(res.exit_code(), 2);
+        let states = res.states()[0].as_ref().unwrap();
+        assert!(states[0].in_parent() && states[0].exit_requested());
+        assert!(res.states()[1].is_none());
+
+        // 'exit 3 | cat' only ends its child
+        let cmd_chain = exit("3")
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+            .build();
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].exit_code(), 3);
+        assert!(!states[0].exit_requested());
+
+        let states = execute_piped_cmd_chain(&exit("x").build()).unwrap();
+        assert_eq!(states[0].exit_code(), 2);
+    }
+}
@@ -0,0 +1,1203 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Capturing of the output of a command chain in the parent. STDOUT of
+//! the last command is connected to a pipe whose read end stays in the
+//! parent. The parent must read from it while the processes are running,
+//! otherwise a command blocks as soon as the pipe buffer is full.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use crate::pipe::{new_cloexec_pipe, PipeEnd, SigpipeBlocked};
+use crate::{ensure_foreground, spawn_piped_cmd_chain, try_update_process_states, ChainIo, ChainResult, CmdChain, ExecOptions,
+            PiperError, ProcessOutcome, ProcessState};
+use crate::wait::{deadline_of, signal_chain, signal_due, try_update_process_states_timeout, try_wait_all_timeout, Watchdog};
+
+/// The parent's ends of the pipes that were requested in
+/// `spawn_captured_streams()`.
+#[derive(Debug, Default)]
+pub(crate) struct CapturedStreams {
+    /// Write end of the pipe connected to STDIN of the first command.
+    pub(crate) stdin: Option<File>,
+    /// Read end of the pipe connected to STDOUT of the last command.
+    pub(crate) stdout: Option<File>,
+    /// Read end of the pipe connected to STDERR of all commands.
+    pub(crate) stderr: Option<File>,
+}
+
+/// Spawns the chain with STDOUT of the last command connected to a new pipe
+/// and returns the read end of it. The parent's copy of the write end is
+/// already closed, i.e. the reader sees EOF after the last command exited.
+/// If the last command has an output redirect, the output goes into the
+/// file and the reader sees EOF immediately.
+pub(crate) fn spawn_captured(cmds: &CmdChain, opts: &ExecOptions) -> Result<(Vec<ProcessState>, File), PiperError> {
+    let (states, streams) = spawn_captured_streams(cmds, opts, false, true, false)?;
+    Ok((states, streams.stdout.unwrap()))
+}
+
+/// Like `spawn_captured()` but can also connect STDIN of the first command
+/// to a pipe and capture STDERR of all commands into another pipe. Returns
+/// the parent's ends of the requested pipes.
+pub(crate) fn spawn_captured_streams(cmds: &CmdChain,
+                                     opts: &ExecOptions,
+                                     stdin: bool,
+                                     stdout: bool,
+                                     stderr: bool) -> Result<(Vec<ProcessState>, CapturedStreams), PiperError> {
+    // (read end, write end)
+    let new_pipe = || {
+        let fds = new_cloexec_pipe()?;
+        unsafe {
+            Ok((File::from_raw_fd(fds[PipeEnd::Read as usize]), File::from_raw_fd(fds[PipeEnd::Write as usize])))
+        }
+    };
+    let stdin_pipe = if stdin { Some(new_pipe()?) } else { None };
+    let stdout_pipe = if stdout { Some(new_pipe()?) } else { None };
+    let stderr_pipe = if stderr { Some(new_pipe()?) } else { None };
+
+    let io = ChainIo {
+        stdin: stdin_pipe.as_ref().map(|(read_end, _)| read_end.as_raw_fd()),
+        stdout: stdout_pipe.as_ref().map(|(_, write_end)| write_end.as_raw_fd()),
+        stderr: stderr_pipe.as_ref().map(|(_, write_end)| write_end.as_raw_fd()),
+    };
+    let states = spawn_piped_cmd_chain(cmds, &io, opts)?;
+
+    // dropping the child's ends closes them in the parent
+    let streams = CapturedStreams {
+        stdin: stdin_pipe.map(|(_, write_end)| write_end),
+        stdout: stdout_pipe.map(|(read_end, _)| read_end),
+        stderr: stderr_pipe.map(|(read_end, _)| read_end),
+    };
+    Ok((states, streams))
+}
+
+/// Spawns a command chain and transfers the ownership of the read end of
+/// the pipe connected to STDOUT of the last command to the caller, e.g. to
+/// integrate it into an own event loop (epoll, mio, ...). The crate doesn't
+/// read from it and doesn't wait for the processes.
+///
+/// The caller is responsible to read until EOF (or to drop the `File`),
+/// otherwise the chain blocks as soon as the pipe buffer is full. Afterwards
+/// the processes must be waited for with `update_process_states()`.
+/// The `File` can be converted into an `OwnedFd` if needed.
+pub fn spawn_piped_cmd_chain_capture_fd(cmds: &CmdChain) -> Result<(Vec<ProcessState>, File), PiperError> {
+    spawn_captured(cmds, &ExecOptions::default())
+}
+
+/// Runs a command chain and returns STDOUT of the last command as buffer
+/// instead of letting it inherit the STDOUT of the parent, e.g. for tests
+/// or to embed the output in a TUI. STDERR is still inherited. If the last
+/// command has an output redirect, the output goes into the file and the
+/// buffer is empty.
+///
+/// The parent reads until EOF before it waits for the processes, hence
+/// output bigger than the pipe buffer can't deadlock the chain.
+/// Waits for all processes, even if the chain is marked as background,
+/// but fails if it's required to run in background.
+pub fn execute_piped_cmd_chain_capture(cmds: &CmdChain) -> Result<(Vec<ProcessState>, Vec<u8>), PiperError> {
+    let mut stdout = vec![];
+    let states = execute_piped_cmd_chain_into(cmds, &mut stdout)?;
+    Ok((states, stdout))
+}
+
+/// Like `execute_piped_cmd_chain_capture()` but returns the output as
+/// `String`, e.g. for commands that print text. Invalid UTF-8 sequences
+/// are replaced with `U+FFFD`.
+pub fn execute_piped_cmd_chain_capture_string(cmds: &CmdChain) -> Result<(Vec<ProcessState>, String), PiperError> {
+    let (states, stdout) = execute_piped_cmd_chain_capture(cmds)?;
+    let stdout = String::from_utf8(stdout).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+    Ok((states, stdout))
+}
+
+/// Runs a command chain and copies STDOUT of the last command into `sink`,
+/// e.g. a `Vec<u8>`, a socket or a `Box<dyn Write>`. Behaves like
+/// `execute_piped_cmd_chain_capture()` otherwise.
+///
+/// If writing into the sink fails, the parent stops reading and closes the
+/// pipe, i.e. the last command gets `SIGPIPE` on its next write. The
+/// processes are waited for before `PiperError::SinkWriteFailed` is returned.
+pub fn execute_piped_cmd_chain_into<W: Write + ?Sized>(cmds: &CmdChain, sink: &mut W) -> Result<Vec<ProcessState>, PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    let (mut states, mut read_end) = spawn_captured(cmds, &ExecOptions::default())?;
+    let watchdog = Watchdog::start(&states, deadline);
+
+    let mut buf = [0_u8; 4096];
+    let mut res = Ok(());
+    loop {
+        match read_end.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                if let Err(e) = sink.write_all(&buf[..n]) {
+                    res = Err(PiperError::SinkWriteFailed(e.raw_os_error().unwrap_or(libc::EIO)));
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // errors are treated like EOF; the processes are waited for anyway
+            Err(_) => break,
+        }
+    }
+    drop(read_end);
+
+    watchdog.wait(&mut states)?;
+    res.map(|_| states)
+}
+
+/// Runs a command chain and calls `on_chunk` with each chunk that is read
+/// from STDOUT of the last command, e.g. for incremental parsing or progress
+/// indicators without buffering the whole output. Chunks have no relation
+/// to lines or to the `write()`s of the command; the concatenation of all
+/// chunks is the complete output. Waits for the processes after EOF.
+///
+/// If `on_chunk` panics, the parent stops reading, kills all processes of
+/// the chain that are still running with `SIGKILL`, waits for them and
+/// resumes the panic. STDERR is inherited. Fails if the chain is required
+/// to run in background.
+pub fn execute_piped_cmd_chain_streaming<F: FnMut(&[u8])>(cmds: &CmdChain,
+                                                          mut on_chunk: F) -> Result<Vec<ProcessState>, PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    let (mut states, mut read_end) = spawn_captured(cmds, &ExecOptions::default())?;
+    let watchdog = Watchdog::start(&states, deadline);
+
+    let mut buf = [0_u8; 4096];
+    let res = panic::catch_unwind(AssertUnwindSafe(|| loop {
+        match read_end.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => on_chunk(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // errors are treated like EOF; the processes are waited for anyway
+            Err(_) => break,
+        }
+    }));
+
+    drop(read_end);
+    if let Err(panic) = res {
+        let _ = watchdog.kill(&mut states);
+        panic::resume_unwind(panic);
+    }
+    watchdog.wait(&mut states)?;
+    Ok(states)
+}
+
+/// What the parent observed while running a chain with
+/// `execute_piped_cmd_chain_traced()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChainEvent {
+    /// The write end of the pipe connected to STDOUT of the last command
+    /// was closed by all processes, i.e. the parent read EOF.
+    StdoutEof,
+    /// The process of the command with this index in the chain finished.
+    ProcessFinished {
+        index: usize,
+        pid: libc::pid_t,
+        outcome: ProcessOutcome,
+    },
+}
+
+/// Like `execute_piped_cmd_chain_capture()`, but reports the events that
+/// end a chain to `on_event` in the order the parent observed them, e.g. to
+/// learn about the cascade of closures: `cat file | grep a | wc -l` ends
+/// when `cat` exits, because this closes the only write end of the pipe to
+/// `grep`, which sees EOF and exits, and so on.
+///
+/// The parent only sees the pipe to itself, not the pipes between the
+/// commands; their EOFs show up as the exits of the readers. The exits
+/// are polled (every 10ms), hence an EOF can be reported before the exit
+/// of the process that closed the pipe. Waits for all processes, even if
+/// the chain is marked as background, but fails if it's required to run
+/// in background.
+pub fn execute_piped_cmd_chain_traced<F: FnMut(ChainEvent)>(cmds: &CmdChain,
+                                                            mut on_event: F) -> Result<(Vec<ProcessState>, Vec<u8>), PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    let (mut states, mut read_end) = spawn_captured(cmds, &ExecOptions::default())?;
+
+    let mut stdout = vec![];
+    let mut buf = [0_u8; 4096];
+    let mut eof = false;
+    // the last signal that was sent because of the timeout
+    let mut sent = None;
+    loop {
+        signal_due(&states, deadline, &mut sent);
+        if !eof {
+            let mut poll_fd = libc::pollfd { fd: read_end.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll_fd, 1, 10) } > 0 {
+                match read_end.read(&mut buf) {
+                    Ok(n) if n > 0 => stdout.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    // errors are treated like EOF; the processes are waited for anyway
+                    _ => {
+                        eof = true;
+                        on_event(ChainEvent::StdoutEof);
+                    }
+                }
+            }
+        }
+
+        let running: Vec<bool> = states.iter().map(|state| !state.finished()).collect();
+        let all_finished = if eof {
+            try_update_process_states_timeout(&mut states, Duration::from_millis(10))?
+        } else {
+            try_update_process_states(&mut states, true)?
+        };
+        states.iter().enumerate()
+            .filter(|(i, state)| running[*i] && state.finished())
+            .for_each(|(index, state)| {
+                on_event(ChainEvent::ProcessFinished { index, pid: state.pid(), outcome: state.outcome() })
+            });
+        if eof && all_finished {
+            break;
+        }
+    }
+    if sent.is_some() {
+        states.iter_mut().for_each(|state| state.set_timed_out());
+    }
+    Ok((states, stdout))
+}
+
+/// The result of `execute_piped_cmd_chain_output()`.
+#[derive(Debug)]
+pub struct ChainOutput {
+    /// States of the finished processes.
+    states: Vec<ProcessState>,
+    /// STDOUT of the last command.
+    stdout: Vec<u8>,
+    /// STDERR of all commands.
+    stderr: Vec<u8>,
+}
+
+impl ChainOutput {
+    /// Getter for states.
+    pub fn states(&self) -> &Vec<ProcessState> {
+        &self.states
+    }
+    /// Getter for stdout.
+    pub fn stdout(&self) -> &Vec<u8> {
+        &self.stdout
+    }
+    /// Getter for stderr.
+    pub fn stderr(&self) -> &Vec<u8> {
+        &self.stderr
+    }
+}
+
+/// Runs a command chain and captures STDOUT of the last command and STDERR
+/// of all commands. Waits for all processes, even if the chain is marked as
+/// background, but fails if it's required to run in background.
+///
+/// Each of the two pipes is drained by its own reader thread while the
+/// parent waits for the processes; the threads are joined afterwards. This
+/// avoids the classic deadlock where the parent blocks on reading one
+/// stream while a child blocks on writing the other (full) one, without
+/// the need for non-blocking IO.
+pub fn execute_piped_cmd_chain_output(cmds: &CmdChain) -> Result<ChainOutput, PiperError> {
+    run_captured(cmds, None::<Vec<String>>)
+}
+
+/// Runs a command chain and writes each of the `lines` (followed by a
+/// newline) to STDIN of the first command, e.g. to feed records to a
+/// filter. STDIN is closed after the last line, so that the command sees
+/// EOF. Waits for all processes and returns the captured output like
+/// `execute_piped_cmd_chain_output()`.
+///
+/// The lines are written by the calling thread while STDOUT and STDERR are
+/// drained by reader threads, hence a command that writes a lot of output
+/// before it reads all of its input can't deadlock the chain. If the first
+/// command exits without reading all lines, the remaining lines are dropped.
+pub fn execute_feeding_lines<I: IntoIterator<Item=String>>(cmds: &CmdChain, lines: I) -> Result<ChainOutput, PiperError> {
+    run_captured(cmds, Some(lines))
+}
+
+/// Runs a command chain and copies everything from `reader` into STDIN of
+/// the first command, e.g. from a file, a socket or a decompressor. STDIN
+/// is closed at the end of `reader`, so that the command sees EOF. STDOUT
+/// and STDERR are inherited.
+///
+/// The calling thread copies the input and then waits for the processes,
+/// even if the chain is marked as background, but fails if it's required
+/// to run in background. If the first command exits without reading all
+/// input, copying stops and the rest stays in `reader`. If `reader` fails,
+/// STDIN is closed and `PiperError::SourceReadFailed` is returned after the
+/// processes finished.
+pub fn execute_piped_cmd_chain_from_reader<R: Read + ?Sized>(cmds: &CmdChain, reader: &mut R) -> Result<Vec<ProcessState>, PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    let (mut states, streams) = spawn_captured_streams(cmds, &ExecOptions::default(), true, false, false)?;
+    let watchdog = Watchdog::start(&states, deadline);
+    let mut stdin = streams.stdin.unwrap();
+
+    let mut buf = [0_u8; 4096];
+    let mut res = Ok(());
+    let sigpipe = SigpipeBlocked::new();
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            // EPIPE: the command doesn't read anymore
+            Ok(n) => if stdin.write_all(&buf[..n]).is_err() {
+                break;
+            },
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                res = Err(PiperError::SourceReadFailed(e.raw_os_error().unwrap_or(libc::EIO)));
+                break;
+            }
+        }
+    }
+    drop(stdin);
+    drop(sigpipe);
+
+    watchdog.wait(&mut states)?;
+    res.map(|_| states)
+}
+
+/// Runs the chain with captured STDOUT and STDERR and optionally feeds
+/// lines into STDIN of the first command.
+fn run_captured<I: IntoIterator<Item=String>>(cmds: &CmdChain, lines: Option<I>) -> Result<ChainOutput, PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    let (mut states, streams) = spawn_captured_streams(cmds, &ExecOptions::default(), lines.is_some(), true, true)?;
+    let watchdog = Watchdog::start(&states, deadline);
+
+    let drain = |mut read_end: File| {
+        std::thread::spawn(move || {
+            let mut buf = vec![];
+            // errors are treated like EOF; the processes are waited for anyway
+            let _ = read_end.read_to_end(&mut buf);
+            buf
+        })
+    };
+    let stdout_thread = drain(streams.stdout.unwrap());
+    let stderr_thread = drain(streams.stderr.unwrap());
+
+    if let (Some(lines), Some(stdin)) = (lines, streams.stdin) {
+        let _sigpipe = SigpipeBlocked::new();
+        let mut writer = BufWriter::new(stdin);
+        for line in lines {
+            // EPIPE: the command doesn't read anymore
+            if writer.write_all(line.as_bytes()).and_then(|_| writer.write_all(b"\n")).is_err() {
+                break;
+            }
+        }
+        // flushes and closes STDIN; an error means the same as above
+        let _ = writer.flush();
+    }
+
+    watchdog.wait(&mut states)?;
+    Ok(ChainOutput {
+        states,
+        stdout: stdout_thread.join().expect("reader thread for STDOUT panicked"),
+        stderr: stderr_thread.join().expect("reader thread for STDERR panicked"),
+    })
+}
+
+/// Spawns a command chain and returns an iterator over the lines of
+/// STDOUT of the last command as they arrive, e.g. to process the output
+/// of a long-running chain incrementally. See `ChainLines`.
+pub fn execute_piped_cmd_chain_lines(cmds: &CmdChain) -> Result<ChainLines, PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    let (states, read_end) = spawn_captured(cmds, &ExecOptions::default())?;
+    Ok(ChainLines {
+        watchdog: Some(Watchdog::start(&states, deadline)),
+        states,
+        reader: Some(BufReader::new(read_end)),
+    })
+}
+
+/// Iterator over the lines of the output of a chain, see
+/// `execute_piped_cmd_chain_lines()`. Like `BufRead::lines()` the lines
+/// have no trailing newline and a line that isn't valid UTF-8 is an
+/// error of kind `InvalidData`. At EOF the processes are waited for
+/// before the iterator ends. Dropping it earlier kills the processes that
+/// are still running with `SIGKILL` and waits for them.
+#[derive(Debug)]
+pub struct ChainLines {
+    states: Vec<ProcessState>,
+    /// `None` after EOF.
+    reader: Option<BufReader<File>>,
+    /// Enforces the timeout of the chain; `None` after EOF.
+    watchdog: Option<Watchdog>,
+}
+
+impl ChainLines {
+    /// Getter for states. The processes are finished after the iterator
+    /// returned `None`.
+    pub fn states(&self) -> &Vec<ProcessState> {
+        &self.states
+    }
+
+    /// Consumes the iterator and returns the states. Like dropping it,
+    /// this kills the processes if the iterator didn't reach EOF yet.
+    pub fn into_states(mut self) -> Vec<ProcessState> {
+        self.finish(true);
+        std::mem::take(&mut self.states)
+    }
+
+    /// Closes the pipe and waits for the processes, optionally after
+    /// killing them.
+    fn finish(&mut self, kill: bool) {
+        let (reader, watchdog) = match (self.reader.take(), self.watchdog.take()) {
+            (Some(reader), Some(watchdog)) => (reader, watchdog),
+            _ => return,
+        };
+        drop(reader);
+        // errors mean that the processes were reaped elsewhere
+        let _ = if kill { watchdog.kill(&mut self.states) } else { watchdog.wait(&mut self.states) };
+    }
+}
+
+impl Iterator for ChainLines {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = self.reader.as_mut()?;
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                self.finish(false);
+                None
+            }
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                }
+                Some(Ok(line))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Drop for ChainLines {
+    fn drop(&mut self) {
+        self.finish(true);
+    }
+}
+
+/// Maximum length of a line in `capture_tail()` in bytes. The rest of a
+/// longer line is discarded.
+pub const TAIL_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Like `BufRead::read_until(b'\n')` but appends at most `max` bytes to
+/// `line`. The rest of the line is consumed anyway. Returns the number of
+/// bytes consumed, i.e. 0 at EOF.
+fn read_line_bounded<R: BufRead>(reader: &mut R, line: &mut Vec<u8>, max: usize) -> std::io::Result<usize> {
+    let mut consumed = 0;
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf.is_empty() {
+            return Ok(consumed);
+        }
+        let (len, done) = match buf.iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        let keep = len.min(max.saturating_sub(line.len()));
+        line.extend_from_slice(&buf[..keep]);
+        reader.consume(len);
+        consumed += len;
+        if done {
+            return Ok(consumed);
+        }
+    }
+}
+
+/// Runs a command chain and keeps only the last `n_lines` lines of the
+/// output of the last command (like `tail -n`). The memory usage is
+/// bounded, no matter how much output the chain produces. Lines are
+/// returned without the trailing newline; invalid UTF-8 is replaced.
+/// Lines longer than `TAIL_MAX_LINE_LEN` bytes are truncated.
+/// Waits for all processes, even if the chain is marked as background,
+/// but fails if it's required to run in background.
+pub fn capture_tail(cmds: &CmdChain, n_lines: usize) -> Result<(Vec<ProcessState>, Vec<String>), PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    let (mut states, read_end) = spawn_captured(cmds, &ExecOptions::default())?;
+    let watchdog = Watchdog::start(&states, deadline);
+
+    let mut reader = BufReader::new(read_end);
+    // `n_lines` may be huge, only the lines that actually arrive are allocated
+    let mut lines: VecDeque<String> = VecDeque::with_capacity(n_lines.min(1024));
+    let mut line = vec![];
+    // errors are treated like EOF; the processes are waited for anyway
+    while let Ok(n) = read_line_bounded(&mut reader, &mut line, TAIL_MAX_LINE_LEN) {
+        if n == 0 {
+            break;
+        }
+        if n_lines > 0 {
+            if lines.len() == n_lines {
+                lines.pop_front();
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            lines.push_back(String::from_utf8_lossy(&line).into_owned());
+        }
+        line.clear();
+    }
+    drop(reader);
+
+    watchdog.wait(&mut states)?;
+    Ok((states, lines.into_iter().collect()))
+}
+
+/// Options of `capture_output_with()`.
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    /// Optional maximum size of the output in bytes.
+    limit: Option<usize>,
+    /// Whether trailing newlines are removed.
+    strip_trailing_newlines: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CaptureOptions {
+    /// The options of a command substitution: no limit and trailing
+    /// newlines are removed.
+    pub fn new() -> Self {
+        CaptureOptions {
+            limit: None,
+            strip_trailing_newlines: true,
+        }
+    }
+
+    /// Fails the capture with `PiperError::OutputLimitExceeded` as soon as
+    /// the output gets bigger than `limit` bytes, e.g. to protect a shell
+    /// against `$(cat /dev/zero)`. The processes that are still running
+    /// are killed with `SIGKILL` then.
+    pub fn set_limit(mut self, limit: usize) -> Self {
+        self.limit.replace(limit);
+        self
+    }
+
+    /// Whether all trailing newlines (`\n`) are removed from the output,
+    /// like a shell does for `$(...)`. Enabled by default.
+    pub fn set_strip_trailing_newlines(mut self, strip: bool) -> Self {
+        self.strip_trailing_newlines = strip;
+        self
+    }
+
+    /// Getter for limit.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+    /// Getter for strip_trailing_newlines.
+    pub fn strip_trailing_newlines(&self) -> bool {
+        self.strip_trailing_newlines
+    }
+}
+
+/// Runs a command chain like a command substitution `$(...)` of a shell:
+/// returns STDOUT of the last command without the trailing newlines and
+/// the exit status of the chain (see `CmdChain::result()`). Invalid UTF-8
+/// sequences are replaced with `U+FFFD`. See `capture_output_with()`.
+pub fn capture_output(cmds: &CmdChain) -> Result<(String, ChainResult), PiperError> {
+    capture_output_with(cmds, &CaptureOptions::new())
+}
+
+/// Like `capture_output()` but with the given options, e.g. a size limit.
+/// STDERR is inherited. Waits for all processes, even if the chain is
+/// marked as background, but fails if it's required to run in background.
+pub fn capture_output_with(cmds: &CmdChain, capture_opts: &CaptureOptions) -> Result<(String, ChainResult), PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds);
+    let (mut states, mut read_end) = spawn_captured(cmds, &ExecOptions::default())?;
+    let watchdog = Watchdog::start(&states, deadline);
+
+    let mut stdout = vec![];
+    let mut buf = [0_u8; 4096];
+    let mut exceeded = false;
+    loop {
+        match read_end.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                stdout.extend_from_slice(&buf[..n]);
+                if capture_opts.limit.is_some_and(|limit| stdout.len() > limit) {
+                    exceeded = true;
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // errors are treated like EOF; the processes are waited for anyway
+            Err(_) => break,
+        }
+    }
+    drop(read_end);
+
+    if exceeded {
+        watchdog.kill(&mut states)?;
+        return Err(PiperError::OutputLimitExceeded(capture_opts.limit.unwrap()));
+    }
+    watchdog.wait(&mut states)?;
+
+    if capture_opts.strip_trailing_newlines {
+        let len = stdout.iter().rposition(|b| *b != b'\n').map_or(0, |i| i + 1);
+        stdout.truncate(len);
+    }
+    let stdout = String::from_utf8(stdout).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+    Ok((stdout, cmds.result(&states)))
+}
+
+/// The output that was captured by `run_with_deadline()`.
+#[derive(Debug)]
+pub struct PartialOutput {
+    /// States of the finished (or killed) processes.
+    states: Vec<ProcessState>,
+    /// STDOUT of the last command up to the deadline.
+    stdout: Vec<u8>,
+}
+
+impl PartialOutput {
+    /// Getter for states.
+    pub fn states(&self) -> &Vec<ProcessState> {
+        &self.states
+    }
+    /// Getter for stdout.
+    pub fn stdout(&self) -> &Vec<u8> {
+        &self.stdout
+    }
+}
+
+/// Runs a command chain and captures STDOUT of the last command until it
+/// is closed or until the `deadline` is reached, whatever comes first, e.g.
+/// for "get as much as you can in N seconds" scenarios like health probes.
+/// At the deadline the parent stops reading and kills all processes of the
+/// chain that are still running with `SIGKILL`. Returns the output that was
+/// read up to then and whether the deadline was reached (timed out); the
+/// states are marked as timed out then, see `ProcessState::timed_out()`.
+/// If the chain has a timeout that expires earlier, that one is used instead.
+/// Waits for all processes, even if the chain is marked as background,
+/// but fails if it's required to run in background.
+pub fn run_with_deadline(cmds: &CmdChain, deadline: Instant) -> Result<(PartialOutput, bool), PiperError> {
+    ensure_foreground(cmds)?;
+    let deadline = deadline_of(cmds).map_or(deadline, |own| own.min(deadline));
+    let (mut states, mut read_end) = spawn_captured(cmds, &ExecOptions::default())?;
+
+    let mut stdout = vec![];
+    let mut buf = [0_u8; 4096];
+    let mut timed_out = false;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut poll_fd = libc::pollfd { fd: read_end.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        // round up, otherwise a sub millisecond timeout would spin
+        let remaining_ms = remaining.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128);
+        let res = unsafe { libc::poll(&mut poll_fd, 1, remaining_ms as libc::c_int) };
+        if res == -1 && errno::errno().0 == libc::EINTR {
+            continue;
+        }
+        // without poll() a read() could block past the deadline, hence an
+        // error is treated like the deadline
+        if res <= 0 {
+            timed_out = true;
+            break;
+        }
+        match read_end.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => stdout.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // errors are treated like EOF; the processes are waited for anyway
+            Err(_) => break,
+        }
+    }
+
+    // don't let a command block on a full pipe that nobody reads anymore
+    drop(read_end);
+    // after EOF a command may still run, e.g. if it closed its STDOUT
+    if !timed_out && !try_wait_all_timeout(&mut states, deadline.saturating_duration_since(Instant::now()))? {
+        timed_out = true;
+    }
+    if timed_out {
+        let _ = signal_chain(&states, libc::SIGKILL);
+        states.iter_mut().for_each(|state| state.set_timed_out());
+    }
+    try_update_process_states(&mut states, false)?;
+    Ok((PartialOutput { states, stdout }, timed_out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{update_process_states, BasicCmdBuilder, Builder, CmdChainBuilder};
+
+    #[test]
+    fn test_capture_tail() {
+        // 'seq 1 10000'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("seq")
+                    .add_arg("seq")
+                    .add_arg("1")
+                    .add_arg("10000")
+            ).build();
+
+        let (states, lines) = capture_tail(&cmd_chain, 3).unwrap();
+        assert_eq!(states[0].exit_code(), 0);
+        assert_eq!(lines, vec!["9998", "9999", "10000"]);
+
+        // doesn't preallocate `n_lines`
+        let (_, lines) = capture_tail(&cmd_chain, usize::MAX).unwrap();
+        assert_eq!(lines.len(), 10000);
+
+        // 'head -c 100000 /dev/zero': a single line without newline is truncated
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("head")
+                    .add_arg("head")
+                    .add_arg("-c")
+                    .add_arg("100000")
+                    .add_arg("/dev/zero")
+            ).build();
+        let (_, lines) = capture_tail(&cmd_chain, 1).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].len(), TAIL_MAX_LINE_LEN);
+    }
+
+    #[test]
+    fn test_spawn_capture_fd() {
+        // 'echo hello | tr a-z A-Z'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("hello")
+            ).add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("tr")
+                    .add_arg("tr")
+                    .add_arg("a-z")
+                    .add_arg("A-Z")
+            ).build();
+
+        let (mut states, mut read_end) = spawn_piped_cmd_chain_capture_fd(&cmd_chain).unwrap();
+        let mut output = String::new();
+        read_end.read_to_string(&mut output).unwrap();
+        update_process_states(&mut states, false);
+        assert_eq!(output, "HELLO\n");
+    }
+
+    #[test]
+    fn test_execute_output_large_stdout_and_stderr() {
+        // both streams are much bigger than the pipe buffer and written concurrently
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("seq 1 100000 & seq 1 100000 >&2; wait")
+            ).build();
+
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(output.states()[0].exit_code(), 0);
+        let expected: String = (1..=100000).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(output.stdout(), expected.as_bytes());
+        assert_eq!(output.stderr(), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_execute_feeding_lines() {
+        // 'wc -l'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("wc")
+                    .add_arg("wc")
+                    .add_arg("-l")
+            ).build();
+
+        let lines = (0..1000).map(|i| format!("record {}", i));
+        let output = execute_feeding_lines(&cmd_chain, lines).unwrap();
+        assert_eq!(output.states()[0].exit_code(), 0);
+        assert_eq!(String::from_utf8_lossy(output.stdout()).trim(), "1000");
+    }
+
+    #[test]
+    fn test_cmd_env() {
+        // "FOO=bar sh -c 'echo $FOO $HOME'"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo $FOO $HOME")
+                    .add_env("FOO", "bar")
+            ).build();
+        assert_eq!(cmd_chain.to_string(), "FOO=bar sh -c 'echo $FOO $HOME'");
+
+        // variables that are not overridden are inherited
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        let expected = format!("bar {}\n", std::env::var("HOME").unwrap_or_default());
+        assert_eq!(String::from_utf8_lossy(output.stdout()), expected);
+        assert!(std::env::var("FOO").is_err());
+
+        // "env -i FOO=bar sh -c 'echo $FOO $HOME'"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo $FOO $HOME")
+                    .set_env("FOO", "bar")
+                    .clear_env()
+            ).build();
+        assert_eq!(cmd_chain.to_string(), "env -i FOO=bar sh -c 'echo $FOO $HOME'");
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(String::from_utf8_lossy(output.stdout()), "bar\n");
+    }
+
+    #[test]
+    fn test_run_with_deadline() {
+        // slow producer: "sh -c 'echo first; exec sleep 5'"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo first; exec sleep 5")
+            ).build();
+
+        let begin = Instant::now();
+        let (output, timed_out) = run_with_deadline(&cmd_chain, begin + std::time::Duration::from_millis(300)).unwrap();
+        assert!(timed_out);
+        assert_eq!(output.stdout(), b"first\n");
+        assert!(output.states()[0].finished());
+        assert!(output.states()[0].timed_out());
+        assert!(begin.elapsed() < std::time::Duration::from_secs(3));
+
+        // EOF before the deadline, but the process keeps running
+        let cmd_chain = crate::parse_cmd_chain("sh -c 'exec >&-; sleep 5'").unwrap();
+        let begin = Instant::now();
+        let (output, timed_out) = run_with_deadline(&cmd_chain, begin + std::time::Duration::from_millis(300)).unwrap();
+        assert!(timed_out);
+        assert_eq!(output.states()[0].term_signal(), Some(libc::SIGKILL));
+        assert!(output.states()[0].timed_out());
+        assert!(begin.elapsed() < std::time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_execute_capture() {
+        // 'cat < src/bin/testfile_65kb.txt | cat', bigger than the pipe buffer
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+                    .set_input_redirect_path("src/bin/testfile_65kb.txt")
+            ).add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+            ).build();
+        let (states, stdout) = execute_piped_cmd_chain_capture(&cmd_chain).unwrap();
+        assert!(states.iter().all(|s| s.exit_code() == 0));
+        assert_eq!(stdout, std::fs::read("src/bin/testfile_65kb.txt").unwrap());
+
+        // the output redirect has precedence
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_capture_{}.txt", std::process::id()));
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("hello")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+        let (_, stdout) = execute_piped_cmd_chain_capture(&cmd_chain).unwrap();
+        assert!(stdout.is_empty());
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "hello\n");
+        std::fs::remove_file(&out_file).unwrap();
+
+        // 'printf "a\377b" | cat' as string
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("printf").add_arg("printf").add_arg("a\\377b"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+            .build();
+        let (_, stdout) = execute_piped_cmd_chain_capture_string(&cmd_chain).unwrap();
+        assert_eq!(stdout, "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn test_execute_streaming() {
+        // 'seq 1 100000'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("seq")
+                    .add_arg("seq")
+                    .add_arg("1")
+                    .add_arg("100000")
+            ).build();
+        let mut output = vec![];
+        let mut chunks = 0;
+        let states = execute_piped_cmd_chain_streaming(&cmd_chain, |chunk| {
+            output.extend_from_slice(chunk);
+            chunks += 1;
+        }).unwrap();
+        assert!(states.iter().all(|s| s.finished()));
+        assert!(chunks > 1);
+        let expected: String = (1..=100000).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(output, expected.into_bytes());
+
+        // 'yes' never ends: a panic in the closure kills it
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("yes").add_arg("yes"))
+            .build();
+        let res = panic::catch_unwind(|| {
+            execute_piped_cmd_chain_streaming(&cmd_chain, |_| panic!("stop"))
+        });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_execute_traced() {
+        // 'sh -c "echo hello; exec >&-; sleep 0.2" | cat': cat sees EOF before sh exits
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo hello; exec >&-; sleep 0.2")
+            ).add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+            .build();
+        let mut events = vec![];
+        let (states, stdout) = execute_piped_cmd_chain_traced(&cmd_chain, |event| events.push(event)).unwrap();
+        assert_eq!(stdout, b"hello\n");
+        // the EOF and the exit of cat can be observed in any order
+        assert_eq!(events.len(), 3);
+        assert!(events[..2].contains(&ChainEvent::StdoutEof));
+        assert_eq!(events[2], ChainEvent::ProcessFinished {
+            index: 0,
+            pid: states[0].pid(),
+            outcome: ProcessOutcome::Exited(0),
+        });
+    }
+
+    #[test]
+    fn test_execute_into() {
+        /// Counts the bytes instead of storing them.
+        struct CountingWriter(usize);
+        impl Write for CountingWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 += buf.len();
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // 'seq 1 10000'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("seq")
+                    .add_arg("seq")
+                    .add_arg("1")
+                    .add_arg("10000")
+            ).build();
+        let expected: String = (1..=10000).map(|i| format!("{}\n", i)).collect();
+
+        let mut sink: Box<dyn Write> = Box::new(vec![]);
+        execute_piped_cmd_chain_into(&cmd_chain, &mut sink).unwrap();
+        let mut vec = vec![];
+        execute_piped_cmd_chain_into(&cmd_chain, &mut vec).unwrap();
+        assert_eq!(vec, expected.as_bytes());
+
+        let mut counter = CountingWriter(0);
+        let states = execute_piped_cmd_chain_into(&cmd_chain, &mut counter).unwrap();
+        assert_eq!(counter.0, expected.len());
+        assert_eq!(states[0].exit_code(), 0);
+
+        // a failing sink
+        let mut full: &mut [u8] = &mut [0_u8; 10];
+        let err = execute_piped_cmd_chain_into(&cmd_chain, &mut full).unwrap_err();
+        assert_eq!(err, PiperError::SinkWriteFailed(libc::EIO));
+    }
+
+    #[test]
+    fn test_execute_from_reader() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_from_reader_{}.txt", std::process::id()));
+        // 'wc -c > out_file' with 100 kB input, more than the pipe buffer
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("wc")
+                    .add_arg("wc")
+                    .add_arg("-c")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+        let mut reader = std::io::Cursor::new(vec![b'x'; 100_000]);
+        let states = execute_piped_cmd_chain_from_reader(&cmd_chain, &mut reader).unwrap();
+        assert_eq!(states[0].exit_code(), 0);
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap().trim(), "100000");
+        std::fs::remove_file(&out_file).unwrap();
+
+        // a failing reader
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::from_raw_os_error(libc::EBADF))
+            }
+        }
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+            .build();
+        let err = execute_piped_cmd_chain_from_reader(&cmd_chain, &mut FailingReader).unwrap_err();
+        assert_eq!(err, PiperError::SourceReadFailed(libc::EBADF));
+
+        // 'true' doesn't read: copying stops at EPIPE, the rest stays in the reader
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .build();
+        let mut reader = std::io::Cursor::new(vec![b'x'; 10_000_000]);
+        let states = execute_piped_cmd_chain_from_reader(&cmd_chain, &mut reader).unwrap();
+        assert_eq!(states[0].exit_code(), 0);
+        assert!(reader.position() < 10_000_000);
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+            .require_background()
+            .set_background(true)
+            .build();
+        let err = execute_piped_cmd_chain_from_reader(&cmd_chain, &mut std::io::empty()).unwrap_err();
+        assert_eq!(err.errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_heredoc() {
+        // "cat <<EOF | wc -l", "cat <<< word"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat").set_heredoc("line 1\nline 2\n"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("wc").add_arg("wc").add_arg("-l"))
+            .build();
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(String::from_utf8_lossy(output.stdout()).trim(), "2");
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat").set_here_string("word"))
+            .build();
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(output.stdout(), b"word\n");
+    }
+
+    #[test]
+    fn test_capture_output() {
+        let cmd_chain = crate::parse_cmd_chain("printf 'a\\n\\nb\\n\\n\\n'").unwrap();
+        let (stdout, result) = capture_output(&cmd_chain).unwrap();
+        assert_eq!(stdout, "a\n\nb");
+        assert!(result.success());
+
+        let opts = CaptureOptions::new().set_strip_trailing_newlines(false);
+        let (stdout, _) = capture_output_with(&cmd_chain, &opts).unwrap();
+        assert_eq!(stdout, "a\n\nb\n\n\n");
+
+        let cmd_chain = crate::parse_cmd_chain("sh -c 'echo out; exit 4'").unwrap();
+        let (stdout, result) = capture_output(&cmd_chain).unwrap();
+        assert_eq!(stdout, "out");
+        assert_eq!(result.exit_code(), 4);
+
+        // would never end without the limit
+        let cmd_chain = crate::parse_cmd_chain("yes").unwrap();
+        let res = capture_output_with(&cmd_chain, &CaptureOptions::new().set_limit(100_000));
+        assert_eq!(res.unwrap_err(), PiperError::OutputLimitExceeded(100_000));
+    }
+
+    #[test]
+    fn test_execute_lines() {
+        let cmd_chain = crate::parse_cmd_chain("printf 'a\\nb\\nc'").unwrap();
+        let mut lines = execute_piped_cmd_chain_lines(&cmd_chain).unwrap();
+        let collected = lines.by_ref().collect::<std::io::Result<Vec<String>>>().unwrap();
+        assert_eq!(collected, vec!["a", "b", "c"]);
+        assert!(lines.states().iter().all(|s| s.finished()));
+
+        // 'yes' never ends: dropping the iterator kills it
+        let cmd_chain = crate::parse_cmd_chain("yes").unwrap();
+        let mut lines = execute_piped_cmd_chain_lines(&cmd_chain).unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "y");
+        let states = lines.into_states();
+        assert_eq!(states[0].term_signal(), Some(libc::SIGKILL));
+    }
+
+    #[test]
+    fn test_timeout() {
+        // 'sleep 10 | cat' with a timeout: the executors must not wait for the EOF
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("10"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+            .set_timeout(std::time::Duration::from_millis(100))
+            .build();
+        let timed_out = |states: &[ProcessState]| states.iter().all(|state| state.finished() && state.timed_out());
+
+        let begin = Instant::now();
+        let (states, _) = execute_piped_cmd_chain_capture(&cmd_chain).unwrap();
+        assert!(timed_out(&states));
+        assert_eq!(states[0].term_signal(), Some(libc::SIGTERM));
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert!(timed_out(output.states()));
+        let (states, _) = execute_piped_cmd_chain_traced(&cmd_chain, |_| {}).unwrap();
+        assert!(timed_out(&states));
+        let (_, result) = capture_output(&cmd_chain).unwrap();
+        assert_eq!(result.exit_code(), 128 + libc::SIGTERM);
+        let mut lines = execute_piped_cmd_chain_lines(&cmd_chain).unwrap();
+        assert!(lines.next().is_none());
+        assert!(timed_out(&lines.into_states()));
+        assert!(begin.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_background_required() {
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .require_background()
+            .set_background(true)
+            .build();
+        let err = execute_piped_cmd_chain_capture(&cmd_chain).unwrap_err();
+        assert_eq!(err, PiperError::InvalidChain(crate::BuildError::ModeMismatch {
+            required: crate::ChainMode::Background,
+            actual: crate::ChainMode::Foreground,
+        }));
+        assert_eq!(err.errno(), libc::EINVAL);
+    }
+}
@@ -0,0 +1,668 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! "Echo-only" run of a shell command line: the line gets parsed and expanded
+//! (variables, `~`, globs), executables and redirect paths get validated and
+//! the resolved plan gets printed. This never forks and never writes
+//! to the file system. Whether the file system may be read at all is
+//! controlled by `FsAccess`.
+//!
+//! `CmdChain::preflight()` does the same checks (and more) for an already
+//! built chain.
+
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use crate::parse::{build_chain, parse_tokens, tokenize, ParseError, Quoting, Word};
+use crate::{diagnose_exec_error, CmdChain, ExecDiagnosis};
+
+/// The file system access the echo-only run may do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FsAccess {
+    /// No access at all. Globs are kept as they are and executables
+    /// and redirect paths are not validated.
+    None,
+    /// Read-only access to the real file system (`stat()`, `access()`,
+    /// reading directories).
+    ReadOnly,
+    /// A virtual file system described by the list of all existing files
+    /// (absolute or relative paths). All of them are considered to be
+    /// readable and executable; their parent directories exist implicitly.
+    Virtual(Vec<String>),
+}
+
+impl FsAccess {
+    /// Whether `path` is an existing file that can be executed.
+    fn is_executable(&self, path: &Path) -> bool {
+        match self {
+            FsAccess::None => false,
+            FsAccess::ReadOnly => {
+                std::fs::metadata(path).map(|m| m.is_file()).unwrap_or(false)
+                    && CString::new(path.as_os_str().as_bytes())
+                    .map(|p| unsafe { libc::access(p.as_ptr(), libc::X_OK) } == 0)
+                    .unwrap_or(false)
+            }
+            FsAccess::Virtual(files) => files.iter().any(|f| OsStr::new(f) == path.as_os_str()),
+        }
+    }
+
+    /// Whether `path` is an existing file that can be read.
+    fn is_readable(&self, path: &Path) -> bool {
+        match self {
+            FsAccess::None => false,
+            FsAccess::ReadOnly => {
+                CString::new(path.as_os_str().as_bytes())
+                    .map(|p| unsafe { libc::access(p.as_ptr(), libc::R_OK) } == 0)
+                    .unwrap_or(false)
+            }
+            FsAccess::Virtual(files) => files.iter().any(|f| OsStr::new(f) == path.as_os_str()),
+        }
+    }
+
+    /// Whether `path` is an existing directory.
+    fn is_dir(&self, path: &Path) -> bool {
+        match self {
+            FsAccess::None => false,
+            FsAccess::ReadOnly => std::fs::metadata(path).map(|m| m.is_dir()).unwrap_or(false),
+            FsAccess::Virtual(files) => {
+                let path = path.as_os_str().as_bytes();
+                path == b"." || path == b"/" || files.iter().any(|f| {
+                    f.as_bytes().starts_with(path) && f.as_bytes().get(path.len()) == Some(&b'/')
+                })
+            }
+        }
+    }
+
+    /// Names of the entries of the directory `dir`.
+    fn list_dir(&self, dir: &str) -> Vec<String> {
+        match self {
+            FsAccess::None => vec![],
+            FsAccess::ReadOnly => std::fs::read_dir(dir)
+                .map(|entries| entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().into_owned())
+                    .collect())
+                .unwrap_or_default(),
+            FsAccess::Virtual(files) => {
+                let mut names: Vec<String> = files.iter()
+                    .filter_map(|f| {
+                        let rel = if dir == "." {
+                            f.strip_prefix("./").unwrap_or(f)
+                        } else {
+                            f.strip_prefix(dir)?.strip_prefix('/')?
+                        };
+                        // first path component below dir
+                        rel.split('/').next().map(|name| name.to_string())
+                    })
+                    .collect();
+                // the files of a subdirectory aren't necessarily adjacent
+                names.sort_unstable();
+                names.dedup();
+                names
+            }
+        }
+    }
+}
+
+/// The resolved plan of an echo-only run.
+#[derive(Debug)]
+pub struct EchoOnlyPlan {
+    /// The parsed and expanded chain.
+    chain: CmdChain,
+    /// Resolved path of each executable, if it was found.
+    executables: Vec<Option<String>>,
+    /// Everything that would prevent a successful execution.
+    problems: Vec<String>,
+}
+
+impl EchoOnlyPlan {
+    /// Getter for chain.
+    pub fn chain(&self) -> &CmdChain {
+        &self.chain
+    }
+    /// Getter for executables.
+    pub fn executables(&self) -> &Vec<Option<String>> {
+        &self.executables
+    }
+    /// Getter for problems.
+    pub fn problems(&self) -> &Vec<String> {
+        &self.problems
+    }
+}
+
+impl fmt::Display for EchoOnlyPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.chain)?;
+        for (i, cmd) in self.chain.cmds().iter().enumerate() {
+            let resolved = self.executables[i].as_deref().unwrap_or("(unresolved)");
+            writeln!(f, "  {}: {} => {}", i + 1, cmd.executable().to_string_lossy(), resolved)?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "  problem: {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses and expands a shell command line, validates the executables and
+/// redirect paths and prints the resolved plan. Guarantees that no process
+/// is forked and that nothing is written to the file system. With
+/// `FsAccess::None` not even the file system is read.
+pub fn echo_only(line: &str, fs: &FsAccess) -> Result<EchoOnlyPlan, ParseError> {
+    let parsed = parse_tokens(tokenize(line)?)?;
+    let chain = build_chain(&parsed, |word| expand_word(word, fs))?;
+
+    let mut executables = vec![];
+    let mut problems = vec![];
+    let check = *fs != FsAccess::None;
+    for cmd in chain.cmds() {
+        let resolved = if check { resolve_executable(cmd.executable(), fs) } else { None };
+        let resolved = resolved.map(|path| path.to_string_lossy().into_owned());
+        if check && resolved.is_none() {
+            // a more helpful message for e.g. scripts without execute bit
+            let diagnosis = match fs {
+                FsAccess::ReadOnly => diagnose_exec_error(cmd.executable(), libc::EACCES),
+                _ => ExecDiagnosis::NotFound,
+            };
+            let diagnosis = match diagnosis {
+                ExecDiagnosis::MissingExecBit { .. } | ExecDiagnosis::IsDirectory { .. } => diagnosis,
+                _ => ExecDiagnosis::NotFound,
+            };
+            problems.push(format!("{}: {}", cmd.executable().to_string_lossy(), diagnosis));
+        }
+        executables.push(resolved);
+
+        if let Some(path) = cmd.in_red_path() {
+            if check && !fs.is_readable(path) {
+                problems.push(format!("{}: input file can't be read", path.display()));
+            }
+        }
+        if let Some(path) = cmd.out_red_path() {
+            if check && !fs.is_dir(dir_of(path)) {
+                problems.push(format!("{}: directory of output file doesn't exist", path.display()));
+            }
+        }
+    }
+
+    let plan = EchoOnlyPlan { chain, executables, problems };
+    println!("{}", plan);
+    Ok(plan)
+}
+
+/// The directory a file `path` would be created in.
+fn dir_of(path: &Path) -> &Path {
+    let bytes = path.as_os_str().as_bytes();
+    match bytes.iter().rposition(|b| *b == b'/') {
+        Some(0) => Path::new("/"),
+        Some(i) => Path::new(OsStr::from_bytes(&bytes[..i])),
+        None => Path::new("."),
+    }
+}
+
+/// How bad a problem found by `CmdChain::preflight()` is.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Severity {
+    /// The chain will fail, e.g. an executable doesn't exist.
+    Error,
+    /// The chain may fail, e.g. because of the limit of open files.
+    Warning,
+}
+
+/// A problem found by `CmdChain::preflight()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreflightIssue {
+    /// Index of the command in the chain; `None` if the issue belongs
+    /// to the whole chain.
+    stage: Option<usize>,
+    severity: Severity,
+    message: String,
+}
+
+impl PreflightIssue {
+    /// Getter for stage.
+    pub fn stage(&self) -> Option<usize> {
+        self.stage
+    }
+    /// Getter for severity.
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+    /// Getter for message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for PreflightIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.stage {
+            Some(stage) => write!(f, "{} (stage {}): {}", severity, stage + 1, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+/// The result of `CmdChain::preflight()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreflightReport {
+    issues: Vec<PreflightIssue>,
+}
+
+impl PreflightReport {
+    /// Getter for issues.
+    pub fn issues(&self) -> &Vec<PreflightIssue> {
+        &self.issues
+    }
+    /// All issues with `Severity::Error`.
+    pub fn errors(&self) -> impl Iterator<Item = &PreflightIssue> {
+        self.issues.iter().filter(|issue| issue.severity == Severity::Error)
+    }
+    /// All issues with `Severity::Warning`.
+    pub fn warnings(&self) -> impl Iterator<Item = &PreflightIssue> {
+        self.issues.iter().filter(|issue| issue.severity == Severity::Warning)
+    }
+    /// Whether no error prevents running the chain.
+    pub fn is_ready(&self) -> bool {
+        self.errors().next().is_none()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    /// E.g. "2 problems prevent running this pipeline" followed by
+    /// one line per issue.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.errors().count() {
+            0 => write!(f, "the pipeline is ready to run")?,
+            1 => write!(f, "1 problem prevents running this pipeline")?,
+            n => write!(f, "{} problems prevent running this pipeline", n)?,
+        }
+        for issue in &self.issues {
+            write!(f, "\n  {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl CmdChain {
+    /// Checks everything that can be checked without running the chain and
+    /// reports all issues at once:
+    ///  * errors: executables (including the fallbacks) that can't be found,
+    ///    input files that can't be read, missing directories of output
+    ///    files and working directories and too long argument lists, and
+    ///  * warnings: chains that may hit the limit of open files.
+    ///
+    /// Only reads the file system. The result is a snapshot; the file
+    /// system may change until the chain is executed.
+    pub fn preflight(&self) -> PreflightReport {
+        let fs = FsAccess::ReadOnly;
+        let mut issues = vec![];
+        let mut error = |stage, message| issues.push(PreflightIssue { stage: Some(stage), severity: Severity::Error, message });
+        let arg_max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+
+        for (i, cmd) in self.cmds().iter().enumerate() {
+            // subshells, functions and builtins execute nothing themselves
+            let executes = cmd.subshell().is_none() && !cmd.is_function() && cmd.builtin().is_none();
+            let found = !executes || std::iter::once(cmd.executable())
+                .chain(cmd.fallbacks().iter().map(|fallback| fallback.as_os_str()))
+                .any(|executable| resolve_executable(executable, &fs).is_some());
+            if !found {
+                error(i, format!("{}: {}", cmd.executable().to_string_lossy(),
+                                 diagnose_exec_error(cmd.executable(), libc::ENOENT)));
+            }
+            if let Some(path) = cmd.in_red_path() {
+                if !fs.is_readable(path) {
+                    error(i, format!("{}: input file can't be read", path.display()));
+                }
+            }
+            for path in cmd.out_red_path().iter().chain(cmd.err_red_path().iter()) {
+                if !fs.is_dir(dir_of(path)) {
+                    error(i, format!("{}: directory of output file doesn't exist", path.display()));
+                }
+            }
+            if let Some(dir) = cmd.work_dir() {
+                if !fs.is_dir(dir) {
+                    error(i, format!("{}: working directory doesn't exist", dir.display()));
+                }
+            }
+            // exec() copies the strings (with NUL) and the pointers to them
+            let arg_size: usize = cmd.args().iter().map(|arg| arg.len() + 1 + std::mem::size_of::<usize>()).sum();
+            if arg_max > 0 && arg_size > arg_max as usize {
+                error(i, format!("argument list too long: {} of {} bytes", arg_size, arg_max));
+            }
+        }
+
+        // pipes to the next command and error pipe of the child; both are
+        // open in the parent while the next child gets forked
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+            let open_fds = std::fs::read_dir("/proc/self/fd").map(|dir| dir.count()).unwrap_or(3);
+            let needed = open_fds as u64 + 6;
+            if needed > limit.rlim_cur as u64 {
+                issues.push(PreflightIssue {
+                    stage: None,
+                    severity: Severity::Warning,
+                    message: format!("{} open files are needed but the limit is {}", needed, limit.rlim_cur),
+                });
+            }
+        }
+        PreflightReport { issues }
+    }
+}
+
+/// Resolves the executable like `execvp()` does: paths with a slash are used
+/// as they are, otherwise `$PATH` is searched.
+fn resolve_executable(executable: &OsStr, fs: &FsAccess) -> Option<PathBuf> {
+    if executable.as_bytes().contains(&b'/') {
+        let path = Path::new(executable);
+        return if fs.is_executable(path) { Some(path.to_owned()) } else { None };
+    }
+    let path = std::env::var("PATH").unwrap_or_default();
+    path.split(':')
+        .map(|dir| if dir.is_empty() { "." } else { dir })
+        .map(|dir| Path::new(dir).join(executable))
+        .find(|candidate| fs.is_executable(candidate))
+}
+
+/// Expands a word: variables (`$VAR`, `${VAR}`) in unquoted and double quoted
+/// parts, a leading unquoted `~` and glob patterns (`*`, `?`, `[...]`) in
+/// unquoted parts. A glob without matches stays as it is (like bash does).
+fn expand_word(word: &Word, fs: &FsAccess) -> Result<Vec<String>, ParseError> {
+    // the expanded word and the same as glob pattern, where
+    // quoted glob chars are escaped with a backslash
+    let mut expanded = String::new();
+    let mut pattern = String::new();
+    let mut has_glob = false;
+
+    for (i, (text, quoting)) in word.parts.iter().enumerate() {
+        let mut text = match quoting {
+            Quoting::Single => text.clone(),
+            _ => expand_vars(text)?,
+        };
+        if i == 0 && *quoting == Quoting::None && (text == "~" || text.starts_with("~/")) {
+            if let Ok(home) = std::env::var("HOME") {
+                text = format!("{}{}", home, &text[1..]);
+            }
+        }
+        for c in text.chars() {
+            let is_glob_char = "*?[]\\".contains(c);
+            if is_glob_char && *quoting == Quoting::None && c != '\\' {
+                has_glob = true;
+            } else if is_glob_char {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        expanded.push_str(&text);
+    }
+
+    if has_glob && *fs != FsAccess::None {
+        let matches = glob(&pattern, fs);
+        if !matches.is_empty() {
+            return Ok(matches);
+        }
+    }
+    Ok(vec![expanded])
+}
+
+/// Expands `$VAR` and `${VAR}` with the values of the environment.
+/// Unset variables expand to an empty string.
+fn expand_vars(text: &str) -> Result<String, ParseError> {
+    let mut result = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            result.push(c);
+            continue;
+        }
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(ParseError::BadSubstitution),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                let valid = c == '_' || c.is_ascii_alphabetic() || (!name.is_empty() && c.is_ascii_digit());
+                if !valid {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            if name.is_empty() {
+                // a '$' without a name stays as it is
+                result.push('$');
+                continue;
+            }
+        }
+        result.push_str(&std::env::var(&name).unwrap_or_default());
+    }
+    Ok(result)
+}
+
+/// Returns the sorted paths matching `pattern`. Only the last path
+/// component may contain glob chars.
+fn glob(pattern: &str, fs: &FsAccess) -> Vec<String> {
+    let (dir, name_pattern) = match pattern.rfind('/') {
+        Some(i) => (&pattern[..i], &pattern[i + 1..]),
+        None => ("", pattern),
+    };
+    if dir.contains(|c| "*?[".contains(c)) {
+        return vec![];
+    }
+    let dir = dir.replace('\\', "");
+    let list_dir = match dir.as_str() {
+        "" if pattern.starts_with('/') => "/",
+        "" => ".",
+        dir => dir,
+    };
+
+    let mut matches: Vec<String> = fs.list_dir(list_dir).into_iter()
+        // hidden files must be matched explicitly
+        .filter(|name| !name.starts_with('.') || name_pattern.starts_with('.'))
+        .filter(|name| glob_match(name_pattern, name))
+        .map(|name| if pattern.contains('/') { format!("{}/{}", dir, name) } else { name })
+        .collect();
+    matches.sort();
+    matches
+}
+
+/// Matches a single path component against a glob pattern
+/// supporting `*`, `?`, `[abc]`, `[a-z]`, `[!a]` and `\` escapes.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_match_from(&pattern, &name)
+}
+
+/// Iterative with backtracking to the last `*` only, i.e. in O(pattern *
+/// name) instead of exponential time for patterns like `a*a*a*b`.
+fn glob_match_from(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the pattern position after the last '*' and the name position it
+    // currently matches from
+    let mut last_star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            last_star = Some((p, n));
+        } else if let Some(len) = match_char(&pattern[p..], name[n]) {
+            p += len;
+            n += 1;
+        } else if let Some((star_p, star_n)) = last_star {
+            // the '*' consumes one more character
+            p = star_p;
+            n = star_n + 1;
+            last_star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// If the first element of the pattern (a character, `?`, a bracket
+/// expression or an escape; not `*`) matches `c`, its length in the pattern.
+fn match_char(pattern: &[char], c: char) -> Option<usize> {
+    match pattern.first()? {
+        '*' => None,
+        '?' => Some(1),
+        '[' => {
+            let end = match pattern.iter().skip(2).position(|c| *c == ']') {
+                Some(i) => i + 2,
+                // no closing bracket: literal '['
+                None => return if c == '[' { Some(1) } else { None },
+            };
+            let mut set = &pattern[1..end];
+            let negated = set.first() == Some(&'!');
+            if negated {
+                set = &set[1..];
+            }
+            let mut matched = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    matched |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    matched |= set[i] == c;
+                    i += 1;
+                }
+            }
+            if matched != negated { Some(end + 1) } else { None }
+        }
+        '\\' if pattern.len() > 1 => if pattern[1] == c { Some(2) } else { None },
+        p => if *p == c { Some(1) } else { None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::{BasicCmdBuilder, Builder, CmdChainBuilder};
+
+    #[test]
+    fn test_preflight() {
+        let cmd_chain = crate::parse_cmd_chain("cat < does-not-exist.txt | does-not-exist-either | wc -l").unwrap();
+        let report = cmd_chain.preflight();
+        assert!(!report.is_ready());
+        let errors: Vec<&PreflightIssue> = report.errors().collect();
+        assert_eq!(errors.len(), 2, "{}", report);
+        assert_eq!(errors[0].stage(), Some(0));
+        assert!(errors[0].message().contains("does-not-exist.txt"));
+        assert_eq!(errors[1].stage(), Some(1));
+        assert!(errors[1].message().contains("does-not-exist-either"));
+        assert!(report.to_string().starts_with("2 problems prevent running this pipeline"));
+
+        let cmd_chain = crate::parse_cmd_chain("cat < Cargo.toml | wc -l > /dev/null").unwrap();
+        assert!(cmd_chain.preflight().is_ready());
+    }
+
+    #[test]
+    fn test_preflight_skips_stages_without_executable() {
+        // none of the names exists in $PATH
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_subshell(crate::parse_cmd_list("echo a").unwrap()))
+            .add_cmd(BasicCmdBuilder::new().set_function(|_, _| 0))
+            .add_cmd(BasicCmdBuilder::new().add_arg("cd").set_builtin(crate::Cd))
+            .build();
+        let report = cmd_chain.preflight();
+        assert!(report.is_ready(), "{}", report);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.txt", "a.txt"));
+        assert!(glob_match("a?[b-d][!x]\\*", "axcy*"));
+        assert!(!glob_match("a*b", "aab.c"));
+        assert!(glob_match("[", "["));
+        assert!(glob_match("**", ""));
+        // would take ages with exponential backtracking
+        let name = "a".repeat(100);
+        assert!(!glob_match(&format!("{}b", "a*".repeat(30)), &name));
+        assert!(glob_match(&"a*".repeat(30), &name));
+    }
+
+    #[test]
+    fn test_echo_only_doesnt_fork_or_write() {
+        let dir = std::env::temp_dir().join(format!("unix_exec_piper_echo_only_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("marker");
+        let out = dir.join("out.txt");
+
+        // if this would be executed, both files would exist afterwards
+        let line = format!("touch {} | cat > {}", marker.display(), out.display());
+        let forks = crate::FORKS.with(|forks| forks.get());
+        let plan = echo_only(&line, &FsAccess::ReadOnly).unwrap();
+        assert_eq!(crate::FORKS.with(|forks| forks.get()), forks);
+        // the counter works
+        crate::execute_piped_cmd_chain(&crate::parse_cmd_chain("true").unwrap()).unwrap();
+        assert_eq!(crate::FORKS.with(|forks| forks.get()), forks + 1);
+        assert!(plan.problems().is_empty(), "{:?}", plan.problems());
+        assert!(plan.executables().iter().all(|e| e.is_some()));
+        assert!(!Path::new(&marker).exists());
+        assert!(!Path::new(&out).exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_echo_only_expands_with_virtual_fs() {
+        let fs = FsAccess::Virtual(vec![
+            "/bin/cat".to_string(),
+            "/data/a.txt".to_string(),
+            "/data/b.txt".to_string(),
+            "/data/c.log".to_string(),
+        ]);
+        std::env::set_var("UNIX_EXEC_PIPER_TEST_VAR", "x y");
+        let plan = echo_only("/bin/cat /data/*.txt '*.log' \"$UNIX_EXEC_PIPER_TEST_VAR\" < /data/c.log | wc > /nope/out", &fs).unwrap();
+        let args = plan.chain().cmds()[0].args();
+        assert_eq!(args, &vec!["/bin/cat", "/data/a.txt", "/data/b.txt", "*.log", "x y"]);
+        // 'wc' isn't in the virtual fs and /nope doesn't exist
+        assert_eq!(plan.problems().len(), 2);
+
+        // the entries of a directory are listed once, also if they aren't adjacent
+        let fs = FsAccess::Virtual(vec![
+            "/bin/ls".to_string(),
+            "/data/sub/a".to_string(),
+            "/data/b.txt".to_string(),
+            "/data/sub/c".to_string(),
+        ]);
+        let plan = echo_only("/bin/ls /data/*", &fs).unwrap();
+        assert_eq!(plan.chain().cmds()[0].args(), &vec!["/bin/ls", "/data/b.txt", "/data/sub"]);
+
+        // a redirect needs exactly one path
+        let res = echo_only("/bin/ls > /data/sub/*", &fs);
+        assert_eq!(res.unwrap_err(), ParseError::AmbiguousRedirect("/data/sub/*".to_string()));
+    }
+}
@@ -0,0 +1,181 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Conversions between `BasicCmd` and `std::process::Command`, e.g. to
+//! reuse existing `Command` setup code and only use this crate for the
+//! piping.
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use crate::{BasicCmd, BasicCmdBuilder, OutputRedirectMode, PiperError};
+
+impl TryFrom<&BasicCmd> for Command {
+    type Error = PiperError;
+
+    /// Builds a `Command` that runs `cmd` on its own, i.e. without the
+    /// pipes of its chain. The files of the redirects are opened (and an
+    /// output redirect truncated) during the conversion. Fails with
+    /// `PiperError::Unsupported` for what `Command` can't express:
+    /// fallbacks, STDIN contents, `2>&1`, other FD duplications,
+    /// supplementary groups, subshells, functions and builtins.
+    fn try_from(cmd: &BasicCmd) -> Result<Self, Self::Error> {
+        if !cmd.fallbacks().is_empty() {
+            return Err(PiperError::Unsupported("fallbacks"));
+        } else if cmd.stdin_contents().is_some() {
+            return Err(PiperError::Unsupported("STDIN contents"));
+        } else if cmd.merge_stderr() || !cmd.fd_dups().is_empty() {
+            return Err(PiperError::Unsupported("FD duplication"));
+        } else if cmd.groups().is_some() {
+            return Err(PiperError::Unsupported("groups"));
+        } else if cmd.subshell().is_some() {
+            return Err(PiperError::Unsupported("subshells"));
+        } else if cmd.is_function() {
+            return Err(PiperError::Unsupported("functions"));
+        } else if cmd.builtin().is_some() {
+            return Err(PiperError::Unsupported("builtins"));
+        }
+
+        let mut command = Command::new(cmd.executable());
+        if let Some((argv0, args)) = cmd.args().split_first() {
+            command.arg0(argv0).args(args);
+        }
+        if cmd.clear_env() {
+            command.env_clear();
+        }
+        command.envs(cmd.env().iter().map(|(key, value)| (key, value)));
+        if let Some(work_dir) = cmd.work_dir() {
+            command.current_dir(work_dir);
+        }
+        if let Some(uid) = cmd.uid() {
+            command.uid(uid);
+        }
+        if let Some(gid) = cmd.gid() {
+            command.gid(gid);
+        }
+
+        // like in a shell a redirect has precedence over a pipe
+        if let Some(path) = cmd.in_red_path() {
+            command.stdin(open_redirect(path, OpenOptions::new().read(true))?);
+        } else if let Some(fd) = cmd.stdin_pipe_fd() {
+            command.stdin(dup_stdio(fd)?);
+        }
+        if let Some(path) = cmd.out_red_path() {
+            let mut options = OpenOptions::new();
+            options.write(true).create(true);
+            match cmd.out_red_mode() {
+                OutputRedirectMode::Truncate | OutputRedirectMode::Clobber => options.truncate(true),
+                OutputRedirectMode::Append => options.append(true),
+            };
+            command.stdout(open_redirect(path, &options)?);
+        } else if let Some(fd) = cmd.stdout_pipe_fd() {
+            command.stdout(dup_stdio(fd)?);
+        }
+        if let Some(path) = cmd.err_red_path() {
+            command.stderr(open_redirect(path, OpenOptions::new().write(true).create(true).truncate(true))?);
+        }
+        Ok(command)
+    }
+}
+
+/// Opens the file of a redirect.
+fn open_redirect(path: &Path, options: &OpenOptions) -> Result<Stdio, PiperError> {
+    options.open(path).map(Stdio::from).map_err(|e| PiperError::RedirectOpenFailed {
+        path: path.to_string_lossy().into_owned(),
+        errno: e.raw_os_error().unwrap_or(libc::EIO),
+    })
+}
+
+/// `Stdio` owns its FD, hence a duplicate of an externally managed pipe.
+fn dup_stdio(fd: libc::c_int) -> Result<Stdio, PiperError> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+        -1 => Err(PiperError::DupFailed(errno::errno().0)),
+        dup => Ok(Stdio::from(unsafe { File::from_raw_fd(dup) })),
+    }
+}
+
+impl BasicCmdBuilder {
+    /// Constructs a builder from the setup of `command`. Only what
+    /// `Command` exposes is taken over: the program (also as args[0]),
+    /// the args, the environment variables and the working directory.
+    /// Removed variables (`Command::env_remove()`) are ignored and names
+    /// and values that aren't valid UTF-8 are converted lossy. An
+    /// `env_clear()`, argv[0], the user and the stdio setup can't be
+    /// read from a `Command`.
+    pub fn from_command(command: &Command) -> Self {
+        let mut builder = BasicCmdBuilder::new()
+            .set_executable(command.get_program())
+            .add_arg(command.get_program());
+        for arg in command.get_args() {
+            builder = builder.add_arg(arg);
+        }
+        for (key, value) in command.get_envs() {
+            if let Some(value) = value {
+                builder = builder.add_env(&key.to_string_lossy(), &value.to_string_lossy());
+            }
+        }
+        if let Some(work_dir) = command.get_current_dir() {
+            builder = builder.set_working_dir(work_dir);
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Builder;
+
+    #[test]
+    fn test_command_conversion() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_command_{}.txt", std::process::id()));
+        let cmd = BasicCmdBuilder::new()
+            .set_executable("sh")
+            .add_arg("sh")
+            .add_arg("-c")
+            .add_arg("echo $FOO; pwd")
+            .add_env("FOO", "bar")
+            .set_working_dir("/")
+            .set_output_redirect_path(&out_file)
+            .build();
+        let status = Command::try_from(&cmd).unwrap().status().unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "bar\n/\n");
+        std::fs::remove_file(&out_file).unwrap();
+
+        let cmd = BasicCmdBuilder::new().set_executable("bat").add_arg("bat").add_fallback("cat").build();
+        assert_eq!(Command::try_from(&cmd).unwrap_err(), PiperError::Unsupported("fallbacks"));
+
+        let mut command = Command::new("grep");
+        command.arg("-i").arg("abc").env("FOO", "bar").env_remove("BAR").current_dir("/tmp");
+        let cmd = BasicCmdBuilder::from_command(&command).build();
+        assert_eq!(cmd.executable(), "grep");
+        assert_eq!(cmd.args(), &vec!["grep", "-i", "abc"]);
+        assert_eq!(cmd.env(), &vec![("FOO".to_owned(), "bar".to_owned())]);
+        assert_eq!(cmd.work_dir().as_deref(), Some(Path::new("/tmp")));
+    }
+}
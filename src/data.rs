@@ -22,8 +22,21 @@
     SOFTWARE.
 */
 
-use std::ffi::CString;
-use crate::libc_util::{construct_libc_cstring, construct_libc_cstring_arr};
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::error::{BuildError, PiperError};
+use crate::pipe::Pipe;
+use crate::list::CmdList;
+use crate::builtin::Builtin;
+use crate::observer::ChainObserver;
+use crate::stage::StageThread;
+use crate::libc_util::{construct_libc_argv_with, construct_libc_envp_with, CStringArray, MallocAllocator};
 
 /// Common trait for the two builders.
 pub trait Builder<To>  {
@@ -34,17 +47,63 @@ pub trait Builder<To>  {
 ///  * `cat < in.txt`, or
 ///  * `tee file.txt`, or
 ///  * `wc -l > out.txt`
+///
 /// inside `cat < in.txt | tee file.txt | wc -l > out.txt &`.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BasicCmd {
     /// Absolute or relative path (or no path at all; just name)
-    executable: String,
+    executable: OsString,
     /// Args including the executable name as first argument (Posix convention; or UNIX, don't know)
-    args: Vec<String>,
+    args: Vec<OsString>,
+    /// Executables that are tried in order if the executable can't be found.
+    fallbacks: Vec<OsString>,
+    /// Additional environment variables (`FOO=bar cmd`). If empty the process
+    /// inherits the environment of the parent unchanged.
+    env: Vec<(String, String)>,
+    /// Whether the process starts with only the variables of `env` instead
+    /// of the environment of the parent (`env -i FOO=bar cmd`).
+    clear_env: bool,
     /// Optional the file for the input redirect (only for first command in the chain).
-    in_red_path: Option<String>,
+    in_red_path: Option<PathBuf>,
     /// Optional the file for the output redirect (only for last command in the chain).
-    out_red_path: Option<String>,
+    out_red_path: Option<PathBuf>,
+    /// Whether the output redirect truncates (`>`) or appends (`>>`).
+    out_red_mode: OutputRedirectMode,
+    /// Optional the file for the STDERR redirect (`2> err.txt`). Allowed for every
+    /// command in the chain because STDERR is never piped to the next command.
+    err_red_path: Option<PathBuf>,
+    /// Whether STDERR follows STDOUT (`2>&1`), i.e. goes into the pipe to
+    /// the next command, the output redirect or the terminal.
+    merge_stderr: bool,
+    /// FD duplications like `2>&1` or `1>&2`, in the order they are applied.
+    fd_dups: Vec<FdDup>,
+    /// Optional working directory of the process. The parent's one otherwise.
+    work_dir: Option<PathBuf>,
+    /// Optional user ID the process runs as.
+    uid: Option<libc::uid_t>,
+    /// Optional group ID the process runs as.
+    gid: Option<libc::gid_t>,
+    /// Optional supplementary groups of the process.
+    groups: Option<Vec<libc::gid_t>>,
+    /// Optional bytes that are written into STDIN, like a heredoc
+    /// (only for first command in the chain).
+    stdin_contents: Option<Vec<u8>>,
+    /// Optional read end of an externally managed pipe that becomes STDIN
+    /// (only for first command in the chain).
+    stdin_pipe_fd: Option<libc::c_int>,
+    /// Optional write end of an externally managed pipe that becomes STDOUT
+    /// (only for last command in the chain).
+    stdout_pipe_fd: Option<libc::c_int>,
+    /// Whether the write end of the pipe to the next command is nonblocking.
+    nonblocking_pipe_to_next: bool,
+    /// Optional command list that the process runs instead of executing
+    /// the executable, like `(a; b)` in a shell.
+    subshell: Option<Arc<CmdList>>,
+    /// Optional Rust function that the process runs instead of executing
+    /// the executable.
+    function: Option<StageFn>,
+    /// Optional builtin that runs instead of the executable.
+    builtin: Option<Arc<dyn Builtin>>,
     /// Whether it's the first command in the chain.
     is_first: bool,
     /// Whether it's the last command in the chain.
@@ -54,21 +113,98 @@ pub struct BasicCmd {
 impl BasicCmd {
 
     /// Getter for executable.
-    pub fn executable(&self) -> &str {
+    pub fn executable(&self) -> &OsStr {
         &self.executable
     }
     /// Getter for args.
-    pub fn args(&self) -> &Vec<String> {
+    pub fn args(&self) -> &Vec<OsString> {
         &self.args
     }
+    /// Getter for fallbacks.
+    pub fn fallbacks(&self) -> &Vec<OsString> {
+        &self.fallbacks
+    }
+    /// Getter for env.
+    pub fn env(&self) -> &Vec<(String, String)> {
+        &self.env
+    }
+    /// Getter for clear_env.
+    pub fn clear_env(&self) -> bool {
+        self.clear_env
+    }
     /// Getter for in_red_path.
-    pub fn in_red_path(&self) -> &Option<String> {
+    pub fn in_red_path(&self) -> &Option<PathBuf> {
         &self.in_red_path
     }
     /// Getter for in_red_path.
-    pub fn out_red_path(&self) -> &Option<String> {
+    pub fn out_red_path(&self) -> &Option<PathBuf> {
         &self.out_red_path
     }
+    /// Getter for out_red_mode.
+    pub fn out_red_mode(&self) -> OutputRedirectMode {
+        self.out_red_mode
+    }
+    /// Getter for err_red_path.
+    pub fn err_red_path(&self) -> &Option<PathBuf> {
+        &self.err_red_path
+    }
+    /// Getter for merge_stderr.
+    pub fn merge_stderr(&self) -> bool {
+        self.merge_stderr
+    }
+    /// Getter for fd_dups.
+    pub fn fd_dups(&self) -> &Vec<FdDup> {
+        &self.fd_dups
+    }
+    /// Getter for work_dir.
+    pub fn work_dir(&self) -> &Option<PathBuf> {
+        &self.work_dir
+    }
+    /// Getter for uid.
+    pub fn uid(&self) -> Option<libc::uid_t> {
+        self.uid
+    }
+    /// Getter for gid.
+    pub fn gid(&self) -> Option<libc::gid_t> {
+        self.gid
+    }
+    /// Getter for groups.
+    pub fn groups(&self) -> Option<&Vec<libc::gid_t>> {
+        self.groups.as_ref()
+    }
+    /// Getter for stdin_contents.
+    pub fn stdin_contents(&self) -> Option<&Vec<u8>> {
+        self.stdin_contents.as_ref()
+    }
+    /// Getter for stdin_pipe_fd.
+    pub fn stdin_pipe_fd(&self) -> Option<libc::c_int> {
+        self.stdin_pipe_fd
+    }
+    /// Getter for stdout_pipe_fd.
+    pub fn stdout_pipe_fd(&self) -> Option<libc::c_int> {
+        self.stdout_pipe_fd
+    }
+    /// Getter for nonblocking_pipe_to_next.
+    pub fn nonblocking_pipe_to_next(&self) -> bool {
+        self.nonblocking_pipe_to_next
+    }
+    /// Getter for subshell.
+    pub fn subshell(&self) -> Option<&CmdList> {
+        self.subshell.as_deref()
+    }
+    /// Whether the command runs a Rust function, see
+    /// `BasicCmdBuilder::set_function()`.
+    pub fn is_function(&self) -> bool {
+        self.function.is_some()
+    }
+    /// Getter for builtin.
+    pub fn builtin(&self) -> Option<&dyn Builtin> {
+        self.builtin.as_deref()
+    }
+    /// The function of the command, see `BasicCmdBuilder::set_function()`.
+    pub(crate) fn function(&self) -> Option<&StageFunction> {
+        self.function.as_ref().map(|function| &*function.0)
+    }
     /// Getter for is_first.
     pub fn is_first(&self) -> bool {
         self.is_first
@@ -82,48 +218,216 @@ impl BasicCmd {
         !self.is_first && !self.is_last
     }
 
-    /// Constructs the null-terminated argv-array on the heap.
+    /// Constructs the null-terminated argv-array on the heap with
+    /// `malloc()`; `None` if the memory is exhausted.
     /// Memory must be freed theoretically in order to have proper
     /// memory management but because the address space content is
     /// replaced after "exec()" you don't have to free it in
-    /// case of successful exec().
-    pub fn args_to_c_argv(&self) -> *const *const libc::c_char {
-        let argv: *mut *mut libc::c_char = construct_libc_cstring_arr(self.args.len(), true);
-
-        for i in 0..self.args.len() {
-            let arg = &self.args[i];
-            let c_string: *mut libc::c_char = construct_libc_cstring(arg);
-            unsafe {
-                *argv.offset(i as isize) = c_string;
-            }
-        }
+    /// case of successful exec(). Otherwise see `free_c_argv()`.
+    /// `c_argv()` returns an array that frees itself.
+    pub fn args_to_c_argv(&self) -> Option<*const *const libc::c_char> {
+        construct_libc_argv_with(&MallocAllocator, &self.args)
+    }
+
+    /// Constructs the null-terminated envp-array on the heap: the
+    /// environment of the current process merged with `env()`, or only
+    /// `env()` if `clear_env()` is set.
+    /// Same memory considerations as for `args_to_c_argv()`, see
+    /// `free_c_envp()`.
+    pub fn env_to_c_envp(&self) -> Option<*const *const libc::c_char> {
+        construct_libc_envp_with(&MallocAllocator, !self.clear_env, &self.env)
+    }
 
-        argv as *const *const libc::c_char
+    /// Constructs argv as an owned array, see `CStringArray`. Unlike
+    /// `args_to_c_argv()` the memory is freed automatically.
+    pub fn c_argv(&self) -> CStringArray {
+        CStringArray::new(&self.args)
+    }
+
+    /// Constructs envp like `env_to_c_envp()` as an owned array.
+    pub fn c_envp(&self) -> CStringArray {
+        CStringArray::envp(!self.clear_env, &self.env)
     }
 
     /// Constructs a CString for executable.
     pub fn executable_cstring(&self) -> CString {
-        CString::new(self.executable.clone()).unwrap()
+        os_cstring(&self.executable)
     }
 
     /// Constructs a CString for out_red_path.
     pub fn out_red_path_cstring(&self) -> Option<CString> {
-        self.out_red_path.clone().map(|x| CString::new(x).unwrap())
+        self.out_red_path.as_ref().map(|x| os_cstring(x.as_os_str()))
     }
 
     /// Constructs a CString for in_red_path.
     pub fn in_red_path_cstring(&self) -> Option<CString> {
-        self.in_red_path.clone().map(|x| CString::new(x).unwrap())
+        self.in_red_path.as_ref().map(|x| os_cstring(x.as_os_str()))
+    }
+
+    /// Constructs a CString for work_dir.
+    pub fn work_dir_cstring(&self) -> Option<CString> {
+        self.work_dir.as_ref().map(|x| os_cstring(x.as_os_str()))
+    }
+
+    /// Constructs a CString for err_red_path.
+    pub fn err_red_path_cstring(&self) -> Option<CString> {
+        self.err_red_path.as_ref().map(|x| os_cstring(x.as_os_str()))
+    }
+}
+
+/// Constructs a CString from the raw bytes of `value`; they don't have to
+/// be valid UTF-8. Panics if `value` contains a null byte.
+pub(crate) fn os_cstring(value: &OsStr) -> CString {
+    CString::new(value.as_bytes()).unwrap()
+}
+
+impl fmt::Display for BasicCmd {
+    /// Reconstructs the command like it would be typed into a shell,
+    /// e.g. `cat < in.txt` or `wc -l > out.txt`. Bytes that aren't valid
+    /// UTF-8 are replaced with `U+FFFD`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.clear_env {
+            write!(f, "env -i ")?;
+        }
+        for (key, value) in &self.env {
+            write!(f, "{}={} ", key, shell_quote(value))?;
+        }
+        if let Some(list) = &self.subshell {
+            write!(f, "({})", list)?;
+        } else {
+            write!(f, "{}", shell_quote(&self.executable.to_string_lossy()))?;
+            // args[0] is the executable name (by convention)
+            for arg in self.args.iter().skip(1) {
+                write!(f, " {}", shell_quote(&arg.to_string_lossy()))?;
+            }
+        }
+        if let Some(path) = &self.in_red_path {
+            write!(f, " < {}", shell_quote(&path.to_string_lossy()))?;
+        }
+        if let Some(path) = &self.out_red_path {
+            let op = match self.out_red_mode {
+                OutputRedirectMode::Truncate => ">",
+                OutputRedirectMode::Append => ">>",
+                OutputRedirectMode::Clobber => ">|",
+            };
+            write!(f, " {} {}", op, shell_quote(&path.to_string_lossy()))?;
+        }
+        if let Some(path) = &self.err_red_path {
+            write!(f, " 2> {}", shell_quote(&path.to_string_lossy()))?;
+        }
+        if self.merge_stderr {
+            write!(f, " 2>&1")?;
+        }
+        for dup in &self.fd_dups {
+            write!(f, " {}>&{}", dup.from, dup.to)?;
+        }
+        Ok(())
+    }
+}
+
+/// The function of a command, see `BasicCmdBuilder::set_function()`. Each
+/// execution of the chain calls it on a thread of its own.
+#[derive(Clone)]
+struct StageFn(Arc<StageFunction>);
+
+pub(crate) type StageFunction = dyn Fn(&mut dyn Read, &mut dyn Write) -> i32 + Send + Sync;
+
+// A panic of the function is caught on its thread (see `crate::stage`),
+// i.e. the caller never observes a broken state of it; a chain stays
+// unwind safe like with a `Builtin`.
+impl std::panic::RefUnwindSafe for StageFn {}
+
+impl fmt::Debug for StageFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StageFn")
+    }
+}
+
+/// Duplication of a file descriptor of a command, like `2>&1` in a shell,
+/// see `BasicCmdBuilder::add_fd_dup()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FdDup {
+    /// The FD that gets replaced, e.g. 2 in `2>&1`.
+    from: libc::c_int,
+    /// The FD that gets duplicated, e.g. 1 in `2>&1`.
+    to: libc::c_int,
+}
+
+impl FdDup {
+    /// Constructor. `from` becomes a copy of `to`.
+    pub fn new(from: libc::c_int, to: libc::c_int) -> Self {
+        Self { from, to }
+    }
+    /// Getter for from.
+    pub fn from(&self) -> libc::c_int {
+        self.from
+    }
+    /// Getter for to.
+    pub fn to(&self) -> libc::c_int {
+        self.to
+    }
+}
+
+/// Quotes a word with single quotes if it contains characters that
+/// a shell would interpret. Otherwise the word is returned as it is.
+fn shell_quote(word: &str) -> String {
+    let needs_quotes = word.is_empty() || word.chars().any(|c| {
+        c.is_whitespace() || "|&;<>()$`\\\"'*?[]#~{}".contains(c)
+    });
+    if needs_quotes {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    } else {
+        word.to_string()
     }
 }
 
+/// How the file of an output redirect is opened.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum OutputRedirectMode {
+    /// `> out.txt`: the file is truncated (default).
+    #[default]
+    Truncate,
+    /// `>> out.txt`: the output is appended to the file.
+    Append,
+    /// `>| out.txt`: the file is truncated, even if noclobber is set
+    /// (see `ExecOptions::set_noclobber()`).
+    Clobber,
+}
+
 /// Builder for `BasicCmd`.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct BasicCmdBuilder {
-    executable: Option<String>,
-    args: Vec<String>,
-    input_redirect_path: Option<String>,
-    output_redirect_path: Option<String>,
+    executable: Option<OsString>,
+    args: Vec<OsString>,
+    fallbacks: Vec<OsString>,
+    env: Vec<(String, String)>,
+    clear_env: bool,
+    /// Whether args[0] was set explicitly via `set_argv0()`.
+    argv0_override: bool,
+    /// Whether args[0] gets a `-` prefix, see `as_login_shell()`.
+    login_shell: bool,
+    input_redirect_path: Option<PathBuf>,
+    output_redirect_path: Option<PathBuf>,
+    /// The mode of the last `set_output_redirect_*()` call.
+    output_redirect_mode: OutputRedirectMode,
+    /// The mode of `set_output_redirect_mode()`; takes precedence.
+    output_redirect_mode_override: Option<OutputRedirectMode>,
+    error_redirect_path: Option<PathBuf>,
+    merge_stderr: bool,
+    fd_dups: Vec<FdDup>,
+    work_dir: Option<PathBuf>,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    groups: Option<Vec<libc::gid_t>>,
+    stdin_contents: Option<Vec<u8>>,
+    stdin_pipe_fd: Option<libc::c_int>,
+    stdout_pipe_fd: Option<libc::c_int>,
+    nonblocking_pipe_to_next: bool,
+    subshell: Option<Arc<CmdList>>,
+    function: Option<StageFn>,
+    builtin: Option<Arc<dyn Builtin>>,
+    /// FDs that were targeted by multiple redirects.
+    redirect_conflicts: Vec<libc::c_int>,
     is_first: bool,
     is_last: bool,
 }
@@ -134,29 +438,355 @@ impl BasicCmdBuilder {
         BasicCmdBuilder {
             executable: None,
             args: vec![],
+            fallbacks: vec![],
+            env: vec![],
+            clear_env: false,
+            argv0_override: false,
+            login_shell: false,
             input_redirect_path: None,
             output_redirect_path: None,
+            output_redirect_mode: OutputRedirectMode::Truncate,
+            output_redirect_mode_override: None,
+            error_redirect_path: None,
+            merge_stderr: false,
+            fd_dups: vec![],
+            work_dir: None,
+            uid: None,
+            gid: None,
+            groups: None,
+            stdin_contents: None,
+            stdin_pipe_fd: None,
+            stdout_pipe_fd: None,
+            nonblocking_pipe_to_next: false,
+            subshell: None,
+            function: None,
+            builtin: None,
+            redirect_conflicts: vec![],
             is_first: false,
             is_last: false,
         }
     }
 
-    pub fn set_executable(mut self, executable: &str) -> Self {
-        self.executable.replace(executable.to_string());
+    /// Sets the executable. Like all other strings and paths of the
+    /// builder it can be a `&str` or an `OsStr`/`Path` that isn't valid
+    /// UTF-8, e.g. a file name from `std::fs::read_dir()`.
+    pub fn set_executable(mut self, executable: impl AsRef<OsStr>) -> Self {
+        self.executable.replace(executable.as_ref().to_owned());
+        self
+    }
+    pub fn add_arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+    /// Adds an executable that is tried if the executable (and all previously
+    /// added fallbacks) can't be found (`ENOENT`), e.g. `cat` for `bat`. The
+    /// fallback gets its own name as args[0] and the same other args.
+    pub fn add_fallback(mut self, executable: impl AsRef<OsStr>) -> Self {
+        self.fallbacks.push(executable.as_ref().to_owned());
+        self
+    }
+    /// Sets args[0] explicitly, e.g. for programs that behave differently
+    /// depending on the name they were called with (`busybox`, login
+    /// shells). Inserts it, if there are no args yet. Disables the argv[0]
+    /// lint for this command (see `CmdChainBuilder::set_argv0_warning()`).
+    pub fn set_argv0(mut self, argv0: impl AsRef<OsStr>) -> Self {
+        let argv0 = argv0.as_ref().to_owned();
+        if self.args.is_empty() {
+            self.args.push(argv0);
+        } else {
+            self.args[0] = argv0;
+        }
+        self.argv0_override = true;
+        self
+    }
+    /// Starts the command as login shell, e.g. `bash` as `-bash`: by
+    /// convention a shell is a login shell if args[0] starts with `-`.
+    /// The prefix is added during the build, i.e. it also applies to an
+    /// args[0] from `set_argv0()`. The executable isn't changed.
+    pub fn as_login_shell(mut self) -> Self {
+        self.login_shell = true;
+        self
+    }
+    /// Sets an environment variable for the command only (`FOO=bar cmd`).
+    /// The parent's environment isn't modified. Setting the same key
+    /// again replaces the value. Building fails with
+    /// `BuildError::InvalidEnvVar` if `key` is empty or contains `=`, or
+    /// if one of them contains a null byte.
+    pub fn add_env(mut self, key: &str, value: &str) -> Self {
+        self.env.retain(|(k, _)| k != key);
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+    /// Same as `add_env()`.
+    pub fn set_env(self, key: &str, value: &str) -> Self {
+        self.add_env(key, value)
+    }
+    /// The command doesn't inherit the environment of the parent; it only
+    /// gets the variables of `add_env()` (`env -i FOO=bar cmd`). Note that
+    /// the executable is still searched in the `PATH` of the parent.
+    pub fn clear_env(mut self) -> Self {
+        self.clear_env = true;
+        self
+    }
+    pub fn set_input_redirect_path(mut self, input_redirect_path: impl AsRef<Path>) -> Self {
+        self.target_fd(libc::STDIN_FILENO);
+        self.input_redirect_path.replace(input_redirect_path.as_ref().to_owned());
+        self
+    }
+    /// Output redirect that truncates the file (`> out.txt`).
+    pub fn set_output_redirect_path(mut self, output_redirect_path: impl AsRef<Path>) -> Self {
+        self.target_fd(libc::STDOUT_FILENO);
+        self.output_redirect_path.replace(output_redirect_path.as_ref().to_owned());
+        self.output_redirect_mode = OutputRedirectMode::Truncate;
+        self
+    }
+    /// Output redirect that appends to the file (`>> out.txt`).
+    pub fn set_output_redirect_append(mut self, output_redirect_path: impl AsRef<Path>) -> Self {
+        self.target_fd(libc::STDOUT_FILENO);
+        self.output_redirect_path.replace(output_redirect_path.as_ref().to_owned());
+        self.output_redirect_mode = OutputRedirectMode::Append;
+        self
+    }
+    /// Output redirect that truncates the file even if noclobber is set
+    /// (`>| out.txt`), see `ExecOptions::set_noclobber()`.
+    pub fn set_output_redirect_clobber(mut self, output_redirect_path: impl AsRef<Path>) -> Self {
+        self.target_fd(libc::STDOUT_FILENO);
+        self.output_redirect_path.replace(output_redirect_path.as_ref().to_owned());
+        self.output_redirect_mode = OutputRedirectMode::Clobber;
+        self
+    }
+    /// Sets how the output redirect opens the file, e.g. if the mode is only
+    /// known at runtime. Takes precedence over the mode of the
+    /// `set_output_redirect_*()` functions, no matter in which order they
+    /// are called.
+    pub fn set_output_redirect_mode(mut self, mode: OutputRedirectMode) -> Self {
+        self.output_redirect_mode_override.replace(mode);
+        self
+    }
+    /// STDERR redirect that truncates the file (`2> err.txt`). Unlike the
+    /// other redirects it's allowed for every command of the chain.
+    pub fn set_stderr_redirect_path(mut self, error_redirect_path: impl AsRef<Path>) -> Self {
+        self.target_fd(libc::STDERR_FILENO);
+        self.error_redirect_path.replace(error_redirect_path.as_ref().to_owned());
+        self
+    }
+    /// Lets STDERR follow STDOUT (`2>&1`), e.g. for `cmd 2>&1 | grep error`.
+    /// STDERR becomes whatever STDOUT is connected to: the pipe to the next
+    /// command or, for the last command, the output redirect or the
+    /// terminal. Replaces a STDERR redirect and vice versa.
+    pub fn merge_stderr_into_stdout(mut self, merge: bool) -> Self {
+        if merge {
+            self.target_fd(libc::STDERR_FILENO);
+        }
+        self.merge_stderr = merge;
+        self
+    }
+    /// Duplicates an FD like `n>&m` in a shell, i.e. FD `from` refers to
+    /// the same file or pipe as FD `to` afterwards: `add_fd_dup(2, 1)` is
+    /// `2>&1` and `add_fd_dup(1, 2)` is `1>&2`. The duplications are applied
+    /// with `dup2()` in the order they were added, after all redirects and
+    /// the pipes are set up. Hence unlike in a shell `cmd 2>&1 > out.txt`
+    /// also sends STDERR into `out.txt`. With `CmdChainBuilder::set_close_fds()`
+    /// only duplications onto STDIN, STDOUT and STDERR survive.
+    pub fn add_fd_dup(mut self, from: libc::c_int, to: libc::c_int) -> Self {
+        self.fd_dups.push(FdDup::new(from, to));
+        self
+    }
+    /// Same as `set_stderr_redirect_path()`.
+    pub fn set_error_redirect_path(self, error_redirect_path: impl AsRef<Path>) -> Self {
+        self.set_stderr_redirect_path(error_redirect_path)
+    }
+    /// Sets the working directory of the process. The child changes into
+    /// it after the redirects are set up, i.e. relative redirect paths
+    /// are relative to the working directory of the parent.
+    pub fn set_working_dir(mut self, work_dir: impl AsRef<Path>) -> Self {
+        self.work_dir.replace(work_dir.as_ref().to_owned());
+        self
+    }
+    /// Runs the process as this user (`setuid()` before exec()), e.g. to
+    /// drop the privileges of a supervisor that runs as root. The child
+    /// switches right before exec(), i.e. the redirect files are opened and
+    /// the working directory is entered with the privileges of the parent,
+    /// like `sudo -u user cmd > file`.
+    /// Without `CAP_SETUID` spawning fails with
+    /// `PiperError::CredentialsFailed`, unless the user is the current one.
+    pub fn set_uid(mut self, uid: libc::uid_t) -> Self {
+        self.uid.replace(uid);
+        self
+    }
+    /// Runs the process with this group ID (`setgid()`), see `set_uid()`.
+    pub fn set_gid(mut self, gid: libc::gid_t) -> Self {
+        self.gid.replace(gid);
+        self
+    }
+    /// Replaces the supplementary groups of the process (`setgroups()`),
+    /// see `set_uid()`. Without it the process keeps the ones of the
+    /// parent, which usually is not wanted when dropping root privileges.
+    pub fn set_supplementary_groups(mut self, groups: &[libc::gid_t]) -> Self {
+        self.groups.replace(groups.to_vec());
+        self
+    }
+    /// Feeds the bytes into STDIN of the command, like a heredoc, without a
+    /// temporary file. Only used for the first command of a chain. The parent
+    /// writes them into a pipe from a separate thread and closes it
+    /// afterwards, hence contents bigger than the pipe buffer can't deadlock
+    /// the parent while the command hasn't started reading yet.
+    pub fn set_stdin_contents(mut self, contents: impl AsRef<[u8]>) -> Self {
+        self.target_fd(libc::STDIN_FILENO);
+        self.stdin_contents.replace(contents.as_ref().to_vec());
+        self
+    }
+    /// Here-document (`cmd <<EOF`): the text becomes STDIN of the command,
+    /// see `set_stdin_contents()`. The text is used as it is, i.e. the
+    /// caller takes care of the delimiter line and of expansions.
+    pub fn set_heredoc(self, text: &str) -> Self {
+        self.set_stdin_contents(text)
+    }
+    /// Here-string (`cmd <<< word`): like `set_heredoc()` but a newline
+    /// is appended, like bash does.
+    pub fn set_here_string(self, word: &str) -> Self {
+        self.set_stdin_contents(format!("{}\n", word))
+    }
+    /// Connects STDIN of the command with the read end of an externally
+    /// managed pipe, e.g. one that is also used by another chain.
+    /// Only used for the first command of a chain. The caller keeps the
+    /// ownership of the `Pipe` and must keep it alive until all chains that
+    /// use it have been spawned. Afterwards the caller must drop it (or call
+    /// `parent_close_all()`), otherwise the reader never sees EOF.
+    pub fn set_stdin_pipe(mut self, pipe: &Pipe) -> Self {
+        self.target_fd(libc::STDIN_FILENO);
+        self.stdin_pipe_fd.replace(pipe.read_fd());
+        self
+    }
+    /// Connects STDOUT of the command with the write end of an externally
+    /// managed pipe. Only used for the last command of a chain. See
+    /// `set_stdin_pipe()` for the ownership semantics.
+    pub fn set_stdout_pipe(mut self, pipe: &Pipe) -> Self {
+        self.target_fd(libc::STDOUT_FILENO);
+        self.stdout_pipe_fd.replace(pipe.write_fd());
+        self
+    }
+
+    /// Makes STDOUT of this command nonblocking, if it's connected to the
+    /// next command of the chain, see `Pipe::set_write_nonblocking()`. Rarely
+    /// wanted: most commands expect a blocking STDOUT and misbehave otherwise.
+    pub fn set_nonblocking_pipe_to_next(mut self, nonblocking: bool) -> Self {
+        self.nonblocking_pipe_to_next = nonblocking;
         self
     }
-    pub fn add_arg(mut self, arg: &str) -> Self {
-        self.args.push(arg.to_string());
+
+    /// Makes the command a subshell like `(a; b) | c` in a shell: a thread
+    /// of the parent runs `list` (see `execute_cmd_list()`) with the
+    /// STDIN, STDOUT and STDERR of this command, i.e. its pipes and
+    /// redirects, and the exit status of the list becomes the exit code of
+    /// the command. Its state has no process (see
+    /// `ProcessState::in_parent()`); signals to it are forwarded to the
+    /// running processes of the list. The environment variables of the
+    /// command apply to all commands of the list; a working directory,
+    /// credentials, FD duplications to other FDs than STDIN, STDOUT or
+    /// STDERR and `ExecOptions::set_terminal()` aren't supported
+    /// (`PiperError::Unsupported`). Without an explicit executable,
+    /// executable and args[0] are the command line of the list in
+    /// parentheses, e.g. for error messages.
+    pub fn set_subshell(mut self, list: CmdList) -> Self {
+        self.subshell.replace(Arc::new(list));
         self
     }
-    pub fn set_input_redirect_path(mut self, input_redirect_path: &str) -> Self {
-        self.input_redirect_path.replace(input_redirect_path.to_string());
+
+    /// Makes the command a built-in filter: a thread of the parent calls
+    /// `function` with the STDIN and STDOUT of the command (i.e. the
+    /// neighboring pipes or the redirects) and the returned value becomes
+    /// its exit code, 101 if it panics. STDOUT is buffered and flushed when
+    /// the function returns; both are closed afterwards, i.e. the
+    /// neighbors see EOF. Like for `set_subshell()` the state has no
+    /// process (see `ProcessState::in_parent()`) and a working directory,
+    /// credentials and `ExecOptions::set_terminal()` aren't supported.
+    /// Nothing is executed, hence the environment variables of the command
+    /// don't apply. Without an explicit executable, executable and args[0]
+    /// are `<function>`.
+    ///
+    /// Every execution of the chain calls the function again, i.e. the
+    /// chain can be executed multiple times, also concurrently.
+    pub fn set_function<F>(mut self, function: F) -> Self
+        where F: Fn(&mut dyn Read, &mut dyn Write) -> i32 + Send + Sync + 'static {
+        self.function.replace(StageFn(Arc::new(function)));
         self
     }
-    pub fn set_output_redirect_path(mut self, output_redirect_path: &str) -> Self {
-        self.output_redirect_path.replace(output_redirect_path.to_string());
+
+    /// Makes the command a builtin, e.g. `Cd`, that gets the args of the
+    /// command. Without an explicit executable, the executable is args[0].
+    /// A chain of only this command runs it in the parent (with its
+    /// redirects), otherwise it runs in a forked child, see `Builtin`.
+    /// Only `ForkExecBackend` supports builtins in a child.
+    pub fn set_builtin(mut self, builtin: impl Builtin + 'static) -> Self {
+        self.builtin.replace(Arc::new(builtin));
         self
     }
+
+    /// Called before a redirect for `fd` is set. If there is already one,
+    /// the conflict is recorded and the old one is removed (last wins).
+    fn target_fd(&mut self, fd: libc::c_int) {
+        let already_targeted = match fd {
+            libc::STDIN_FILENO => {
+                self.input_redirect_path.take().is_some()
+                    | self.stdin_pipe_fd.take().is_some()
+                    | self.stdin_contents.take().is_some()
+            }
+            libc::STDERR_FILENO => {
+                self.error_redirect_path.take().is_some() | std::mem::take(&mut self.merge_stderr)
+            }
+            _ => self.output_redirect_path.take().is_some() | self.stdout_pipe_fd.take().is_some(),
+        };
+        if already_targeted && !self.redirect_conflicts.contains(&fd) {
+            self.redirect_conflicts.push(fd);
+        }
+    }
+    /// Returns a warning if args[0] has another basename than the executable.
+    fn argv0_mismatch(&self) -> Option<String> {
+        let basename = |path: &OsStr| path.as_bytes().rsplit(|b| *b == b'/').next().unwrap_or_default().to_vec();
+        let login_prefix = |argv0: Vec<u8>| if self.login_shell { [&b"-"[..], &argv0].concat() } else { argv0 };
+        match (&self.executable, self.args.first()) {
+            (Some(executable), Some(argv0))
+                if !self.argv0_override && login_prefix(basename(executable)) != login_prefix(basename(argv0)) => {
+                Some(format!("args[0] '{}' doesn't match the executable '{}'",
+                             argv0.to_string_lossy(), executable.to_string_lossy()))
+            }
+            _ => None,
+        }
+    }
+    /// The executable and args[0] that a subshell, function or builtin
+    /// gets if they aren't set: `(executable, argv0)`, each `None` if it's
+    /// set or can't be inferred.
+    fn implied_names(&self) -> (Option<OsString>, Option<OsString>) {
+        let name = if let Some(list) = &self.subshell {
+            OsString::from(format!("({})", list))
+        } else if self.function.is_some() {
+            OsString::from("<function>")
+        } else if let (Some(_), Some(argv0)) = (&self.builtin, self.args.first()) {
+            argv0.clone()
+        } else {
+            return (None, None);
+        };
+        let argv0 = Some(name.clone()).filter(|_| self.args.is_empty() && self.builtin.is_none());
+        (Some(name).filter(|_| self.executable.is_none()), argv0)
+    }
+    /// Returns the first error that `try_build()` would return.
+    fn validate(&self) -> Result<(), BuildError> {
+        let (executable, argv0) = self.implied_names();
+        if self.args.is_empty() && argv0.is_none() {
+            return Err(BuildError::MissingArgs);
+        }
+        if self.executable.is_none() && executable.is_none() {
+            return Err(BuildError::MissingExecutable);
+        }
+        let invalid = |(key, value): &&(String, String)| {
+            key.is_empty() || key.contains(['=', '\0']) || value.contains('\0')
+        };
+        if let Some((key, _)) = self.env.iter().find(invalid) {
+            return Err(BuildError::InvalidEnvVar { key: key.clone() });
+        }
+        Ok(())
+    }
     // it's intentionally that this doesn't return self
     fn set_is_first(&mut self, is_first: bool) {
         self.is_first = is_first;
@@ -168,19 +798,55 @@ impl BasicCmdBuilder {
     }
 }
 
-impl Builder<BasicCmd> for BasicCmdBuilder {
-
-    /// Builds a `BasicCmd`-object, if self is valid.
-    fn build(self) -> BasicCmd {
-        assert!(!self.args.is_empty(), "args must at least contain the executable name!");
-        BasicCmd {
-            executable: self.executable.expect("Must have value"),
+impl BasicCmdBuilder {
+    /// Builds a `BasicCmd`-object or returns an error if self is invalid.
+    pub fn try_build(mut self) -> Result<BasicCmd, BuildError> {
+        self.validate()?;
+        let (executable, argv0) = self.implied_names();
+        self.args.extend(argv0);
+        if let Some(executable) = executable {
+            self.executable.replace(executable);
+        }
+        if self.login_shell && self.args[0].as_bytes().first() != Some(&b'-') {
+            let mut argv0 = OsString::from("-");
+            argv0.push(&self.args[0]);
+            self.args[0] = argv0;
+        }
+        Ok(BasicCmd {
+            executable: self.executable.ok_or(BuildError::MissingExecutable)?,
             args: self.args,
+            fallbacks: self.fallbacks,
+            env: self.env,
+            clear_env: self.clear_env,
             in_red_path: self.input_redirect_path,
             out_red_path: self.output_redirect_path,
+            out_red_mode: self.output_redirect_mode_override.unwrap_or(self.output_redirect_mode),
+            err_red_path: self.error_redirect_path,
+            merge_stderr: self.merge_stderr,
+            fd_dups: self.fd_dups,
+            work_dir: self.work_dir,
+            uid: self.uid,
+            gid: self.gid,
+            groups: self.groups,
+            stdin_contents: self.stdin_contents,
+            stdin_pipe_fd: self.stdin_pipe_fd,
+            stdout_pipe_fd: self.stdout_pipe_fd,
+            nonblocking_pipe_to_next: self.nonblocking_pipe_to_next,
+            subshell: self.subshell,
+            function: self.function,
+            builtin: self.builtin,
             is_first: self.is_first,
             is_last: self.is_last,
-        }
+        })
+    }
+}
+
+impl Builder<BasicCmd> for BasicCmdBuilder {
+
+    /// Builds a `BasicCmd`-object, if self is valid. Panics otherwise,
+    /// see `try_build()`.
+    fn build(self) -> BasicCmd {
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
     }
 }
 
@@ -189,6 +855,7 @@ impl Builder<BasicCmd> for BasicCmdBuilder {
 ///  * `ps`
 ///  * `ls -l`
 ///  * `cat < in.txt | tee file.txt | wc -l > out.txt &`
+///
 /// It knows whether it should put the started process(es) in background
 /// or in foreground (blocking/waiting when executed).
 #[derive(Debug)]
@@ -196,6 +863,19 @@ pub struct CmdChain {
     /// Whether the waiting for the processes should be done
     /// blocking or non-blocking.
     background: bool,
+    /// Whether all file descriptors except STDIN, STDOUT and STDERR
+    /// are closed in the childs before exec().
+    close_fds: bool,
+    /// Optional mode the chain must be used in.
+    required_mode: Option<ChainMode>,
+    /// Whether a failing command fails the whole chain, see `result()`.
+    pipefail: bool,
+    /// Whether the exit status of the chain is inverted, see `result()`.
+    negate_status: bool,
+    /// Optional maximum run time of a foreground chain.
+    timeout: Option<Duration>,
+    /// Which commands the pty executors attach to the pseudo terminal.
+    pty_mode: PtyMode,
     /// All commands in correct order.
     cmds: Vec<BasicCmd>,
 }
@@ -207,6 +887,61 @@ impl CmdChain {
         self.background
     }
 
+    /// Getter for close_fds.
+    pub fn close_fds(&self) -> bool {
+        self.close_fds
+    }
+
+    /// Whether STDERR of the command with the index also goes into the pipe
+    /// to the next command (`|&`). Always false for the last command.
+    pub fn pipes_stderr(&self, index: usize) -> bool {
+        index + 1 < self.cmds.len() && self.cmds[index].merge_stderr
+    }
+
+    /// Getter for pipefail.
+    pub fn pipefail(&self) -> bool {
+        self.pipefail
+    }
+
+    /// Getter for negate_status.
+    pub fn negate_status(&self) -> bool {
+        self.negate_status
+    }
+
+    /// Getter for timeout.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Getter for pty_mode.
+    pub fn pty_mode(&self) -> PtyMode {
+        self.pty_mode
+    }
+
+    /// Aggregates the states of the finished processes of this chain into
+    /// one exit status, respecting `pipefail()` and `negate_status()`.
+    /// See `ChainResult`.
+    pub fn result(&self, states: &[ProcessState]) -> ChainResult {
+        let result = ChainResult::from_states(states, self.pipefail);
+        if self.negate_status { result.negated() } else { result }
+    }
+
+    /// Getter for required_mode.
+    pub fn required_mode(&self) -> Option<ChainMode> {
+        self.required_mode
+    }
+
+    /// Returns an error if the chain is required to run in
+    /// another mode than `mode`. Used by executors that imply
+    /// a mode, e.g. capturing the output requires to wait for
+    /// the processes (foreground).
+    pub fn ensure_mode(&self, mode: ChainMode) -> Result<(), BuildError> {
+        match self.required_mode {
+            Some(required) if required != mode => Err(BuildError::ModeMismatch { required, actual: mode }),
+            _ => Ok(()),
+        }
+    }
+
     /// Getter for cmds.
     pub fn cmds(&self) -> &Vec<BasicCmd> {
         &self.cmds
@@ -216,12 +951,258 @@ impl CmdChain {
     pub fn length(&self) -> usize {
         self.cmds.len()
     }
+
+    /// Returns a foreground chain with the commands `range` of self. If the
+    /// first (last) command of the segment gets its STDIN (STDOUT) from (to)
+    /// another segment, `in_path` (`out_path`) replaces its input (output).
+    /// `timeout` is the part of the timeout of self that is left for the
+    /// segment.
+    pub(crate) fn segment(&self,
+                          range: std::ops::Range<usize>,
+                          in_path: Option<&Path>,
+                          out_path: Option<&Path>,
+                          timeout: Option<Duration>) -> CmdChain {
+        let len = range.len();
+        let mut cmds = self.cmds[range].to_vec();
+        for (i, cmd) in cmds.iter_mut().enumerate() {
+            cmd.is_first = i == 0;
+            cmd.is_last = i + 1 == len;
+        }
+        if let (Some(path), Some(cmd)) = (in_path, cmds.first_mut()) {
+            cmd.in_red_path.replace(path.to_owned());
+            cmd.stdin_contents = None;
+            cmd.stdin_pipe_fd = None;
+        }
+        if let (Some(path), Some(cmd)) = (out_path, cmds.last_mut()) {
+            cmd.out_red_path.replace(path.to_owned());
+            cmd.out_red_mode = OutputRedirectMode::Truncate;
+            cmd.stdout_pipe_fd = None;
+        }
+        CmdChain {
+            background: false,
+            close_fds: self.close_fds,
+            required_mode: None,
+            pipefail: self.pipefail,
+            negate_status: false,
+            timeout,
+            pty_mode: self.pty_mode,
+            cmds,
+        }
+    }
+
+    /// Computes a deterministic signature of the chain, e.g. as key for
+    /// caching the output of a chain. It's derived from the executables,
+    /// args, redirects, environment variables and flags of the chain and
+    /// is stable across runs and Rust versions. FDs of externally managed pipes are only valid
+    /// during one run and therefore not part of the signature.
+    pub fn signature(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write_bool(self.background);
+        hasher.write_bool(self.close_fds);
+        if self.negate_status {
+            hasher.write_str("!");
+        }
+        hasher.write_usize(self.cmds.len());
+        for cmd in &self.cmds {
+            hasher.write_os_str(&cmd.executable);
+            hasher.write_usize(cmd.args.len());
+            for arg in &cmd.args {
+                hasher.write_os_str(arg);
+            }
+            hasher.write_opt_os_str(cmd.in_red_path.as_deref().map(Path::as_os_str));
+            hasher.write_opt_os_str(cmd.out_red_path.as_deref().map(Path::as_os_str));
+            // Properties added later only contribute if they differ from
+            // the default, so that existing signatures don't change.
+            match cmd.out_red_mode {
+                OutputRedirectMode::Truncate => {}
+                OutputRedirectMode::Append => hasher.write_str(">>"),
+                OutputRedirectMode::Clobber => hasher.write_str(">|"),
+            }
+            if let Some(path) = &cmd.err_red_path {
+                hasher.write_str("2>");
+                hasher.write_os_str(path.as_os_str());
+            }
+            if cmd.merge_stderr {
+                hasher.write_str("2>&1");
+            }
+            for dup in &cmd.fd_dups {
+                hasher.write_str(">&");
+                hasher.write_usize(dup.from as usize);
+                hasher.write_usize(dup.to as usize);
+            }
+            for fallback in &cmd.fallbacks {
+                hasher.write_str("||");
+                hasher.write_os_str(fallback);
+            }
+            if let Some(work_dir) = &cmd.work_dir {
+                hasher.write_str("cd");
+                hasher.write_os_str(work_dir.as_os_str());
+            }
+            if let Some(uid) = cmd.uid {
+                hasher.write_str("uid");
+                hasher.write_usize(uid as usize);
+            }
+            if let Some(gid) = cmd.gid {
+                hasher.write_str("gid");
+                hasher.write_usize(gid as usize);
+            }
+            if let Some(groups) = &cmd.groups {
+                hasher.write_str("groups");
+                for gid in groups {
+                    hasher.write_usize(*gid as usize);
+                }
+            }
+            if let Some(contents) = &cmd.stdin_contents {
+                hasher.write_str("<<<");
+                hasher.write_usize(contents.len());
+                hasher.write(contents);
+            }
+            if cmd.clear_env {
+                hasher.write_str("env -i");
+            }
+            if let Some(list) = &cmd.subshell {
+                hasher.write_str("()");
+                hasher.write_str(&list.to_string());
+            }
+            // a function can't be hashed, only its executable name is used
+            if cmd.function.is_some() {
+                hasher.write_str("fn");
+            }
+            if cmd.builtin.is_some() {
+                hasher.write_str("builtin");
+            }
+            // the keys are unique; the order in which they were set doesn't matter
+            let mut env: Vec<&(String, String)> = cmd.env.iter().collect();
+            env.sort();
+            for (key, value) in env {
+                hasher.write_str("env");
+                hasher.write_str(key);
+                hasher.write_str(value);
+            }
+        }
+        hasher.finish()
+    }
+}
+
+/// 64 bit FNV-1a hash. Unlike `std::collections::hash_map::DefaultHasher`
+/// its output is guaranteed to never change.
+/// See http://www.isthe.com/chongo/tech/comp/fnv/
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write(&(value as u64).to_le_bytes());
+    }
+
+    fn write_bool(&mut self, value: bool) {
+        self.write(&[value as u8]);
+    }
+
+    // length prefixed, so that ["ab", "c"] and ["a", "bc"] differ
+    fn write_str(&mut self, value: &str) {
+        self.write_os_str(OsStr::new(value));
+    }
+
+    // the raw bytes; the same as write_str() for valid UTF-8
+    fn write_os_str(&mut self, value: &OsStr) {
+        self.write_usize(value.len());
+        self.write(value.as_bytes());
+    }
+
+    fn write_opt_os_str(&mut self, value: Option<&OsStr>) {
+        self.write_bool(value.is_some());
+        if let Some(value) = value {
+            self.write_os_str(value);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for CmdChain {
+    /// Reconstructs the command line, e.g.
+    /// `cat < in.txt | tee file.txt | wc -l > out.txt &`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negate_status {
+            write!(f, "! ")?;
+        }
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            if i > 0 {
+                write!(f, " | ")?;
+            }
+            write!(f, "{}", cmd)?;
+        }
+        if self.background {
+            write!(f, " &")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the processes of a chain are waited for (foreground)
+/// or not (background).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChainMode {
+    Foreground,
+    Background,
+}
+
+/// Which file descriptors of a chain are connected to the slave side of a
+/// pseudo terminal, e.g. for programs that only colorize their output or
+/// behave interactively if `isatty()` is true. The pipes between the
+/// commands always stay pipes and redirects have precedence.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PtyMode {
+    /// STDIN of the first command.
+    FirstStage,
+    /// STDOUT of the last command.
+    LastStage,
+    /// STDIN of the first command, STDOUT of the last command and STDERR
+    /// of every command (default).
+    #[default]
+    AllStages,
+}
+
+/// What happens if multiple redirects target the same file descriptor of
+/// a command, e.g. two output redirects (`> a > b`) or an output redirect
+/// and `set_stdout_pipe()`.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum RedirectConflictPolicy {
+    /// The redirect that was set last is used, the others are discarded (default).
+    #[default]
+    LastWins,
+    /// Building the chain fails with `BuildError::ConflictingRedirect`.
+    Error,
 }
 
 /// Builder for `CmdChain`.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CmdChainBuilder {
     background: bool,
+    close_fds: bool,
+    required_mode: Option<ChainMode>,
+    pipefail: bool,
+    negate_status: bool,
+    timeout: Option<Duration>,
+    pty_mode: PtyMode,
+    redirect_conflict_policy: RedirectConflictPolicy,
+    /// Optional callback for the argv[0] lint, see `set_argv0_warning()`.
+    argv0_warning: Option<fn(&str)>,
+    /// Optional STDIN contents of the first command, see `set_stdin_bytes()`.
+    stdin_bytes: Option<Vec<u8>>,
     cmds: Vec<BasicCmdBuilder>,
 }
 
@@ -230,6 +1211,15 @@ impl CmdChainBuilder {
     pub fn new() -> Self {
         CmdChainBuilder {
             background: false,
+            close_fds: false,
+            required_mode: None,
+            pipefail: false,
+            negate_status: false,
+            timeout: None,
+            pty_mode: PtyMode::AllStages,
+            redirect_conflict_policy: RedirectConflictPolicy::LastWins,
+            argv0_warning: None,
+            stdin_bytes: None,
             cmds: vec![]
         }
     }
@@ -239,26 +1229,308 @@ impl CmdChainBuilder {
         self
     }
 
+    /// If true, every child closes all file descriptors except STDIN, STDOUT
+    /// and STDERR right before exec(), so that the executed programs don't
+    /// inherit FDs of the parent they don't know about.
+    pub fn set_close_fds(mut self, close_fds: bool) -> Self {
+        self.close_fds = close_fds;
+        self
+    }
+
+    /// Like `set -o pipefail` in a shell: if true, the exit status of the
+    /// chain (see `CmdChain::result()`) is the one of the first failed
+    /// command instead of the one of the last command.
+    pub fn set_pipefail(mut self, pipefail: bool) -> Self {
+        self.pipefail = pipefail;
+        self
+    }
+
+    /// Like the `!` prefix of a pipeline in a shell: if true, the exit
+    /// status of the chain (see `CmdChain::result()`) is inverted, i.e.
+    /// 0 becomes 1 and every other status becomes 0. Applied after
+    /// `set_pipefail()`. The exit codes of the processes stay unchanged.
+    pub fn set_negate_status(mut self, negate_status: bool) -> Self {
+        self.negate_status = negate_status;
+        self
+    }
+
+    /// Limits the run time of a foreground chain. If the processes aren't
+    /// finished after `timeout`, the executor sends `SIGTERM` to the ones
+    /// that are still running and `SIGKILL` after `TIMEOUT_KILL_GRACE`;
+    /// to the whole process group if the chain has one (see
+    /// `ExecOptions::set_process_group()`). Then all states are marked as
+    /// timed out, see `ProcessState::timed_out()`. Every executor enforces
+    /// it, also while it reads the output or writes the input of the chain,
+    /// with a single deadline that counts from the spawn. Background chains
+    /// aren't waited for, hence the timeout doesn't apply; see
+    /// `wait_all_timeout()` and `kill_all()` for them. It also doesn't
+    /// apply together with `ExecOptions::set_terminal()`.
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout.replace(timeout);
+        self
+    }
+
+    /// Which commands get the pseudo terminal of
+    /// `execute_piped_cmd_chain_in_pty()` and `spawn_piped_cmd_chain_pty()`,
+    /// `PtyMode::AllStages` by default. The other executors ignore it.
+    pub fn set_pty_mode(mut self, pty_mode: PtyMode) -> Self {
+        self.pty_mode = pty_mode;
+        self
+    }
+
+    /// Requires the chain to run in foreground (`set_background(false)`).
+    /// Otherwise `try_build()` returns an error.
+    pub fn require_foreground(mut self) -> Self {
+        self.required_mode.replace(ChainMode::Foreground);
+        self
+    }
+
+    /// Requires the chain to run in background (`set_background(true)`).
+    /// Otherwise `try_build()` returns an error. Executors that wait for
+    /// the chain (e.g. capturing its output) refuse to run it.
+    pub fn require_background(mut self) -> Self {
+        self.required_mode.replace(ChainMode::Background);
+        self
+    }
+
+    /// Sets what happens if multiple redirects of a command target the same
+    /// file descriptor. Default is `RedirectConflictPolicy::LastWins`.
+    pub fn set_redirect_conflict_policy(mut self, policy: RedirectConflictPolicy) -> Self {
+        self.redirect_conflict_policy = policy;
+        self
+    }
+
+    /// Enables a lint during the build: `callback` receives a warning for
+    /// every command whose args[0] has another basename than the executable
+    /// (e.g. executable `grep` but args[0] `ls`), which is usually a
+    /// copy-paste error. Commands with `set_argv0()` are not checked.
+    /// It's only a diagnostic; the build doesn't fail.
+    pub fn set_argv0_warning(mut self, callback: fn(&str)) -> Self {
+        self.argv0_warning.replace(callback);
+        self
+    }
+
     pub fn add_cmd(mut self, cmd: BasicCmdBuilder) -> Self {
         self.cmds.push(cmd);
         self
     }
+
+    /// Like `|&` in bash: STDERR of the most recently added command also
+    /// goes into the pipe to the next command, e.g. to post-process the
+    /// diagnostics of a compiler. Same as `2>&1 |`, i.e.
+    /// `BasicCmdBuilder::merge_stderr_into_stdout()` on that command.
+    /// Panics if no command was added yet.
+    pub fn pipe_stderr(mut self) -> Self {
+        let cmd = self.cmds.pop().expect("pipe_stderr() requires a command before the pipe");
+        self.cmds.push(cmd.merge_stderr_into_stdout(true));
+        self
+    }
+
+    /// Feeds the bytes into STDIN of the first command of the chain, see
+    /// `BasicCmdBuilder::set_stdin_contents()`. Replaces other STDIN sources
+    /// of the first command (a conflict, see `RedirectConflictPolicy`).
+    pub fn set_stdin_bytes(mut self, bytes: impl AsRef<[u8]>) -> Self {
+        self.stdin_bytes.replace(bytes.as_ref().to_vec());
+        self
+    }
 }
 
-impl Builder<CmdChain> for CmdChainBuilder {
-    /// Builds a `CmdChain`-object, if self is valid.
-    fn build(mut self) -> CmdChain {
+impl CmdChainBuilder {
+    /// Builds a `CmdChain`-object or returns the first error if self
+    /// or one of the commands is invalid.
+    pub fn try_build(mut self) -> Result<CmdChain, BuildError> {
+        self.apply_stdin_bytes();
+        let actual = if self.background { ChainMode::Background } else { ChainMode::Foreground };
+        match self.required_mode {
+            Some(required) if required != actual => {
+                return Err(BuildError::ModeMismatch { required, actual });
+            }
+            _ => {}
+        }
+
+        if self.redirect_conflict_policy == RedirectConflictPolicy::Error {
+            if let Some(fd) = self.cmds.iter().flat_map(|cmd| cmd.redirect_conflicts.iter()).next() {
+                return Err(BuildError::ConflictingRedirect { fd: *fd });
+            }
+        }
+
+        if let Some(callback) = self.argv0_warning {
+            self.cmds.iter()
+                .filter_map(|cmd| cmd.argv0_mismatch())
+                .for_each(|warning| callback(&warning));
+        }
+
         let len = self.cmds.len();
         for i in 0..len {
             let cmd = &mut self.cmds[i];
             cmd.set_is_first(i == 0);
             cmd.set_is_last(i + 1 == len);
         }
-        CmdChain {
+        Ok(CmdChain {
             background: self.background,
+            close_fds: self.close_fds,
+            required_mode: self.required_mode,
+            pipefail: self.pipefail,
+            negate_status: self.negate_status,
+            timeout: self.timeout,
+            pty_mode: self.pty_mode,
             cmds: self.cmds.into_iter()
-                .map(|cmd| cmd.build())
-                .collect()
+                .map(|cmd| cmd.try_build())
+                .collect::<Result<Vec<BasicCmd>, BuildError>>()?
+        })
+    }
+
+    /// Moves the bytes of `set_stdin_bytes()` into the first command.
+    fn apply_stdin_bytes(&mut self) {
+        if let (Some(bytes), Some(first)) = (self.stdin_bytes.take(), self.cmds.first_mut()) {
+            *first = std::mem::take(first).set_stdin_contents(bytes);
+        }
+    }
+
+    /// Like `try_build()` but reports all errors instead of only the first
+    /// one, each with the index of the invalid command, e.g. for chains that
+    /// are generated programmatically. A `ModeMismatch` belongs to the whole
+    /// chain; it's reported with the index of the last command, where a
+    /// shell line has the `&`.
+    pub fn try_build_collecting(mut self) -> Result<CmdChain, Vec<(usize, BuildError)>> {
+        self.apply_stdin_bytes();
+        let mut errors = vec![];
+        let actual = if self.background { ChainMode::Background } else { ChainMode::Foreground };
+        match self.required_mode {
+            Some(required) if required != actual => {
+                errors.push((self.cmds.len().saturating_sub(1), BuildError::ModeMismatch { required, actual }));
+            }
+            _ => {}
+        }
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            if self.redirect_conflict_policy == RedirectConflictPolicy::Error {
+                if let Some(fd) = cmd.redirect_conflicts.first() {
+                    errors.push((i, BuildError::ConflictingRedirect { fd: *fd }));
+                }
+            }
+            if let Err(e) = cmd.validate() {
+                errors.push((i, e));
+            }
+        }
+        // sort by index, so that a ModeMismatch is next to the errors of its command
+        errors.sort_by_key(|(i, _)| *i);
+        if errors.is_empty() {
+            Ok(self.try_build().expect("all errors are collected above"))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl Builder<CmdChain> for CmdChainBuilder {
+    /// Builds a `CmdChain`-object, if self is valid. Panics otherwise,
+    /// see `try_build()`.
+    fn build(self) -> CmdChain {
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+/// What STDOUT of a process was connected to when it was executed.
+/// Helps to find out why a program did or didn't colorize its output.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StdoutTarget {
+    /// A terminal, e.g. the terminal of the parent or a pty.
+    Tty,
+    /// A pipe (or socket), e.g. to the next command or to the parent.
+    Pipe,
+    /// A file, e.g. from a `> out.txt` redirect.
+    File,
+    /// `/dev/null` or a closed FD.
+    Null,
+}
+
+/// What STDIN of a process was connected to when it was executed.
+/// Counterpart of `StdoutTarget`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StdinSource {
+    /// A terminal, e.g. the terminal of the parent or a pty.
+    Tty,
+    /// A pipe (or socket), e.g. from the previous command or from the parent.
+    Pipe,
+    /// A file, e.g. from a `< in.txt` redirect.
+    File,
+    /// `/dev/null` or a closed FD.
+    Null,
+    /// Bytes from memory, see `BasicCmdBuilder::set_stdin_contents()`.
+    Bytes,
+}
+
+/// How a finished process ended.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ProcessOutcome {
+    /// The process exited normally with this exit code.
+    Exited(libc::c_int),
+    /// The process was terminated by this signal.
+    Signaled(libc::c_int),
+}
+
+/// How a finished process ended, including whether a signal produced a
+/// core dump. Unlike the exit code it distinguishes a process that was
+/// killed by `SIGKILL` from one that exited with 137.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TerminationReason {
+    /// The process exited normally with this exit code (`WEXITSTATUS`).
+    Exited(libc::c_int),
+    /// The process was terminated by this signal (`WTERMSIG`) and whether
+    /// the kernel wrote a core dump (`WCOREDUMP`).
+    Signaled(libc::c_int, bool),
+}
+
+/// The exit status of a whole chain, i.e. what a shell stores in `$?`.
+/// Without pipefail it's the status of the last command. With pipefail
+/// it's the status of the first command (in the order of the chain) that
+/// didn't exit with 0, or 0 if all succeeded. Note that bash reports the
+/// last failed command instead. A command that was terminated by a signal
+/// has the status 128 + signal.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChainResult {
+    /// The exit status of the chain.
+    exit_code: libc::c_int,
+    /// Index of the command the status comes from. `None` for an empty chain.
+    stage: Option<usize>,
+}
+
+impl ChainResult {
+    /// Aggregates the states of finished processes. Panics if a process
+    /// isn't finished yet.
+    pub fn from_states(states: &[ProcessState], pipefail: bool) -> Self {
+        // exit_code() only panics for the states it's called for
+        assert!(states.iter().all(ProcessState::finished), "All processes must be finished before the chain has a result!");
+        let failed = states.iter().position(|state| state.exit_code() != 0);
+        let stage = match failed {
+            Some(i) if pipefail => Some(i),
+            _ => states.len().checked_sub(1),
+        };
+        Self {
+            exit_code: stage.map(|i| states[i].exit_code()).unwrap_or(0),
+            stage,
+        }
+    }
+
+    /// Getter for exit_code.
+    pub fn exit_code(&self) -> libc::c_int {
+        self.exit_code
+    }
+    /// Getter for stage, i.e. the index of the command that determined
+    /// the exit status.
+    pub fn stage(&self) -> Option<usize> {
+        self.stage
+    }
+    /// Whether the exit status is 0.
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+    /// Inverts the exit status like `!` in a shell: 0 becomes 1 and every
+    /// other status becomes 0. The stage stays the same.
+    pub fn negated(self) -> Self {
+        Self {
+            exit_code: (self.exit_code == 0) as libc::c_int,
+            ..self
         }
     }
 }
@@ -271,16 +1543,127 @@ pub struct ProcessState {
     executable: String,
     /// Pid.
     pid: libc::pid_t,
+    /// The process group, if the chain runs in its own one.
+    pgid: Option<libc::pid_t>,
     /// If the process is finished or still running.
     finished: bool,
     /// Exit code. Only sane value if finished is true.
     exit_code: libc::c_int,
+    /// The signal that terminated the process, if it didn't exit normally.
+    term_signal: Option<libc::c_int>,
+    /// Whether the signal that terminated the process produced a core dump.
+    core_dumped: bool,
+    /// Whether the chain of the process ran into its timeout.
+    timed_out: bool,
+    /// Why the command couldn't be started, if it failed before exec().
+    spawn_error: Option<PiperError>,
+    /// What STDOUT was connected to.
+    stdout_target: StdoutTarget,
+    /// What STDIN was connected to.
+    stdin_source: StdinSource,
+    /// When the process (or its chain) was started.
+    started_at: Instant,
+    /// When the process was reaped.
+    finished_at: Option<Instant>,
+    /// A pidfd of the process, see `ExecOptions::set_pidfds()`.
+    pidfd: Option<OwnedFd>,
+    /// Gets notified when the process is reaped, see `ExecOptions::set_observer()`.
+    observer: Option<Arc<dyn ChainObserver>>,
+    /// The thread of a command that runs in the parent, see `in_parent()`.
+    stage: Option<Arc<StageThread>>,
+    /// Whether the command asks the caller to exit, see `exit_requested()`.
+    exit_requested: bool,
 }
 
 impl ProcessState {
     /// Constructor.
     pub fn new(executable: String, pid: i32) -> Self {
-        Self { executable, pid, finished: false, exit_code: -1 }
+        Self {
+            executable,
+            pid,
+            pgid: None,
+            finished: false,
+            exit_code: -1,
+            term_signal: None,
+            core_dumped: false,
+            timed_out: false,
+            spawn_error: None,
+            stdout_target: StdoutTarget::Pipe,
+            stdin_source: StdinSource::Pipe,
+            started_at: Instant::now(),
+            finished_at: None,
+            pidfd: None,
+            observer: None,
+            stage: None,
+            exit_requested: false,
+        }
+    }
+
+    /// Sets what STDIN of the process is connected to.
+    pub(crate) fn set_stdin_source(&mut self, source: StdinSource) {
+        self.stdin_source = source;
+    }
+
+    /// Sets the process group of the process.
+    pub(crate) fn set_observer(&mut self, observer: Arc<dyn ChainObserver>) {
+        self.observer.replace(observer);
+    }
+
+    pub(crate) fn observer(&self) -> Option<&Arc<dyn ChainObserver>> {
+        self.observer.as_ref()
+    }
+
+    pub(crate) fn set_pidfd(&mut self, pidfd: OwnedFd) {
+        self.pidfd.replace(pidfd);
+    }
+
+    pub(crate) fn set_stage(&mut self, stage: Arc<StageThread>) {
+        self.stage.replace(stage);
+    }
+
+    pub(crate) fn stage(&self) -> Option<&Arc<StageThread>> {
+        self.stage.as_ref()
+    }
+
+    /// An FD that becomes readable when the process terminates: the pidfd
+    /// or, for a command that runs in the parent, one of its thread.
+    pub(crate) fn wait_fd(&self) -> Option<libc::c_int> {
+        self.pidfd().or_else(|| self.stage.as_ref().map(|stage| stage.done_fd()))
+    }
+
+    pub(crate) fn set_pgid(&mut self, pgid: libc::pid_t) {
+        self.pgid.replace(pgid);
+    }
+
+    /// Marks the state of a builtin that asks the caller to exit.
+    pub(crate) fn set_exit_requested(&mut self) {
+        self.exit_requested = true;
+    }
+
+    /// Sets why the command couldn't be started.
+    pub(crate) fn set_spawn_error(&mut self, err: PiperError) {
+        self.spawn_error.replace(err);
+    }
+
+    /// Marks that the chain of the process ran into its timeout.
+    pub(crate) fn set_timed_out(&mut self) {
+        self.timed_out = true;
+    }
+
+    /// Marks that the process produced a core dump (`WCOREDUMP`).
+    pub(crate) fn set_core_dumped(&mut self) {
+        self.core_dumped = true;
+    }
+
+    /// Sets when the process was started. The executor uses the time
+    /// before the first `fork()` of the chain.
+    pub(crate) fn set_started_at(&mut self, started_at: Instant) {
+        self.started_at = started_at;
+    }
+
+    /// Sets what STDOUT of the process is connected to.
+    pub(crate) fn set_stdout_target(&mut self, target: StdoutTarget) {
+        self.stdout_target = target;
     }
 
     /// Updates the struct.
@@ -290,12 +1673,44 @@ impl ProcessState {
         }
         self.finished = true;
         self.exit_code = exit_code;
+        self.finished_at.replace(Instant::now());
+    }
+
+    /// Updates the struct for a process that was terminated by a signal.
+    /// Like a shell, the exit code becomes 128 + signal.
+    pub fn finish_by_signal(&mut self, signal: libc::c_int) {
+        self.finish(128 + signal);
+        self.term_signal.replace(signal);
     }
 
     /// Getter for pid.
     pub fn pid(&self) -> i32 {
         self.pid
     }
+    /// Getter for pgid. `None` if the process runs in the process group
+    /// of the parent, see `ExecOptions::set_process_group()`.
+    pub fn pgid(&self) -> Option<libc::pid_t> {
+        self.pgid
+    }
+    /// Getter for pidfd. The pidfd becomes readable when the process
+    /// terminates, e.g. for `poll()`/`epoll` in an event loop of the
+    /// caller. It stays valid (and owned by the state) after the process
+    /// was reaped. `None` unless `ExecOptions::set_pidfds()` is set and the
+    /// kernel supports pidfds (Linux >= 5.3).
+    pub fn pidfd(&self) -> Option<libc::c_int> {
+        self.pidfd.as_ref().map(|pidfd| pidfd.as_raw_fd())
+    }
+    /// Whether the command runs (or ran) in the parent instead of in a
+    /// process of its own, e.g. a subshell (see
+    /// `BasicCmdBuilder::set_subshell()`); its pid is 0 then.
+    pub fn in_parent(&self) -> bool {
+        self.pid == 0
+    }
+    /// Whether the command was a builtin like `Exit` that ran in the parent
+    /// and asks the caller (e.g. a shell) to exit with `exit_code()`.
+    pub fn exit_requested(&self) -> bool {
+        self.exit_requested
+    }
     /// Getter for finished. If false process
     /// is still running.
     pub fn finished(&self) -> bool {
@@ -312,4 +1727,413 @@ impl ProcessState {
     pub fn executable(&self) -> &str {
         &self.executable
     }
+
+    /// Getter for started_at.
+    pub fn started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    /// Getter for finished_at. `None` if the process is still running. It's
+    /// the time when the process was reaped, i.e. when the state was updated.
+    pub fn finished_at(&self) -> Option<Instant> {
+        self.finished_at
+    }
+
+    /// Getter for term_signal. `None` if the process is still running or
+    /// exited normally.
+    pub fn term_signal(&self) -> Option<libc::c_int> {
+        self.term_signal
+    }
+
+    /// Getter for core_dumped. Only true if the process was terminated by a
+    /// signal and the kernel wrote a core dump, which depends on the signal
+    /// and the `RLIMIT_CORE` of the process.
+    pub fn core_dumped(&self) -> bool {
+        self.core_dumped
+    }
+
+    /// Getter for spawn_error. Only set with
+    /// `ExecOptions::set_spawn_failures_as_exit_codes()`; otherwise the
+    /// whole chain fails to start.
+    pub fn spawn_error(&self) -> Option<&PiperError> {
+        self.spawn_error.as_ref()
+    }
+
+    /// Getter for timed_out. True for all processes of a chain that ran
+    /// into its timeout (see `CmdChainBuilder::set_timeout()`), also for
+    /// the ones that finished in time.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// How the process ended.
+    pub fn outcome(&self) -> ProcessOutcome {
+        assert!(self.finished, "A process must be finished before it has an outcome!");
+        match self.term_signal {
+            Some(signal) => ProcessOutcome::Signaled(signal),
+            None => ProcessOutcome::Exited(self.exit_code),
+        }
+    }
+
+    /// How the process ended. `None` if it isn't finished yet.
+    pub fn termination_reason(&self) -> Option<TerminationReason> {
+        if !self.finished {
+            return None;
+        }
+        Some(match self.term_signal {
+            Some(signal) => TerminationReason::Signaled(signal, self.core_dumped),
+            None => TerminationReason::Exited(self.exit_code),
+        })
+    }
+
+    /// Getter for stdout_target. It's derived from how the executor set up
+    /// the process; for all but the last command of a chain it's `Pipe`.
+    pub fn stdout_target(&self) -> StdoutTarget {
+        self.stdout_target
+    }
+
+    /// Getter for stdin_source. It's derived from how the executor set up
+    /// the process; for all but the first command of a chain it's `Pipe`.
+    pub fn stdin_source(&self) -> StdinSource {
+        self.stdin_source
+    }
+
+    /// Sends the signal to the process, e.g. to stop a hanging chain. A no-op
+    /// if the process is already finished, because its pid may be reused.
+    /// Uses `pidfd_send_signal()` if the state has a pidfd. For a command
+    /// that runs in the parent (see `in_parent()`) the signal is forwarded
+    /// to the processes it started, e.g. the ones of a subshell.
+    /// The process still has to be waited for with `update_process_states()`.
+    pub fn send_signal(&self, signal: libc::c_int) -> Result<(), PiperError> {
+        if self.finished {
+            return Ok(());
+        }
+        if self.in_parent() {
+            return self.stage.as_ref().map_or(Ok(()), |stage| stage.send_signal(signal));
+        }
+        if let Some(pidfd) = self.pidfd() {
+            return crate::wait::pidfd_send_signal(pidfd, signal)
+                .map_err(|errno| PiperError::SignalFailed { pid: self.pid, errno });
+        }
+        if unsafe { libc::kill(self.pid, signal) } == -1 {
+            return Err(PiperError::SignalFailed { pid: self.pid, errno: errno::errno().0 });
+        }
+        Ok(())
+    }
+
+    /// Asks the process to terminate with `SIGTERM`, see `send_signal()`.
+    pub fn terminate(&self) -> Result<(), PiperError> {
+        self.send_signal(libc::SIGTERM)
+    }
+
+    /// Kills the process with `SIGKILL`, see `send_signal()`.
+    pub fn kill(&self) -> Result<(), PiperError> {
+        self.send_signal(libc::SIGKILL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grep_chain(pattern: &str) -> CmdChain {
+        CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+                    .set_input_redirect_path("in.txt")
+            ).add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("grep")
+                    .add_arg("grep")
+                    .add_arg(pattern)
+            ).build()
+    }
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(grep_chain("abc").signature(), grep_chain("abc").signature());
+        assert_ne!(grep_chain("abc").signature(), grep_chain("abd").signature());
+        // must never change across runs/versions
+        assert_eq!(grep_chain("abc").signature(), 4679466312667694981);
+        assert_ne!(grep_chain("ab").signature(), grep_chain("ab ").signature());
+
+        // 'A=1 B=2 env'
+        let env_chain = |vars: &[(&str, &str)]| {
+            let cmd = vars.iter().fold(BasicCmdBuilder::new().set_executable("env").add_arg("env"), |cmd, (key, value)| {
+                cmd.add_env(key, value)
+            });
+            CmdChainBuilder::new().add_cmd(cmd).build()
+        };
+        assert_ne!(env_chain(&[]).signature(), env_chain(&[("A", "1")]).signature());
+        assert_ne!(env_chain(&[("A", "1")]).signature(), env_chain(&[("A", "2")]).signature());
+        assert_eq!(env_chain(&[("A", "1"), ("B", "2")]).signature(), env_chain(&[("B", "2"), ("A", "1")]).signature());
+    }
+
+    #[test]
+    fn test_required_mode() {
+        let builder = || CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("ls").add_arg("ls"));
+
+        let err = builder().require_foreground().set_background(true).try_build().unwrap_err();
+        assert_eq!(err, BuildError::ModeMismatch { required: ChainMode::Foreground, actual: ChainMode::Background });
+
+        let chain = builder().require_background().set_background(true).try_build().unwrap();
+        // e.g. capturing the output requires foreground
+        assert_eq!(
+            chain.ensure_mode(ChainMode::Foreground).unwrap_err(),
+            BuildError::ModeMismatch { required: ChainMode::Background, actual: ChainMode::Foreground }
+        );
+        assert!(chain.ensure_mode(ChainMode::Background).is_ok());
+    }
+
+    #[test]
+    fn test_redirect_conflict_policy() {
+        let builder = |policy| CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("ls")
+                    .add_arg("ls")
+                    .set_output_redirect_path("a.txt")
+                    .set_output_redirect_path("b.txt")
+            )
+            .set_redirect_conflict_policy(policy);
+
+        let chain = builder(RedirectConflictPolicy::LastWins).try_build().unwrap();
+        assert_eq!(chain.cmds()[0].out_red_path().as_deref(), Some(Path::new("b.txt")));
+
+        let err = builder(RedirectConflictPolicy::Error).try_build().unwrap_err();
+        assert_eq!(err, BuildError::ConflictingRedirect { fd: libc::STDOUT_FILENO });
+    }
+
+    thread_local! {
+        static ARGV0_WARNINGS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(vec![]) };
+    }
+
+    fn record_argv0_warning(warning: &str) {
+        ARGV0_WARNINGS.with(|w| w.borrow_mut().push(warning.to_owned()));
+    }
+
+    #[test]
+    fn test_argv0_warning() {
+        CmdChainBuilder::new()
+            .set_argv0_warning(record_argv0_warning)
+            .add_cmd(BasicCmdBuilder::new().set_executable("/bin/grep").add_arg("grep").add_arg("a"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("busybox").set_argv0("sh"))
+            .build();
+        assert!(ARGV0_WARNINGS.with(|w| w.borrow().is_empty()));
+
+        // copy-paste error
+        CmdChainBuilder::new()
+            .set_argv0_warning(record_argv0_warning)
+            .add_cmd(BasicCmdBuilder::new().set_executable("grep").add_arg("ls"))
+            .build();
+        assert_eq!(
+            ARGV0_WARNINGS.with(|w| w.borrow().clone()),
+            vec!["args[0] 'ls' doesn't match the executable 'grep'"]
+        );
+    }
+
+    #[test]
+    fn test_as_login_shell() {
+        let cmd = BasicCmdBuilder::new().set_executable("bash").add_arg("bash").as_login_shell().build();
+        assert_eq!(cmd.args()[0], "-bash");
+        assert_eq!(cmd.executable(), "bash");
+
+        // composes with set_argv0() in any order
+        let cmd = BasicCmdBuilder::new().set_executable("/bin/bash").as_login_shell().set_argv0("sh").build();
+        assert_eq!(cmd.args()[0], "-sh");
+        assert_eq!(cmd.executable(), "/bin/bash");
+    }
+
+    #[test]
+    fn test_kill() {
+        // 'sleep 10 | sleep 10 &'
+        let sleep = || BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("10");
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(sleep())
+            .add_cmd(sleep())
+            .set_background(true)
+            .build();
+        let mut states = crate::execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].termination_reason(), None);
+
+        states[0].kill().unwrap();
+        states[1].terminate().unwrap();
+        crate::update_process_states(&mut states, false);
+        assert_eq!(states[0].outcome(), ProcessOutcome::Signaled(libc::SIGKILL));
+        assert_eq!(states[1].outcome(), ProcessOutcome::Signaled(libc::SIGTERM));
+        // exit code 137 isn't confused with a normal exit
+        assert_eq!(states[0].exit_code(), 128 + libc::SIGKILL);
+        assert_eq!(states[0].term_signal(), Some(libc::SIGKILL));
+        assert!(!states[0].core_dumped());
+        assert_eq!(states[0].termination_reason(), Some(TerminationReason::Signaled(libc::SIGKILL, false)));
+
+        // no-op for finished processes
+        assert_eq!(states[0].kill(), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid_env_var() {
+        let cmd = |key: &str, value: &str| BasicCmdBuilder::new().set_executable("env").add_arg("env").add_env(key, value);
+        for key in ["", "A=B", "A\0"] {
+            assert_eq!(cmd(key, "x").try_build().unwrap_err(), BuildError::InvalidEnvVar { key: key.to_owned() });
+        }
+        assert_eq!(cmd("A", "x\0").try_build().unwrap_err(), BuildError::InvalidEnvVar { key: "A".to_owned() });
+        assert!(cmd("A_1", "x=y").try_build().is_ok());
+    }
+
+    #[test]
+    fn test_try_build_collecting() {
+        let valid = || BasicCmdBuilder::new().set_executable("cat").add_arg("cat");
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(valid())
+            .add_cmd(BasicCmdBuilder::new().add_arg("grep"))
+            .add_cmd(valid())
+            .add_cmd(BasicCmdBuilder::new().set_executable("wc"))
+            .try_build_collecting();
+        assert_eq!(
+            cmd_chain.unwrap_err(),
+            vec![(1, BuildError::MissingExecutable), (3, BuildError::MissingArgs)]
+        );
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(valid())
+            .add_cmd(valid())
+            .try_build_collecting()
+            .unwrap();
+        assert_eq!(cmd_chain.length(), 2);
+
+        // executable and args[0] of these are inferred, like in try_build()
+        let list = crate::parse_cmd_list("echo a").unwrap();
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_subshell(list))
+            .add_cmd(BasicCmdBuilder::new().set_function(|_, _| 0))
+            .add_cmd(BasicCmdBuilder::new().add_arg("cd").set_builtin(crate::Cd))
+            .try_build_collecting()
+            .unwrap();
+        let executables: Vec<_> = cmd_chain.cmds().iter().map(|cmd| cmd.executable().to_owned()).collect();
+        assert_eq!(executables, vec!["(echo a)", "<function>", "cd"]);
+    }
+
+    #[test]
+    fn test_set_output_redirect_mode() {
+        for mode in [OutputRedirectMode::Truncate, OutputRedirectMode::Append, OutputRedirectMode::Clobber].iter() {
+            let cmd = BasicCmdBuilder::new()
+                .set_executable("echo")
+                .add_arg("echo")
+                .set_output_redirect_path("out.txt")
+                .set_output_redirect_mode(*mode)
+                .build();
+            assert_eq!(cmd.out_red_mode(), *mode);
+            assert_eq!(cmd.out_red_path().as_deref(), Some(Path::new("out.txt")));
+
+            // independent of the call order
+            let cmd = BasicCmdBuilder::new()
+                .set_executable("echo")
+                .add_arg("echo")
+                .set_output_redirect_mode(*mode)
+                .set_output_redirect_append("out.txt")
+                .build();
+            assert_eq!(cmd.out_red_mode(), *mode);
+        }
+    }
+
+    #[test]
+    fn test_set_stdin_bytes() {
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("grep").add_arg("grep").add_arg("b"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+            .set_stdin_bytes("a\nb\n")
+            .build();
+        assert_eq!(cmd_chain.cmds()[0].stdin_contents(), Some(&b"a\nb\n".to_vec()));
+        assert_eq!(cmd_chain.cmds()[1].stdin_contents(), None);
+
+        let res = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat").set_input_redirect_path("in.txt"))
+            .set_stdin_bytes("a")
+            .set_redirect_conflict_policy(RedirectConflictPolicy::Error)
+            .try_build();
+        assert_eq!(res.unwrap_err(), BuildError::ConflictingRedirect { fd: libc::STDIN_FILENO });
+    }
+
+    #[test]
+    fn test_chain_result() {
+        // 'false | true'
+        let chain = |pipefail| CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("false").add_arg("false"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .set_pipefail(pipefail)
+            .build();
+
+        let cmd_chain = chain(false);
+        let states = crate::execute_piped_cmd_chain(&cmd_chain).unwrap();
+        let result = cmd_chain.result(&states);
+        assert_eq!(result.exit_code(), 0);
+        assert_eq!(result.stage(), Some(1));
+        assert!(result.success());
+
+        let cmd_chain = chain(true);
+        assert!(cmd_chain.pipefail());
+        let states = crate::execute_piped_cmd_chain(&cmd_chain).unwrap();
+        let result = cmd_chain.result(&states);
+        assert_eq!(result.exit_code(), 1);
+        assert_eq!(result.stage(), Some(0));
+        assert!(!result.success());
+
+        assert_eq!(ChainResult::from_states(&[], true).exit_code(), 0);
+
+        // also an unfinished process in the middle, whose exit code isn't needed
+        let state = |exit_code: Option<i32>| {
+            let mut state = ProcessState::new("true".to_owned(), 0);
+            if let Some(exit_code) = exit_code {
+                state.finish(exit_code);
+            }
+            state
+        };
+        let states = [state(Some(1)), state(None), state(Some(0))];
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ChainResult::from_states(&states, false)));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_negate_status() {
+        // '! false | true' with pipefail
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("false").add_arg("false"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .set_pipefail(true)
+            .set_negate_status(true)
+            .build();
+        assert!(cmd_chain.negate_status());
+        assert_eq!(cmd_chain.to_string(), "! false | true");
+
+        let states = crate::execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].exit_code(), 1);
+        let result = cmd_chain.result(&states);
+        assert_eq!(result.exit_code(), 0);
+        assert_eq!(result.stage(), Some(0));
+        assert_eq!(result.negated().exit_code(), 1);
+    }
+
+    #[test]
+    fn test_pipe_stderr() {
+        // "sh -c 'echo out; echo err >&2' |& sort"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo out; echo err >&2")
+            ).pipe_stderr()
+            .add_cmd(BasicCmdBuilder::new().set_executable("sort").add_arg("sort"))
+            .build();
+        assert!(cmd_chain.pipes_stderr(0));
+        assert!(!cmd_chain.pipes_stderr(1));
+        let output = crate::execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(String::from_utf8_lossy(output.stdout()), "err\nout\n");
+        assert!(output.stderr().is_empty());
+    }
 }
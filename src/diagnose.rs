@@ -0,0 +1,122 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Diagnostics that turn a failed `exec()` into an actionable message.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+/// The structured reason why `exec()` of an executable failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecDiagnosis {
+    /// Nothing with this name was found (`ENOENT`).
+    NotFound,
+    /// The file was found and is a regular file but the execute bit
+    /// is missing, e.g. a shell script that wasn't `chmod +x`ed.
+    MissingExecBit {
+        path: PathBuf,
+    },
+    /// The found file is a directory.
+    IsDirectory {
+        path: PathBuf,
+    },
+    /// `EACCES` for another reason, e.g. a `noexec` mount or missing
+    /// search permission on a directory of the path.
+    PermissionDenied,
+    /// Any other errno.
+    Other(libc::c_int),
+}
+
+impl fmt::Display for ExecDiagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecDiagnosis::NotFound => write!(f, "command not found"),
+            ExecDiagnosis::MissingExecBit { path } => write!(
+                f,
+                "permission denied: {} is a regular file without execute permission (try 'chmod +x {}')",
+                path.display(),
+                path.display()
+            ),
+            ExecDiagnosis::IsDirectory { path } => write!(f, "permission denied: {} is a directory", path.display()),
+            ExecDiagnosis::PermissionDenied => write!(f, "permission denied"),
+            ExecDiagnosis::Other(errno) => write!(f, "{}", errno::Errno(*errno)),
+        }
+    }
+}
+
+/// Figures out why `execvp(executable, ...)` failed with `errno`. For
+/// `EACCES` the file is searched like `execvp()` does it (`$PATH` if the
+/// executable contains no slash) and the first candidate is inspected
+/// with `stat()`.
+pub fn diagnose_exec_error(executable: impl AsRef<OsStr>, errno: libc::c_int) -> ExecDiagnosis {
+    let executable = executable.as_ref();
+    match errno {
+        libc::ENOENT => ExecDiagnosis::NotFound,
+        libc::EACCES => {
+            let candidates: Vec<PathBuf> = if executable.as_bytes().contains(&b'/') {
+                vec![PathBuf::from(executable)]
+            } else {
+                std::env::var("PATH").unwrap_or_default()
+                    .split(':')
+                    .map(|dir| if dir.is_empty() { "." } else { dir })
+                    .map(|dir| PathBuf::from(dir).join(executable))
+                    .collect()
+            };
+            for path in candidates {
+                let metadata = match std::fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                if metadata.is_dir() {
+                    return ExecDiagnosis::IsDirectory { path };
+                }
+                if metadata.is_file() && metadata.permissions().mode() & 0o111 == 0 {
+                    return ExecDiagnosis::MissingExecBit { path };
+                }
+            }
+            ExecDiagnosis::PermissionDenied
+        }
+        errno => ExecDiagnosis::Other(errno),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnose_script_without_exec_bit() {
+        let script = std::env::temp_dir().join(format!("unix_exec_piper_script_{}.sh", std::process::id()));
+        std::fs::write(&script, "#!/bin/sh\necho hi\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let diagnosis = diagnose_exec_error(script.to_str().unwrap(), libc::EACCES);
+        assert_eq!(diagnosis, ExecDiagnosis::MissingExecBit { path: script.clone() });
+        assert!(diagnosis.to_string().contains(&format!("chmod +x {}", script.display())));
+        std::fs::remove_file(&script).unwrap();
+    }
+}
@@ -0,0 +1,445 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+use std::fmt;
+use std::io;
+use crate::data::ChainMode;
+
+/// Describes the first process of a command chain that didn't
+/// succeed (exit code != 0), e.g. because it was terminated by a signal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessFailure {
+    /// Index of the failed command inside the chain (starting at 0).
+    stage: usize,
+    /// The executable of the failed process.
+    executable: String,
+    /// Exit code of the failed process; 128 + signal if it was terminated
+    /// by a signal, like in a shell.
+    exit_code: libc::c_int,
+    /// The signal that terminated the process.
+    signal: Option<libc::c_int>,
+}
+
+impl ProcessFailure {
+    /// Constructor for a process that exited.
+    pub fn new(stage: usize, executable: String, exit_code: libc::c_int) -> Self {
+        Self { stage, executable, exit_code, signal: None }
+    }
+
+    /// Constructor for a process that was terminated by `signal`.
+    pub fn from_signal(stage: usize, executable: String, signal: libc::c_int) -> Self {
+        Self { stage, executable, exit_code: 128 + signal, signal: Some(signal) }
+    }
+
+    /// Getter for stage (index starting at 0).
+    pub fn stage(&self) -> usize {
+        self.stage
+    }
+    /// Getter for executable.
+    pub fn executable(&self) -> &str {
+        &self.executable
+    }
+    /// Getter for exit_code.
+    pub fn exit_code(&self) -> libc::c_int {
+        self.exit_code
+    }
+    /// Getter for signal. `None` if the process exited.
+    pub fn signal(&self) -> Option<libc::c_int> {
+        self.signal
+    }
+}
+
+impl fmt::Display for ProcessFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // humans count stages starting at 1
+        match self.signal {
+            Some(signal) => write!(f, "stage {} ({}) was terminated by signal {}", self.stage + 1, self.executable, signal),
+            None => write!(f, "stage {} ({}) exited {}", self.stage + 1, self.executable, self.exit_code),
+        }
+    }
+}
+
+/// Error of `execute_piped_cmd_chain_checked()`. Contains the whole
+/// context of the pipeline, i.e. the reconstructed command line
+/// and which process failed. Produces messages like:
+/// `pipeline 'cat x | grep y' failed: stage 2 (grep) exited 2`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineError {
+    /// The reconstructed command line of the chain.
+    cmd_line: String,
+    /// Why the pipeline failed.
+    cause: PipelineErrorCause,
+}
+
+/// Why a pipeline failed.
+#[derive(Debug, Clone, PartialEq)]
+enum PipelineErrorCause {
+    /// A process exited with an exit code != 0.
+    Failure(ProcessFailure),
+    /// The chain couldn't be started.
+    Spawn(PiperError),
+}
+
+impl PipelineError {
+    /// Constructor.
+    pub fn new(cmd_line: String, failure: ProcessFailure) -> Self {
+        Self { cmd_line, cause: PipelineErrorCause::Failure(failure) }
+    }
+
+    /// Constructor for a chain that couldn't be started.
+    pub fn from_spawn_error(cmd_line: String, err: PiperError) -> Self {
+        Self { cmd_line, cause: PipelineErrorCause::Spawn(err) }
+    }
+
+    /// Getter for cmd_line.
+    pub fn cmd_line(&self) -> &str {
+        &self.cmd_line
+    }
+    /// Getter for failure. `None` if the chain couldn't be started.
+    pub fn failure(&self) -> Option<&ProcessFailure> {
+        match &self.cause {
+            PipelineErrorCause::Failure(failure) => Some(failure),
+            PipelineErrorCause::Spawn(_) => None,
+        }
+    }
+    /// Getter for the error if the chain couldn't be started.
+    pub fn spawn_error(&self) -> Option<&PiperError> {
+        match &self.cause {
+            PipelineErrorCause::Failure(_) => None,
+            PipelineErrorCause::Spawn(err) => Some(err),
+        }
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cause {
+            PipelineErrorCause::Failure(failure) => write!(f, "pipeline '{}' failed: {}", self.cmd_line, failure),
+            PipelineErrorCause::Spawn(err) => write!(f, "pipeline '{}' failed: {}", self.cmd_line, err),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+impl ProcessFailure {
+    /// The `std::io::ErrorKind` of the failure. Follows the exit codes of
+    /// a shell: 127 means command not found, 126 means not executable.
+    fn io_error_kind(&self) -> io::ErrorKind {
+        match (self.signal, self.exit_code) {
+            (Some(_), _) => io::ErrorKind::Other,
+            (None, 127) => io::ErrorKind::NotFound,
+            (None, 126) => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl std::error::Error for ProcessFailure {}
+
+impl From<ProcessFailure> for io::Error {
+    fn from(failure: ProcessFailure) -> Self {
+        io::Error::new(failure.io_error_kind(), failure)
+    }
+}
+
+impl From<PipelineError> for io::Error {
+    /// Maps e.g. a command that can't be found to `ErrorKind::NotFound` and
+    /// missing permissions to `ErrorKind::PermissionDenied`.
+    fn from(err: PipelineError) -> Self {
+        let kind = match &err.cause {
+            PipelineErrorCause::Failure(failure) => failure.io_error_kind(),
+            PipelineErrorCause::Spawn(spawn_err) => spawn_err.io_error_kind(),
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Error of the builders if the described command or chain is invalid.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    /// No executable was set.
+    MissingExecutable,
+    /// The args are empty but must at least contain the executable name.
+    MissingArgs,
+    /// The chain is used in another mode than the required one,
+    /// e.g. it's marked as background but was required to be foreground.
+    ModeMismatch {
+        required: ChainMode,
+        actual: ChainMode,
+    },
+    /// Multiple redirects of a command target the same file descriptor
+    /// (see `RedirectConflictPolicy::Error`).
+    ConflictingRedirect {
+        fd: libc::c_int,
+    },
+    /// An environment variable of the command (see
+    /// `BasicCmdBuilder::add_env()`) can't be passed to `execve()`: the
+    /// name is empty or contains `=` or a null byte, or the value
+    /// contains a null byte.
+    InvalidEnvVar {
+        key: String,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingExecutable => write!(f, "no executable was set!"),
+            BuildError::MissingArgs => write!(f, "args must at least contain the executable name!"),
+            BuildError::ModeMismatch { required, actual } => {
+                write!(f, "chain is required to run in {:?} but is used in {:?}", required, actual)
+            }
+            BuildError::ConflictingRedirect { fd } => write!(f, "multiple redirects for file descriptor {}", fd),
+            BuildError::InvalidEnvVar { key } => write!(f, "invalid environment variable {:?}", key),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Error if a command chain can't be started. Only the setup is covered;
+/// if the programs run and fail, that's reported via their exit codes.
+///
+/// Errors of a child between `fork()` and `exec()` (e.g. a redirect file
+/// can't be opened or `exec()` fails) are sent to the parent via a pipe
+/// with the close-on-exec flag: the parent reads from it until EOF, which
+/// happens either when `exec()` succeeded or when the child wrote the
+/// error and exited. In the latter case the child exits like a shell with
+/// 127 for an unknown command, 126 for one that can't be executed and 1
+/// otherwise, without running any destructors of the parent's address space.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PiperError {
+    /// `fork()` failed with this errno.
+    ForkFailed(libc::c_int),
+    /// `exec()` of the executable failed with this errno.
+    ExecFailed {
+        executable: String,
+        errno: libc::c_int,
+    },
+    /// `pipe()` failed with this errno.
+    PipeCreationFailed(libc::c_int),
+    /// The file of a redirect can't be opened.
+    RedirectOpenFailed {
+        path: String,
+        errno: libc::c_int,
+    },
+    /// `dup2()` failed in the child with this errno.
+    DupFailed(libc::c_int),
+    /// The child can't change into the working directory.
+    ChdirFailed {
+        path: String,
+        errno: libc::c_int,
+    },
+    /// The child can't enter a new namespace (`unshare()`) with this errno,
+    /// see `ExecOptions::set_new_mount_namespace()`.
+    UnshareFailed(libc::c_int),
+    /// `setpgid()` failed in the child, see `ExecOptions::set_process_group()`.
+    SetpgidFailed(libc::c_int),
+    /// Changing the user or the groups (`setgroups()`, `setgid()` or
+    /// `setuid()`) failed in the child with this errno, see
+    /// `BasicCmdBuilder::set_uid()`.
+    CredentialsFailed(libc::c_int),
+    /// Writing the output into the sink of the caller failed with this
+    /// errno (`EIO` if the error has none), see
+    /// `execute_piped_cmd_chain_into()`.
+    SinkWriteFailed(libc::c_int),
+    /// `waitpid()` failed with this errno, e.g. `ECHILD` if the process
+    /// isn't a child or was already waited for.
+    WaitFailed(libc::c_int),
+    /// Opening a pseudo terminal failed with this errno.
+    PtyOpenFailed(libc::c_int),
+    /// Reading the input from the source of the caller failed with this
+    /// errno (`EIO` if the error has none), see
+    /// `execute_piped_cmd_chain_from_reader()`.
+    SourceReadFailed(libc::c_int),
+    /// There is no job with this ID, see `JobManager`.
+    NoSuchJob(usize),
+    /// Handing the terminal to a process group (`tcsetpgrp()`) failed
+    /// with this errno, see `JobManager::set_terminal()`.
+    TerminalControlFailed(libc::c_int),
+    /// `kill()` of a process failed, see `ProcessState::send_signal()`.
+    SignalFailed {
+        pid: libc::pid_t,
+        errno: libc::c_int,
+    },
+    /// A `SpawnBackend` couldn't start the process of the executable, e.g.
+    /// `posix_spawn()` failed with this errno, see `PosixSpawnBackend`.
+    SpawnFailed {
+        executable: String,
+        errno: libc::c_int,
+    },
+    /// The chain uses a feature that the `SpawnBackend` doesn't support,
+    /// e.g. a working directory with `PosixSpawnBackend`.
+    Unsupported(&'static str),
+    /// Starting a `Reaper` failed with this errno, `EBUSY` if another
+    /// one is running.
+    ReaperFailed(libc::c_int),
+    /// The output of the chain is bigger than this limit (in bytes), see
+    /// `CaptureOptions::set_limit()`.
+    OutputLimitExceeded(usize),
+    /// Reading or setting the size of a terminal (`TIOCGWINSZ` or
+    /// `TIOCSWINSZ`) failed with this errno, or starting a
+    /// `WinsizeForwarder` failed (`EBUSY` if another one runs).
+    TerminalSizeFailed(libc::c_int),
+    /// The thread of a command that runs in the parent (see
+    /// `ProcessState::in_parent()`) can't be started; holds the errno.
+    ThreadSpawnFailed(libc::c_int),
+    /// The executor can't run the chain as it's described, e.g. it waits
+    /// for the processes but the chain is required to run in background
+    /// (`BuildError::ModeMismatch`).
+    InvalidChain(BuildError),
+    /// Changing the flags of an FD (`fcntl()`) failed with this errno,
+    /// see `Pipe::set_write_nonblocking()`.
+    FcntlFailed(libc::c_int),
+}
+
+impl fmt::Display for PiperError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PiperError::ForkFailed(errno) => write!(f, "fork failed: {}", errno::Errno(*errno)),
+            PiperError::ExecFailed { executable, errno } => {
+                write!(f, "exec of {} failed: {}", executable, crate::diagnose_exec_error(executable, *errno))
+            }
+            PiperError::PipeCreationFailed(errno) => write!(f, "pipe creation failed: {}", errno::Errno(*errno)),
+            PiperError::RedirectOpenFailed { path, errno } => {
+                write!(f, "redirect path {} can't be opened: {}", path, errno::Errno(*errno))
+            }
+            PiperError::DupFailed(errno) => write!(f, "dup2 failed: {}", errno::Errno(*errno)),
+            PiperError::ChdirFailed { path, errno } => {
+                write!(f, "can't change into working directory {}: {}", path, errno::Errno(*errno))
+            }
+            PiperError::UnshareFailed(errno) => write!(f, "unshare failed: {}", errno::Errno(*errno)),
+            PiperError::SetpgidFailed(errno) => write!(f, "setpgid failed: {}", errno::Errno(*errno)),
+            PiperError::CredentialsFailed(errno) => write!(f, "changing the user or groups failed: {}", errno::Errno(*errno)),
+            PiperError::SinkWriteFailed(errno) => write!(f, "writing the output failed: {}", errno::Errno(*errno)),
+            PiperError::WaitFailed(errno) => write!(f, "waitpid failed: {}", errno::Errno(*errno)),
+            PiperError::PtyOpenFailed(errno) => write!(f, "opening pty failed: {}", errno::Errno(*errno)),
+            PiperError::SourceReadFailed(errno) => write!(f, "reading the input failed: {}", errno::Errno(*errno)),
+            PiperError::NoSuchJob(id) => write!(f, "no such job: %{}", id),
+            PiperError::TerminalControlFailed(errno) => write!(f, "tcsetpgrp failed: {}", errno::Errno(*errno)),
+            PiperError::SignalFailed { pid, errno } => {
+                write!(f, "sending a signal to process {} failed: {}", pid, errno::Errno(*errno))
+            }
+            PiperError::SpawnFailed { executable, errno } => {
+                write!(f, "spawning {} failed: {}", executable, errno::Errno(*errno))
+            }
+            PiperError::Unsupported(feature) => write!(f, "not supported by the spawn backend: {}", feature),
+            PiperError::ReaperFailed(errno) => write!(f, "starting the reaper failed: {}", errno::Errno(*errno)),
+            PiperError::OutputLimitExceeded(limit) => write!(f, "output is bigger than {} bytes", limit),
+            PiperError::TerminalSizeFailed(errno) => write!(f, "terminal size can't be forwarded: {}", errno::Errno(*errno)),
+            PiperError::ThreadSpawnFailed(errno) => write!(f, "thread can't be spawned: {}", errno::Errno(*errno)),
+            PiperError::InvalidChain(err) => write!(f, "invalid chain: {}", err),
+            PiperError::FcntlFailed(errno) => write!(f, "fcntl failed: {}", errno::Errno(*errno)),
+        }
+    }
+}
+
+impl std::error::Error for PiperError {}
+
+impl PiperError {
+    /// The errno of the error, e.g. `ENOENT` if `exec()` of a command
+    /// failed because the executable doesn't exist.
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            PiperError::ForkFailed(errno) => *errno,
+            PiperError::ExecFailed { errno, .. } => *errno,
+            PiperError::PipeCreationFailed(errno) => *errno,
+            PiperError::RedirectOpenFailed { errno, .. } => *errno,
+            PiperError::DupFailed(errno) => *errno,
+            PiperError::ChdirFailed { errno, .. } => *errno,
+            PiperError::UnshareFailed(errno) => *errno,
+            PiperError::SetpgidFailed(errno) => *errno,
+            PiperError::CredentialsFailed(errno) => *errno,
+            PiperError::SinkWriteFailed(errno) => *errno,
+            PiperError::WaitFailed(errno) => *errno,
+            PiperError::PtyOpenFailed(errno) => *errno,
+            PiperError::SourceReadFailed(errno) => *errno,
+            PiperError::NoSuchJob(_) => libc::ESRCH,
+            PiperError::TerminalControlFailed(errno) => *errno,
+            PiperError::SignalFailed { errno, .. } => *errno,
+            PiperError::SpawnFailed { errno, .. } => *errno,
+            PiperError::Unsupported(_) => libc::ENOTSUP,
+            PiperError::ReaperFailed(errno) => *errno,
+            PiperError::OutputLimitExceeded(_) => libc::EFBIG,
+            PiperError::TerminalSizeFailed(errno) => *errno,
+            PiperError::ThreadSpawnFailed(errno) => *errno,
+            PiperError::InvalidChain(_) => libc::EINVAL,
+            PiperError::FcntlFailed(errno) => *errno,
+        }
+    }
+
+    /// The `std::io::ErrorKind` that corresponds to the errno of the error.
+    fn io_error_kind(&self) -> io::ErrorKind {
+        io::Error::from_raw_os_error(self.errno()).kind()
+    }
+}
+
+impl From<PiperError> for io::Error {
+    fn from(err: PiperError) -> Self {
+        io::Error::new(err.io_error_kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute_piped_cmd_chain_checked, parse_cmd_chain};
+
+    #[test]
+    fn test_into_io_error() {
+        let cmd_chain = parse_cmd_chain("does-not-exist | cat").unwrap();
+        let err: io::Error = execute_piped_cmd_chain_checked(&cmd_chain).unwrap_err().into();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let err: io::Error = ProcessFailure::new(0, "sh".to_owned(), 127).into();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err: io::Error = ProcessFailure::new(0, "sh".to_owned(), 126).into();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err: io::Error = ProcessFailure::new(1, "grep".to_owned(), 1).into();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(err.to_string(), "stage 2 (grep) exited 1");
+        let err: io::Error = ProcessFailure::from_signal(0, "sleep".to_owned(), libc::SIGKILL).into();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(err.to_string(), format!("stage 1 (sleep) was terminated by signal {}", libc::SIGKILL));
+    }
+
+    #[test]
+    fn test_exec_failure_errno() {
+        // a file without the executable bit
+        let path = std::env::temp_dir().join(format!("unix_exec_piper_not_executable_{}", std::process::id()));
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        let cmd_chain = parse_cmd_chain(path.to_str().unwrap()).unwrap();
+        let err = crate::execute_piped_cmd_chain(&cmd_chain).unwrap_err();
+        assert_eq!(err, PiperError::ExecFailed { executable: path.to_str().unwrap().to_owned(), errno: libc::EACCES });
+        assert_eq!(err.errno(), libc::EACCES);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_build_error_display() {
+        assert_eq!(BuildError::MissingExecutable.to_string(), "no executable was set!");
+        assert_eq!(BuildError::MissingArgs.to_string(), "args must at least contain the executable name!");
+    }
+}
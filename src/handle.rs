@@ -0,0 +1,294 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! A handle to a running command chain, as alternative to a bare
+//! `Vec<ProcessState>` that has to be passed to `update_process_states()`.
+
+use std::fs::File;
+use std::time::{Duration, Instant};
+use crate::capture::spawn_captured_streams;
+use crate::wait::{deadline_of, signal_chain, try_wait_all_timeout, wait_or_kill};
+use crate::{kill_all, spawn_piped_cmd_chain, try_update_process_states, ChainIo, CmdChain, ExecOptions, PiperError,
+            ProcessState};
+
+/// Starts a command chain and returns a handle that owns its processes;
+/// unlike `execute_piped_cmd_chain()` it never waits for them, regardless
+/// of `CmdChainBuilder::set_background()`. A timeout of the chain (see
+/// `CmdChainBuilder::set_timeout()`) counts from now and is enforced by
+/// `ChainHandle::wait()`. Job control with `ExecOptions::set_terminal()`
+/// isn't supported (see `JobManager`) and fails with
+/// `PiperError::Unsupported`.
+pub fn spawn_piped_cmd_chain_handle(cmds: &CmdChain, opts: &ExecOptions) -> Result<ChainHandle, PiperError> {
+    if opts.terminal().is_some() {
+        return Err(PiperError::Unsupported("terminal"));
+    }
+    let states = spawn_piped_cmd_chain(cmds, &ChainIo::default(), opts)?;
+    Ok(ChainHandle::new(states, cmds))
+}
+
+/// Starts a command chain as coprocess, like `coproc` in bash: STDIN of
+/// the first command and STDOUT of the last command are connected to pipes
+/// whose other ends are returned to the parent as `(handle, stdin, stdout)`
+/// for a bidirectional interaction with the running chain. STDERR is
+/// inherited. Otherwise like `spawn_piped_cmd_chain_handle()`.
+///
+/// Dropping `stdin` closes the pipe, i.e. the first command sees EOF. The
+/// parent must read `stdout` while it writes into `stdin`: once both pipe
+/// buffers are full the chain and the parent block each other. Many
+/// programs also buffer their output if it isn't a terminal, i.e. an
+/// answer may only arrive after more input or EOF.
+pub fn spawn_coproc(cmds: &CmdChain) -> Result<(ChainHandle, File, File), PiperError> {
+    let (states, streams) = spawn_captured_streams(cmds, &ExecOptions::default(), true, true, false)?;
+    Ok((ChainHandle::new(states, cmds), streams.stdin.unwrap(), streams.stdout.unwrap()))
+}
+
+/// The processes of a running command chain, see
+/// `spawn_piped_cmd_chain_handle()`. Like `std::process::Child` dropping
+/// the handle neither kills nor waits for the processes, unless
+/// `set_kill_on_drop()` was used.
+#[derive(Debug)]
+pub struct ChainHandle {
+    states: Vec<ProcessState>,
+    /// When the chain runs into its timeout.
+    deadline: Option<Instant>,
+    /// Whether dropping the handle kills and reaps the processes.
+    kill_on_drop: bool,
+}
+
+impl ChainHandle {
+    /// Constructor for the just spawned processes of `cmds`; the timeout
+    /// of the chain counts from now.
+    pub(crate) fn new(states: Vec<ProcessState>, cmds: &CmdChain) -> Self {
+        ChainHandle { states, deadline: deadline_of(cmds), kill_on_drop: false }
+    }
+
+    /// Opt-in: if the handle is dropped (e.g. on a panic or an early
+    /// return) while processes are still running, they are killed with
+    /// `SIGKILL` and reaped, so that no `cat` or `tail -f` is leaked. The
+    /// whole process group gets the signal if the chain has one (see
+    /// `pgid()`). `into_states()` hands the processes over without killing.
+    pub fn set_kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+
+    /// Getter for kill_on_drop.
+    pub fn kill_on_drop(&self) -> bool {
+        self.kill_on_drop
+    }
+
+    /// Getter for states. They are only updated by the wait methods.
+    pub fn states(&self) -> &Vec<ProcessState> {
+        &self.states
+    }
+
+    /// The pids of the processes in the order of the chain.
+    pub fn pids(&self) -> Vec<libc::pid_t> {
+        self.states.iter().map(|state| state.pid()).collect()
+    }
+
+    /// The process group of the chain, see `ExecOptions::set_process_group()`.
+    pub fn pgid(&self) -> Option<libc::pid_t> {
+        self.states.first().and_then(|state| state.pgid())
+    }
+
+    /// Blocks until all processes are finished. If the chain has a timeout
+    /// the processes are killed when it's reached and marked as timed out,
+    /// like `execute_piped_cmd_chain()` does.
+    pub fn wait(&mut self) -> Result<&Vec<ProcessState>, PiperError> {
+        match self.deadline {
+            Some(deadline) => {
+                wait_or_kill(&mut self.states, deadline.saturating_duration_since(Instant::now()));
+            }
+            None => {
+                try_update_process_states(&mut self.states, false)?;
+            }
+        }
+        Ok(&self.states)
+    }
+
+    /// Like `wait()` but without blocking a thread: the future resolves
+    /// when all processes are finished (feature `async`). The future is
+    /// woken up via the pidfds of the processes (`ExecOptions::set_pidfds()`),
+    /// without them it's polled every few milliseconds. The timeout of the
+    /// chain is applied like in `wait()`.
+    #[cfg(feature = "async")]
+    pub fn wait_async(&mut self) -> crate::WaitFuture<'_> {
+        crate::WaitFuture::new(&mut self.states, self.deadline)
+    }
+
+    /// Updates the states without blocking. Returns them if all processes
+    /// are finished.
+    pub fn try_wait(&mut self) -> Result<Option<&Vec<ProcessState>>, PiperError> {
+        let all_finished = try_update_process_states(&mut self.states, true)?;
+        Ok(if all_finished { Some(&self.states) } else { None })
+    }
+
+    /// Blocks up to `timeout` until all processes are finished without
+    /// busy-looping, see `try_wait_all_timeout()`. Returns the states if
+    /// all are finished; the processes keep running otherwise.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<&Vec<ProcessState>>, PiperError> {
+        let all_finished = try_wait_all_timeout(&mut self.states, timeout)?;
+        Ok(if all_finished { Some(&self.states) } else { None })
+    }
+
+    /// Kills all processes that aren't finished yet with `SIGKILL`, see
+    /// `kill_all()`. They still have to be waited for.
+    pub fn kill(&self) -> Result<(), PiperError> {
+        self.send_signal(libc::SIGKILL)
+    }
+
+    /// Sends the signal to all processes that aren't finished yet.
+    pub fn send_signal(&self, signal: libc::c_int) -> Result<(), PiperError> {
+        kill_all(&self.states, signal)
+    }
+
+    /// Shuts the chain down gracefully: sends `SIGTERM`, waits up to `grace`
+    /// and then kills the survivors with `SIGKILL`. The signals go to the
+    /// whole process group if the chain has one (see `pgid()`), so that
+    /// children of the commands are stopped as well. Returns the states,
+    /// which are all finished afterwards.
+    pub fn terminate(&mut self, grace: Duration) -> Result<&Vec<ProcessState>, PiperError> {
+        if try_update_process_states(&mut self.states, true)? {
+            return Ok(&self.states);
+        }
+        // errors only mean that a process exited meanwhile
+        let _ = signal_chain(&self.states, libc::SIGTERM);
+        if !try_wait_all_timeout(&mut self.states, grace)? {
+            let _ = signal_chain(&self.states, libc::SIGKILL);
+            try_update_process_states(&mut self.states, false)?;
+        }
+        Ok(&self.states)
+    }
+
+    /// Returns the states, e.g. after `wait()`.
+    pub fn into_states(mut self) -> Vec<ProcessState> {
+        std::mem::take(&mut self.states)
+    }
+}
+
+impl Drop for ChainHandle {
+    fn drop(&mut self) {
+        if !self.kill_on_drop || self.states.iter().all(|state| state.finished()) {
+            return;
+        }
+        // errors only mean that a process exited meanwhile
+        let _ = signal_chain(&self.states, libc::SIGKILL);
+        let _ = try_update_process_states(&mut self.states, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_cmd_chain;
+
+    #[test]
+    fn test_chain_handle() {
+        let cmd_chain = parse_cmd_chain("sleep 5 | cat").unwrap();
+        let mut handle = spawn_piped_cmd_chain_handle(&cmd_chain, &ExecOptions::new().set_process_group(true)).unwrap();
+        assert_eq!(handle.pgid(), Some(handle.pids()[0]));
+        assert!(handle.try_wait().unwrap().is_none());
+        assert!(handle.wait_timeout(Duration::from_millis(50)).unwrap().is_none());
+        handle.kill().unwrap();
+        let states = handle.wait().unwrap();
+        assert!(states.iter().all(|s| s.finished()));
+        assert_eq!(states[0].term_signal(), Some(libc::SIGKILL));
+        assert!(handle.try_wait().unwrap().is_some());
+
+        let cmd_chain = parse_cmd_chain("true").unwrap();
+        let mut handle = spawn_piped_cmd_chain_handle(&cmd_chain, &ExecOptions::new()).unwrap();
+        assert_eq!(handle.wait().unwrap()[0].exit_code(), 0);
+        assert_eq!(handle.into_states().len(), 1);
+    }
+
+    #[test]
+    fn test_terminate() {
+        // the first stage ignores SIGTERM and needs SIGKILL
+        let cmd_chain = parse_cmd_chain("sh -c 'trap \"\" TERM; sleep 5' | sleep 5").unwrap();
+        let mut handle = spawn_piped_cmd_chain_handle(&cmd_chain, &ExecOptions::new().set_process_group(true)).unwrap();
+        // give 'sh' time to install the trap
+        std::thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        let states = handle.terminate(Duration::from_millis(200)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert!(states.iter().all(|s| s.finished()));
+        assert_eq!(states[0].term_signal(), Some(libc::SIGKILL));
+        assert_eq!(states[1].term_signal(), Some(libc::SIGTERM));
+
+        // finished chains aren't signaled
+        let cmd_chain = parse_cmd_chain("true").unwrap();
+        let mut handle = spawn_piped_cmd_chain_handle(&cmd_chain, &ExecOptions::new()).unwrap();
+        handle.wait().unwrap();
+        assert_eq!(handle.terminate(Duration::from_millis(0)).unwrap()[0].exit_code(), 0);
+    }
+
+    #[test]
+    fn test_kill_on_drop() {
+        let cmd_chain = parse_cmd_chain("sleep 5 | cat").unwrap();
+        let handle = spawn_piped_cmd_chain_handle(&cmd_chain, &ExecOptions::new().set_process_group(true))
+            .unwrap()
+            .set_kill_on_drop(true);
+        assert!(handle.kill_on_drop());
+        let pids = handle.pids();
+        let start = Instant::now();
+        drop(handle);
+        assert!(start.elapsed() < Duration::from_secs(4));
+        // killed and reaped, not even a zombie is left
+        for pid in pids {
+            assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+        }
+
+        // handing the processes over doesn't kill them
+        let cmd_chain = parse_cmd_chain("sleep 5").unwrap();
+        let handle = spawn_piped_cmd_chain_handle(&cmd_chain, &ExecOptions::new()).unwrap().set_kill_on_drop(true);
+        let mut states = handle.into_states();
+        assert!(!crate::try_update_process_states(&mut states, true).unwrap());
+        kill_all(&states, libc::SIGKILL).unwrap();
+        crate::update_process_states(&mut states, false);
+    }
+
+    #[test]
+    fn test_spawn_coproc() {
+        use std::io::{BufRead, BufReader, Write};
+
+        let cmd_chain = parse_cmd_chain("cat").unwrap();
+        let (mut handle, mut stdin, stdout) = spawn_coproc(&cmd_chain).unwrap();
+        let mut stdout = BufReader::new(stdout);
+        let mut line = String::new();
+        for request in &["ping\n", "pong\n"] {
+            stdin.write_all(request.as_bytes()).unwrap();
+            line.clear();
+            stdout.read_line(&mut line).unwrap();
+            assert_eq!(&line, request);
+        }
+        assert!(handle.try_wait().unwrap().is_none());
+
+        // EOF ends 'cat'
+        drop(stdin);
+        line.clear();
+        assert_eq!(stdout.read_line(&mut line).unwrap(), 0);
+        assert_eq!(handle.wait().unwrap()[0].exit_code(), 0);
+    }
+}
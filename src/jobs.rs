@@ -0,0 +1,368 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+//! Job control on top of the executor, the way an interactive shell does
+//! it: every chain runs in its own process group, gets a job ID and can be
+//! stopped (e.g. by Ctrl-Z/`SIGTSTP`), continued in foreground (`fg`) or
+//! background (`bg`) and listed (`jobs`).
+//! See https://www.gnu.org/software/libc/manual/html_node/Job-Control.html
+
+use crate::{builtin, stage, spawn_piped_cmd_chain, ChainIo, ChainMode, CmdChain, ExecOptions, PiperError, ProcessState};
+
+/// The state of a job as a whole.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JobStatus {
+    /// At least one process is running and none is stopped.
+    Running,
+    /// At least one process that isn't finished is stopped.
+    Stopped,
+    /// All processes are finished.
+    Done,
+}
+
+/// A command chain that is managed by a `JobManager`.
+#[derive(Debug)]
+pub struct Job {
+    /// The job ID, starting at 1 like `%1` in a shell.
+    id: usize,
+    /// The reconstructed command line, e.g. for the output of `jobs`.
+    cmd_line: String,
+    /// The process group of all processes of the job.
+    pgid: libc::pid_t,
+    /// Whether the job was last run in foreground or in background.
+    mode: ChainMode,
+    /// One state per process.
+    states: Vec<ProcessState>,
+    /// Whether the process with the same index is stopped.
+    stopped: Vec<bool>,
+}
+
+impl Job {
+    /// Getter for id.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    /// Getter for cmd_line.
+    pub fn cmd_line(&self) -> &str {
+        &self.cmd_line
+    }
+    /// Getter for pgid.
+    pub fn pgid(&self) -> libc::pid_t {
+        self.pgid
+    }
+    /// Getter for mode.
+    pub fn mode(&self) -> ChainMode {
+        self.mode
+    }
+    /// Getter for states.
+    pub fn states(&self) -> &Vec<ProcessState> {
+        &self.states
+    }
+
+    /// The state of the job as of the last update, see `JobStatus`.
+    pub fn status(&self) -> JobStatus {
+        group_status(&self.states, &self.stopped)
+    }
+
+    /// See `update_group()`.
+    fn update(&mut self, block: bool) -> Result<(), PiperError> {
+        update_group(self.pgid, &mut self.states, &mut self.stopped, block)
+    }
+}
+
+/// The state of the processes of a group, `stopped[i]` tells whether
+/// `states[i]` is stopped.
+fn group_status(states: &[ProcessState], stopped: &[bool]) -> JobStatus {
+    let mut unfinished = states.iter()
+        .zip(stopped.iter())
+        .filter(|(state, _)| !state.finished())
+        .peekable();
+    if unfinished.peek().is_none() {
+        JobStatus::Done
+    } else if unfinished.any(|(_, stopped)| *stopped) {
+        JobStatus::Stopped
+    } else {
+        JobStatus::Running
+    }
+}
+
+/// Collects the state changes of the processes of a process group with
+/// `waitpid(-pgid, WUNTRACED | WCONTINUED)`. If `block` is set, it waits
+/// until all processes are finished or one is stopped, otherwise it only
+/// collects the pending changes.
+pub(crate) fn update_group(pgid: libc::pid_t,
+                           states: &mut [ProcessState],
+                           stopped: &mut [bool],
+                           block: bool) -> Result<(), PiperError> {
+    let mut flags = libc::WUNTRACED | libc::WCONTINUED;
+    if !block {
+        flags |= libc::WNOHANG;
+    }
+    loop {
+        match group_status(states, stopped) {
+            JobStatus::Done => break,
+            JobStatus::Stopped if block => break,
+            _ => {}
+        }
+        let mut status_code: libc::c_int = 0;
+        let pid = unsafe { libc::waitpid(-pgid, &mut status_code, flags) };
+        if pid == 0 {
+            break;
+        }
+        if pid == -1 {
+            match errno::errno().0 {
+                libc::EINTR => continue,
+                // all processes of the group were already reaped
+                libc::ECHILD => break,
+                errno => return Err(PiperError::WaitFailed(errno)),
+            }
+        }
+        let i = match states.iter().position(|state| state.pid() == pid) {
+            Some(i) => i,
+            None => continue,
+        };
+        if libc::WIFSTOPPED(status_code) {
+            stopped[i] = true;
+        } else if libc::WIFCONTINUED(status_code) {
+            stopped[i] = false;
+        } else if libc::WIFSIGNALED(status_code) {
+            states[i].finish_by_signal(libc::WTERMSIG(status_code));
+            if libc::WCOREDUMP(status_code) {
+                states[i].set_core_dumped();
+            }
+        } else {
+            states[i].finish(libc::WEXITSTATUS(status_code));
+        }
+    }
+    Ok(())
+}
+
+/// Job table of an interactive shell. Each spawned chain runs in its own
+/// process group (see `ExecOptions::set_process_group()`), so that the
+/// whole job can be signaled at once.
+///
+/// If a terminal is set (see `set_terminal()`), it's handed to the process
+/// group of a job while it runs in foreground and taken back afterwards
+/// with `tcsetpgrp()`, see `ExecOptions::set_terminal()`. The calling
+/// process should ignore `SIGTSTP` and `SIGINT` like shells do; the
+/// terminal only sends them to the foreground job anyway.
+#[derive(Debug, Default)]
+pub struct JobManager {
+    /// The terminal that is handed to foreground jobs.
+    terminal: Option<libc::c_int>,
+    /// All jobs that weren't removed yet, ordered by ID.
+    jobs: Vec<Job>,
+}
+
+impl JobManager {
+    /// Constructor. Same as `JobManager::default()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the FD of the controlling terminal, usually `STDIN_FILENO`.
+    pub fn set_terminal(mut self, fd: libc::c_int) -> Self {
+        self.terminal.replace(fd);
+        self
+    }
+
+    /// Getter for jobs. The states are as of the last update, see `poll()`.
+    pub fn jobs(&self) -> &Vec<Job> {
+        &self.jobs
+    }
+
+    /// Returns the job with the ID.
+    pub fn job(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    /// Starts the chain as a new job and returns its ID. A chain that is
+    /// marked as background keeps running, otherwise the job runs in
+    /// foreground like with `fg()`: the function returns when the job is
+    /// done or got stopped.
+    pub fn spawn(&mut self, cmds: &CmdChain) -> Result<usize, PiperError> {
+        let opts = match self.terminal {
+            Some(fd) => ExecOptions::new().set_terminal(fd),
+            None => ExecOptions::new().set_process_group(true),
+        };
+        // a job is a process group
+        if let Some(feature) = cmds.cmds().iter().find_map(stage::thread_feature) {
+            return Err(PiperError::Unsupported(feature));
+        }
+        if builtin::runs_in_parent(cmds, &ChainIo::default()) {
+            return Err(PiperError::Unsupported("builtins"));
+        }
+        let states = spawn_piped_cmd_chain(cmds, &ChainIo::default(), &opts)?;
+        // one above the highest ID in use, like bash
+        let id = self.jobs.last().map(|job| job.id + 1).unwrap_or(1);
+        self.jobs.push(Job {
+            id,
+            cmd_line: cmds.to_string(),
+            pgid: states[0].pid(),
+            mode: ChainMode::Background,
+            stopped: vec![false; states.len()],
+            states,
+        });
+        if !cmds.background() {
+            self.fg(id)?;
+        }
+        Ok(id)
+    }
+
+    /// Collects the state changes of all jobs without blocking, e.g.
+    /// before a shell prints its prompt.
+    pub fn poll(&mut self) -> Result<(), PiperError> {
+        for job in self.jobs.iter_mut() {
+            job.update(false)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the job in foreground: continues it with `SIGCONT` if it's
+    /// stopped and waits until it's done or stopped again.
+    pub fn fg(&mut self, id: usize) -> Result<JobStatus, PiperError> {
+        let terminal = self.terminal;
+        let job = self.job_mut(id)?;
+        job.mode = ChainMode::Foreground;
+        if let Some(fd) = terminal {
+            set_foreground_group(fd, job.pgid).map_err(PiperError::TerminalControlFailed)?;
+        }
+        let res = continue_job(job).and_then(|_| job.update(true));
+        if let Some(fd) = terminal {
+            set_foreground_group(fd, unsafe { libc::getpgrp() }).map_err(PiperError::TerminalControlFailed)?;
+        }
+        res.map(|_| job.status())
+    }
+
+    /// Continues a stopped job in background with `SIGCONT`.
+    pub fn bg(&mut self, id: usize) -> Result<(), PiperError> {
+        let job = self.job_mut(id)?;
+        job.mode = ChainMode::Background;
+        continue_job(job)
+    }
+
+    /// Removes all jobs that are done from the table and returns them,
+    /// e.g. to report `[1]+  Done  sleep 10` once.
+    pub fn remove_done(&mut self) -> Vec<Job> {
+        let (done, jobs) = std::mem::take(&mut self.jobs)
+            .into_iter()
+            .partition(|job| job.status() == JobStatus::Done);
+        self.jobs = jobs;
+        done
+    }
+
+    fn job_mut(&mut self, id: usize) -> Result<&mut Job, PiperError> {
+        self.jobs.iter_mut()
+            .find(|job| job.id == id)
+            .ok_or(PiperError::NoSuchJob(id))
+    }
+}
+
+/// Sends `SIGCONT` to the job if it's stopped.
+fn continue_job(job: &mut Job) -> Result<(), PiperError> {
+    if job.status() != JobStatus::Stopped {
+        return Ok(());
+    }
+    crate::signal_group(job.pgid, libc::SIGCONT)?;
+    for stopped in job.stopped.iter_mut() {
+        *stopped = false;
+    }
+    Ok(())
+}
+
+/// Makes the process group the foreground group of the terminal. Returns
+/// the errno on failure. `SIGTTOU` is blocked meanwhile; otherwise the
+/// caller gets stopped if it isn't in the foreground group itself, which
+/// is the case in a new child and when taking the terminal back.
+pub(crate) fn set_foreground_group(fd: libc::c_int, pgid: libc::pid_t) -> Result<(), libc::c_int> {
+    unsafe {
+        let mut block: libc::sigset_t = std::mem::zeroed();
+        let mut old: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut block);
+        libc::sigaddset(&mut block, libc::SIGTTOU);
+        libc::sigprocmask(libc::SIG_BLOCK, &block, &mut old);
+        let res = libc::tcsetpgrp(fd, pgid);
+        let errno = errno::errno().0;
+        libc::sigprocmask(libc::SIG_SETMASK, &old, std::ptr::null_mut());
+        if res == -1 {
+            return Err(errno);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BasicCmdBuilder, Builder, CmdChainBuilder};
+
+    #[test]
+    fn test_job_manager() {
+        let mut jobs = JobManager::new();
+
+        // 'sleep 10 | sleep 10 &'
+        let sleep = || BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("10");
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(sleep())
+            .add_cmd(sleep())
+            .set_background(true)
+            .build();
+        let id = jobs.spawn(&cmd_chain).unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(jobs.job(id).unwrap().status(), JobStatus::Running);
+        assert_eq!(jobs.job(id).unwrap().cmd_line(), "sleep 10 | sleep 10 &");
+
+        // like Ctrl-Z
+        let pgid = jobs.job(id).unwrap().pgid();
+        crate::signal_group(pgid, libc::SIGTSTP).unwrap();
+        while jobs.job(id).unwrap().status() != JobStatus::Stopped {
+            jobs.poll().unwrap();
+        }
+        jobs.bg(id).unwrap();
+        assert_eq!(jobs.job(id).unwrap().status(), JobStatus::Running);
+        assert_eq!(jobs.job(id).unwrap().mode(), ChainMode::Background);
+
+        // a foreground job that stops itself and exits after 'fg'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("kill -STOP $$; exit 3")
+            ).build();
+        let id2 = jobs.spawn(&cmd_chain).unwrap();
+        assert_eq!(id2, 2);
+        assert_eq!(jobs.job(id2).unwrap().status(), JobStatus::Stopped);
+        assert_eq!(jobs.fg(id2).unwrap(), JobStatus::Done);
+        assert_eq!(jobs.job(id2).unwrap().states()[0].exit_code(), 3);
+
+        crate::signal_group(pgid, libc::SIGTERM).unwrap();
+        assert_eq!(jobs.fg(id).unwrap(), JobStatus::Done);
+
+        let done = jobs.remove_done();
+        assert_eq!(done.iter().map(|job| job.id()).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(jobs.jobs().is_empty());
+        assert_eq!(jobs.fg(1).unwrap_err(), PiperError::NoSuchJob(1));
+    }
+}
@@ -22,185 +22,1352 @@
     SOFTWARE.
 */
 
-pub use crate::data::{CmdChain, BasicCmd, CmdChainBuilder, BasicCmdBuilder, Builder, ProcessState};
+pub use crate::data::{CmdChain, BasicCmd, CmdChainBuilder, BasicCmdBuilder, Builder, ChainMode, ChainResult, OutputRedirectMode, ProcessOutcome,
+                      ProcessState, PtyMode, RedirectConflictPolicy, StdinSource, StdoutTarget, TerminationReason, FdDup};
+pub use crate::error::{BuildError, PipelineError, PiperError, ProcessFailure};
+pub use crate::pty::{copy_terminal_size, execute_piped_cmd_chain_in_pty, spawn_piped_cmd_chain_pty, WinsizeForwarder};
+pub use crate::wait::{try_update_process_states_timeout, try_wait_all_timeout, update_process_states_timeout, wait_all_timeout,
+                      wait_background_poll, TIMEOUT_KILL_GRACE};
+pub use crate::parse::{parse_cmd_chain, parse_cmd_list, ParseError};
+pub use crate::list::{execute_cmd_list, execute_cmd_list_with_options, CmdList, CmdListBuilder, CmdListResult, Connector};
+pub use crate::check::{echo_only, EchoOnlyPlan, FsAccess, PreflightIssue, PreflightReport, Severity};
+pub use crate::options::ExecOptions;
+pub use crate::capture::{capture_output, capture_output_with, capture_tail, execute_feeding_lines, execute_piped_cmd_chain_capture,
+                         execute_piped_cmd_chain_capture_string, execute_piped_cmd_chain_from_reader, execute_piped_cmd_chain_into,
+                         execute_piped_cmd_chain_lines, execute_piped_cmd_chain_output, execute_piped_cmd_chain_streaming,
+                         execute_piped_cmd_chain_traced, run_with_deadline, spawn_piped_cmd_chain_capture_fd, CaptureOptions, ChainEvent,
+                         ChainLines, ChainOutput, PartialOutput, TAIL_MAX_LINE_LEN};
+pub use crate::diagnose::{diagnose_exec_error, ExecDiagnosis};
+pub use crate::budget::execute_with_fd_budget;
+pub use crate::summary::{ChainSummary, StageSummary};
+pub use crate::jobs::{Job, JobManager, JobStatus};
+pub use crate::handle::{spawn_coproc, spawn_piped_cmd_chain_handle, ChainHandle};
+pub use crate::reaper::{ReapedChain, Reaper};
+pub use crate::observer::ChainObserver;
+pub use crate::builtin::{Builtin, Cd, Exit, Export, Umask};
+pub use crate::spawn::{FileAction, ForkExecBackend, PosixSpawnBackend, SpawnBackend, SpawnFailure, SpawnRequest,
+                       StdCommandBackend};
+#[cfg(feature = "async")]
+pub use crate::async_wait::{execute_piped_cmd_chain_async, WaitFuture};
+#[cfg(all(feature = "systemd", target_os = "linux"))]
+pub use crate::systemd::{execute_piped_cmd_chain_in_systemd_scope, SystemdScope};
 // public in case someone want to use this abstraction
-pub use crate::pipe::Pipe;
+pub use crate::pipe::{Pipe, PipeEnd, PipeReader, PipeWriter};
+pub use crate::libc_util::{close_fds_from, close_fds_from_with, free_c_argv, free_c_envp, CStringArray, CloseFdsMethod};
 
 mod libc_util;
 mod data;
 mod pipe;
+mod error;
+mod pty;
+mod wait;
+mod parse;
+mod check;
+mod options;
+mod capture;
+mod diagnose;
+mod budget;
+mod summary;
+mod jobs;
+mod spawn;
+mod command;
+mod handle;
+mod reaper;
+mod observer;
+mod list;
+mod builtin;
+mod stage;
+#[cfg(all(feature = "systemd", target_os = "linux"))]
+mod systemd;
+#[cfg(feature = "async")]
+mod async_wait;
+
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use crate::data::os_cstring;
+use crate::pipe::new_cloexec_pipe;
+use crate::stage::StageThread;
 
 
 /// Runs a command chain. The parent process creates n childs and
 /// connects them (stdout => stdin) together via pipes.
-pub fn execute_piped_cmd_chain(cmds: &CmdChain) -> Vec<ProcessState> {
-    let mut pids: Vec<libc::pid_t> = vec![];
-
-    let mut pipe_to_current: Option<Pipe> = Option::None;
-    let mut pipe_to_next: Option<Pipe> = Option::None;
-    for i in 0..cmds.length() {
-        let cmd = &cmds.cmds()[i];
+/// Fails if a command can't be started, see `PiperError`.
+pub fn execute_piped_cmd_chain(cmds: &CmdChain) -> Result<Vec<ProcessState>, PiperError> {
+    execute_piped_cmd_chain_with_options(cmds, &ExecOptions::default())
+}
 
-        if pipe_to_next.is_some() {
-            pipe_to_current.replace(
-                pipe_to_next.take().unwrap()
-            );
+/// Like `execute_piped_cmd_chain()` but with additional options
+/// for the execution, see `ExecOptions`.
+pub fn execute_piped_cmd_chain_with_options(cmds: &CmdChain, opts: &ExecOptions) -> Result<Vec<ProcessState>, PiperError> {
+    let deadline = wait::deadline_of(cmds);
+    let spawned = spawn_piped_cmd_chain(cmds, &ChainIo::default(), opts);
+    if let (Some(fd), false) = (opts.terminal(), cmds.background()) {
+        if spawned.is_err() {
+            // an already started command may have taken the terminal
+            let _ = jobs::set_foreground_group(fd, unsafe { libc::getpgrp() });
         }
+    }
+    let mut process_states = spawned?;
+    if process_states.iter().all(|state| state.in_parent() && state.finished()) {
+        // a builtin that ran in the parent
+        return Ok(process_states);
+    }
+    if let (Some(deadline), None, false) = (deadline, opts.terminal(), cmds.background()) {
+        wait::wait_or_kill(&mut process_states, deadline.saturating_duration_since(Instant::now()));
+        return Ok(process_states);
+    }
+    if let (Some(fd), false) = (opts.terminal(), cmds.background()) {
+        // also returns if the chain got stopped, e.g. by Ctrl-Z
+        let pgid = process_states[0].pid();
+        let mut stopped = vec![false; process_states.len()];
+        let res = jobs::update_group(pgid, &mut process_states, &mut stopped, true);
+        jobs::set_foreground_group(fd, unsafe { libc::getpgrp() })
+            .map_err(PiperError::TerminalControlFailed)?;
+        res?;
+        return Ok(process_states);
+    }
+    update_process_states(&mut process_states, cmds.background());
+    Ok(process_states)
+}
 
-        if !cmd.is_last() {
-            pipe_to_next.replace(Pipe::new());
+/// Executes two command chains where STDOUT of the last command of `first`
+/// is connected to STDIN of the first command of `second`, like
+/// `(a | b) | (c | d)` in a shell, without merging them into one chain.
+/// Each chain keeps its own properties, e.g. its redirects have precedence
+/// over the connecting pipe and the processes of a foreground chain are
+/// waited for while a background chain keeps running. Returns the states
+/// of the processes of `first` followed by the ones of `second`.
+///
+/// If `second` can't be spawned, the already running processes of `first`
+/// are killed with `SIGKILL` and waited for.
+pub fn execute_connected_cmd_chains(first: &CmdChain, second: &CmdChain) -> Result<Vec<ProcessState>, PiperError> {
+    // like in a shell the timeout only applies to a foreground chain
+    let deadline_of = |cmds: &CmdChain| if cmds.background() { None } else { wait::deadline_of(cmds) };
+    let (first_deadline, second_deadline) = (deadline_of(first), deadline_of(second));
+    let fds = new_cloexec_pipe()?;
+    let (read_end, write_end) = unsafe {
+        (File::from_raw_fd(fds[PipeEnd::Read as usize]), File::from_raw_fd(fds[PipeEnd::Write as usize]))
+    };
+    let first_io = ChainIo { stdout: Some(write_end.as_raw_fd()), ..ChainIo::default() };
+    let mut first_states = spawn_piped_cmd_chain(first, &first_io, &ExecOptions::default())?;
+    let first_watchdog = wait::Watchdog::start(&first_states, first_deadline);
+    // otherwise `second` never sees EOF
+    drop(write_end);
+
+    let second_io = ChainIo { stdin: Some(read_end.as_raw_fd()), ..ChainIo::default() };
+    let second_states = spawn_piped_cmd_chain(second, &second_io, &ExecOptions::default());
+    drop(read_end);
+    let mut second_states = match second_states {
+        Ok(states) => states,
+        Err(e) => {
+            // the spawn error is the one that matters
+            let _ = first_watchdog.kill(&mut first_states);
+            return Err(e);
         }
+    };
+    let second_watchdog = wait::Watchdog::start(&second_states, second_deadline);
 
-        let pid = unsafe { libc::fork() };
-        if pid == -1 {
-            panic!("Fork failed! {}", errno::errno());
+    // both watchdogs run until their chain is waited for
+    for (cmds, states, watchdog) in [(first, &mut first_states, first_watchdog), (second, &mut second_states, second_watchdog)] {
+        if cmds.background() {
+            update_process_states(states, true);
+        } else {
+            watchdog.wait(states)?;
         }
+    }
+    first_states.extend(second_states);
+    Ok(first_states)
+}
+
+/// Optional file descriptors the outer ends of a command chain get connected
+/// to instead of the ones inherited from the parent. Used by the executor
+/// variants that need to talk to the chain (pty, capture, ...).
+/// File redirects of the commands (`< in.txt`, `> out.txt`) still have
+/// precedence.
+///
+/// The parent should create these FDs with `O_CLOEXEC`, so that the
+/// originals don't leak into the executed programs. `dup2()` clears
+/// the flag on the duplicate.
+#[derive(Debug, Default, Copy, Clone)]
+struct ChainIo {
+    /// Becomes STDIN of the first command.
+    stdin: Option<libc::c_int>,
+    /// Becomes STDOUT of the last command.
+    stdout: Option<libc::c_int>,
+    /// Becomes STDERR of every command.
+    stderr: Option<libc::c_int>,
+}
+
+/// Exit code of a child whose setup between `fork()` and `exec()` failed.
+/// The child now exits like a shell instead: with
+/// `CHILD_NOT_FOUND_EXIT_CODE`, `CHILD_EXEC_FAILED_EXIT_CODE` or
+/// `CHILD_PRE_EXEC_FAILED_EXIT_CODE`, depending on what failed.
+#[deprecated(note = "use CHILD_NOT_FOUND_EXIT_CODE, CHILD_EXEC_FAILED_EXIT_CODE or CHILD_PRE_EXEC_FAILED_EXIT_CODE")]
+pub const CHILD_SETUP_FAILED_EXIT_CODE: libc::c_int = 127;
+
+/// Exit code of a child whose executable can't be found, like in a shell.
+/// The parent reports this as an error, see `PiperError`, unless
+/// `ExecOptions::set_spawn_failures_as_exit_codes()` is set.
+pub const CHILD_NOT_FOUND_EXIT_CODE: libc::c_int = 127;
+
+/// Exit code of a child whose executable was found but can't be executed,
+/// e.g. because of missing permissions (`EACCES`), like in a shell.
+pub const CHILD_EXEC_FAILED_EXIT_CODE: libc::c_int = 126;
+
+/// Exit code of a child whose setup before `exec()` failed, e.g. because a
+/// redirect file can't be opened or the working directory doesn't exist,
+/// like in a shell.
+pub const CHILD_PRE_EXEC_FAILED_EXIT_CODE: libc::c_int = 1;
+
+/// Forks and execs all commands of the chain and connects them via pipes
+/// but doesn't wait for them. The caller is responsible for this,
+/// i.e. via `update_process_states()`.
+///
+/// If a command can't be started, the already started commands of the
+/// chain are killed and waited for, so that neither processes nor FDs
+/// of the chain are left behind.
+fn spawn_piped_cmd_chain(cmds: &CmdChain, io: &ChainIo, opts: &ExecOptions) -> Result<Vec<ProcessState>, PiperError> {
+    if builtin::runs_in_parent(cmds, io) {
+        return builtin::run_in_parent(&cmds.cmds()[0], opts).map(|state| vec![state]);
+    }
+    let mut pids: Vec<libc::pid_t> = vec![];
+    let started_at = Instant::now();
+    let backend = opts.spawn_backend();
+    if let Some(feature) = backend.unsupported_feature(cmds, opts) {
+        return Err(PiperError::Unsupported(feature));
+    }
+    // the terminal is handed to the process group of the chain
+    if let (Some(_), Some(feature)) = (opts.terminal(), cmds.cmds().iter().find_map(stage::thread_feature)) {
+        return Err(PiperError::Unsupported(feature));
+    }
+    // before the first fork(), see `ExecPlan`
+    let plans: Vec<ExecPlan> = (0..cmds.length()).map(|i| ExecPlan::new(cmds, i, opts)).collect();
 
-        // parent code
-        if pid > 0 {
-            pids.push(pid);
+    // STDIN contents of the first command are written into this pipe; like
+    // a redirect of the command it has precedence over `io`
+    let stdin_contents = cmds.cmds().first().and_then(|cmd| cmd.stdin_contents());
+    let contents_pipe = match stdin_contents {
+        Some(_) => Some(new_cloexec_pipe().map(|fds| unsafe {
+            (File::from_raw_fd(fds[PipeEnd::Read as usize]), File::from_raw_fd(fds[PipeEnd::Write as usize]))
+        })?),
+        None => None,
+    };
+    let io = &ChainIo {
+        stdin: contents_pipe.as_ref().map(|(read_end, _)| read_end.as_raw_fd()).or(io.stdin),
+        ..*io
+    };
+
+    // All pipes the parent created. pipes[i] connects command i with
+    // command i + 1. Each pipe is closed in the parent as soon as both of
+    // its processes are forked but we keep all of them, so that we can
+    // make sure in every case that no FD leaks in the parent.
+    let mut pipes: Vec<Pipe> = vec![];
+    // failures of single commands if they don't abort the chain
+    let mut spawn_errors: Vec<Option<PiperError>> = vec![];
+    // the threads of the commands that run in the parent; their pid is 0
+    let mut stages: Vec<Option<Arc<StageThread>>> = vec![];
+    for (i, plan) in plans.iter().enumerate() {
+        let cmd = &cmds.cmds()[i];
 
-            // We MUST close all FDs in the Parent
-            if pipe_to_current.is_some() {
-                pipe_to_current.as_mut().unwrap().parent_close_all();
+        if !cmd.is_last() {
+            let pipe = Pipe::try_new().and_then(|pipe| {
+                if cmd.nonblocking_pipe_to_next() {
+                    pipe.set_write_nonblocking(true)?;
+                }
+                Ok(pipe)
+            });
+            match pipe {
+                Ok(pipe) => pipes.push(pipe),
+                Err(e) => return Err(abort_spawn(e, &mut pipes, &pids, &stages)),
             }
         }
-        // child code
-        else {
-            // handle optional initial '< in.file' redirect
-            if cmd.is_first() && cmd.in_red_path().is_some() {
-                initial_ir(cmd);
+        if stage::runs_on_thread(cmd) {
+            match stage::spawn_stage(cmds, plan, io, opts, &pipes) {
+                Ok(stage) => {
+                    pids.push(0);
+                    spawn_errors.push(None);
+                    stages.push(Some(stage));
+                }
+                // like a process whose redirect fails
+                Err(e @ PiperError::RedirectOpenFailed { .. }) if opts.spawn_failures_as_exit_codes() => {
+                    pids.push(0);
+                    spawn_errors.push(Some(e));
+                    stages.push(None);
+                }
+                Err(e) => return Err(abort_spawn(e, &mut pipes, &pids, &stages)),
             }
-            // handle optional final '> out.file' redirect
-            if cmd.is_last() && cmd.out_red_path().is_some() {
-                final_or(cmd);
+            if let Some(index) = i.checked_sub(1) {
+                pipes[index].parent_close_all();
             }
-
-            if pipe_to_current.is_some() {
-                pipe_to_current.as_mut().unwrap().as_read_end();
+            continue;
+        }
+        let request = SpawnRequest {
+            executable: cmd.executable().to_string_lossy().into_owned(),
+            cmds,
+            plan,
+            io,
+            opts,
+            pipes: &pipes,
+            leader: leader(&pids),
+        };
+        let spawned = backend.spawn(&request);
+        if let (Some(observer), Ok(pid)) = (opts.observer(), &spawned) {
+            observer.on_spawn(*pid, cmd);
+        }
+        if let (Some(observer), Err(failure)) = (opts.observer(), &spawned) {
+            observer.on_exec_error(cmd, failure.error.errno());
+        }
+        match spawned {
+            Ok(pid) => {
+                pids.push(pid);
+                spawn_errors.push(None);
+                stages.push(None);
             }
-            if pipe_to_next.is_some() {
-                pipe_to_next.as_mut().unwrap().as_write_end();
+            // the process exited with the matching exit code, see `ChildFailure::exit_code()`
+            Err(SpawnFailure { error, pid: Some(pid) }) if opts.spawn_failures_as_exit_codes() => {
+                pids.push(pid);
+                spawn_errors.push(Some(error));
+                stages.push(None);
             }
-
-            let _res = unsafe {
-                libc::execvp(
-                    cmd.executable_cstring().as_ptr(),
-                    cmd.args_to_c_argv()
-                )
-            };
-            panic!("Exec failed! {}", errno::errno());
+            Err(failure) => {
+                pids.extend(failure.pid);
+                return Err(abort_spawn(failure.error, &mut pipes, &pids, &stages));
+            }
+        }
+        // We MUST close all FDs in the Parent: both processes of the pipe
+        // to the previous command exist
+        if let Some(index) = i.checked_sub(1) {
+            pipes[index].parent_close_all();
         }
     }
 
+    // should be a no-op because all pipes are closed at this point
+    close_pipes(&mut pipes);
+
+    if let (Some(contents), Some((read_end, mut write_end))) = (stdin_contents, contents_pipe) {
+        drop(read_end);
+        let contents = contents.clone();
+        // dropping the write end closes it, i.e. the command sees EOF;
+        // an error (EPIPE) means that the command doesn't read anymore
+        std::thread::spawn(move || {
+            let _sigpipe = pipe::SigpipeBlocked::new();
+            let _ = write_end.write_all(&contents);
+        });
+    }
+
+    let pgid = Some(leader(&pids)).filter(|pgid| *pgid > 0);
     let mut i = 0;
-    let mut process_states: Vec<ProcessState> = pids.into_iter()
-        .map(|pid| {
-            let executable = cmds.cmds()[i].executable().to_owned();
+    let states = pids.into_iter()
+        .zip(spawn_errors)
+        .zip(stages)
+        .map(|((pid, spawn_error), stage)| {
+            let cmd = &cmds.cmds()[i];
             i += 1;
-            ProcessState::new(executable, pid)
+            let mut state = ProcessState::new(cmd.executable().to_string_lossy().into_owned(), pid);
+            state.set_started_at(started_at);
+            if let Some(err) = spawn_error {
+                state.set_spawn_error(err);
+                if pid == 0 {
+                    // a command that runs in the parent and whose redirect failed
+                    state.finish(CHILD_PRE_EXEC_FAILED_EXIT_CODE);
+                }
+            }
+            if let (true, Some(pgid)) = (opts.process_group(), pgid) {
+                state.set_pgid(pgid);
+            }
+            if let Some(stage) = stage {
+                state.set_stage(stage);
+                return state;
+            }
+            if let Some(observer) = opts.observer() {
+                state.set_observer(observer.clone());
+            }
+            if let Some(pidfd) = Some(pid).filter(|_| opts.pidfds()).and_then(wait::pidfd_open) {
+                state.set_pidfd(unsafe { OwnedFd::from_raw_fd(pidfd) });
+            }
+            if cmd.is_first() {
+                state.set_stdin_source(first_stdin_source_of(cmd, io));
+            }
+            if cmd.is_last() {
+                state.set_stdout_target(last_stdout_target_of(cmd, io));
+            }
+            state
         })
         .collect();
+    Ok(states)
+}
 
-    update_process_states(&mut process_states, cmds.background());
+/// The leader of the process group of the chain: the first process, i.e.
+/// commands that run in the parent (pid 0) are skipped. 0 if there is none
+/// yet.
+fn leader(pids: &[libc::pid_t]) -> libc::pid_t {
+    pids.iter().copied().find(|pid| *pid > 0).unwrap_or(0)
+}
+
+/// Cleans up after a command of the chain couldn't be started: closes all
+/// pipes and kills and waits for the already started commands. The
+/// processes of commands that run in the parent are killed as well; their
+/// threads finish on their own.
+fn abort_spawn(err: PiperError,
+               pipes: &mut [Pipe],
+               pids: &[libc::pid_t],
+               stages: &[Option<Arc<StageThread>>]) -> PiperError {
+    close_pipes(pipes);
+    for stage in stages.iter().flatten() {
+        let _ = stage.send_signal(libc::SIGKILL);
+    }
+    for pid in pids.iter().filter(|pid| **pid > 0) {
+        unsafe {
+            libc::kill(*pid, libc::SIGKILL);
+            libc::waitpid(*pid, std::ptr::null_mut(), 0);
+        }
+    }
+    err
+}
+
+/// What failed in a child between `fork()` and `exec()`. Each variant
+/// holds the errno.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ChildFailure {
+    Exec(libc::c_int),
+    InputRedirect(libc::c_int),
+    OutputRedirect(libc::c_int),
+    ErrorRedirect(libc::c_int),
+    Dup(libc::c_int),
+    Chdir(libc::c_int),
+    Unshare(libc::c_int),
+    Setpgid(libc::c_int),
+    TerminalControl(libc::c_int),
+    Credentials(libc::c_int),
+}
+
+impl ChildFailure {
+    /// Serializes the failure for the error pipe: a tag followed by the errno.
+    fn to_bytes(self) -> [u8; 5] {
+        let (tag, errno) = match self {
+            ChildFailure::Exec(errno) => (1, errno),
+            ChildFailure::InputRedirect(errno) => (2, errno),
+            ChildFailure::OutputRedirect(errno) => (3, errno),
+            ChildFailure::Dup(errno) => (4, errno),
+            ChildFailure::ErrorRedirect(errno) => (5, errno),
+            ChildFailure::Chdir(errno) => (6, errno),
+            ChildFailure::Unshare(errno) => (7, errno),
+            ChildFailure::Setpgid(errno) => (8, errno),
+            ChildFailure::TerminalControl(errno) => (9, errno),
+            ChildFailure::Credentials(errno) => (10, errno),
+        };
+        let errno = errno.to_ne_bytes();
+        [tag, errno[0], errno[1], errno[2], errno[3]]
+    }
+
+    /// Counterpart of `to_bytes()`. `None` if the child didn't report
+    /// anything, i.e. exec() succeeded.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 5 {
+            return None;
+        }
+        let errno = libc::c_int::from_ne_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        match bytes[0] {
+            1 => Some(ChildFailure::Exec(errno)),
+            2 => Some(ChildFailure::InputRedirect(errno)),
+            3 => Some(ChildFailure::OutputRedirect(errno)),
+            4 => Some(ChildFailure::Dup(errno)),
+            5 => Some(ChildFailure::ErrorRedirect(errno)),
+            6 => Some(ChildFailure::Chdir(errno)),
+            7 => Some(ChildFailure::Unshare(errno)),
+            8 => Some(ChildFailure::Setpgid(errno)),
+            9 => Some(ChildFailure::TerminalControl(errno)),
+            10 => Some(ChildFailure::Credentials(errno)),
+            _ => None,
+        }
+    }
+
+    /// The exit code of the child, like in a shell.
+    fn exit_code(self) -> libc::c_int {
+        match self {
+            ChildFailure::Exec(libc::ENOENT) => CHILD_NOT_FOUND_EXIT_CODE,
+            ChildFailure::Exec(_) => CHILD_EXEC_FAILED_EXIT_CODE,
+            _ => CHILD_PRE_EXEC_FAILED_EXIT_CODE,
+        }
+    }
+
+    /// Converts the failure into the public error with the context of the command.
+    fn into_error(self, cmd: &BasicCmd, executable: &str) -> PiperError {
+        match self {
+            ChildFailure::Exec(errno) => PiperError::ExecFailed { executable: executable.to_owned(), errno },
+            ChildFailure::InputRedirect(errno) => PiperError::RedirectOpenFailed {
+                path: lossy_path(cmd.in_red_path()),
+                errno,
+            },
+            ChildFailure::OutputRedirect(errno) => PiperError::RedirectOpenFailed {
+                path: lossy_path(cmd.out_red_path()),
+                errno,
+            },
+            ChildFailure::ErrorRedirect(errno) => PiperError::RedirectOpenFailed {
+                path: lossy_path(cmd.err_red_path()),
+                errno,
+            },
+            ChildFailure::Dup(errno) => PiperError::DupFailed(errno),
+            ChildFailure::Chdir(errno) => PiperError::ChdirFailed {
+                path: lossy_path(cmd.work_dir()),
+                errno,
+            },
+            ChildFailure::Unshare(errno) => PiperError::UnshareFailed(errno),
+            ChildFailure::Setpgid(errno) => PiperError::SetpgidFailed(errno),
+            ChildFailure::TerminalControl(errno) => PiperError::TerminalControlFailed(errno),
+            ChildFailure::Credentials(errno) => PiperError::CredentialsFailed(errno),
+        }
+    }
+}
+
+/// The path of a redirect or working directory for an error message.
+fn lossy_path(path: &Option<PathBuf>) -> String {
+    path.as_ref().map(|path| path.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Everything the child of a command needs, prepared by the parent before
+/// `fork()`: the argv and envp arrays, the `$PATH` lookup of the executables,
+/// the C strings of the paths and the flags of the output redirect. After
+/// `fork()` only async-signal-safe functions may be called (`dup2()`,
+/// `open()`, `execve()`, ...): another thread of the parent (e.g. the one that
+/// writes the STDIN contents) may have held the lock of the allocator or of
+/// stdio during `fork()`, hence the child must not allocate, format or panic.
+#[derive(Debug)]
+struct ExecPlan {
+    /// The index of the command in the chain.
+    index: usize,
+    /// The paths `execve()` tries for the executable, i.e. the `$PATH`
+    /// lookup of `execvp()`, and its argv; then the ones of every fallback.
+    /// With a wrapper the executable is the wrapper.
+    candidates: Vec<(Vec<CString>, CStringArray)>,
+    /// The environment of the process.
+    envp: CStringArray,
+    in_red_path: Option<CString>,
+    out_red_path: Option<CString>,
+    /// The `open()` flags of the output redirect.
+    out_red_flags: libc::c_int,
+    err_red_path: Option<CString>,
+    work_dir: Option<CString>,
+}
+
+impl ExecPlan {
+    /// Prepares command `i` of the chain. Panics if a string contains a null byte.
+    fn new(cmds: &CmdChain, i: usize, opts: &ExecOptions) -> Self {
+        let cmd = &cmds.cmds()[i];
+        // the executable and then the fallbacks
+        let candidates = std::iter::once(cmd.executable())
+            .chain(cmd.fallbacks().iter().map(|s| s.as_os_str()))
+            .enumerate()
+            .map(|(n, candidate)| {
+                let mut args = cmd.args().clone();
+                if n > 0 {
+                    args[0] = candidate.to_owned();
+                }
+                match opts.wrapper() {
+                    // e.g. 'strace -f grep -i abc'
+                    Some(wrapper) => {
+                        args[0] = candidate.to_owned();
+                        args.splice(0..0, wrapper.iter().map(OsString::from));
+                        (search_path(OsStr::new(&wrapper[0])), CStringArray::new(&args))
+                    }
+                    None => (search_path(candidate), CStringArray::new(&args)),
+                }
+            })
+            .collect();
+        // '> out.file' truncates while '>> out.file' appends
+        let out_red_flags = libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC | match cmd.out_red_mode() {
+            OutputRedirectMode::Truncate if opts.noclobber() => libc::O_EXCL,
+            OutputRedirectMode::Truncate | OutputRedirectMode::Clobber => libc::O_TRUNC,
+            OutputRedirectMode::Append => libc::O_APPEND,
+        };
+        ExecPlan {
+            index: i,
+            candidates,
+            envp: subshell_envp(cmd, opts),
+            in_red_path: cmd.in_red_path_cstring(),
+            out_red_path: cmd.out_red_path_cstring(),
+            out_red_flags,
+            err_red_path: cmd.err_red_path_cstring(),
+            work_dir: cmd.work_dir_cstring(),
+        }
+    }
+}
+
+/// The environment of a command of the list of a subshell: the one of the
+/// subshell with the variables of the command on top, see
+/// `ExecOptions::with_subshell_env()`.
+fn subshell_envp(cmd: &BasicCmd, opts: &ExecOptions) -> CStringArray {
+    match opts.subshell_env() {
+        Some((clear, vars)) if !cmd.clear_env() => {
+            let vars: Vec<(String, String)> = vars.iter()
+                .filter(|(key, _)| !cmd.env().iter().any(|(k, _)| k == key))
+                .chain(cmd.env().iter())
+                .cloned()
+                .collect();
+            CStringArray::envp(!clear, &vars)
+        }
+        _ => cmd.c_envp(),
+    }
+}
+
+/// The paths that `execvp()` would try for `executable`: the executable
+/// itself if it contains a slash, otherwise every directory of `$PATH`
+/// (an empty entry is the working directory).
+fn search_path(executable: &OsStr) -> Vec<CString> {
+    if executable.as_bytes().contains(&b'/') {
+        return vec![os_cstring(executable)];
+    }
+    // the default of glibc if $PATH isn't set
+    let path = std::env::var_os("PATH").unwrap_or_else(|| OsString::from("/bin:/usr/bin"));
+    path.as_bytes()
+        .split(|b| *b == b':')
+        .map(|dir| {
+            let dir: &[u8] = if dir.is_empty() { b"." } else { dir };
+            os_cstring(OsStr::from_bytes(&[dir, b"/", executable.as_bytes()].concat()))
+        })
+        .collect()
+}
+
+/// Child code: connects the FDs of the command of `plan` and execs it. If
+/// something fails, it's written to `err_fd` and the child exits immediately
+/// with the exit code of the failure. `_exit()` makes sure that no
+/// destructors or atexit handlers of the parent's address space run.
+fn exec_child(cmds: &CmdChain,
+              plan: &ExecPlan,
+              io: &ChainIo,
+              opts: &ExecOptions,
+              pgid: libc::pid_t,
+              pipes: &[Pipe],
+              mut err_fd: libc::c_int) -> ! {
+    // The parent doesn't call setpgid() itself: it waits for exec()
+    // (see the error pipe), hence the group exists before it's used.
+    let res = if opts.process_group() && unsafe { libc::setpgid(0, pgid) } == -1 {
+        Err(ChildFailure::Setpgid(errno::errno().0))
+    } else if let (Some(fd), false) = (opts.terminal(), cmds.background()) {
+        let pgid = if pgid == 0 { unsafe { libc::getpid() } } else { pgid };
+        jobs::set_foreground_group(fd, pgid)
+            .map_err(ChildFailure::TerminalControl)
+            .and_then(|_| setup_child_fds(cmds, plan, io, opts, pipes, &mut err_fd))
+    } else {
+        setup_child_fds(cmds, plan, io, opts, pipes, &mut err_fd)
+    };
+    let failure = match res {
+        Err(failure) => failure,
+        Ok(()) if cmds.cmds()[plan.index].builtin().is_some() => {
+            libc_util::close_cloexec_fds();
+            let exit_code = builtin::run_in_child(&cmds.cmds()[plan.index]);
+            unsafe { libc::_exit(exit_code) }
+        }
+        Ok(()) => {
+            // the executable and then the fallbacks, as long as they can't be found
+            let mut errno = 0;
+            for (paths, argv) in &plan.candidates {
+                errno = exec_search(paths, argv, &plan.envp);
+                if errno != libc::ENOENT {
+                    break;
+                }
+            }
+            ChildFailure::Exec(errno)
+        }
+    };
+
+    let bytes = failure.to_bytes();
+    unsafe {
+        libc::write(err_fd, bytes.as_ptr() as *const libc::c_void, bytes.len());
+        libc::_exit(failure.exit_code())
+    }
+}
+
+/// Child code: connects STDIN, STDOUT and STDERR of the command of `plan`.
+/// If all other FDs get closed, the error pipe is moved to FD 3 and `err_fd`
+/// is updated.
+fn setup_child_fds(cmds: &CmdChain,
+                   plan: &ExecPlan,
+                   io: &ChainIo,
+                   opts: &ExecOptions,
+                   pipes: &[Pipe],
+                   err_fd: &mut libc::c_int) -> Result<(), ChildFailure> {
+    let i = plan.index;
+    let cmd = &cmds.cmds()[i];
+
+    // connect the outer ends of the chain; before the file
+    // redirects, because they have precedence
+    if cmd.is_first() {
+        if let Some(fd) = io.stdin {
+            connect_fd(fd, libc::STDIN_FILENO)?;
+        }
+    }
+    if cmd.is_last() {
+        if let Some(fd) = io.stdout {
+            connect_fd(fd, libc::STDOUT_FILENO)?;
+        }
+    }
+    if let Some(fd) = io.stderr {
+        connect_fd(fd, libc::STDERR_FILENO)?;
+    }
+    // externally managed pipes of the command itself
+    if cmd.is_first() {
+        if let Some(fd) = cmd.stdin_pipe_fd() {
+            connect_fd(fd, libc::STDIN_FILENO)?;
+        }
+    }
+    if cmd.is_last() {
+        if let Some(fd) = cmd.stdout_pipe_fd() {
+            connect_fd(fd, libc::STDOUT_FILENO)?;
+        }
+    }
+
+    // handle optional initial '< in.file' redirect
+    if let (true, Some(path)) = (cmd.is_first(), &plan.in_red_path) {
+        initial_ir(path)?;
+    }
+    // handle optional final '> out.file' redirect
+    if let (true, Some(path)) = (cmd.is_last(), &plan.out_red_path) {
+        final_or(path, plan.out_red_flags)?;
+    }
+    // handle optional '2> err.file' redirect (of any command)
+    if let Some(path) = &plan.err_red_path {
+        stderr_or(path)?;
+    }
+
+    // pipe to the current and to the next child
+    // (the original FDs are closed during exec(), see `new_cloexec_pipe()`)
+    if let Some(index) = i.checked_sub(1) {
+        connect_fd(pipes[index].read_fd(), libc::STDIN_FILENO)?;
+    }
+    if !cmd.is_last() {
+        connect_fd(pipes[i].write_fd(), libc::STDOUT_FILENO)?;
+    }
+    // '2>&1': after STDOUT got its final target (pipe, redirect or terminal)
+    if cmd.merge_stderr() {
+        connect_fd(libc::STDOUT_FILENO, libc::STDERR_FILENO)?;
+    }
+    for dup in cmd.fd_dups() {
+        if dup.from() == *err_fd {
+            // move the error pipe out of the way
+            let fd = unsafe { libc::fcntl(*err_fd, libc::F_DUPFD_CLOEXEC, 3) };
+            if fd == -1 {
+                return Err(ChildFailure::Dup(errno::errno().0));
+            }
+            *err_fd = fd;
+        }
+        connect_fd(dup.to(), dup.from())?;
+    }
+
+    // after the redirects, so that their paths are relative to the parent's
+    // working directory; doesn't affect the parent
+    if let Some(work_dir) = &plan.work_dir {
+        if unsafe { libc::chdir(work_dir.as_ptr()) } == -1 {
+            return Err(ChildFailure::Chdir(errno::errno().0));
+        }
+    }
+    if opts.new_mount_namespace() {
+        unshare_mount_namespace()?;
+    }
+    // the groups first; afterwards the privileges to change them are gone
+    if let Some(groups) = cmd.groups() {
+        if unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) } == -1 {
+            return Err(ChildFailure::Credentials(errno::errno().0));
+        }
+    }
+    if let Some(gid) = cmd.gid() {
+        if unsafe { libc::setgid(gid) } == -1 {
+            return Err(ChildFailure::Credentials(errno::errno().0));
+        }
+    }
+    if let Some(uid) = cmd.uid() {
+        if unsafe { libc::setuid(uid) } == -1 {
+            return Err(ChildFailure::Credentials(errno::errno().0));
+        }
+    }
+
+    if cmds.close_fds() {
+        // keep the error pipe; dup2() clears the close-on-exec flag
+        connect_fd(*err_fd, 3)?;
+        unsafe { libc::fcntl(3, libc::F_SETFD, libc::FD_CLOEXEC) };
+        *err_fd = 3;
+        libc_util::close_fds_from(4);
+    }
+    Ok(())
+}
+
+/// Spawns the process of `request` for `ForkExecBackend`. The child
+/// reports errors before `exec()` through a pipe, hence a failure of the
+/// child is known when this returns.
+fn fork_exec(request: &SpawnRequest) -> Result<libc::pid_t, SpawnFailure> {
+    let (cmds, plan, opts) = (request.cmds, request.plan, request.opts);
+    let (mut err_read, err_write) = new_cloexec_pipe().map(|fds| unsafe {
+        (File::from_raw_fd(fds[PipeEnd::Read as usize]), File::from_raw_fd(fds[PipeEnd::Write as usize]))
+    })?;
+
+    let pid = fork(plan.index);
+    if pid == -1 {
+        return Err(PiperError::ForkFailed(errno::errno().0).into());
+    }
+    // child code
+    if pid == 0 {
+        drop(err_read);
+        // the first command becomes the leader of the new group
+        exec_child(cmds, plan, request.io, opts, request.leader, request.pipes, err_write.as_raw_fd());
+    }
+
+    // EOF as soon as the child exec'd or exited
+    drop(err_write);
+    let mut report = vec![];
+    let _ = err_read.read_to_end(&mut report);
+    match ChildFailure::from_bytes(&report) {
+        Some(failure) => {
+            let cmd = request.cmd();
+            let executable = match opts.wrapper() {
+                Some(wrapper) => wrapper[0].clone(),
+                None => cmd.executable().to_string_lossy().into_owned(),
+            };
+            Err(SpawnFailure::with_pid(failure.into_error(cmd, &executable), pid))
+        }
+        None => Ok(pid),
+    }
+}
+
+/// The same FD setup as `setup_child_fds()` as file actions for a
+/// `SpawnBackend`.
+fn file_actions(cmds: &CmdChain, plan: &ExecPlan, io: &ChainIo, pipes: &[Pipe]) -> Vec<FileAction> {
+    let i = plan.index;
+    let cmd = &cmds.cmds()[i];
+    let dup2 = |from, to| FileAction::Dup2 { from, to };
+    let mut actions = vec![];
+
+    if let (true, Some(fd)) = (cmd.is_first(), io.stdin) {
+        actions.push(dup2(fd, libc::STDIN_FILENO));
+    }
+    if let (true, Some(fd)) = (cmd.is_last(), io.stdout) {
+        actions.push(dup2(fd, libc::STDOUT_FILENO));
+    }
+    if let Some(fd) = io.stderr {
+        actions.push(dup2(fd, libc::STDERR_FILENO));
+    }
+    if let (true, Some(fd)) = (cmd.is_first(), cmd.stdin_pipe_fd()) {
+        actions.push(dup2(fd, libc::STDIN_FILENO));
+    }
+    if let (true, Some(fd)) = (cmd.is_last(), cmd.stdout_pipe_fd()) {
+        actions.push(dup2(fd, libc::STDOUT_FILENO));
+    }
+    // the files are opened directly as STDIN/STDOUT/STDERR, hence
+    // without the close-on-exec flag
+    if let (true, Some(path)) = (cmd.is_first(), &plan.in_red_path) {
+        actions.push(FileAction::Open { fd: libc::STDIN_FILENO, path: path.clone(), flags: libc::O_RDONLY, mode: 0 });
+    }
+    if let (true, Some(path)) = (cmd.is_last(), &plan.out_red_path) {
+        let mut flags = plan.out_red_flags & !libc::O_CLOEXEC;
+        // see final_or()
+        if flags & libc::O_EXCL != 0 && std::fs::metadata(cmd.out_red_path().as_ref().unwrap())
+            .is_ok_and(|m| !m.file_type().is_file()) {
+            flags = flags & !libc::O_EXCL | libc::O_TRUNC;
+        }
+        actions.push(FileAction::Open { fd: libc::STDOUT_FILENO, path: path.clone(), flags, mode: 0o644 });
+    }
+    if let Some(path) = &plan.err_red_path {
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+        actions.push(FileAction::Open { fd: libc::STDERR_FILENO, path: path.clone(), flags, mode: 0o644 });
+    }
+    if let Some(index) = i.checked_sub(1) {
+        actions.push(dup2(pipes[index].read_fd(), libc::STDIN_FILENO));
+    }
+    if !cmd.is_last() {
+        actions.push(dup2(pipes[i].write_fd(), libc::STDOUT_FILENO));
+    }
+    if cmd.merge_stderr() {
+        actions.push(dup2(libc::STDOUT_FILENO, libc::STDERR_FILENO));
+    }
+    for dup in cmd.fd_dups() {
+        actions.push(dup2(dup.to(), dup.from()));
+    }
+    actions
+}
+
+/// The first feature of the chain that only `ForkExecBackend` supports,
+/// see `PosixSpawnBackend`.
+fn fork_only_feature(cmds: &CmdChain, opts: &ExecOptions) -> Option<&'static str> {
+    let cmd_feature = |cmd: &BasicCmd| {
+        if cmd.builtin().is_some() {
+            Some("builtins")
+        } else if cmd.work_dir().is_some() {
+            Some("working directory")
+        } else if cmd.uid().is_some() || cmd.gid().is_some() || cmd.groups().is_some() {
+            Some("user or groups")
+        } else {
+            None
+        }
+    };
+    if opts.new_mount_namespace() {
+        Some("new mount namespace")
+    } else if opts.terminal().is_some() {
+        Some("terminal")
+    } else if cmds.close_fds() {
+        Some("closing all FDs")
+    } else {
+        cmds.cmds().iter().filter(|cmd| !stage::runs_on_thread(cmd)).find_map(cmd_feature)
+    }
+}
+
+/// Returns what STDOUT of the last command of a chain was connected to,
+/// e.g. to find out why a program did or didn't colorize its output.
+/// `None` if `states` is empty.
+pub fn last_stdout_target(states: &[ProcessState]) -> Option<StdoutTarget> {
+    states.last().map(|state| state.stdout_target())
+}
+
+/// Returns the wall-clock time of a chain from the first `fork()` until the
+/// last process was reaped. `None` if `states` is empty or a process is
+/// still running.
+pub fn total_duration(states: &[ProcessState]) -> Option<Duration> {
+    let started_at = states.iter().map(|state| state.started_at()).min()?;
+    let finished_at = states.iter().map(|state| state.finished_at()).collect::<Option<Vec<_>>>()?;
+    finished_at.into_iter().max().map(|finished_at| finished_at - started_at)
+}
+
+/// Sends the signal to every process of a chain that isn't finished yet,
+/// e.g. `SIGTERM` to stop a background chain. All processes are tried even
+/// if sending to one fails; the first error is returned. The processes
+/// still have to be waited for with `update_process_states()`.
+pub fn kill_all(states: &[ProcessState], signal: libc::c_int) -> Result<(), PiperError> {
+    let mut res = Ok(());
+    for state in states {
+        if let Err(e) = state.send_signal(signal) {
+            res = res.and(Err(e));
+        }
+    }
+    res
+}
+
+/// Sends the signal to all processes of a process group at once, e.g.
+/// `SIGINT` for Ctrl-C to a whole chain that was started with
+/// `ExecOptions::set_process_group()` (see `ProcessState::pgid()`).
+/// The error holds the negated pgid, like `kill(-pgid, signal)`.
+pub fn signal_group(pgid: libc::pid_t, signal: libc::c_int) -> Result<(), PiperError> {
+    if unsafe { libc::killpg(pgid, signal) } == -1 {
+        return Err(PiperError::SignalFailed { pid: -pgid, errno: errno::errno().0 });
+    }
+    Ok(())
+}
+
+/// Returns what STDIN of the first command of a chain was connected to,
+/// counterpart of `last_stdout_target()`. `None` if `states` is empty.
+pub fn first_stdin_source(states: &[ProcessState]) -> Option<StdinSource> {
+    states.first().map(|state| state.stdin_source())
+}
+
+/// Like `execute_piped_cmd_chain()` but waits until all processes are finished
+/// (also for background chains) and checks their exit codes. If a process
+/// didn't exit with 0, an error with the whole context of the pipeline
+/// is returned, e.g. `pipeline 'cat x | grep y' failed: stage 2 (grep) exited 2`.
+/// If multiple processes failed, the first one in the chain is reported.
+/// Fails if the chain is required to run in background.
+pub fn execute_piped_cmd_chain_checked(cmds: &CmdChain) -> Result<Vec<ProcessState>, PipelineError> {
+    ensure_foreground(cmds).map_err(|e| PipelineError::from_spawn_error(cmds.to_string(), e))?;
+    let mut states = execute_piped_cmd_chain(cmds)
+        .map_err(|e| PipelineError::from_spawn_error(cmds.to_string(), e))?;
+    if cmds.background() {
+        // wait blocking for the rest
+        update_process_states(&mut states, false);
+    }
 
-    process_states
+    let failure = states.iter()
+        .enumerate()
+        .find(|(_, state)| state.exit_code() != 0)
+        .map(|(i, state)| match state.outcome() {
+            ProcessOutcome::Signaled(signal) => ProcessFailure::from_signal(i, state.executable().to_owned(), signal),
+            ProcessOutcome::Exited(exit_code) => ProcessFailure::new(i, state.executable().to_owned(), exit_code),
+        });
+
+    match failure {
+        Some(failure) => Err(PipelineError::new(cmds.to_string(), failure)),
+        None => Ok(states),
+    }
+}
+
+/// Returns `PiperError::InvalidChain` if the chain is required to run in
+/// background. Used by the executors that wait for the processes.
+fn ensure_foreground(cmds: &CmdChain) -> Result<(), PiperError> {
+    cmds.ensure_mode(ChainMode::Foreground).map_err(PiperError::InvalidChain)
+}
+
+/// Whether the crate prints status messages, see `set_quiet()`.
+static QUIET: AtomicBool = AtomicBool::new(true);
+
+/// Receives the status messages instead of STDOUT and STDERR, see `set_logger()`.
+static LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+
+/// A function that receives the status messages of the crate, see `set_logger()`.
+pub type Logger = fn(LogLevel, &str);
+
+/// The severity of a status message of the crate, see `set_logger()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LogLevel {
+    /// E.g. "Process 42 finished with status code 0". Printed to STDOUT.
+    Info,
+    /// E.g. a process that was terminated by a signal. Printed to STDERR.
+    Warn,
+}
+
+/// Switches the status messages the crate prints to STDOUT and STDERR,
+/// e.g. "Process 42 finished with status code 0" from
+/// `update_process_states()`. The crate is quiet by default, i.e.
+/// `set_quiet(false)` enables them. Affects all threads. Output that is
+/// the purpose of a function (e.g. the plan of `echo_only()`) is always
+/// printed. With the feature `log` the messages are never printed but
+/// emitted via the `log` crate, whose filter applies instead.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Getter for the switch of `set_quiet()`.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Hands every status message to `logger` instead of printing it,
+/// regardless of `set_quiet()`, e.g. to forward them to the `tracing`
+/// crate of the application; for the `log` crate there is the feature
+/// `log`. `None` restores printing (or logging). Affects all threads.
+pub fn set_logger(logger: Option<Logger>) {
+    *LOGGER.write().unwrap_or_else(|e| e.into_inner()) = logger;
+}
+
+/// Reports a status message (a line) to the logger, or emits it via the
+/// `log` crate (feature `log`), or prints it unless the crate is quiet.
+fn report(level: LogLevel, msg: std::fmt::Arguments) {
+    if let Some(logger) = *LOGGER.read().unwrap_or_else(|e| e.into_inner()) {
+        count_reported_line();
+        logger(level, &msg.to_string());
+        return;
+    }
+    #[cfg(feature = "log")]
+    {
+        let level = match level {
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Warn => log::Level::Warn,
+        };
+        if log::log_enabled!(level) {
+            count_reported_line();
+            log::log!(level, "{}", msg);
+        }
+    }
+    #[cfg(not(feature = "log"))]
+    {
+        if quiet() {
+            return;
+        }
+        count_reported_line();
+        match level {
+            LogLevel::Warn => eprintln!("{}", msg),
+            LogLevel::Info => println!("{}", msg),
+        }
+    }
+}
+
+/// Counts the lines of `report()` for the tests.
+fn count_reported_line() {
+    #[cfg(test)]
+    REPORTED_LINES.with(|lines| lines.set(lines.get() + 1));
 }
 
 /// Updates the process state values if the pid is done running.
 /// Returns true if all pids are finished, otherwise false.
 ///
 ///  * `wnohang` if waitpid uses WNOHANG-flag. In other words: true means "wait blocking"
-///     and false means "update but don't block".
-pub fn update_process_states(states: &mut Vec<ProcessState>, wnohang: bool) -> bool {
+///    and false means "update but don't block".
+///
+/// Panics if `waitpid()` fails, see `try_update_process_states()`.
+pub fn update_process_states(states: &mut [ProcessState], wnohang: bool) -> bool {
+    try_update_process_states(states, wnohang).unwrap_or_else(|e| panic!("Failure during waitpid! {}", e))
+}
+
+/// Like `update_process_states()` but returns an error if `waitpid()`
+/// fails, e.g. because a process was already waited for elsewhere. The
+/// states that were updated before the error keep their update.
+pub fn try_update_process_states(states: &mut [ProcessState], wnohang: bool) -> Result<bool, PiperError> {
     // decide whether we wait blocking or non blocking
     let wait_flags: libc::c_int = if wnohang { libc::WNOHANG } else { 0 };
     let mut all_finished = true;
 
     // only check those that are not finished yet!
     // Important, otherwise failures happen
-    states.into_iter()
-        .filter(|state| !state.finished())
-        .for_each(|state| {
-            let mut status_code: libc::c_int = 0;
-            let status_code_ptr = &mut status_code as * mut libc::c_int;
-
-            let res = unsafe { libc::waitpid(state.pid(), status_code_ptr, wait_flags) };
-
-            // IDE doesn't find this functions but they exist
-            // returns true if the child terminated normally
-            let exited_normally: bool = unsafe { libc::WIFEXITED(status_code) };
-
-            if wait_flags == libc::WNOHANG && res == 0 {
-                all_finished = false;
-                // not done yet
-            } else if res == -1 {
-                panic!("Failure during waitpid! {}", errno::errno());
-            } else {
-                if !exited_normally {
-                    eprintln!("Process did not exited normally! {:#?}", state);
-                }
-                // exit code (only if exited_normally is true)
-                let exit_code: libc::c_int = unsafe { libc::WEXITSTATUS(status_code) };
+    for state in states.iter_mut().filter(|state| !state.finished()) {
+        // a command that runs on a thread of the parent
+        if let Some(stage) = state.stage().cloned() {
+            match stage.exit_code(!wnohang) {
+                Some(exit_code) => state.finish(exit_code),
+                None => all_finished = false,
+            }
+            continue;
+        }
+        let mut status_code: libc::c_int = 0;
+        let status_code_ptr = &mut status_code as * mut libc::c_int;
 
-                state.finish(exit_code);
-                println!("Process {} finished with status code {}", state.pid(), status_code);
+        let res = unsafe { libc::waitpid(state.pid(), status_code_ptr, wait_flags) };
+        // the observer gets the status instead of the output
+        let observed = state.observer().cloned();
+
+        // IDE doesn't find this functions but they exist
+        // returns true if the child terminated normally
+        let exited_normally: bool = libc::WIFEXITED(status_code);
+
+        if wait_flags == libc::WNOHANG && res == 0 {
+            all_finished = false;
+            // not done yet
+        } else if res == -1 {
+            return Err(PiperError::WaitFailed(errno::errno().0));
+        } else if libc::WIFSIGNALED(status_code) {
+            let signal = libc::WTERMSIG(status_code);
+            let core_dumped = libc::WCOREDUMP(status_code);
+            if observed.is_none() {
+                report(LogLevel::Warn, format_args!("Process {} was terminated by signal {}{}", state.pid(), signal,
+                                                    if core_dumped { " (core dumped)" } else { "" }));
             }
-        });
-    all_finished
+            state.finish_by_signal(signal);
+            if core_dumped {
+                state.set_core_dumped();
+            }
+        } else {
+            if !exited_normally && observed.is_none() {
+                report(LogLevel::Warn, format_args!("Process did not exited normally! {:#?}", state));
+            }
+            // exit code (only if exited_normally is true)
+            let exit_code: libc::c_int = libc::WEXITSTATUS(status_code);
+
+            state.finish(exit_code);
+            if observed.is_none() {
+                report(LogLevel::Info, format_args!("Process {} finished with status code {}", state.pid(), status_code));
+            }
+        }
+        if let (Some(observer), true) = (observed, state.finished()) {
+            observer.on_exit(state.pid(), state.outcome());
+        }
+    }
+    Ok(all_finished)
 }
 
-/// Handles initial input redirect (from file).
-fn initial_ir(cmd: &BasicCmd) {
-    let fd = unsafe {
-        libc::open(
-            cmd.in_red_path_cstring().unwrap().as_ptr(),
-            libc::O_RDONLY,
-        )
-    };
-    if fd == -1 {
-        panic!("Input redirect path {} can't be opened/read! {}", cmd.in_red_path().as_ref().unwrap(), errno::errno());
+#[cfg(test)]
+thread_local! {
+    /// Test-only: lets `fork()` fail for the command with this index.
+    static FAIL_FORK_AT: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+    /// Test-only: number of `fork()` calls of this thread.
+    static FORKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    /// Test-only: number of status messages printed by `report()`.
+    static REPORTED_LINES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// `fork()` for the command with the given index.
+fn fork(_index: usize) -> libc::pid_t {
+    #[cfg(test)]
+    {
+        FORKS.with(|forks| forks.set(forks.get() + 1));
+        if FAIL_FORK_AT.with(|f| f.get()) == Some(_index) {
+            return -1;
+        }
+    }
+    unsafe { libc::fork() }
+}
+
+/// Closes all FDs of all pipes in the parent that are not closed yet.
+fn close_pipes(pipes: &mut [Pipe]) {
+    pipes.iter_mut().for_each(|pipe| pipe.parent_close_all());
+}
+
+/// Child code: `execve()` with every path until one can be executed, like
+/// `execvp()` does it. Returns the errno if none works: `EACCES` if a file
+/// was found but couldn't be executed, `ENOENT` if nothing was found.
+/// Unlike `execvp()` a file without shebang isn't run with `/bin/sh`.
+fn exec_search(paths: &[CString], argv: &CStringArray, envp: &CStringArray) -> libc::c_int {
+    let mut errno = libc::ENOENT;
+    for path in paths {
+        unsafe { libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr()) };
+        match errno::errno().0 {
+            libc::ENOENT | libc::ENOTDIR => {}
+            libc::EACCES => errno = libc::EACCES,
+            other => return other,
+        }
     }
-    let ret = unsafe { libc::dup2(fd, libc::STDIN_FILENO) };
+    errno
+}
+
+/// Duplicates `fd` onto `file_no` (in the child).
+fn connect_fd(fd: libc::c_int, file_no: libc::c_int) -> Result<(), ChildFailure> {
+    let ret = unsafe { libc::dup2(fd, file_no) };
     if ret == -1 {
-        panic!("Error dup2() input redirect! {}", errno::errno());
+        return Err(ChildFailure::Dup(errno::errno().0));
     }
+    Ok(())
 }
 
-/// Handles final output redirect (to file).
-fn final_or(cmd: &BasicCmd) {
+/// Handles initial input redirect (from file).
+fn initial_ir(path: &CStr) -> Result<(), ChildFailure> {
     let fd = unsafe {
-        // note that append won't work here because we only use the
-        // '> out.file' functionality but not '>> out.file' which
-        // would require the O_APPEND flag!
-
-        // open() doesn't work; file remains empty
-        // somehow fopen does some more magic..
-        /*libc::open(
-            cmd.out_red_path_cstring().unwrap().as_ptr(),
-            libc::O_WRONLY | libc::O_CREAT,
-            0644,
-        );*/
-        let file = libc::fopen(
-            cmd.out_red_path_cstring().unwrap().as_ptr(),
-            "w".as_ptr() as * const i8
-        );
-        // get file descriptor
-        libc::fileno(file)
+        libc::open(
+            path.as_ptr(),
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
     };
     if fd == -1 {
-        panic!("Output redirect path {} can't be opened/written! {}", cmd.out_red_path().as_ref().unwrap(), errno::errno());
+        return Err(ChildFailure::InputRedirect(errno::errno().0));
     }
-    let ret = unsafe { libc::dup2(fd, libc::STDOUT_FILENO) };
-    if ret == -1 {
-        panic!("Error dup2() output redirect! {}", errno::errno());
+    connect_fd(fd, libc::STDIN_FILENO)
+}
+
+/// Handles final output redirect (to file) with the flags of
+/// `ExecPlan`. With `O_EXCL` (noclobber) a '>' redirect doesn't
+/// overwrite an existing regular file.
+fn final_or(path: &CStr, mut flags: libc::c_int) -> Result<(), ChildFailure> {
+    if flags & libc::O_EXCL != 0 {
+        // like bash: only regular files are protected, e.g. '> /dev/null' is fine
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::stat(path.as_ptr(), &mut stat) } == 0 && stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+            flags = flags & !libc::O_EXCL | libc::O_TRUNC;
+        }
+    }
+    let fd = unsafe { libc::open(path.as_ptr(), flags, 0o644) };
+    if fd == -1 {
+        return Err(ChildFailure::OutputRedirect(errno::errno().0));
+    }
+    connect_fd(fd, libc::STDOUT_FILENO)
+}
+
+/// Moves the child into a new mount namespace whose mounts don't
+/// propagate back to the host (like `unshare -m`).
+#[cfg(target_os = "linux")]
+fn unshare_mount_namespace() -> Result<(), ChildFailure> {
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } == -1 {
+        return Err(ChildFailure::Unshare(errno::errno().0));
+    }
+    // otherwise mounts in shared subtrees (systemd's default) still propagate
+    let root = b"/\0";
+    let res = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            root.as_ptr() as *const libc::c_char,
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    };
+    if res == -1 {
+        return Err(ChildFailure::Unshare(errno::errno().0));
+    }
+    Ok(())
+}
+
+/// Mount namespaces only exist on Linux.
+#[cfg(not(target_os = "linux"))]
+fn unshare_mount_namespace() -> Result<(), ChildFailure> {
+    Err(ChildFailure::Unshare(libc::ENOSYS))
+}
+
+/// Handles STDERR redirect (to file).
+fn stderr_or(path: &CStr) -> Result<(), ChildFailure> {
+    let fd = unsafe {
+        libc::open(
+            path.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+            0o644,
+        )
+    };
+    if fd == -1 {
+        return Err(ChildFailure::ErrorRedirect(errno::errno().0));
+    }
+    connect_fd(fd, libc::STDERR_FILENO)
+}
+
+/// Determines what STDOUT of the last command gets connected to. Follows
+/// the same precedence as the child: file redirect, then the pipe of the
+/// command itself, then the FD of `io` and finally the inherited STDOUT.
+fn last_stdout_target_of(cmd: &BasicCmd, io: &ChainIo) -> StdoutTarget {
+    if let Some(path) = cmd.out_red_path() {
+        return if is_dev_null(std::fs::metadata(path).ok()) {
+            StdoutTarget::Null
+        } else {
+            StdoutTarget::File
+        };
+    }
+    let fd = cmd.stdout_pipe_fd()
+        .or(io.stdout)
+        .unwrap_or(libc::STDOUT_FILENO);
+    fd_target(fd)
+}
+
+/// Determines what STDIN of the first command of a chain is connected to.
+fn first_stdin_source_of(cmd: &BasicCmd, io: &ChainIo) -> StdinSource {
+    if cmd.stdin_contents().is_some() {
+        return StdinSource::Bytes;
+    }
+    if let Some(path) = cmd.in_red_path() {
+        return if is_dev_null(std::fs::metadata(path).ok()) {
+            StdinSource::Null
+        } else {
+            StdinSource::File
+        };
+    }
+    let fd = cmd.stdin_pipe_fd()
+        .or(io.stdin)
+        .unwrap_or(libc::STDIN_FILENO);
+    match fd_target(fd) {
+        StdoutTarget::Tty => StdinSource::Tty,
+        StdoutTarget::Pipe => StdinSource::Pipe,
+        StdoutTarget::File => StdinSource::File,
+        StdoutTarget::Null => StdinSource::Null,
     }
 }
 
+/// Classifies an open FD of the parent.
+fn fd_target(fd: libc::c_int) -> StdoutTarget {
+    if unsafe { libc::isatty(fd) } == 1 {
+        return StdoutTarget::Tty;
+    }
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+        // closed FD; writes go nowhere
+        return StdoutTarget::Null;
+    }
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFIFO | libc::S_IFSOCK => StdoutTarget::Pipe,
+        libc::S_IFCHR if is_dev_null_rdev(stat.st_rdev) => StdoutTarget::Null,
+        _ => StdoutTarget::File,
+    }
+}
+
+/// Checks if the metadata belongs to `/dev/null`.
+fn is_dev_null(metadata: Option<std::fs::Metadata>) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    metadata.is_some_and(|m| m.file_type().is_char_device() && is_dev_null_rdev(m.rdev()))
+}
+
+/// Checks if the device number is the one of `/dev/null`.
+fn is_dev_null_rdev(rdev: libc::dev_t) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata("/dev/null").is_ok_and(|m| m.rdev() == rdev)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::data::{CmdChainBuilder, BasicCmdBuilder, Builder};
-    use crate::execute_piped_cmd_chain;
+    use crate::{execute_connected_cmd_chains, execute_piped_cmd_chain, execute_piped_cmd_chain_checked, execute_piped_cmd_chain_output,
+                execute_piped_cmd_chain_with_options,
+                last_stdout_target, total_duration, update_process_states, ExecOptions, OutputRedirectMode, Pipe, PiperError,
+                set_logger, first_stdin_source, LogLevel, kill_all, signal_group, FdDup, try_update_process_states, ProcessState, StdinSource, StdoutTarget, FAIL_FORK_AT};
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Serializes the tests that switch the global `set_quiet()` and
+    /// `set_logger()`: a logger takes the messages of every thread.
+    static REPORT_LOCK: Mutex<()> = Mutex::new(());
 
     #[test]
     fn test_execute_chain() {
@@ -225,6 +1392,955 @@ mod tests {
                     .add_arg("-l")
             ).build();
 
-        execute_piped_cmd_chain(&cmd_chain);
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+    }
+
+    #[test]
+    fn test_execute_chain_checked_error_context() {
+        // 'echo Hallo | grep -i abc' => grep exits with 1 (no match)
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("Hallo")
+            ).add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("grep")
+                    .add_arg("grep")
+                    .add_arg("-i")
+                    .add_arg("abc")
+            ).build();
+
+        let err = execute_piped_cmd_chain_checked(&cmd_chain).unwrap_err();
+        assert_eq!(err.failure().unwrap().stage(), 1);
+        assert_eq!(err.failure().unwrap().exit_code(), 1);
+        assert_eq!(err.to_string(), "pipeline 'echo Hallo | grep -i abc' failed: stage 2 (grep) exited 1");
+
+        let cmd_chain = crate::parse_cmd_chain("sh -c 'kill -KILL $$'").unwrap();
+        let err = execute_piped_cmd_chain_checked(&cmd_chain).unwrap_err();
+        assert_eq!(err.failure().unwrap().signal(), Some(libc::SIGKILL));
+        assert_eq!(err.failure().unwrap().exit_code(), 128 + libc::SIGKILL);
+    }
+
+    #[test]
+    fn test_connect_chains_via_shared_pipe() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_shared_pipe_{}.txt", std::process::id()));
+        let pipe = Pipe::new();
+
+        // 'echo hello' | 'tr a-z A-Z > out_file' as two separate chains
+        let writer = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("hello")
+                    .set_stdout_pipe(&pipe)
+            )
+            .set_background(true)
+            .build();
+        let reader = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("tr")
+                    .add_arg("tr")
+                    .add_arg("a-z")
+                    .add_arg("A-Z")
+                    .set_stdin_pipe(&pipe)
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            )
+            .set_background(true)
+            .build();
+
+        let mut states = execute_piped_cmd_chain(&reader).unwrap();
+        states.extend(execute_piped_cmd_chain(&writer).unwrap());
+        // all chains are spawned; now the reader must be able to see EOF
+        drop(pipe);
+        update_process_states(&mut states, false);
+
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "HELLO\n");
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[test]
+    fn test_execute_connected_cmd_chains() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_connected_{}.txt", std::process::id()));
+
+        // "(printf 'hello\nworld\n' | tac) | (tr a-z A-Z | cat > out_file)"
+        let first = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("printf").add_arg("printf").add_arg("hello\\nworld\\n"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("tac").add_arg("tac"))
+            .build();
+        let second = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("tr").add_arg("tr").add_arg("a-z").add_arg("A-Z"))
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+
+        let states = execute_connected_cmd_chains(&first, &second).unwrap();
+        assert_eq!(states.len(), 4);
+        assert!(states.iter().all(|state| state.finished() && state.exit_code() == 0));
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "WORLD\nHELLO\n");
+        std::fs::remove_file(&out_file).unwrap();
+
+        // the running first chain is cleaned up if the second one fails
+        let second = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("does-not-exist").add_arg("does-not-exist"))
+            .build();
+        let err = execute_connected_cmd_chains(&first, &second).unwrap_err();
+        assert!(matches!(err, PiperError::ExecFailed { errno: libc::ENOENT, .. }));
+    }
+
+    #[test]
+    fn test_execute_with_wrapper() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_wrapper_{}.txt", std::process::id()));
+
+        // 'echo hi > out_file'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("hi")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+        // the wrapper prints how it was invoked instead of executing the command
+        let opts = ExecOptions::new()
+            .set_wrapper(&["sh", "-c", "echo wrapped: \"$0\" \"$@\""]);
+
+        execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "wrapped: echo hi\n");
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    /// Number of open FDs of this process.
+    fn count_open_fds() -> usize {
+        std::fs::read_dir("/proc/self/fd").unwrap().count()
+    }
+
+    #[test]
+    fn test_no_fd_leak_on_success_and_failure() {
+        // Counting FDs is only reliable without other threads (other tests)
+        // that open FDs concurrently, therefore it's done in a child.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let cmd_chain = CmdChainBuilder::new()
+                .add_cmd(BasicCmdBuilder::new().set_executable("echo").add_arg("echo").add_arg("a"))
+                .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+                .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+                .build();
+
+            let before = count_open_fds();
+            execute_piped_cmd_chain(&cmd_chain).unwrap();
+            let after_success = count_open_fds();
+
+            // fork() of the last command fails
+            FAIL_FORK_AT.with(|f| f.set(Some(2)));
+            let res = execute_piped_cmd_chain(&cmd_chain);
+            let after_failure = count_open_fds();
+
+            let ok = before == after_success && matches!(res, Err(PiperError::ForkFailed(_))) && before == after_failure;
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    #[test]
+    fn test_output_redirect_append() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_append_{}.txt", std::process::id()));
+        std::fs::write(&out_file, "old\n").unwrap();
+
+        // 'echo new >> out_file'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("new")
+                    .set_output_redirect_append(out_file.to_str().unwrap())
+            ).build();
+        assert_eq!(cmd_chain.cmds()[0].out_red_mode(), OutputRedirectMode::Append);
+
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "old\nnew\nnew\n");
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[test]
+    fn test_noclobber() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_noclobber_{}.txt", std::process::id()));
+        std::fs::write(&out_file, "old\n").unwrap();
+        let opts = ExecOptions::new().set_noclobber(true);
+        let echo_new = || BasicCmdBuilder::new().set_executable("echo").add_arg("echo").add_arg("new");
+
+        // 'echo new > out_file' doesn't overwrite the existing file
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(echo_new().set_output_redirect_path(out_file.to_str().unwrap()))
+            .build();
+        let err = execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap_err();
+        assert!(matches!(err, PiperError::RedirectOpenFailed { errno: libc::EEXIST, .. }));
+        assert_eq!(std::io::Error::from(err).kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "old\n");
+
+        // 'echo new >| out_file' overwrites it anyway
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(echo_new().set_output_redirect_clobber(out_file.to_str().unwrap()))
+            .build();
+        execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "new\n");
+
+        // new files and non-regular files are fine
+        std::fs::remove_file(&out_file).unwrap();
+        for path in [out_file.to_str().unwrap(), "/dev/null"].iter() {
+            let cmd_chain = CmdChainBuilder::new()
+                .add_cmd(echo_new().set_output_redirect_path(path))
+                .build();
+            execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+        }
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[test]
+    fn test_first_stdin_source() {
+        let cat = || BasicCmdBuilder::new().set_executable("cat").add_arg("cat").set_output_redirect_path("/dev/null");
+
+        // 'cat < Cargo.toml > /dev/null'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(cat().set_input_redirect_path("Cargo.toml"))
+            .build();
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(first_stdin_source(&states), Some(StdinSource::File));
+
+        // 'cat < /dev/null > /dev/null &'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(cat().set_input_redirect_path("/dev/null"))
+            .set_background(true)
+            .build();
+        let mut states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(first_stdin_source(&states), Some(StdinSource::Null));
+        update_process_states(&mut states, false);
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(cat().set_stdin_contents("abc"))
+            .build();
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(first_stdin_source(&states), Some(StdinSource::Bytes));
+        assert_eq!(first_stdin_source(&[]), None);
+    }
+
+    #[test]
+    fn test_try_update_process_states() {
+        // pid 1 is never a child of the test
+        let mut states = vec![ProcessState::new("init".to_owned(), 1)];
+        assert_eq!(try_update_process_states(&mut states, true), Err(PiperError::WaitFailed(libc::ECHILD)));
+        assert!(!states[0].finished());
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("0.1"))
+            .set_background(true)
+            .build();
+        let mut states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(try_update_process_states(&mut states, false), Ok(true));
+    }
+
+    #[test]
+    #[cfg(not(feature = "log"))]
+    fn test_quiet() {
+        use super::{set_quiet, REPORTED_LINES};
+
+        let _lock = REPORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .build();
+
+        // quiet by default
+        REPORTED_LINES.with(|lines| lines.set(0));
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(REPORTED_LINES.with(|lines| lines.get()), 0);
+
+        set_quiet(false);
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(REPORTED_LINES.with(|lines| lines.get()), 1);
+        set_quiet(true);
+    }
+
+    #[test]
+    fn test_logger() {
+        static LOGGED: AtomicUsize = AtomicUsize::new(0);
+        fn logger(level: LogLevel, msg: &str) {
+            // other tests may log concurrently
+            if level == LogLevel::Info && msg.ends_with("finished with status code 0") {
+                LOGGED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .build();
+
+        let _lock = REPORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_logger(Some(logger));
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        set_logger(None);
+        assert!(LOGGED.load(Ordering::SeqCst) >= 1);
+    }
+
+    #[test]
+    #[cfg(feature = "log")]
+    fn test_log_feature() {
+        static LOGGED: AtomicUsize = AtomicUsize::new(0);
+        struct Counter;
+        impl log::Log for Counter {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.level() <= log::Level::Info
+            }
+            fn log(&self, record: &log::Record) {
+                // other tests may log concurrently
+                if record.level() == log::Level::Info && record.args().to_string().ends_with("finished with status code 0") {
+                    LOGGED.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            fn flush(&self) {}
+        }
+        log::set_logger(&Counter).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .build();
+        // regardless of set_quiet()
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert!(LOGGED.load(Ordering::SeqCst) >= 1);
+    }
+
+    #[test]
+    fn test_kill_all() {
+        // 'sleep 10 | sleep 10 | sleep 10 &'
+        let sleep = || BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("10");
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(sleep())
+            .add_cmd(sleep())
+            .add_cmd(sleep())
+            .set_background(true)
+            .build();
+        let mut states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+
+        kill_all(&states, libc::SIGTERM).unwrap();
+        update_process_states(&mut states, false);
+        assert!(states.iter().all(|state| state.term_signal() == Some(libc::SIGTERM)));
+
+        // finished processes are skipped
+        assert_eq!(kill_all(&states, libc::SIGKILL), Ok(()));
+    }
+
+    #[test]
+    fn test_signal_group() {
+        // 'sleep 10 | sleep 10 &' in its own process group
+        let sleep = || BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("10");
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(sleep())
+            .add_cmd(sleep())
+            .set_background(true)
+            .build();
+        let opts = ExecOptions::new().set_process_group(true);
+        let mut states = execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+        let pgid = states[0].pgid().unwrap();
+        assert_eq!(pgid, states[0].pid());
+        assert_eq!(states[1].pgid(), Some(pgid));
+        assert_eq!(unsafe { libc::getpgid(states[1].pid()) }, pgid);
+        assert_ne!(pgid, unsafe { libc::getpgrp() });
+
+        signal_group(pgid, libc::SIGINT).unwrap();
+        update_process_states(&mut states, false);
+        assert!(states.iter().all(|state| state.term_signal() == Some(libc::SIGINT)));
+
+        // the group is gone
+        let err = signal_group(pgid, libc::SIGINT).unwrap_err();
+        assert_eq!(err, PiperError::SignalFailed { pid: -pgid, errno: libc::ESRCH });
+
+        // default: same group as the parent
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .build();
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].pgid(), None);
+    }
+
+    #[test]
+    fn test_set_terminal() {
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+            .build();
+        let opts = ExecOptions::new().set_terminal(libc::STDIN_FILENO);
+        assert!(opts.process_group());
+
+        // the tests usually don't have a controlling terminal
+        let is_tty = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1
+            && unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) } == unsafe { libc::getpgrp() };
+        match execute_piped_cmd_chain_with_options(&cmd_chain, &opts) {
+            Ok(states) => {
+                assert!(is_tty);
+                assert_eq!(states[0].exit_code(), 0);
+                // the terminal was given back
+                assert_eq!(unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) }, unsafe { libc::getpgrp() });
+            }
+            Err(e) => assert!(matches!(e, PiperError::TerminalControlFailed(_)), "{:?}", e),
+        }
+
+        // not a terminal at all
+        use std::os::unix::io::AsRawFd;
+        let null = std::fs::File::open("/dev/null").unwrap();
+        let opts = ExecOptions::new().set_terminal(null.as_raw_fd());
+        let err = execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap_err();
+        assert_eq!(err, PiperError::TerminalControlFailed(libc::ENOTTY));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let sh = |script: &str| {
+            CmdChainBuilder::new()
+                .add_cmd(
+                    BasicCmdBuilder::new()
+                        .set_executable("sh")
+                        .add_arg("sh")
+                        .add_arg("-c")
+                        .add_arg(script)
+                ).add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true"))
+                .set_timeout(Duration::from_millis(100))
+        };
+
+        let cmd_chain = sh("exec sleep 10").build();
+        assert_eq!(cmd_chain.timeout(), Some(Duration::from_millis(100)));
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].term_signal(), Some(libc::SIGTERM));
+        assert!(states.iter().all(|state| state.finished() && state.timed_out()));
+
+        // SIGTERM is ignored
+        let begin = std::time::Instant::now();
+        let states = execute_piped_cmd_chain(&sh("trap '' TERM; exec sleep 10").build()).unwrap();
+        assert_eq!(states[0].term_signal(), Some(libc::SIGKILL));
+        assert!(begin.elapsed() < Duration::from_secs(5));
+
+        let states = execute_piped_cmd_chain(&sh("exit 0").build()).unwrap();
+        assert!(states.iter().all(|state| !state.timed_out()));
+    }
+
+    #[test]
+    fn test_total_duration() {
+        // 'echo hello | sleep 0.2 &'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("echo").add_arg("echo").add_arg("hello"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("0.2"))
+            .set_background(true)
+            .build();
+        let mut states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(total_duration(&states), None);
+
+        update_process_states(&mut states, false);
+        assert!(total_duration(&states).unwrap() >= Duration::from_millis(200));
+        assert_eq!(total_duration(&[]), None);
+    }
+
+    #[test]
+    fn test_new_mount_namespace() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_mntns_{}.txt", std::process::id()));
+        // 'readlink /proc/self/ns/mnt > out_file'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("readlink")
+                    .add_arg("readlink")
+                    .add_arg("/proc/self/ns/mnt")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+        let opts = ExecOptions::new().set_new_mount_namespace(true);
+        let host_ns = std::fs::read_link("/proc/self/ns/mnt").unwrap();
+
+        // without CAP_SYS_ADMIN the failure of unshare() is reported
+        match execute_piped_cmd_chain_with_options(&cmd_chain, &opts) {
+            Ok(_) => {
+                let child_ns = std::fs::read_to_string(&out_file).unwrap();
+                assert_ne!(child_ns.trim(), host_ns.to_str().unwrap());
+            }
+            Err(e) => assert!(matches!(e, PiperError::UnshareFailed(libc::EPERM)), "{:?}", e),
+        }
+
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        let child_ns = std::fs::read_to_string(&out_file).unwrap();
+        assert_eq!(child_ns.trim(), host_ns.to_str().unwrap());
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[test]
+    fn test_last_stdout_target_file() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_target_{}.txt", std::process::id()));
+
+        // 'echo hello | cat > out_file'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("hello")
+            ).add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].stdout_target(), StdoutTarget::Pipe);
+        assert_eq!(last_stdout_target(&states), Some(StdoutTarget::File));
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[test]
+    fn test_spawn_errors() {
+        // 'does-not-exist | cat'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("does-not-exist").add_arg("does-not-exist"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat"))
+            .build();
+        let err = execute_piped_cmd_chain(&cmd_chain).unwrap_err();
+        assert_eq!(err, PiperError::ExecFailed { executable: "does-not-exist".to_owned(), errno: libc::ENOENT });
+
+        // the error is also reported if all other FDs get closed
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("does-not-exist").add_arg("does-not-exist"))
+            .set_close_fds(true)
+            .build();
+        let err = execute_piped_cmd_chain(&cmd_chain).unwrap_err();
+        assert_eq!(err, PiperError::ExecFailed { executable: "does-not-exist".to_owned(), errno: libc::ENOENT });
+
+        // 'cat < does-not-exist.txt'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+                    .set_input_redirect_path("does-not-exist.txt")
+            ).build();
+        let err = execute_piped_cmd_chain(&cmd_chain).unwrap_err();
+        assert_eq!(err, PiperError::RedirectOpenFailed { path: "does-not-exist.txt".to_owned(), errno: libc::ENOENT });
+    }
+
+    #[test]
+    fn test_stderr_redirect() {
+        let dir = std::env::temp_dir();
+        let out_file = dir.join(format!("unix_exec_piper_stderr_out_{}.txt", std::process::id()));
+        let err_file = dir.join(format!("unix_exec_piper_stderr_err_{}.txt", std::process::id()));
+
+        // "sh -c 'echo out; echo err >&2' 2> err_file | cat > out_file"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo out; echo err >&2")
+                    .set_stderr_redirect_path(err_file.to_str().unwrap())
+            ).add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "out\n");
+        assert_eq!(std::fs::read_to_string(&err_file).unwrap(), "err\n");
+
+        // both redirects on the same command
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo out2; echo err2 >&2")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+                    .set_stderr_redirect_path(err_file.to_str().unwrap())
+            ).build();
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "out2\n");
+        assert_eq!(std::fs::read_to_string(&err_file).unwrap(), "err2\n");
+
+        std::fs::remove_file(&out_file).unwrap();
+        std::fs::remove_file(&err_file).unwrap();
+    }
+
+    #[test]
+    fn test_merge_stderr_into_stdout() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_merge_{}.txt", std::process::id()));
+        let sh = |script: &str| {
+            BasicCmdBuilder::new()
+                .set_executable("sh")
+                .add_arg("sh")
+                .add_arg("-c")
+                .add_arg(script)
+                .merge_stderr_into_stdout(true)
+        };
+
+        // "sh -c 'echo out; echo err >&2' 2>&1 | cat > out_file"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(sh("echo out; echo err >&2"))
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+        assert_eq!(cmd_chain.cmds()[0].to_string(), "sh -c 'echo out; echo err >&2' 2>&1");
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "out\nerr\n");
+
+        // last command without a pipe merges into its output redirect
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(sh("echo out2; echo err2 >&2").set_output_redirect_path(out_file.to_str().unwrap()))
+            .build();
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "out2\nerr2\n");
+
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[test]
+    fn test_fd_dup() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_fd_dup_{}.txt", std::process::id()));
+        let err_file = std::env::temp_dir().join(format!("unix_exec_piper_fd_dup_err_{}.txt", std::process::id()));
+
+        // "sh -c 'echo err >&2' 2>&1 | cat > out_file"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo err >&2")
+                    .add_fd_dup(2, 1)
+            ).add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("cat")
+                    .add_arg("cat")
+                    .set_output_redirect_path(out_file.to_str().unwrap())
+            ).build();
+        assert_eq!(cmd_chain.cmds()[0].fd_dups(), &vec![FdDup::new(2, 1)]);
+        assert_eq!(cmd_chain.cmds()[0].to_string(), "sh -c 'echo err >&2' 2>&1");
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "err\n");
+
+        // "echo out 2> err_file 1>&2"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("out")
+                    .set_stderr_redirect_path(err_file.to_str().unwrap())
+                    .add_fd_dup(1, 2)
+            ).build();
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(std::fs::read_to_string(&err_file).unwrap(), "out\n");
+
+        // an FD that isn't open
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("true").add_arg("true").add_fd_dup(1, 1000))
+            .build();
+        assert_eq!(execute_piped_cmd_chain(&cmd_chain).unwrap_err(), PiperError::DupFailed(libc::EBADF));
+
+        std::fs::remove_file(&out_file).unwrap();
+        std::fs::remove_file(&err_file).unwrap();
+    }
+
+    #[test]
+    fn test_drop_privileges() {
+        // 'id -u -g -G'
+        let id = |arg: &str| {
+            BasicCmdBuilder::new()
+                .set_executable("id")
+                .add_arg("id")
+                .add_arg(arg)
+        };
+        let run = |cmd: BasicCmdBuilder| {
+            let cmd_chain = CmdChainBuilder::new().add_cmd(cmd).build();
+            crate::execute_piped_cmd_chain_output(&cmd_chain)
+                .map(|output| String::from_utf8_lossy(output.stdout()).trim().to_owned())
+        };
+
+        if unsafe { libc::getuid() } == 0 {
+            let nobody = |cmd: BasicCmdBuilder| cmd.set_supplementary_groups(&[65534]).set_gid(65534).set_uid(65534);
+            assert_eq!(run(nobody(id("-u"))).unwrap(), "65534");
+            assert_eq!(run(nobody(id("-g"))).unwrap(), "65534");
+            assert_eq!(run(nobody(id("-G"))).unwrap(), "65534");
+            // the parent keeps its privileges
+            assert_eq!(unsafe { libc::getuid() }, 0);
+        } else {
+            // switching to the own user is always allowed
+            let uid = unsafe { libc::getuid() };
+            assert_eq!(run(id("-u").set_uid(uid)).unwrap(), uid.to_string());
+            assert_eq!(run(id("-u").set_uid(0)).unwrap_err(), PiperError::CredentialsFailed(libc::EPERM));
+        }
+    }
+
+    #[test]
+    fn test_spawn_failures_as_exit_codes() {
+        let not_executable = std::env::temp_dir().join(format!("unix_exec_piper_no_x_{}", std::process::id()));
+        std::fs::write(&not_executable, "").unwrap();
+
+        // 'does-not-exist | not_executable | echo hi'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("does-not-exist").add_arg("does-not-exist"))
+            .add_cmd(BasicCmdBuilder::new().set_executable(not_executable.to_str().unwrap()).add_arg("x"))
+            .add_cmd(BasicCmdBuilder::new().set_executable("echo").add_arg("echo").add_arg("hi"))
+            .build();
+        assert!(execute_piped_cmd_chain(&cmd_chain).is_err());
+
+        let opts = ExecOptions::new().set_spawn_failures_as_exit_codes(true);
+        let states = execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+        assert_eq!(states[0].exit_code(), 127);
+        assert!(matches!(states[0].spawn_error(), Some(PiperError::ExecFailed { errno: libc::ENOENT, .. })));
+        assert_eq!(states[1].exit_code(), 126);
+        assert!(matches!(states[1].spawn_error(), Some(PiperError::ExecFailed { errno: libc::EACCES, .. })));
+        assert_eq!(states[2].exit_code(), 0);
+        assert_eq!(states[2].spawn_error(), None);
+
+        // 'cat < does-not-exist.txt'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("cat").add_arg("cat").set_input_redirect_path("does-not-exist.txt"))
+            .build();
+        let states = execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+        assert_eq!(states[0].exit_code(), 1);
+        assert!(matches!(states[0].spawn_error(), Some(PiperError::RedirectOpenFailed { .. })));
+
+        std::fs::remove_file(&not_executable).unwrap();
+    }
+
+    #[test]
+    fn test_working_dir() {
+        let work_dir = std::env::temp_dir().canonicalize().unwrap();
+        let cwd_before = std::env::current_dir().unwrap();
+
+        // 'pwd' in the temp dir
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("pwd")
+                    .add_arg("pwd")
+                    .set_working_dir(work_dir.to_str().unwrap())
+            ).build();
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(String::from_utf8_lossy(output.stdout()), format!("{}\n", work_dir.display()));
+        assert_eq!(std::env::current_dir().unwrap(), cwd_before);
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("pwd")
+                    .add_arg("pwd")
+                    .set_working_dir("/does-not-exist")
+            ).build();
+        let err = execute_piped_cmd_chain(&cmd_chain).unwrap_err();
+        assert_eq!(err, PiperError::ChdirFailed { path: "/does-not-exist".to_owned(), errno: libc::ENOENT });
+    }
+
+    #[test]
+    fn test_fallback_executable() {
+        // 'does-not-exist hello' with 'echo' as fallback
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("does-not-exist")
+                    .add_arg("does-not-exist")
+                    .add_arg("hello")
+                    .add_fallback("also-does-not-exist")
+                    .add_fallback("echo")
+            ).build();
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(output.stdout(), b"hello\n");
+    }
+
+    #[test]
+    fn test_stdin_contents() {
+        // 'grep foo' with a heredoc
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("grep")
+                    .add_arg("grep")
+                    .add_arg("foo")
+                    .set_stdin_contents("foo 1\nbar\nfoo 2\n")
+            ).build();
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(output.stdout(), b"foo 1\nfoo 2\n");
+
+        // much bigger than the pipe buffer: 'wc -c'
+        let contents = vec![b'x'; 1024 * 1024];
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("wc")
+                    .add_arg("wc")
+                    .add_arg("-c")
+                    .set_stdin_contents(&contents)
+            ).build();
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(String::from_utf8_lossy(output.stdout()).trim(), contents.len().to_string());
+    }
+
+    #[test]
+    fn test_non_utf8_arg_and_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut name = b"unix_exec_piper_non_utf8_\xff_".to_vec();
+        name.extend_from_slice(std::process::id().to_string().as_bytes());
+        let out_file = std::env::temp_dir().join(OsStr::from_bytes(&name));
+
+        // "echo $'\xfe' > out_file"
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg(OsStr::from_bytes(b"\xfe"))
+                    .set_output_redirect_path(&out_file)
+            ).build();
+        assert_eq!(cmd_chain.cmds()[0].args()[1].as_bytes(), b"\xfe");
+        assert!(cmd_chain.to_string().starts_with("echo \u{FFFD} > "));
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(std::fs::read(&out_file).unwrap(), b"\xfe\n");
+
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[test]
+    fn test_non_ascii_args() {
+        // 'echo äöü 日本' with an own environment variable
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sh")
+                    .add_arg("sh")
+                    .add_arg("-c")
+                    .add_arg("echo \"$@\" $GREETING")
+                    .add_arg("sh")
+                    .add_arg("äöü")
+                    .add_arg("日本")
+                    .add_env("GREETING", "grüß")
+            ).build();
+        let output = execute_piped_cmd_chain_output(&cmd_chain).unwrap();
+        assert_eq!(String::from_utf8_lossy(output.stdout()), "äöü 日本 grüß\n");
+    }
+
+    #[test]
+    fn test_redirect_fds_dont_leak() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_no_leak_{}.txt", std::process::id()));
+
+        // 'ls /proc/self/fd < /dev/null > out_file': only STDIN, STDOUT,
+        // STDERR and the FD of the directory that ls reads
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("ls")
+                    .add_arg("ls")
+                    .add_arg("/proc/self/fd")
+                    .set_input_redirect_path("/dev/null")
+                    .set_output_redirect_path(&out_file)
+            ).build();
+        execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "0\n1\n2\n3\n");
+
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pidfds() {
+        let cmd_chain = crate::parse_cmd_chain("sleep 5 &").unwrap();
+        let mut states = execute_piped_cmd_chain_with_options(&cmd_chain, &ExecOptions::new().set_pidfds(true)).unwrap();
+        let pidfd = match states[0].pidfd() {
+            Some(pidfd) => pidfd,
+            // kernel before 5.3
+            None => return,
+        };
+        let readable = |timeout: libc::c_int| {
+            let mut poll_fd = libc::pollfd { fd: pidfd, events: libc::POLLIN, revents: 0 };
+            unsafe { libc::poll(&mut poll_fd, 1, timeout) == 1 }
+        };
+        assert!(!readable(0));
+        // via pidfd_send_signal()
+        states[0].kill().unwrap();
+        assert!(readable(5000));
+        update_process_states(&mut states, false);
+        assert_eq!(states[0].term_signal(), Some(libc::SIGKILL));
+        assert_eq!(states[0].pidfd(), Some(pidfd));
+
+        let states = execute_piped_cmd_chain(&crate::parse_cmd_chain("true").unwrap()).unwrap();
+        assert_eq!(states[0].pidfd(), None);
+    }
+
+    #[test]
+    fn test_subshell() {
+        // the inner commands read the pipe from 'printf' and write into the one to 'sort'
+        let cmd_chain = crate::parse_cmd_chain("printf 'b\\na\\n' | (cat; echo c; false) | sort -r").unwrap();
+        assert!(cmd_chain.cmds()[1].subshell().is_some());
+        let (states, stdout) = crate::execute_piped_cmd_chain_capture_string(&cmd_chain).unwrap();
+        assert_eq!(stdout, "c\nb\na\n");
+        assert_eq!(states[1].exit_code(), 1);
+        assert_eq!(states[1].executable(), "(cat; echo c; false)");
+        assert!(states[1].in_parent());
+        assert!(!states[0].in_parent());
+
+        // the list runs in the parent, hence every backend supports it
+        let opts = ExecOptions::new().set_spawn_backend(crate::PosixSpawnBackend);
+        let (mut states, mut stdout) = crate::capture::spawn_captured(&cmd_chain, &opts).unwrap();
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).unwrap();
+        update_process_states(&mut states, false);
+        assert_eq!(output, "c\nb\na\n");
+        assert_eq!(states[1].exit_code(), 1);
+
+        // signals to the subshell reach the processes of the list
+        let cmd_chain = crate::parse_cmd_chain("(sleep 10; true) | cat").unwrap();
+        let mut handle = crate::spawn_piped_cmd_chain_handle(&cmd_chain, &ExecOptions::new()).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        handle.kill().unwrap();
+        let states = handle.wait().unwrap();
+        assert_eq!(states[0].exit_code(), 128 + libc::SIGKILL);
+    }
+
+    #[test]
+    fn test_function_stage() {
+        // 'printf "a\nb\n" | <uppercase> | sort -r', executed twice
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("printf").add_arg("printf").add_arg("a\nb\n"))
+            .add_cmd(BasicCmdBuilder::new().set_function(|stdin, stdout| {
+                let mut input = String::new();
+                stdin.read_to_string(&mut input).unwrap();
+                stdout.write_all(input.to_uppercase().as_bytes()).unwrap();
+                7
+            }))
+            .add_cmd(BasicCmdBuilder::new().set_executable("sort").add_arg("sort").add_arg("-r"))
+            .build();
+        assert!(cmd_chain.cmds()[1].is_function());
+        assert_eq!(cmd_chain.to_string(), "printf 'a\nb\n' | '<function>' | sort -r");
+        for _ in 0..2 {
+            let (states, stdout) = crate::execute_piped_cmd_chain_capture_string(&cmd_chain).unwrap();
+            assert_eq!(stdout, "B\nA\n");
+            assert_eq!(states[1].exit_code(), 7);
+        }
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_function(|_, _| panic!("in the thread")))
+            .build();
+        let states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(states[0].exit_code(), 101);
     }
 }
@@ -22,59 +22,435 @@
     SOFTWARE.
 */
 
-/// Utility functions on top of libc.
-/// I've chosen to use `*mut libc::c_char"` rather than `std::ffi::CStr`
-/// because of educational purposes, to gain more experience, and just
-/// for fun.
+//! Utility functions on top of libc.
+//! I've chosen to use `*mut libc::c_char"` rather than `std::ffi::CStr`
+//! because of educational purposes, to gain more experience, and just
+//! for fun.
+
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+
+/// Strategy that allocates the memory for the C strings and the arrays of
+/// C strings constructed by this module. The default is `MallocAllocator`.
+/// Advanced users can provide their own, e.g. an arena allocator for
+/// the construction of argv.
+///
+/// # Safety
+/// The functions of this module write to the returned memory. An
+/// implementation must return either null or a pointer to at least the
+/// requested number of writable bytes, suitably aligned for pointers,
+/// that stays valid as long as the constructed strings are used.
+/// `alloc_zeroed()` must return memory that is initialized with zero.
+pub unsafe trait CStringAllocator {
+    /// Allocates `size` bytes, like `malloc()`. Returns null on failure.
+    fn alloc(&self, size: usize) -> *mut libc::c_void;
+    /// Allocates `count * size` bytes initialized with zero, like `calloc()`.
+    /// Returns null on failure.
+    fn alloc_zeroed(&self, count: usize, size: usize) -> *mut libc::c_void;
+}
+
+/// Default `CStringAllocator` that uses `malloc()` and `calloc()` from libc.
+/// Memory can be freed with `libc::free()`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct MallocAllocator;
+
+unsafe impl CStringAllocator for MallocAllocator {
+    fn alloc(&self, size: usize) -> *mut libc::c_void {
+        unsafe { libc::malloc(size) }
+    }
+
+    fn alloc_zeroed(&self, count: usize, size: usize) -> *mut libc::c_void {
+        unsafe { libc::calloc(count, size) }
+    }
+}
+
+/// Test-only: how many C strings and arrays of C strings were allocated and
+/// freed by the functions of this module, so that tests can verify the
+/// memory management contract. Counted per thread, because the tests run
+/// in parallel.
+#[cfg(test)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub(crate) struct AllocCounters {
+    pub(crate) cstrings_allocated: usize,
+    pub(crate) arrays_allocated: usize,
+    pub(crate) cstrings_freed: usize,
+    pub(crate) arrays_freed: usize,
+}
+
+#[cfg(test)]
+thread_local! {
+    static ALLOC_COUNTERS: std::cell::RefCell<AllocCounters> = std::cell::RefCell::new(AllocCounters::default());
+}
+
+/// Test-only: the counters of the current thread.
+#[cfg(test)]
+pub(crate) fn alloc_counters() -> AllocCounters {
+    ALLOC_COUNTERS.with(|c| *c.borrow())
+}
 
-/// Constructs an array of C strings aka. array of `*mut libc::c_char"` on
-/// the heap. Allocates memory. Memory must be freed manually somewhere in
-/// order to have proper memory management.
+/// Constructs an array of C strings aka. array of `*mut libc::c_char"` with
+/// memory from `allocator`. Memory must be freed manually somewhere in
+/// order to have proper memory management. `None` if the allocation fails.
 /// I've chosen to use `*mut libc::c_char"` rather than `std::ffi::CStr`
 /// because of educational purposes, to gain more experience, and just
 /// for fun.
-pub fn construct_libc_cstring_arr(elements_count: usize, null_terminated: bool) -> *mut *mut libc::c_char {
-    let elements = if null_terminated { elements_count + 1 } else { elements_count };
+pub fn construct_libc_cstring_arr_with(allocator: &dyn CStringAllocator,
+                                       elements_count: usize,
+                                       null_terminated: bool) -> Option<*mut *mut libc::c_char> {
+    let elements = if null_terminated { elements_count.checked_add(1)? } else { elements_count };
     let ptr_size = get_c_ptr_size();
-    let argv: *mut *mut libc::c_char;
-    unsafe {
-        // allocate memory for array of pointers
-        // we use calloc for null terminated array
-        argv = std::mem::transmute(
-            libc::calloc(ptr_size, elements)
-        );
+    // allocate memory for array of pointers
+    // we use calloc for null terminated array
+    let arr = allocator.alloc_zeroed(ptr_size, elements) as *mut *mut libc::c_char;
+    if arr.is_null() {
+        return None;
     }
-    argv
+    #[cfg(test)]
+    ALLOC_COUNTERS.with(|c| c.borrow_mut().arrays_allocated += 1);
+    Some(arr)
 }
 
-/// Allocates memory on the heap and constructs a null-terminated C string
-/// from a Rust string.
-/// I've chosen to use `*mut libc::c_char"` rather than `std::ffi::CStr`
-/// because of educational purposes, to gain more experience, and just
-/// for fun.
-pub fn construct_libc_cstring(string: &str) -> *mut libc::c_char {
-    let char_size = 1; // 1 byte
-    let c_string: *mut libc::c_char;
+/// Allocates memory with `allocator` and constructs a null-terminated C
+/// string from a Rust string. `None` if the allocation fails.
+pub fn construct_libc_cstring_with(allocator: &dyn CStringAllocator, string: &str) -> Option<*mut libc::c_char> {
+    // copy the raw UTF-8 bytes; a char can consist of multiple bytes
+    construct_libc_cstring_from_bytes_with(allocator, string.as_bytes())
+}
+
+/// Like `construct_libc_cstring_with()` but from raw bytes, e.g. of an
+/// `OsStr` that isn't valid UTF-8. The bytes must not contain a null byte.
+pub fn construct_libc_cstring_from_bytes_with(allocator: &dyn CStringAllocator, bytes: &[u8]) -> Option<*mut libc::c_char> {
+    // + 1: null terminated
+    let c_string = allocator.alloc(bytes.len().checked_add(1)?) as *mut libc::c_char;
+    if c_string.is_null() {
+        return None;
+    }
+    #[cfg(test)]
+    ALLOC_COUNTERS.with(|c| c.borrow_mut().cstrings_allocated += 1);
+
     unsafe {
-        c_string = std::mem::transmute(
-            // + 1: null terminated
-            libc::malloc( char_size * (string.len() + 1))
-        );
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), c_string as *mut u8, bytes.len());
+        // null terminated
+        *c_string.add(bytes.len()) = 0;
     }
 
-    let chars = string.chars().collect::<Vec<char>>();
-    for i in 0..chars.len() {
+    Some(c_string)
+}
+
+/// Constructs the null-terminated array of C strings, for example argv,
+/// from Rust strings (`String`, `&str` or `OsString` with arbitrary bytes).
+/// Memory comes from `allocator`. Like with the other functions, memory
+/// must be freed manually. `None` if an allocation fails; the memory that
+/// was already allocated is leaked then, because `CStringAllocator` can't
+/// free.
+pub fn construct_libc_argv_with<S: AsRef<OsStr>>(allocator: &dyn CStringAllocator, strings: &[S]) -> Option<*const *const libc::c_char> {
+    let argv: *mut *mut libc::c_char = construct_libc_cstring_arr_with(allocator, strings.len(), true)?;
+
+    for (i, string) in strings.iter().enumerate() {
+        let bytes = string.as_ref().as_bytes();
+        let c_string: *mut libc::c_char = construct_libc_cstring_from_bytes_with(allocator, bytes)?;
         unsafe {
-            *c_string.offset(i as isize) = chars[i] as libc::c_char;
+            *argv.add(i) = c_string;
+        }
+    }
+
+    Some(argv as *const *const libc::c_char)
+}
+
+/// Frees an array of C strings that was constructed with `MallocAllocator`,
+/// e.g. by `construct_libc_argv_with()` or `BasicCmd::args_to_c_argv()`:
+/// every element and then the array itself. Stops at the null terminator or
+/// after `len` elements, whatever comes first; it never reads past the
+/// terminator. Does nothing for a null pointer.
+///
+/// # Safety
+/// `argv` must be null or a null-terminated array allocated with `malloc()`
+/// whose elements were allocated with `malloc()`. It must not be used afterwards.
+pub unsafe fn free_c_argv(argv: *const *const libc::c_char, len: usize) {
+    if argv.is_null() {
+        return;
+    }
+    for i in 0..len {
+        let element = *argv.add(i);
+        if element.is_null() {
+            break;
         }
+        libc::free(element as *mut libc::c_void);
+        #[cfg(test)]
+        ALLOC_COUNTERS.with(|c| c.borrow_mut().cstrings_freed += 1);
     }
+    libc::free(argv as *mut libc::c_void);
+    #[cfg(test)]
+    ALLOC_COUNTERS.with(|c| c.borrow_mut().arrays_freed += 1);
+}
 
-    // null terminated
+/// Frees an envp array that was constructed by `construct_libc_envp_with()`
+/// with `MallocAllocator`. Only the last `overrides_len` entries were
+/// allocated; the others belong to the environment of the process and are
+/// not freed. Does nothing for a null pointer.
+///
+/// # Safety
+/// `envp` must be null or constructed like described above with the same
+/// `overrides_len`. It must not be used afterwards.
+pub unsafe fn free_c_envp(envp: *const *const libc::c_char, overrides_len: usize) {
+    if envp.is_null() {
+        return;
+    }
+    let mut len = 0;
+    while !(*envp.add(len)).is_null() {
+        len += 1;
+    }
+    for i in len.saturating_sub(overrides_len)..len {
+        libc::free(*envp.add(i) as *mut libc::c_void);
+        #[cfg(test)]
+        ALLOC_COUNTERS.with(|c| c.borrow_mut().cstrings_freed += 1);
+    }
+    libc::free(envp as *mut libc::c_void);
+    #[cfg(test)]
+    ALLOC_COUNTERS.with(|c| c.borrow_mut().arrays_freed += 1);
+}
+
+extern "C" {
+    /// The environment of the current process, see `man 7 environ`.
+    static environ: *const *const libc::c_char;
+}
+
+/// Constructs the null-terminated envp array for `execve()`: the
+/// environment of the current process (if `inherit` is set) where the
+/// entries of `overrides` replace existing variables with the same name
+/// or are added. The kept
+/// entries point to the strings of the current environment, only the
+/// new ones are allocated with `allocator`. Like with the other
+/// functions, memory must be freed manually. `None` if an allocation
+/// fails, like `construct_libc_argv_with()`.
+pub fn construct_libc_envp_with(allocator: &dyn CStringAllocator,
+                                inherit: bool,
+                                overrides: &[(String, String)]) -> Option<*const *const libc::c_char> {
+    let mut kept: Vec<*const libc::c_char> = vec![];
     unsafe {
-        *c_string.offset(string.len() as isize) = 0;
+        let mut entry = if inherit { environ } else { std::ptr::null() };
+        while !entry.is_null() && !(*entry).is_null() {
+            let bytes = std::ffi::CStr::from_ptr(*entry).to_bytes();
+            let key = bytes.split(|b| *b == b'=').next().unwrap_or(bytes);
+            if !overrides.iter().any(|(k, _)| k.as_bytes() == key) {
+                kept.push(*entry);
+            }
+            entry = entry.add(1);
+        }
+    }
+
+    let envp: *mut *mut libc::c_char = construct_libc_cstring_arr_with(allocator, kept.len() + overrides.len(), true)?;
+    for (i, entry) in kept.iter().enumerate() {
+        unsafe {
+            *envp.add(i) = *entry as *mut libc::c_char;
+        }
+    }
+    for (i, (key, value)) in overrides.iter().enumerate() {
+        let c_string = construct_libc_cstring_with(allocator, &format!("{}={}", key, value))?;
+        unsafe {
+            *envp.add(kept.len() + i) = c_string;
+        }
     }
 
-    c_string
+    Some(envp as *const *const libc::c_char)
+}
+
+/// A null-terminated array of C strings, e.g. argv or envp, that owns its
+/// strings and frees them when dropped. Unlike the arrays of the functions
+/// above it's built from `CString`s and can be prepared before `fork()`,
+/// hence the child doesn't have to allocate memory before `exec()`.
+#[derive(Debug)]
+pub struct CStringArray {
+    /// The strings; moving a `CString` doesn't move its bytes, hence
+    /// the pointers stay valid.
+    strings: Vec<CString>,
+    /// Pointers to `strings` plus the null terminator.
+    ptrs: Vec<*const libc::c_char>,
+}
+
+impl CStringArray {
+    /// Constructs the array from the raw bytes of `strings`, e.g. argv.
+    /// Panics if a string contains a null byte.
+    pub fn new<S: AsRef<OsStr>>(strings: &[S]) -> Self {
+        Self::from_cstrings(strings.iter()
+            .map(|string| CString::new(string.as_ref().as_bytes()).unwrap())
+            .collect())
+    }
+
+    /// Constructs the array from already converted strings.
+    pub fn from_cstrings(strings: Vec<CString>) -> Self {
+        let ptrs = strings.iter()
+            .map(|string| string.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect();
+        Self { strings, ptrs }
+    }
+
+    /// Constructs envp for `execve()` like `construct_libc_envp_with()`:
+    /// the environment of the current process (if `inherit` is set) where
+    /// the entries of `overrides` replace existing variables with the same
+    /// name or are added.
+    pub fn envp(inherit: bool, overrides: &[(String, String)]) -> Self {
+        let inherited = std::env::vars_os()
+            .filter(|_| inherit)
+            .filter(|(key, _)| !overrides.iter().any(|(k, _)| OsStr::new(k) == key))
+            .map(|(key, value)| [key.as_bytes(), b"=", value.as_bytes()].concat());
+        let overridden = overrides.iter().map(|(key, value)| format!("{}={}", key, value).into_bytes());
+        Self::from_cstrings(inherited.chain(overridden).map(|entry| CString::new(entry).unwrap()).collect())
+    }
+
+    /// Getter for strings.
+    pub fn strings(&self) -> &Vec<CString> {
+        &self.strings
+    }
+
+    /// The null-terminated array; valid as long as self lives.
+    pub fn as_ptr(&self) -> *const *const libc::c_char {
+        self.ptrs.as_ptr()
+    }
+
+    /// The number of strings, without the null terminator.
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Whether there are no strings.
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+/// The ways `close_fds_from()` can close file descriptors,
+/// from the fastest to the most portable one.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CloseFdsMethod {
+    /// The `close_range()` syscall (Linux >= 5.9).
+    CloseRange,
+    /// Closing every FD listed in `/proc/self/fd`.
+    ProcSelfFd,
+    /// Calling `close()` on every FD up to the `RLIMIT_NOFILE` limit.
+    RlimitLoop,
+}
+
+/// Closes all file descriptors `>= low_fd`, like `closefrom()` on the BSDs.
+/// Uses the first method that works and returns it.
+/// Useful in a child right before exec() so that the executed program
+/// doesn't inherit FDs it doesn't know about; no method allocates.
+pub fn close_fds_from(low_fd: libc::c_int) -> CloseFdsMethod {
+    [CloseFdsMethod::CloseRange, CloseFdsMethod::ProcSelfFd]
+        .iter()
+        .copied()
+        .find(|method| close_fds_from_with(low_fd, *method))
+        .unwrap_or_else(|| {
+            close_fds_from_with(low_fd, CloseFdsMethod::RlimitLoop);
+            CloseFdsMethod::RlimitLoop
+        })
+}
+
+/// Closes all file descriptors `>= low_fd` with the given method.
+/// Returns false if the method isn't available on this system.
+pub fn close_fds_from_with(low_fd: libc::c_int, method: CloseFdsMethod) -> bool {
+    match method {
+        CloseFdsMethod::CloseRange => close_range(low_fd),
+        CloseFdsMethod::ProcSelfFd => close_listed_fds(low_fd, false),
+        CloseFdsMethod::RlimitLoop => {
+            for fd in low_fd..fd_limit() {
+                unsafe { libc::close(fd) };
+            }
+            true
+        }
+    }
+}
+
+/// Closes all FDs above STDERR that have the close-on-exec flag, like
+/// `exec()` does, e.g. in a child that runs a builtin instead of
+/// executing a program. Doesn't allocate.
+pub(crate) fn close_cloexec_fds() {
+    if !close_listed_fds(libc::STDERR_FILENO + 1, true) {
+        for fd in libc::STDERR_FILENO + 1..fd_limit() {
+            if is_cloexec(fd) {
+                unsafe { libc::close(fd) };
+            }
+        }
+    }
+}
+
+/// Whether the FD is open and has the close-on-exec flag.
+fn is_cloexec(fd: libc::c_int) -> bool {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    flags != -1 && flags & libc::FD_CLOEXEC != 0
+}
+
+/// The `RLIMIT_NOFILE` limit, i.e. the FDs are below it.
+fn fd_limit() -> libc::c_int {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    let res = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    // fallback if the limit is unknown or unlimited
+    if res == -1 || limit.rlim_cur == libc::RLIM_INFINITY {
+        1024
+    } else {
+        limit.rlim_cur.min(libc::c_int::MAX as libc::rlim_t) as libc::c_int
+    }
+}
+
+/// `close_range(low_fd, ~0, 0)`. Returns false if the kernel doesn't support it.
+#[cfg(target_os = "linux")]
+fn close_range(low_fd: libc::c_int) -> bool {
+    let res = unsafe { libc::syscall(libc::SYS_close_range, low_fd as libc::c_uint, libc::c_uint::MAX, 0 as libc::c_uint) };
+    res == 0
+}
+
+/// Closes every FD `>= low_fd` that is listed in `/proc/self/fd`, with
+/// `only_cloexec` only those with the close-on-exec flag. Reads the
+/// directory with `getdents64()` into a buffer on the stack, i.e. without
+/// allocating. Returns false if `/proc` isn't mounted.
+#[cfg(target_os = "linux")]
+fn close_listed_fds(low_fd: libc::c_int, only_cloexec: bool) -> bool {
+    let dir = unsafe { libc::open(b"/proc/self/fd\0".as_ptr() as *const libc::c_char, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+    if dir == -1 {
+        return false;
+    }
+    // struct linux_dirent64: d_ino (8 bytes), d_off (8), d_reclen (2),
+    // d_type (1), then the null-terminated d_name
+    const NAME_OFFSET: usize = 19;
+    let mut buf = [0_u8; 1024];
+    loop {
+        let n = unsafe { libc::syscall(libc::SYS_getdents64, dir, buf.as_mut_ptr(), buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        let mut offset = 0;
+        while offset < n as usize {
+            let reclen = u16::from_ne_bytes([buf[offset + 16], buf[offset + 17]]) as usize;
+            // "." and ".." aren't numbers; closing while reading is fine
+            let mut name = buf[offset + NAME_OFFSET..offset + reclen].iter().take_while(|b| **b != 0);
+            let fd = name.try_fold(0 as libc::c_int, |fd, b| match b {
+                b'0'..=b'9' => fd.checked_mul(10)?.checked_add((b - b'0') as libc::c_int),
+                _ => None,
+            });
+            match fd {
+                Some(fd) if fd >= low_fd && fd != dir && (!only_cloexec || is_cloexec(fd)) => {
+                    unsafe { libc::close(fd) };
+                }
+                _ => {}
+            }
+            offset += reclen;
+        }
+    }
+    unsafe { libc::close(dir) };
+    true
+}
+
+/// `/proc/self/fd` is Linux specific.
+#[cfg(not(target_os = "linux"))]
+fn close_listed_fds(_low_fd: libc::c_int, _only_cloexec: bool) -> bool {
+    false
+}
+
+/// `close_range()` only exists on Linux.
+#[cfg(not(target_os = "linux"))]
+fn close_range(_low_fd: libc::c_int) -> bool {
+    false
 }
 
 // we don't have "sizeof()" in Rust like we have it in C/C++.
@@ -87,10 +463,9 @@ pub fn construct_libc_cstring(string: &str) -> *mut libc::c_char {
 /// ideal.
 fn get_c_ptr_size() -> usize {
     let example_char: libc::c_char = 5;
-    let example_char_ptr = &example_char;
+    let example_char_ptr: *const libc::c_char = &example_char;
     // TODO make this 'const fn' when size_of_val() supports it
-    let c_ptr_size = std::mem::size_of_val(&example_char_ptr);
-    c_ptr_size
+    std::mem::size_of_val(&example_char_ptr)
 }
 
 #[cfg(test)]
@@ -103,30 +478,39 @@ mod tests {
         let input = String::from("Hello World!");
 
         // Check against std::ffi::&CStr
-        let c_str: &CStr = unsafe { CStr::from_ptr(construct_libc_cstring(&input)) };
+        let c_str: &CStr = unsafe { CStr::from_ptr(construct_libc_cstring_with(&MallocAllocator, &input).unwrap()) };
         println!("expected: '{}'", input);
         println!("actual:   '{}'", c_str.to_str().unwrap().to_owned());
-        assert_eq!(unsafe {libc::strlen(c_str.as_ptr())}, input.len());
+        assert_eq!(c_str.to_bytes().len(), input.len());
+    }
+
+    #[test]
+    fn test_construct_libc_cstring_multibyte() {
+        let input = "grep café 漢字";
+
+        let c_string = construct_libc_cstring_with(&MallocAllocator, input).unwrap();
+        let c_str: &CStr = unsafe { CStr::from_ptr(c_string) };
+        assert_eq!(c_str.to_bytes(), input.as_bytes());
+        assert_eq!(c_str.to_str().unwrap(), input);
+        unsafe { libc::free(c_string as *mut libc::c_void) };
     }
 
     #[test]
     fn test_construct_libc_cstring_arr() {
         let elem_count = 2;
         let is_null_terminated = true;
-        let arr = construct_libc_cstring_arr(2, is_null_terminated);
+        let arr = construct_libc_cstring_arr_with(&MallocAllocator, 2, is_null_terminated).unwrap();
         unsafe {
-            *arr.offset(0) = construct_libc_cstring("First");
-            *arr.offset(1) = construct_libc_cstring("Second");
+            *arr.offset(0) = construct_libc_cstring_with(&MallocAllocator, "First").unwrap();
+            *arr.offset(1) = construct_libc_cstring_with(&MallocAllocator, "Second").unwrap();
         }
 
-        let input = String::from("Hello World!");
-
         // Check against std::ffi::&CStr to see if we did correct work
         let c_str1: &CStr = unsafe { CStr::from_ptr(*arr.offset(0)) };
         let c_str2: &CStr = unsafe { CStr::from_ptr(*arr.offset(1)) };
-        println!("expected 1: '{}'", "First");
+        println!("expected 1: 'First'");
         println!("actual 1:   '{}'", c_str1.to_str().unwrap().to_owned());
-        println!("expected 2: '{}'", "Second");
+        println!("expected 2: 'Second'");
         println!("actual 2:   '{}'", c_str2.to_str().unwrap().to_owned());
 
 
@@ -149,5 +533,144 @@ mod tests {
         }
     }
 
+    /// Counts the allocations and forwards them to `MallocAllocator`.
+    #[derive(Default)]
+    struct CountingAllocator {
+        allocs: std::cell::Cell<usize>,
+        zeroed_allocs: std::cell::Cell<usize>,
+    }
+
+    unsafe impl CStringAllocator for CountingAllocator {
+        fn alloc(&self, size: usize) -> *mut libc::c_void {
+            self.allocs.set(self.allocs.get() + 1);
+            MallocAllocator.alloc(size)
+        }
+
+        fn alloc_zeroed(&self, count: usize, size: usize) -> *mut libc::c_void {
+            self.zeroed_allocs.set(self.zeroed_allocs.get() + 1);
+            MallocAllocator.alloc_zeroed(count, size)
+        }
+    }
+
+    #[test]
+    fn test_free_c_argv() {
+        let arr = construct_libc_cstring_arr_with(&MallocAllocator, 2, true).unwrap();
+        unsafe {
+            *arr.add(0) = construct_libc_cstring_with(&MallocAllocator, "First").unwrap();
+            *arr.add(1) = construct_libc_cstring_with(&MallocAllocator, "Second").unwrap();
+        }
+        unsafe {
+            // a len beyond the null terminator must stop at the terminator
+            free_c_argv(arr as *const *const libc::c_char, 10);
+
+            let argv = construct_libc_argv_with(&MallocAllocator, &["a".to_owned(), "b".to_owned()]).unwrap();
+            free_c_argv(argv, 2);
+            free_c_argv(std::ptr::null(), 0);
+
+            let envp = construct_libc_envp_with(&MallocAllocator, true, &[("FOO".to_owned(), "bar".to_owned())]).unwrap();
+            free_c_envp(envp, 1);
+
+            let envp = construct_libc_envp_with(&MallocAllocator, false, &[("FOO".to_owned(), "bar".to_owned())]).unwrap();
+            assert_eq!(std::ffi::CStr::from_ptr(*envp).to_str().unwrap(), "FOO=bar");
+            assert!((*envp.add(1)).is_null());
+            free_c_envp(envp, 1);
+        }
+    }
+
+    #[test]
+    fn test_cstring_array() {
+        let argv = CStringArray::new(&["grep", "-i", "äöü"]);
+        assert_eq!(argv.len(), 3);
+        unsafe {
+            assert_eq!(std::ffi::CStr::from_ptr(*argv.as_ptr().add(2)).to_str().unwrap(), "äöü");
+            assert!((*argv.as_ptr().add(3)).is_null());
+        }
+
+        let envp = CStringArray::envp(false, &[("FOO".to_owned(), "bar".to_owned())]);
+        assert_eq!(envp.len(), 1);
+        unsafe {
+            assert_eq!(std::ffi::CStr::from_ptr(*envp.as_ptr()).to_str().unwrap(), "FOO=bar");
+        }
+        assert!(!CStringArray::envp(true, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_alloc_counters_balance() {
+        let before = alloc_counters();
+        let args = vec!["grep".to_owned(), "-i".to_owned(), "abc".to_owned()];
+        let argv = construct_libc_argv_with(&MallocAllocator, &args).unwrap();
+        unsafe { free_c_argv(argv, args.len()) };
+        let after = alloc_counters();
+
+        assert_eq!(after.cstrings_allocated - before.cstrings_allocated, 3);
+        assert_eq!(after.arrays_allocated - before.arrays_allocated, 1);
+        assert_eq!(after.cstrings_freed - before.cstrings_freed, 3);
+        assert_eq!(after.arrays_freed - before.arrays_freed, 1);
+    }
 
+    #[test]
+    fn test_custom_allocator_is_used() {
+        let allocator = CountingAllocator::default();
+        let arr = construct_libc_cstring_arr_with(&allocator, 2, true).unwrap();
+        unsafe {
+            *arr.offset(0) = construct_libc_cstring_with(&allocator, "First").unwrap();
+            *arr.offset(1) = construct_libc_cstring_with(&allocator, "Second").unwrap();
+        }
+        assert_eq!(allocator.zeroed_allocs.get(), 1);
+        assert_eq!(allocator.allocs.get(), 2);
+
+        let c_str: &CStr = unsafe { CStr::from_ptr(*arr.offset(1)) };
+        assert_eq!(c_str.to_str().unwrap(), "Second");
+    }
+
+    /// Always fails, like `malloc()` when the memory is exhausted.
+    struct FailingAllocator;
+
+    unsafe impl CStringAllocator for FailingAllocator {
+        fn alloc(&self, _size: usize) -> *mut libc::c_void {
+            std::ptr::null_mut()
+        }
+
+        fn alloc_zeroed(&self, _count: usize, _size: usize) -> *mut libc::c_void {
+            std::ptr::null_mut()
+        }
+    }
+
+    #[test]
+    fn test_failing_allocator() {
+        assert!(construct_libc_cstring_with(&FailingAllocator, "abc").is_none());
+        assert!(construct_libc_cstring_arr_with(&FailingAllocator, 2, true).is_none());
+        assert!(construct_libc_cstring_arr_with(&MallocAllocator, usize::MAX, true).is_none());
+        assert!(construct_libc_argv_with(&FailingAllocator, &["a"]).is_none());
+        assert!(construct_libc_envp_with(&FailingAllocator, false, &[]).is_none());
+    }
+
+    #[test]
+    fn test_close_fds_from() {
+        for method in [CloseFdsMethod::CloseRange, CloseFdsMethod::ProcSelfFd, CloseFdsMethod::RlimitLoop].iter() {
+            let pid = unsafe { libc::fork() };
+            if pid == 0 {
+                // child: open some FDs, close everything >= the first one
+                // and check that STDIN/STDOUT/STDERR are still open
+                let mut fds = [0; 2];
+                unsafe { libc::pipe(fds.as_mut_ptr()) };
+                let low_fd = fds[0].min(fds[1]);
+                let supported = close_fds_from_with(low_fd, *method);
+                let fd_open = |fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1;
+                // 2: method not available on this system (e.g. old kernel)
+                let code = if !supported {
+                    2
+                } else if fds.iter().all(|fd| !fd_open(*fd)) && (0..3).all(fd_open) {
+                    0
+                } else {
+                    1
+                };
+                unsafe { libc::_exit(code) };
+            }
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            let code = libc::WEXITSTATUS(status);
+            assert!(code == 0 || (code == 2 && *method == CloseFdsMethod::CloseRange), "{:?} failed", method);
+        }
+    }
 }
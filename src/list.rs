@@ -0,0 +1,273 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Lists of command chains like `a && b || c; d` that are executed one
+//! after another, see `CmdList`.
+
+use std::fmt;
+use std::time::Instant;
+use crate::stage::StageThread;
+use crate::{execute_piped_cmd_chain_with_options, spawn_piped_cmd_chain, try_update_process_states, wait, Builder, ChainIo, CmdChain,
+            ExecOptions, PiperError, ProcessState};
+
+/// How a chain of a `CmdList` is connected to the one before it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Connector {
+    /// `;`: the chain always runs.
+    Always,
+    /// `&&`: the chain only runs if the exit status so far is 0.
+    And,
+    /// `||`: the chain only runs if the exit status so far isn't 0.
+    Or,
+}
+
+impl Connector {
+    /// Whether a chain with this connector runs if the exit status of the
+    /// list so far is `status`.
+    pub fn runs_after(self, status: libc::c_int) -> bool {
+        match self {
+            Connector::Always => true,
+            Connector::And => status == 0,
+            Connector::Or => status != 0,
+        }
+    }
+}
+
+impl fmt::Display for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connector::Always => write!(f, ";"),
+            Connector::And => write!(f, "&&"),
+            Connector::Or => write!(f, "||"),
+        }
+    }
+}
+
+/// Command chains that are executed sequentially, like `a && b || c; d` in
+/// a shell. Like in a shell `&&` and `||` have the same precedence and are
+/// evaluated from left to right: the exit status of a skipped chain doesn't
+/// change the status of the list, hence in `false && a || b` `b` runs.
+#[derive(Debug)]
+pub struct CmdList {
+    /// The chains and how each one is connected to its predecessor. The
+    /// connector of the first chain is always `Connector::Always`.
+    items: Vec<(Connector, CmdChain)>,
+}
+
+impl CmdList {
+    /// Getter for items.
+    pub fn items(&self) -> &Vec<(Connector, CmdChain)> {
+        &self.items
+    }
+    /// Number of chains in the list.
+    pub fn length(&self) -> usize {
+        self.items.len()
+    }
+}
+
+impl fmt::Display for CmdList {
+    /// Reconstructs the command line, e.g. `make && make install || echo failed`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (connector, chain)) in self.items.iter().enumerate() {
+            match (i, connector) {
+                (0, _) => {}
+                (_, Connector::Always) => write!(f, "; ")?,
+                _ => write!(f, " {} ", connector)?,
+            }
+            write!(f, "{}", chain)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builder for `CmdList`.
+#[derive(Debug, Default)]
+pub struct CmdListBuilder {
+    items: Vec<(Connector, CmdChain)>,
+}
+
+impl CmdListBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a chain that is connected with `connector` to the previous
+    /// one. The connector of the first chain is ignored.
+    pub fn add_chain(mut self, connector: Connector, chain: CmdChain) -> Self {
+        let connector = if self.items.is_empty() { Connector::Always } else { connector };
+        self.items.push((connector, chain));
+        self
+    }
+}
+
+impl Builder<CmdList> for CmdListBuilder {
+    fn build(self) -> CmdList {
+        CmdList { items: self.items }
+    }
+}
+
+/// The result of `execute_cmd_list()`.
+#[derive(Debug)]
+pub struct CmdListResult {
+    /// Per chain of the list the states of its processes, `None` if
+    /// the chain was skipped.
+    states: Vec<Option<Vec<ProcessState>>>,
+    /// The exit status of the last chain that ran.
+    exit_code: libc::c_int,
+}
+
+impl CmdListResult {
+    /// Getter for states. Same order as the chains of the list; `None`
+    /// for a skipped chain.
+    pub fn states(&self) -> &Vec<Option<Vec<ProcessState>>> {
+        &self.states
+    }
+    /// Getter for exit_code, i.e. what a shell stores in `$?` after the
+    /// list. 0 for an empty list.
+    pub fn exit_code(&self) -> libc::c_int {
+        self.exit_code
+    }
+    /// Whether the exit status is 0.
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+    /// Consumes the result and returns the states.
+    pub fn into_states(self) -> Vec<Option<Vec<ProcessState>>> {
+        self.states
+    }
+}
+
+/// Executes the chains of the list one after another. Whether a chain runs
+/// depends on its `Connector` and on the exit status of the last chain that
+/// ran (see `CmdChain::result()`). A background chain doesn't wait and
+/// counts as success, like in a shell.
+///
+/// Like a shell, a command that can't be started only fails its chain with
+/// the exit code 127 or 126 (see
+/// `ExecOptions::set_spawn_failures_as_exit_codes()`), so that e.g.
+/// `unknown || echo fallback` works. An `Exit` builtin that runs in the
+/// parent stops the list with its status, the following chains count as
+/// skipped (see `ProcessState::exit_requested()`).
+pub fn execute_cmd_list(list: &CmdList) -> Result<CmdListResult, PiperError> {
+    execute_cmd_list_with_options(list, &ExecOptions::new().set_spawn_failures_as_exit_codes(true))
+}
+
+/// Like `execute_cmd_list()` but every chain is executed with `opts`.
+/// Without `ExecOptions::set_spawn_failures_as_exit_codes()` the first
+/// chain that can't be started aborts the whole list with its error.
+pub fn execute_cmd_list_with_options(list: &CmdList, opts: &ExecOptions) -> Result<CmdListResult, PiperError> {
+    let mut states = Vec::with_capacity(list.items.len());
+    let mut exit_code = 0;
+    for (connector, chain) in &list.items {
+        if !connector.runs_after(exit_code) {
+            states.push(None);
+            continue;
+        }
+        let chain_states = execute_piped_cmd_chain_with_options(chain, opts)?;
+        exit_code = if chain.background() { 0 } else { chain.result(&chain_states).exit_code() };
+        let exits = exit_requested(&chain_states);
+        states.push(Some(chain_states));
+        if exits {
+            states.resize_with(list.items.len(), || None);
+            break;
+        }
+    }
+    Ok(CmdListResult { states, exit_code })
+}
+
+/// Whether the chain was an `Exit` builtin that ran in the parent.
+fn exit_requested(states: &[ProcessState]) -> bool {
+    states.iter().any(|state| state.exit_requested())
+}
+
+/// Thread code of a subshell command (see `crate::stage`): executes the
+/// list like `execute_cmd_list_with_options()` with the FDs of `io` and
+/// returns its exit status. The running processes are registered at
+/// `stage`, so that signals to the subshell reach them. Like a shell
+/// process that got such a signal, the list stops then with the exit
+/// status 128 + signal. An error that aborts the list is the exit
+/// status 1, like in a shell.
+pub(crate) fn run_in_stage(list: &CmdList, io: &ChainIo, opts: &ExecOptions, stage: &StageThread) -> libc::c_int {
+    let mut exit_code = 0;
+    for (connector, chain) in &list.items {
+        if !connector.runs_after(exit_code) {
+            continue;
+        }
+        if let Some(signal) = stage.terminated_by() {
+            return 128 + signal;
+        }
+        let deadline = wait::deadline_of(chain);
+        let mut states = match spawn_piped_cmd_chain(chain, io, opts) {
+            Ok(states) => states,
+            Err(_) => return 1,
+        };
+        if chain.background() {
+            // nobody else waits for it
+            std::thread::spawn(move || try_update_process_states(&mut states, false));
+            exit_code = 0;
+            continue;
+        }
+        let pids: Vec<libc::pid_t> = states.iter().map(|state| state.pid()).collect();
+        stage.register(&pids);
+        let res = match deadline {
+            Some(deadline) => {
+                wait::wait_or_kill(&mut states, deadline.saturating_duration_since(Instant::now()));
+                Ok(())
+            }
+            None => try_update_process_states(&mut states, false).map(|_| ()),
+        };
+        stage.unregister(&pids);
+        if res.is_err() {
+            return 1;
+        }
+        exit_code = chain.result(&states).exit_code();
+        if exit_requested(&states) {
+            break;
+        }
+    }
+    stage.terminated_by().map_or(exit_code, |signal| 128 + signal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_cmd_list;
+
+    #[test]
+    fn test_execute_cmd_list() {
+        let list = parse_cmd_list("false && echo skipped || true; false").unwrap();
+        assert_eq!(list.length(), 4);
+        assert_eq!(list.to_string(), "false && echo skipped || true; false");
+
+        let res = execute_cmd_list(&list).unwrap();
+        let ran = res.states().iter().map(|s| s.is_some()).collect::<Vec<_>>();
+        assert_eq!(ran, vec![true, false, true, true]);
+        assert_eq!(res.exit_code(), 1);
+
+        let list = parse_cmd_list("this-executable-does-not-exist || true").unwrap();
+        let res = execute_cmd_list(&list).unwrap();
+        assert_eq!(res.states()[0].as_ref().unwrap()[0].exit_code(), 127);
+        assert!(res.success());
+    }
+}
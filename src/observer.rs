@@ -0,0 +1,84 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Hooks into the lifecycle of the processes of a chain, see
+//! `ExecOptions::set_observer()`.
+
+use std::fmt;
+use crate::{BasicCmd, ProcessOutcome};
+
+/// Gets notified about the processes of the chains that are executed with
+/// `ExecOptions::set_observer()`, e.g. to log, collect metrics or update
+/// an UI. The crate doesn't print the status messages of these processes
+/// itself then. All hooks do nothing by default.
+///
+/// The hooks are called in the thread that spawns respectively waits for
+/// the processes, i.e. they shouldn't block.
+pub trait ChainObserver: fmt::Debug + Send + Sync {
+    /// The process of `cmd` was started.
+    fn on_spawn(&self, _pid: libc::pid_t, _cmd: &BasicCmd) {}
+
+    /// The process was reaped, e.g. by `update_process_states()`.
+    fn on_exit(&self, _pid: libc::pid_t, _outcome: ProcessOutcome) {}
+
+    /// `cmd` couldn't be started, e.g. `ENOENT` if the executable
+    /// doesn't exist. See `ProcessState::spawn_error()` for the details.
+    fn on_exec_error(&self, _cmd: &BasicCmd, _errno: libc::c_int) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::{execute_piped_cmd_chain_with_options, parse_cmd_chain, ExecOptions};
+
+    /// Records the hooks as strings.
+    #[derive(Debug, Default, Clone)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl ChainObserver for Recorder {
+        fn on_spawn(&self, _pid: libc::pid_t, cmd: &BasicCmd) {
+            self.0.lock().unwrap().push(format!("spawn {}", cmd));
+        }
+        fn on_exit(&self, _pid: libc::pid_t, outcome: ProcessOutcome) {
+            self.0.lock().unwrap().push(format!("exit {:?}", outcome));
+        }
+        fn on_exec_error(&self, cmd: &BasicCmd, errno: libc::c_int) {
+            self.0.lock().unwrap().push(format!("error {} {}", cmd, errno));
+        }
+    }
+
+    #[test]
+    fn test_chain_observer() {
+        let recorder = Recorder::default();
+        let opts = ExecOptions::new().set_observer(recorder.clone()).set_spawn_failures_as_exit_codes(true);
+
+        execute_piped_cmd_chain_with_options(&parse_cmd_chain("true | false").unwrap(), &opts).unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["spawn true", "spawn false", "exit Exited(0)", "exit Exited(1)"]);
+
+        recorder.0.lock().unwrap().clear();
+        execute_piped_cmd_chain_with_options(&parse_cmd_chain("does-not-exist").unwrap(), &opts).unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), vec![format!("error does-not-exist {}", libc::ENOENT), "exit Exited(127)".to_owned()]);
+    }
+}
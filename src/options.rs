@@ -0,0 +1,236 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+use std::sync::Arc;
+use crate::observer::ChainObserver;
+use crate::spawn::{ForkExecBackend, SpawnBackend};
+
+/// Options for the execution of a command chain that are not part
+/// of the chain definition itself. Used by
+/// `execute_piped_cmd_chain_with_options()`.
+#[derive(Debug, Default, Clone)]
+pub struct ExecOptions {
+    /// Optional launcher command that is prepended to every command
+    /// of the chain, e.g. `["strace", "-f"]`.
+    wrapper: Option<Vec<String>>,
+    /// Like `set -o noclobber`: `>` doesn't overwrite existing files.
+    noclobber: bool,
+    /// Whether every command gets its own mount namespace.
+    new_mount_namespace: bool,
+    /// Whether the commands of the chain run in a new process group.
+    process_group: bool,
+    /// The terminal that is handed to the process group of a foreground chain.
+    terminal: Option<libc::c_int>,
+    /// Whether a command that can't be started only fails itself.
+    spawn_failures_as_exit_codes: bool,
+    /// Optional backend that creates the processes instead of `fork()`.
+    spawn_backend: Option<Arc<dyn SpawnBackend>>,
+    /// Whether every process state gets a pidfd.
+    pidfds: bool,
+    /// Optional observer of the processes of the chain.
+    observer: Option<Arc<dyn ChainObserver>>,
+    /// The environment of the enclosing subshells (see
+    /// `BasicCmdBuilder::set_subshell()`): whether it's cleared and the
+    /// variables, which the commands of the chain inherit.
+    subshell_env: Option<(bool, Vec<(String, String)>)>,
+}
+
+impl ExecOptions {
+    /// Constructor. Same as `ExecOptions::default()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a launcher command like `["strace", "-f"]`, `["time"]` or
+    /// `["taskset", "-c", "0"]` that executes every command of the chain.
+    /// For `grep -i abc` the child executes `strace -f grep -i abc`, i.e.
+    /// the wrapper receives the original executable as first argument
+    /// after its own arguments. An empty wrapper is ignored.
+    pub fn set_wrapper(mut self, wrapper: &[&str]) -> Self {
+        if wrapper.is_empty() {
+            self.wrapper = None;
+        } else {
+            self.wrapper.replace(wrapper.iter().map(|s| s.to_string()).collect());
+        }
+        self
+    }
+
+    /// Like bash's `set -o noclobber`: an output redirect with `>` fails
+    /// with `EEXIST` (`ErrorKind::AlreadyExists`) if the file already exists
+    /// instead of truncating it. Existing files that aren't regular files,
+    /// e.g. `/dev/null`, are still allowed. `>>` and `>|` (see
+    /// `BasicCmdBuilder::set_output_redirect_clobber()`) aren't affected.
+    pub fn set_noclobber(mut self, noclobber: bool) -> Self {
+        self.noclobber = noclobber;
+        self
+    }
+
+    /// Runs every command of the chain in a new mount namespace, i.e. mounts
+    /// and unmounts of a command don't affect the host (Linux only). Like
+    /// `unshare -m`, the child calls `unshare(CLONE_NEWNS)` and makes all
+    /// mounts private before `exec()`. Requires `CAP_SYS_ADMIN`; otherwise
+    /// spawning fails with `PiperError::UnshareFailed`.
+    pub fn set_new_mount_namespace(mut self, new_mount_namespace: bool) -> Self {
+        self.new_mount_namespace = new_mount_namespace;
+        self
+    }
+
+    /// Puts all commands of the chain into a new process group, like a shell
+    /// with job control does for each pipeline. The first command becomes
+    /// the group leader, i.e. the pgid is its pid (see
+    /// `ProcessState::pgid()`). The whole chain can then be signaled at
+    /// once with `signal_group()`. Note that the group isn't made the
+    /// foreground group of the terminal; a command that reads from the
+    /// terminal gets stopped by `SIGTTIN`.
+    pub fn set_process_group(mut self, process_group: bool) -> Self {
+        self.process_group = process_group;
+        self
+    }
+
+    /// Makes the process group of a foreground chain the foreground group of
+    /// the terminal (`tcsetpgrp()`), like an interactive shell does, so that
+    /// Ctrl-C and Ctrl-Z reach the chain instead of the caller. Implies
+    /// `set_process_group(true)`. `fd` is usually `STDIN_FILENO` and must
+    /// refer to the controlling terminal; otherwise spawning fails with
+    /// `PiperError::TerminalControlFailed`.
+    ///
+    /// Every child takes over the terminal itself before `exec()`, hence a
+    /// command can't read from the terminal before it's in foreground. The
+    /// executor gives the terminal back to the group of the caller when the
+    /// chain is finished or stopped. A stopped chain can be continued with
+    /// `signal_group()` and `SIGCONT`. Background chains aren't affected.
+    pub fn set_terminal(mut self, fd: libc::c_int) -> Self {
+        self.terminal.replace(fd);
+        self.process_group = true;
+        self
+    }
+
+    /// Like a shell: a command that can't be started (unknown executable,
+    /// missing permissions, a redirect that can't be opened) doesn't abort
+    /// the whole chain with an error. The other commands run and the state
+    /// of the failed one has the exit code 127 (not found, see
+    /// `CHILD_NOT_FOUND_EXIT_CODE`), 126 (not executable, see
+    /// `CHILD_EXEC_FAILED_EXIT_CODE`) or 1 (other failures, see
+    /// `CHILD_PRE_EXEC_FAILED_EXIT_CODE`) and the cause in
+    /// `ProcessState::spawn_error()`.
+    pub fn set_spawn_failures_as_exit_codes(mut self, enabled: bool) -> Self {
+        self.spawn_failures_as_exit_codes = enabled;
+        self
+    }
+
+    /// Creates the processes with `backend`, e.g. `PosixSpawnBackend`,
+    /// instead of the default `ForkExecBackend`. A backend may not
+    /// support every feature of a chain; spawning fails with
+    /// `PiperError::Unsupported` then. `set_spawn_failures_as_exit_codes()`
+    /// only affects failures of processes that were created, i.e. a
+    /// failure of `PosixSpawnBackend` or `StdCommandBackend` always aborts
+    /// the chain.
+    pub fn set_spawn_backend(mut self, backend: impl SpawnBackend + 'static) -> Self {
+        self.spawn_backend.replace(Arc::new(backend));
+        self
+    }
+
+    /// Linux only: opens a pidfd for every process right after it was
+    /// started, see `ProcessState::pidfd()`. As long as the process isn't
+    /// reaped its pid can't be reused, hence the pidfd refers to the right
+    /// process. Signals are sent race-free with `pidfd_send_signal()` then
+    /// and the timed waits (`update_process_states_timeout()`) reuse the
+    /// pidfds. Ignored on other systems and kernels before 5.3.
+    pub fn set_pidfds(mut self, enabled: bool) -> Self {
+        self.pidfds = enabled;
+        self
+    }
+
+    /// Reports the lifecycle of the processes to `observer` instead of
+    /// printing status messages, see `ChainObserver`. The observer stays
+    /// attached to the process states, i.e. it also learns about exits if
+    /// the states are updated later, e.g. of a background chain.
+    pub fn set_observer(mut self, observer: impl ChainObserver + 'static) -> Self {
+        self.observer.replace(Arc::new(observer));
+        self
+    }
+
+    /// Getter for wrapper.
+    pub fn wrapper(&self) -> Option<&Vec<String>> {
+        self.wrapper.as_ref()
+    }
+
+    /// Getter for noclobber.
+    pub fn noclobber(&self) -> bool {
+        self.noclobber
+    }
+
+    /// Getter for new_mount_namespace.
+    pub fn new_mount_namespace(&self) -> bool {
+        self.new_mount_namespace
+    }
+
+    /// Getter for process_group.
+    pub fn process_group(&self) -> bool {
+        self.process_group
+    }
+
+    /// Getter for spawn_failures_as_exit_codes.
+    pub fn spawn_failures_as_exit_codes(&self) -> bool {
+        self.spawn_failures_as_exit_codes
+    }
+
+    /// Getter for terminal.
+    pub fn terminal(&self) -> Option<libc::c_int> {
+        self.terminal
+    }
+
+    /// Getter for pidfds.
+    pub fn pidfds(&self) -> bool {
+        self.pidfds
+    }
+
+    /// Getter for observer.
+    pub fn observer(&self) -> Option<&Arc<dyn ChainObserver>> {
+        self.observer.as_ref()
+    }
+
+    /// Applies the environment of a subshell command to the commands of
+    /// its list, on top of the one of enclosing subshells.
+    pub(crate) fn with_subshell_env(mut self, clear_env: bool, env: &[(String, String)]) -> Self {
+        let (clear, vars) = self.subshell_env.get_or_insert_with(|| (false, vec![]));
+        if clear_env {
+            *clear = true;
+            vars.clear();
+        }
+        vars.retain(|(key, _)| !env.iter().any(|(k, _)| k == key));
+        vars.extend(env.iter().cloned());
+        self
+    }
+
+    /// Getter for subshell_env.
+    pub(crate) fn subshell_env(&self) -> Option<&(bool, Vec<(String, String)>)> {
+        self.subshell_env.as_ref()
+    }
+
+    /// Getter for spawn_backend; `ForkExecBackend` if none was set.
+    pub fn spawn_backend(&self) -> &dyn SpawnBackend {
+        self.spawn_backend.as_deref().unwrap_or(&ForkExecBackend)
+    }
+}
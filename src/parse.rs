@@ -0,0 +1,673 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Parsing of shell command lines like `cat < in.txt | grep -i abc | wc -l > out.txt &`
+//! into a `CmdChain`. Supported are words with single and double quotes,
+//! `|`, an input redirect `<` or a here-string `<<<` on the first command,
+//! an output redirect `>`, `>>` or `>|` on the last command, a STDERR
+//! redirect `2>` and FD duplications like `2>&1` on every command, `|&`
+//! a leading `!` that negates the exit status, subshells like `(a; b) | c`
+//! and a trailing `&` for background execution. `parse_cmd_list()` also
+//! splits the line at `;`, `&&` and `||` into a `CmdList`.
+//!
+//! The tokenizer remembers how each part of a word was quoted, so that
+//! an expansion step (see `check.rs`) can treat quoted parts differently.
+
+use std::fmt;
+use std::str::FromStr;
+use crate::{BasicCmdBuilder, Builder, CmdChain, CmdChainBuilder, CmdList, CmdListBuilder, Connector, OutputRedirectMode};
+
+/// Error while parsing a shell command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// A single or double quote was opened but never closed.
+    UnterminatedQuote,
+    /// A command of the chain contains no words, e.g. in `ls | | wc`.
+    EmptyCommand,
+    /// A redirect operator (`<` or `>`) isn't followed by a path.
+    MissingRedirectPath(char),
+    /// The input redirect (`<`) is only allowed on the first command.
+    InputRedirectNotFirst,
+    /// The output redirect (`>` or `>>`) is only allowed on the last command.
+    OutputRedirectNotLast,
+    /// The same redirect was given multiple times for a command.
+    DuplicateRedirect(char),
+    /// `&` is only allowed at the very end of the line.
+    MisplacedBackground,
+    /// A `${` without the closing `}`.
+    BadSubstitution,
+    /// `;`, `&&` or `||` in a line that is parsed as single chain,
+    /// see `parse_cmd_list()`.
+    ListConnector,
+    /// A `(` without the closing `)` or the other way round.
+    UnbalancedParenthesis,
+    /// A subshell `( ... )` that isn't a whole command, e.g. `echo (a)`.
+    MisplacedSubshell,
+    /// The path of a redirect expands to zero or more than one word, e.g.
+    /// a glob that matches several files. Holds the unexpanded word.
+    AmbiguousRedirect(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote => write!(f, "unterminated quote"),
+            ParseError::EmptyCommand => write!(f, "empty command"),
+            ParseError::MissingRedirectPath(op) => write!(f, "missing path after '{}'", op),
+            ParseError::InputRedirectNotFirst => write!(f, "'<' is only allowed on the first command"),
+            ParseError::OutputRedirectNotLast => write!(f, "'>' is only allowed on the last command"),
+            ParseError::DuplicateRedirect(op) => write!(f, "multiple '{}' redirects for one command", op),
+            ParseError::MisplacedBackground => write!(f, "'&' is only allowed at the end of the line"),
+            ParseError::BadSubstitution => write!(f, "bad substitution"),
+            ParseError::ListConnector => write!(f, "';', '&&' and '||' are only allowed in a command list"),
+            ParseError::UnbalancedParenthesis => write!(f, "unbalanced parenthesis"),
+            ParseError::MisplacedSubshell => write!(f, "a subshell must be a whole command"),
+            ParseError::AmbiguousRedirect(word) => write!(f, "{}: ambiguous redirect", word),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// How a part of a word was quoted.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Quoting {
+    None,
+    Single,
+    Double,
+}
+
+/// A word consists of parts that were quoted differently,
+/// e.g. `abc"d e"'$f'` has three parts.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Word {
+    pub(crate) parts: Vec<(String, Quoting)>,
+}
+
+impl Word {
+    /// The word with quotes removed but without any expansion.
+    pub(crate) fn literal(&self) -> String {
+        self.parts.iter().map(|(text, _)| text.as_str()).collect()
+    }
+}
+
+/// A token of a shell command line.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    Word(Word),
+    Pipe,
+    /// `|&`
+    PipeAmp,
+    Less,
+    /// `<<<`
+    LessLessLess,
+    Greater,
+    GreaterGreater,
+    GreaterPipe,
+    /// `2>`
+    ErrGreater,
+    /// `n>&m`, `n` is 1 if it's omitted
+    DupFd(libc::c_int, libc::c_int),
+    Amp,
+    /// `&&`
+    AndAnd,
+    /// `||`
+    OrOr,
+    Semicolon,
+    /// `( ... )` with the unparsed text between the parentheses
+    Subshell(String),
+}
+
+/// Splits a line into tokens. Whitespace separates words unless quoted.
+/// A backslash outside of single quotes escapes the next character.
+pub(crate) fn tokenize(line: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut parts: Vec<(String, Quoting)> = vec![];
+    let mut chars = line.chars().peekable();
+
+    // finishes the current word (if any)
+    fn flush(parts: &mut Vec<(String, Quoting)>, tokens: &mut Vec<Token>) {
+        if !parts.is_empty() {
+            tokens.push(Token::Word(Word { parts: std::mem::take(parts) }));
+        }
+    }
+    // appends a char to the last part if it has the same quoting
+    fn push(parts: &mut Vec<(String, Quoting)>, c: char, quoting: Quoting) {
+        match parts.last_mut() {
+            Some((text, q)) if *q == quoting => text.push(c),
+            _ => parts.push((c.to_string(), quoting)),
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => flush(&mut parts, &mut tokens),
+            // 'n>&m'; an unquoted number directly in front of '>' is the FD
+            '>' if chars.peek() == Some(&'&') => {
+                chars.next();
+                let from = match parts.as_slice() {
+                    [(n, Quoting::None)] if n.chars().all(|c| c.is_ascii_digit()) => n.parse().ok(),
+                    _ => None,
+                };
+                match from {
+                    Some(_) => parts.clear(),
+                    None => flush(&mut parts, &mut tokens),
+                }
+                let mut to = String::new();
+                while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    to.push(*c);
+                    chars.next();
+                }
+                let to = to.parse().map_err(|_| ParseError::MissingRedirectPath('&'))?;
+                tokens.push(Token::DupFd(from.unwrap_or(libc::STDOUT_FILENO), to));
+            }
+            // an unquoted "2" directly in front of '>' is the FD, not a word
+            '>' if parts == [("2".to_string(), Quoting::None)] => {
+                parts.clear();
+                tokens.push(Token::ErrGreater);
+            }
+            '|' | '<' | '>' | '&' | ';' => {
+                flush(&mut parts, &mut tokens);
+                tokens.push(match c {
+                    '|' if chars.peek() == Some(&'|') => {
+                        chars.next();
+                        Token::OrOr
+                    }
+                    '|' if chars.peek() == Some(&'&') => {
+                        chars.next();
+                        Token::PipeAmp
+                    }
+                    '|' => Token::Pipe,
+                    '<' if chars.clone().take(2).eq("<<".chars()) => {
+                        chars.nth(1);
+                        Token::LessLessLess
+                    }
+                    '<' => Token::Less,
+                    '>' if chars.peek() == Some(&'>') => {
+                        chars.next();
+                        Token::GreaterGreater
+                    }
+                    '>' if chars.peek() == Some(&'|') => {
+                        chars.next();
+                        Token::GreaterPipe
+                    }
+                    '>' => Token::Greater,
+                    ';' => Token::Semicolon,
+                    '&' if chars.peek() == Some(&'&') => {
+                        chars.next();
+                        Token::AndAnd
+                    }
+                    _ => Token::Amp,
+                });
+            }
+            '(' => {
+                flush(&mut parts, &mut tokens);
+                tokens.push(Token::Subshell(subshell_text(&mut chars)?));
+            }
+            ')' => return Err(ParseError::UnbalancedParenthesis),
+            '\\' => {
+                // escaped chars behave like single quoted ones
+                let escaped = chars.next().unwrap_or('\\');
+                push(&mut parts, escaped, Quoting::Single);
+            }
+            '\'' => {
+                // '' is an empty word
+                parts.push((String::new(), Quoting::Single));
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => push(&mut parts, c, Quoting::Single),
+                        None => return Err(ParseError::UnterminatedQuote),
+                    }
+                }
+            }
+            '"' => {
+                parts.push((String::new(), Quoting::Double));
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"') | Some('\\') | Some('$')) => {
+                            // an escaped '$' must not be expanded later
+                            let escaped = chars.next().unwrap();
+                            push(&mut parts, escaped, Quoting::Single);
+                        }
+                        Some(c) => push(&mut parts, c, Quoting::Double),
+                        None => return Err(ParseError::UnterminatedQuote),
+                    }
+                }
+            }
+            c => push(&mut parts, c, Quoting::None),
+        }
+    }
+    flush(&mut parts, &mut tokens);
+    Ok(tokens)
+}
+
+/// Returns the text up to the `)` that closes an already consumed `(`.
+/// Quotes and backslashes are kept, they are parsed with the text later.
+fn subshell_text(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, ParseError> {
+    let mut text = String::new();
+    let mut depth = 1;
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                text.push(c);
+                if let Some(escaped) = chars.next() {
+                    text.push(escaped);
+                }
+                continue;
+            }
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(text);
+                }
+            }
+            _ => {}
+        }
+        text.push(c);
+    }
+    match quote {
+        Some(_) => Err(ParseError::UnterminatedQuote),
+        None => Err(ParseError::UnbalancedParenthesis),
+    }
+}
+
+/// A command of a parsed line whose words are not expanded yet.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct ParsedCmd {
+    pub(crate) words: Vec<Word>,
+    pub(crate) in_red: Option<Word>,
+    /// The word of a here-string (`<<< word`).
+    pub(crate) here_string: Option<Word>,
+    pub(crate) out_red: Option<Word>,
+    pub(crate) err_red: Option<Word>,
+    /// Whether the output redirect is `>`, `>>` or `>|`.
+    pub(crate) out_red_mode: OutputRedirectMode,
+    /// `n>&m` as `(n, m)`.
+    pub(crate) fd_dups: Vec<(libc::c_int, libc::c_int)>,
+    /// Whether the command is followed by `|&`.
+    pub(crate) pipe_stderr: bool,
+    /// The unparsed list of a subshell `( ... )`.
+    pub(crate) subshell: Option<String>,
+}
+
+/// A parsed line whose words are not expanded yet.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ParsedChain {
+    pub(crate) cmds: Vec<ParsedCmd>,
+    pub(crate) background: bool,
+    /// Whether the line starts with `!`.
+    pub(crate) negate_status: bool,
+}
+
+/// Builds the structure of a command chain from the tokens and
+/// validates the position of the redirects and of `&`.
+pub(crate) fn parse_tokens(tokens: Vec<Token>) -> Result<ParsedChain, ParseError> {
+    let mut cmds = vec![];
+    let mut current = ParsedCmd::default();
+    let mut background = false;
+
+    let mut tokens = tokens.into_iter().peekable();
+    // an unquoted '!' as first word negates the exit status
+    let negate_status = matches!(tokens.peek(), Some(Token::Word(word)) if word.parts == [("!".to_string(), Quoting::None)]);
+    if negate_status {
+        tokens.next();
+    }
+    while let Some(token) = tokens.next() {
+        if background {
+            // nothing is allowed after '&'
+            return Err(ParseError::MisplacedBackground);
+        }
+        match token {
+            Token::Word(_) | Token::Subshell(_) if current.subshell.is_some() => {
+                return Err(ParseError::MisplacedSubshell);
+            }
+            Token::Word(word) => current.words.push(word),
+            Token::Subshell(_) if !current.words.is_empty() => return Err(ParseError::MisplacedSubshell),
+            Token::Subshell(text) => current.subshell = Some(text),
+            Token::Pipe | Token::PipeAmp => {
+                let mut cmd = std::mem::take(&mut current);
+                cmd.pipe_stderr = token == Token::PipeAmp;
+                cmds.push(cmd);
+            }
+            Token::Less | Token::Greater | Token::GreaterGreater | Token::GreaterPipe => {
+                let op = if token == Token::Less { '<' } else { '>' };
+                match token {
+                    Token::GreaterGreater => current.out_red_mode = OutputRedirectMode::Append,
+                    Token::GreaterPipe => current.out_red_mode = OutputRedirectMode::Clobber,
+                    Token::Greater => current.out_red_mode = OutputRedirectMode::Truncate,
+                    _ => {}
+                }
+                let path = match tokens.next() {
+                    Some(Token::Word(word)) => word,
+                    _ => return Err(ParseError::MissingRedirectPath(op)),
+                };
+                let target = if op == '<' { &mut current.in_red } else { &mut current.out_red };
+                if target.replace(path).is_some() || (op == '<' && current.here_string.is_some()) {
+                    return Err(ParseError::DuplicateRedirect(op));
+                }
+            }
+            Token::LessLessLess => {
+                let word = match tokens.next() {
+                    Some(Token::Word(word)) => word,
+                    _ => return Err(ParseError::MissingRedirectPath('<')),
+                };
+                if current.here_string.replace(word).is_some() || current.in_red.is_some() {
+                    return Err(ParseError::DuplicateRedirect('<'));
+                }
+            }
+            Token::ErrGreater => {
+                let path = match tokens.next() {
+                    Some(Token::Word(word)) => word,
+                    _ => return Err(ParseError::MissingRedirectPath('>')),
+                };
+                if current.err_red.replace(path).is_some() {
+                    return Err(ParseError::DuplicateRedirect('>'));
+                }
+            }
+            Token::DupFd(from, to) => current.fd_dups.push((from, to)),
+            Token::Amp => background = true,
+            Token::AndAnd | Token::OrOr | Token::Semicolon => return Err(ParseError::ListConnector),
+        }
+    }
+    cmds.push(current);
+
+    let len = cmds.len();
+    for (i, cmd) in cmds.iter().enumerate() {
+        if cmd.words.is_empty() && cmd.subshell.is_none() {
+            return Err(ParseError::EmptyCommand);
+        }
+        if (cmd.in_red.is_some() || cmd.here_string.is_some()) && i != 0 {
+            return Err(ParseError::InputRedirectNotFirst);
+        }
+        if cmd.out_red.is_some() && i + 1 != len {
+            return Err(ParseError::OutputRedirectNotLast);
+        }
+    }
+
+    Ok(ParsedChain { cmds, background, negate_status })
+}
+
+/// Builds the `CmdChain` from a parsed line. `expand` turns a word into
+/// zero or more strings (e.g. a glob pattern into several paths).
+pub(crate) fn build_chain<F>(parsed: &ParsedChain, mut expand: F) -> Result<CmdChain, ParseError>
+    where F: FnMut(&Word) -> Result<Vec<String>, ParseError> {
+    let mut builder = CmdChainBuilder::new()
+        .set_background(parsed.background)
+        .set_negate_status(parsed.negate_status);
+    for cmd in &parsed.cmds {
+        let mut cmd_builder = BasicCmdBuilder::new();
+        if let Some(text) = &cmd.subshell {
+            // the words of the list aren't expanded
+            cmd_builder = cmd_builder.set_subshell(parse_cmd_list(text)?);
+        } else {
+            let mut args = vec![];
+            for word in &cmd.words {
+                args.extend(expand(word)?);
+            }
+            if args.is_empty() {
+                // e.g. a single unset variable
+                return Err(ParseError::EmptyCommand);
+            }
+            cmd_builder = cmd_builder.set_executable(&args[0]);
+            for arg in &args {
+                cmd_builder = cmd_builder.add_arg(arg);
+            }
+        }
+        if let Some(word) = &cmd.in_red {
+            cmd_builder = cmd_builder.set_input_redirect_path(redirect_path(&mut expand, word)?);
+        }
+        if let Some(word) = &cmd.out_red {
+            let path = redirect_path(&mut expand, word)?;
+            cmd_builder = cmd_builder
+                .set_output_redirect_path(&path)
+                .set_output_redirect_mode(cmd.out_red_mode);
+        }
+        if let Some(word) = &cmd.here_string {
+            cmd_builder = cmd_builder.set_here_string(&expand(word)?.join(" "));
+        }
+        if let Some(word) = &cmd.err_red {
+            cmd_builder = cmd_builder.set_stderr_redirect_path(redirect_path(&mut expand, word)?);
+        }
+        for (from, to) in &cmd.fd_dups {
+            cmd_builder = cmd_builder.add_fd_dup(*from, *to);
+        }
+        if cmd.pipe_stderr {
+            cmd_builder = cmd_builder.merge_stderr_into_stdout(true);
+        }
+        builder = builder.add_cmd(cmd_builder);
+    }
+    Ok(builder.build())
+}
+
+/// Expands the path of a redirect. Like in a shell it must be exactly one
+/// word, e.g. a glob must match a single file.
+fn redirect_path<F>(expand: &mut F, word: &Word) -> Result<String, ParseError>
+    where F: FnMut(&Word) -> Result<Vec<String>, ParseError> {
+    match expand(word)?.as_slice() {
+        [path] => Ok(path.clone()),
+        _ => Err(ParseError::AmbiguousRedirect(word.literal())),
+    }
+}
+
+/// Parses a shell command line into a `CmdChain`. Quotes are removed but
+/// no expansion (variables, `~`, globs) is done.
+pub fn parse_cmd_chain(line: &str) -> Result<CmdChain, ParseError> {
+    let parsed = parse_tokens(tokenize(line)?)?;
+    build_chain(&parsed, |word| Ok(vec![word.literal()]))
+}
+
+/// Parses a shell command line like `make && make install || echo failed`
+/// into a `CmdList`. Each chain is parsed like by `parse_cmd_chain()`; a
+/// trailing `;` is allowed, an empty chain elsewhere is an error.
+pub fn parse_cmd_list(line: &str) -> Result<CmdList, ParseError> {
+    let mut segments = vec![(Connector::Always, vec![])];
+    for token in tokenize(line)? {
+        let connector = match token {
+            Token::Semicolon => Connector::Always,
+            Token::AndAnd => Connector::And,
+            Token::OrOr => Connector::Or,
+            token => {
+                segments.last_mut().unwrap().1.push(token);
+                continue;
+            }
+        };
+        segments.push((connector, vec![]));
+    }
+    if let [_, .., (Connector::Always, last)] = segments.as_slice() {
+        if last.is_empty() {
+            // trailing ';'
+            segments.pop();
+        }
+    }
+
+    let mut builder = CmdListBuilder::new();
+    for (connector, tokens) in segments {
+        let parsed = parse_tokens(tokens)?;
+        builder = builder.add_chain(connector, build_chain(&parsed, |word| Ok(vec![word.literal()]))?);
+    }
+    Ok(builder.build())
+}
+
+impl CmdChain {
+    /// Parses a shell command line into a `CmdChain`, see `parse_cmd_chain()`.
+    pub fn parse(line: &str) -> Result<CmdChain, ParseError> {
+        parse_cmd_chain(line)
+    }
+
+    /// Same as `parse()`, under the name shell frontends tend to look for.
+    pub fn from_shell_str(line: &str) -> Result<CmdChain, ParseError> {
+        parse_cmd_chain(line)
+    }
+}
+
+impl FromStr for CmdList {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        parse_cmd_list(line)
+    }
+}
+
+impl FromStr for CmdChain {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        parse_cmd_chain(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OutputRedirectMode;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_cmd_chain() {
+        let chain = parse_cmd_chain("cat < in.txt | grep -i 'a b' | wc -l > out.txt &").unwrap();
+        assert!(chain.background());
+        assert_eq!(chain.length(), 3);
+        assert_eq!(chain.cmds()[0].in_red_path().as_deref(), Some(Path::new("in.txt")));
+        assert_eq!(chain.cmds()[1].args(), &vec!["grep", "-i", "a b"]);
+        assert_eq!(chain.cmds()[2].out_red_path().as_deref(), Some(Path::new("out.txt")));
+        assert_eq!(chain.to_string(), "cat < in.txt | grep -i 'a b' | wc -l > out.txt &");
+
+        let chain = parse_cmd_chain("echo a >> log.txt").unwrap();
+        assert_eq!(chain.cmds()[0].out_red_mode(), OutputRedirectMode::Append);
+        assert_eq!(chain.to_string(), "echo a >> log.txt");
+
+        let chain = parse_cmd_chain("echo a >| log.txt").unwrap();
+        assert_eq!(chain.cmds()[0].out_red_mode(), OutputRedirectMode::Clobber);
+        assert_eq!(chain.to_string(), "echo a >| log.txt");
+
+        let chain = parse_cmd_chain("make 2> errors.log | wc -l").unwrap();
+        assert_eq!(chain.cmds()[0].args(), &vec!["make"]);
+        assert_eq!(chain.cmds()[0].err_red_path().as_deref(), Some(Path::new("errors.log")));
+        assert_eq!(chain.to_string(), "make 2> errors.log | wc -l");
+        // only a separate, unquoted "2" is the FD
+        assert_eq!(parse_cmd_chain("echo 2 > a | cat").unwrap_err(), ParseError::OutputRedirectNotLast);
+        let chain = parse_cmd_chain("echo '2'> a").unwrap();
+        assert_eq!(chain.cmds()[0].args()[1], "2");
+
+        assert_eq!(parse_cmd_chain("ls | | wc").unwrap_err(), ParseError::EmptyCommand);
+        assert_eq!(parse_cmd_chain("ls & | wc").unwrap_err(), ParseError::MisplacedBackground);
+    }
+
+    #[test]
+    fn test_cmd_chain_parse() {
+        let chain = CmdChain::parse("echo \"a b\" | wc -c").unwrap();
+        assert_eq!(chain.cmds()[0].args(), &vec!["echo", "a b"]);
+        assert!(chain.cmds()[0].is_first() && chain.cmds()[1].is_last());
+
+        let chain: CmdChain = "sleep 1 &".parse().unwrap();
+        assert!(chain.background());
+
+        assert_eq!(CmdChain::parse("cat | grep a < in.txt").unwrap_err(), ParseError::InputRedirectNotFirst);
+        assert_eq!(CmdChain::parse("cat > out.txt | wc").unwrap_err(), ParseError::OutputRedirectNotLast);
+
+        let chain = CmdChain::from_shell_str("cat < in.txt | grep -i abc | wc -l > out.txt &").unwrap();
+        assert_eq!(chain.signature(), CmdChain::parse("cat < in.txt | grep -i abc | wc -l > out.txt &").unwrap().signature());
+        assert!(chain.background());
+    }
+
+    #[test]
+    fn test_parse_fd_dup() {
+        let chain = CmdChain::parse("make 2>&1 | grep error").unwrap();
+        assert_eq!(chain.cmds()[0].args(), &vec!["make"]);
+        assert_eq!(chain.cmds()[0].fd_dups(), &vec![crate::FdDup::new(2, 1)]);
+
+        let chain = CmdChain::parse("echo a>&2 '2'>&1").unwrap();
+        assert_eq!(chain.cmds()[0].args(), &vec!["echo", "a", "2"]);
+        assert_eq!(chain.cmds()[0].fd_dups(), &vec![crate::FdDup::new(1, 2), crate::FdDup::new(1, 1)]);
+
+        assert_eq!(CmdChain::parse("echo a >&").unwrap_err(), ParseError::MissingRedirectPath('&'));
+
+        let chain = CmdChain::parse("make |& grep error").unwrap();
+        assert!(chain.pipes_stderr(0));
+        assert_eq!(chain.cmds()[1].args(), &vec!["grep", "error"]);
+    }
+
+    #[test]
+    fn test_parse_here_string() {
+        let chain = CmdChain::parse("tr a-z A-Z <<< 'hello world' | wc -c").unwrap();
+        assert_eq!(chain.cmds()[0].stdin_contents(), Some(&b"hello world\n".to_vec()));
+        assert_eq!(chain.cmds()[0].args().len(), 3);
+
+        assert_eq!(CmdChain::parse("cat < in.txt <<< a").unwrap_err(), ParseError::DuplicateRedirect('<'));
+        assert_eq!(CmdChain::parse("cat | cat <<< a").unwrap_err(), ParseError::InputRedirectNotFirst);
+        assert_eq!(CmdChain::parse("cat <<<").unwrap_err(), ParseError::MissingRedirectPath('<'));
+    }
+
+    #[test]
+    fn test_parse_cmd_list() {
+        let list = parse_cmd_list("make && make install || echo 'a;b' > log.txt; ls | wc -l &;").unwrap();
+        let connectors = list.items().iter().map(|(c, _)| *c).collect::<Vec<_>>();
+        assert_eq!(connectors, vec![Connector::Always, Connector::And, Connector::Or, Connector::Always]);
+        assert_eq!(list.items()[2].1.cmds()[0].args(), &vec!["echo", "a;b"]);
+        assert!(list.items()[3].1.background());
+        assert_eq!(list.to_string(), "make && make install || echo 'a;b' > log.txt; ls | wc -l &");
+
+        assert_eq!(parse_cmd_list("a && && b").unwrap_err(), ParseError::EmptyCommand);
+        assert_eq!(parse_cmd_list("; a").unwrap_err(), ParseError::EmptyCommand);
+        assert_eq!(parse_cmd_list("a &&").unwrap_err(), ParseError::EmptyCommand);
+        assert_eq!(parse_cmd_chain("a && b").unwrap_err(), ParseError::ListConnector);
+    }
+
+    #[test]
+    fn test_parse_negated_chain() {
+        let chain = parse_cmd_chain("! grep -q x file.txt | cat").unwrap();
+        assert!(chain.negate_status());
+        assert_eq!(chain.cmds()[0].args(), &vec!["grep", "-q", "x", "file.txt"]);
+        assert_eq!(chain.to_string(), "! grep -q x file.txt | cat");
+
+        // a quoted '!' is a normal word
+        let chain = parse_cmd_chain("'!' a").unwrap();
+        assert!(!chain.negate_status());
+        assert_eq!(chain.cmds()[0].args(), &vec!["!", "a"]);
+        assert_eq!(parse_cmd_chain("!").unwrap_err(), ParseError::EmptyCommand);
+    }
+
+    #[test]
+    fn test_parse_subshell() {
+        let chain = parse_cmd_chain("(echo a; echo ')' && (true)) | sort > out.txt").unwrap();
+        let list = chain.cmds()[0].subshell().unwrap();
+        assert_eq!(list.length(), 3);
+        assert_eq!(list.items()[1].1.cmds()[0].args(), &vec!["echo", ")"]);
+        assert!(list.items()[2].1.cmds()[0].subshell().is_some());
+        assert_eq!(chain.to_string(), "(echo a; echo ')' && (true)) | sort > out.txt");
+
+        assert_eq!(parse_cmd_chain("(echo a").unwrap_err(), ParseError::UnbalancedParenthesis);
+        assert_eq!(parse_cmd_chain("echo a)").unwrap_err(), ParseError::UnbalancedParenthesis);
+        assert_eq!(parse_cmd_chain("echo (a)").unwrap_err(), ParseError::MisplacedSubshell);
+        assert_eq!(parse_cmd_chain("(a) b").unwrap_err(), ParseError::MisplacedSubshell);
+        assert_eq!(parse_cmd_chain("()").unwrap_err(), ParseError::EmptyCommand);
+    }
+}
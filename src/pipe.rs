@@ -22,29 +22,34 @@
     SOFTWARE.
 */
 
-/// Abstraction over UNIX pipe. This Pipe abstraction is specific
-/// to the case of connecting once process' STDOUT with next
-/// process' STDIN. A parent process is creating n childs and
-/// creates n-1 pipes. Each child process knows it's
-/// optional pipe_to_current and it's optional pipe_to_next.
-///
-/// Please note that each pipe object exists in each address space
-/// of each child. See fork() for more information:
-/// https://man7.org/linux/man-pages/man2/fork.2.html
-///
-/// ```
-/// /*
-/// child process 0    child process 1    child process n
-/// _______________    _______________    _________
-/// | cat foo.txt |    | grep -i abc |    | wc -l |
-/// ---------------    ---------------    ---------
-///             ^        ^         ^        ^
-///       WRITE |--------|  R / W  |--------| READ
-///       END               E   E             END
-///                    (current child)
-///         -Pipe to Current-   -Pipe to Next-`
-/// */
-/// ```
+//! Abstraction over UNIX pipe. This Pipe abstraction is specific
+//! to the case of connecting once process' STDOUT with next
+//! process' STDIN. A parent process is creating n childs and
+//! creates n-1 pipes. Each child process knows it's
+//! optional pipe_to_current and it's optional pipe_to_next.
+//!
+//! Please note that each pipe object exists in each address space
+//! of each child. See fork() for more information:
+//! https://man7.org/linux/man-pages/man2/fork.2.html
+//!
+//! ```
+//! /*
+//! child process 0    child process 1    child process n
+//! _______________    _______________    _________
+//! | cat foo.txt |    | grep -i abc |    | wc -l |
+//! ---------------    ---------------    ---------
+//!             ^        ^         ^        ^
+//!       WRITE |--------|  R / W  |--------| READ
+//!       END               E   E             END
+//!                    (current child)
+//!         -Pipe to Current-   -Pipe to Next-`
+//! */
+//! ```
+
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use crate::error::PiperError;
 
 /// Index in the `fd[i32; 2]`-array.
 /// See https://man7.org/linux/man-pages/man2/pipe.2.html
@@ -95,16 +100,57 @@ pub struct Pipe {
 
 impl Pipe {
 
+    /// Constructor. Panics if the pipe can't be created, see `try_new()`.
     pub fn new() -> Self {
-        let mut fds: [libc::c_int; 2] = [0; 2];
-        let res = unsafe { libc::pipe(fds.as_mut_ptr()) };
-        if res == -1 { panic!("Pipe creation failed!") }
-        Self {
+        Self::try_new().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Constructor. Fails if the pipe can't be created, e.g. because the
+    /// process has too many open files.
+    pub fn try_new() -> Result<Self, PiperError> {
+        let fds = new_cloexec_pipe()?;
+        Ok(Self {
             fds,
             locked: false,
             read_closed: false,
             write_closed: false
+        })
+    }
+
+    /// Getter for the file descriptor of the read end. -1 if it was
+    /// closed, like in `into_raw_fds()`: the number may belong to another
+    /// file by now.
+    pub fn read_fd(&self) -> libc::c_int {
+        if self.read_closed { -1 } else { self.fds[PipeEnd::Read as usize] }
+    }
+
+    /// Getter for the file descriptor of the write end. -1 if it was
+    /// closed, see `read_fd()`.
+    pub fn write_fd(&self) -> libc::c_int {
+        if self.write_closed { -1 } else { self.fds[PipeEnd::Write as usize] }
+    }
+
+    /// Sets or clears `O_NONBLOCK` on the write end. With the flag a writer
+    /// gets `EAGAIN` instead of blocking if the pipe buffer is full. Most
+    /// programs expect blocking pipes and misbehave (e.g. lose output or
+    /// fail) with nonblocking ones, so this is only for specialized
+    /// producers that handle the backpressure themselves. The flag belongs
+    /// to the open pipe, i.e. it's inherited by the child via dup2().
+    /// Fails with `EBADF` if the write end was closed.
+    pub fn set_write_nonblocking(&self, nonblocking: bool) -> Result<(), PiperError> {
+        if self.write_closed {
+            return Err(PiperError::FcntlFailed(libc::EBADF));
+        }
+        let fd = self.write_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(PiperError::FcntlFailed(errno::errno().0));
         }
+        let flags = if nonblocking { flags | libc::O_NONBLOCK } else { flags & !libc::O_NONBLOCK };
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } == -1 {
+            return Err(PiperError::FcntlFailed(errno::errno().0));
+        }
+        Ok(())
     }
 
     /// Marks and locks the Pipe in the current address space
@@ -129,6 +175,57 @@ impl Pipe {
         self.connect_pipe_end(PipeEnd::Write, libc::STDOUT_FILENO);
     }
 
+    /// Closes the read end in this address space, e.g. in the process that
+    /// only writes into a custom status channel.
+    pub fn close_read_end(&mut self) {
+        if !self.read_closed {
+            self.close_pipe_end(PipeEnd::Read);
+            self.read_closed = true;
+        }
+    }
+
+    /// Closes the write end in this address space, see `close_read_end()`.
+    pub fn close_write_end(&mut self) {
+        if !self.write_closed {
+            self.close_pipe_end(PipeEnd::Write);
+            self.write_closed = true;
+        }
+    }
+
+    /// Duplicates a pipe end onto `target_fd` with `dup2()`, e.g. onto FD 3
+    /// of a child as an extra channel besides STDIN and STDOUT. The
+    /// duplicate doesn't have the close-on-exec flag, i.e. it's inherited
+    /// by exec(). The pipe keeps owning its own FD.
+    pub fn dup_to(&self, pe: PipeEnd, target_fd: libc::c_int) -> Result<(), PiperError> {
+        let closed = match pe {
+            PipeEnd::Read => self.read_closed,
+            PipeEnd::Write => self.write_closed,
+        };
+        if closed || unsafe { libc::dup2(self.fds[pe as usize], target_fd) } == -1 {
+            let errno = if closed { libc::EBADF } else { errno::errno().0 };
+            return Err(PiperError::DupFailed(errno));
+        }
+        Ok(())
+    }
+
+    /// Gives up the ownership of the FDs (index see `PipeEnd`); the caller
+    /// has to close them. Ends that were already closed are -1.
+    pub fn into_raw_fds(mut self) -> [libc::c_int; 2] {
+        let fds = [self.read_fd(), self.write_fd()];
+        // nothing is closed on drop anymore
+        self.read_closed = true;
+        self.write_closed = true;
+        fds
+    }
+
+    /// Splits the pipe into its ends, which own their FD and close it when
+    /// they are dropped. Ends that were already closed are `None`.
+    pub fn into_ends(self) -> (Option<PipeReader>, Option<PipeWriter>) {
+        let [read_fd, write_fd] = self.into_raw_fds();
+        let owned = |fd: libc::c_int| Some(fd).filter(|fd| *fd != -1).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+        (owned(read_fd).map(PipeReader), owned(write_fd).map(PipeWriter))
+    }
+
     /// Connects a pipe end with another file descriptor.
     fn connect_pipe_end(&mut self, pe: PipeEnd, file_no: libc::c_int) {
         assert!(file_no == libc::STDIN_FILENO || file_no == libc::STDOUT_FILENO);
@@ -162,6 +259,83 @@ impl Pipe {
 
 }
 
+/// The read end of a pipe, see `Pipe::into_ends()`.
+#[derive(Debug)]
+pub struct PipeReader(OwnedFd);
+
+impl AsRawFd for PipeReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for PipeReader {
+    fn into_raw_fd(self) -> RawFd {
+        self.0.into_raw_fd()
+    }
+}
+
+/// The write end of a pipe, see `Pipe::into_ends()`.
+#[derive(Debug)]
+pub struct PipeWriter(OwnedFd);
+
+impl AsRawFd for PipeWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for PipeWriter {
+    fn into_raw_fd(self) -> RawFd {
+        self.0.into_raw_fd()
+    }
+}
+
+/// Creates a pipe and returns the raw FDs (index see `PipeEnd`).
+/// Both FDs are closed automatically during exec(). Otherwise every
+/// child that is forked while this pipe exists would keep the write
+/// end open and the reader would never see EOF. dup2() clears the
+/// flag on the duplicate, therefore STDIN/STDOUT stay connected.
+#[cfg(target_os = "linux")]
+pub(crate) fn new_cloexec_pipe() -> Result<[libc::c_int; 2], PiperError> {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    // sets the flag atomically: a fork() of another thread can't
+    // inherit the FDs without it
+    let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    if res == -1 {
+        return Err(PiperError::PipeCreationFailed(errno::errno().0));
+    }
+    Ok(fds)
+}
+
+/// Like the Linux version but without `pipe2()`. A child that is forked
+/// by another thread between `pipe()` and `fcntl()` inherits the FDs
+/// until it exec()s.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn new_cloexec_pipe() -> Result<[libc::c_int; 2], PiperError> {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    let res = unsafe { libc::pipe(fds.as_mut_ptr()) };
+    if res == -1 {
+        return Err(PiperError::PipeCreationFailed(errno::errno().0));
+    }
+    for fd in fds.iter() {
+        if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            let errno = errno::errno().0;
+            for fd in fds.iter() {
+                unsafe { libc::close(*fd) };
+            }
+            return Err(PiperError::FcntlFailed(errno));
+        }
+    }
+    Ok(fds)
+}
+
+impl Default for Pipe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for Pipe {
     /// Makes sure all FD's are closed when Pipe is dropped.
     fn drop(&mut self) {
@@ -177,3 +351,224 @@ impl Drop for Pipe {
         }
     }
 }
+
+/// A self-pipe that lets a signal handler wake up a thread, e.g. the one of
+/// the `Reaper`. It lives in a static and is never closed: a handler that
+/// runs while the thread is stopped would otherwise write into a closed
+/// FD, or into an unrelated file that reused the FD number meanwhile.
+/// Only one thread at a time uses it, see `acquire()`.
+#[derive(Debug)]
+pub(crate) struct SelfPipe {
+    read_fd: libc::c_int,
+    /// Nonblocking, so that the signal handler never blocks.
+    write_fd: libc::c_int,
+    /// Whether a thread uses the pipe.
+    busy: AtomicBool,
+}
+
+impl SelfPipe {
+    /// The pipe of `cell`; it's created on first use.
+    pub(crate) fn get(cell: &'static OnceLock<SelfPipe>) -> Result<&'static SelfPipe, PiperError> {
+        if let Some(pipe) = cell.get() {
+            return Ok(pipe);
+        }
+        let fds = new_cloexec_pipe()?;
+        unsafe { libc::fcntl(fds[PipeEnd::Write as usize], libc::F_SETFL, libc::O_NONBLOCK) };
+        let pipe = SelfPipe { read_fd: fds[PipeEnd::Read as usize], write_fd: fds[PipeEnd::Write as usize], busy: AtomicBool::new(false) };
+        // another thread may have been faster
+        if let Err(pipe) = cell.set(pipe) {
+            unsafe {
+                libc::close(pipe.read_fd);
+                libc::close(pipe.write_fd);
+            }
+        }
+        Ok(cell.get().unwrap())
+    }
+
+    /// Marks the pipe as used by the calling thread. Returns false if
+    /// another thread uses it.
+    pub(crate) fn acquire(&self) -> bool {
+        self.busy.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+    /// Counterpart of `acquire()`, after the thread stopped reading.
+    pub(crate) fn release(&self) {
+        self.busy.store(false, Ordering::SeqCst);
+    }
+
+    /// Wakes up the thread that waits in `wait()`, if any. Only
+    /// async-signal-safe calls, i.e. usable in a signal handler.
+    pub(crate) fn notify(&self) {
+        if self.busy.load(Ordering::SeqCst) {
+            // write() may change errno of the interrupted code
+            let saved = errno::errno();
+            // EAGAIN if the pipe is full; the thread wakes up anyway then
+            unsafe { libc::write(self.write_fd, [0_u8].as_ptr() as *const libc::c_void, 1) };
+            errno::set_errno(saved);
+        }
+    }
+
+    /// Blocks until `notify()` was called or a signal interrupted the call.
+    pub(crate) fn wait(&self) {
+        let mut buf = [0_u8; 64];
+        unsafe { libc::read(self.read_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    }
+}
+
+/// Blocks `SIGPIPE` for the calling thread while it exists, so that
+/// writing into a pipe whose reader is gone fails with `EPIPE` instead of
+/// terminating the process. Rust programs ignore `SIGPIPE` anyway, but
+/// e.g. a C program that uses the library usually doesn't. A `SIGPIPE`
+/// that was raised meanwhile is discarded on drop.
+pub(crate) struct SigpipeBlocked {
+    /// The signal mask of the thread before.
+    old_mask: libc::sigset_t,
+    /// Whether a `SIGPIPE` was already pending; it's kept then.
+    was_pending: bool,
+}
+
+impl SigpipeBlocked {
+    /// Blocks `SIGPIPE` for the calling thread.
+    pub(crate) fn new() -> Self {
+        let mut old_mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &sigpipe_set(), &mut old_mask) };
+        Self { old_mask, was_pending: sigpipe_pending() }
+    }
+}
+
+impl Drop for SigpipeBlocked {
+    fn drop(&mut self) {
+        if !self.was_pending && sigpipe_pending() {
+            // returns immediately because the signal is pending
+            let mut signal = 0;
+            unsafe { libc::sigwait(&sigpipe_set(), &mut signal) };
+        }
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &self.old_mask, std::ptr::null_mut()) };
+    }
+}
+
+/// A signal set that only contains `SIGPIPE`.
+fn sigpipe_set() -> libc::sigset_t {
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGPIPE);
+    }
+    set
+}
+
+/// Whether `SIGPIPE` is pending for the calling thread.
+fn sigpipe_pending() -> bool {
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::sigpending(&mut set) == 0 && libc::sigismember(&set, libc::SIGPIPE) == 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_nonblocking(fd: libc::c_int) -> bool {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        flags & libc::O_NONBLOCK != 0
+    }
+
+    #[test]
+    fn test_new_cloexec_pipe() {
+        let fds = new_cloexec_pipe().unwrap();
+        for fd in fds.iter() {
+            let flags = unsafe { libc::fcntl(*fd, libc::F_GETFD) };
+            assert_ne!(flags & libc::FD_CLOEXEC, 0);
+            unsafe { libc::close(*fd) };
+        }
+    }
+
+    #[test]
+    fn test_set_write_nonblocking() {
+        let mut pipe = Pipe::new();
+        assert!(!is_nonblocking(pipe.write_fd()));
+
+        pipe.set_write_nonblocking(true).unwrap();
+        assert!(is_nonblocking(pipe.write_fd()));
+        assert!(!is_nonblocking(pipe.read_fd()));
+
+        pipe.set_write_nonblocking(false).unwrap();
+        assert!(!is_nonblocking(pipe.write_fd()));
+
+        pipe.close_write_end();
+        assert_eq!(pipe.set_write_nonblocking(true), Err(PiperError::FcntlFailed(libc::EBADF)));
+    }
+
+    #[test]
+    fn test_raw_fd_api() {
+        use std::io::{Read, Write};
+
+        // a status channel on an extra FD
+        let mut pipe = Pipe::new();
+        // a free FD number; dup_to() replaces the duplicate
+        let status_fd = unsafe { libc::fcntl(pipe.read_fd(), libc::F_DUPFD_CLOEXEC, 100) };
+        assert_ne!(status_fd, -1);
+        pipe.dup_to(PipeEnd::Write, status_fd).unwrap();
+        pipe.close_write_end();
+        assert_eq!(pipe.write_fd(), -1);
+        assert_eq!(pipe.dup_to(PipeEnd::Write, status_fd), Err(PiperError::DupFailed(libc::EBADF)));
+
+        let mut writer = unsafe { std::fs::File::from_raw_fd(status_fd) };
+        writer.write_all(b"ready").unwrap();
+        drop(writer);
+        let fds = pipe.into_raw_fds();
+        assert_eq!(fds[PipeEnd::Write as usize], -1);
+        let mut reader = unsafe { std::fs::File::from_raw_fd(fds[PipeEnd::Read as usize]) };
+        let mut status = String::new();
+        reader.read_to_string(&mut status).unwrap();
+        assert_eq!(status, "ready");
+
+        let pipe = Pipe::new();
+        let fds = pipe.into_raw_fds();
+        // still open, i.e. not closed by drop
+        for fd in fds.iter() {
+            assert_eq!(unsafe { libc::close(*fd) }, 0);
+        }
+
+        let (reader, writer) = Pipe::new().into_ends();
+        let mut reader = unsafe { std::fs::File::from_raw_fd(reader.unwrap().into_raw_fd()) };
+        let writer = writer.unwrap();
+        assert_eq!(unsafe { libc::write(writer.as_raw_fd(), b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+        // closes the write end
+        drop(writer);
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "x");
+
+        let mut pipe = Pipe::new();
+        pipe.close_read_end();
+        let (reader, writer) = pipe.into_ends();
+        assert!(reader.is_none() && writer.is_some());
+    }
+
+    #[test]
+    fn test_sigpipe_blocked() {
+        let mut pipe = Pipe::new();
+        pipe.close_read_end();
+        let guard = SigpipeBlocked::new();
+        let res = unsafe { libc::write(pipe.write_fd(), [0_u8].as_ptr() as *const libc::c_void, 1) };
+        assert_eq!((res, errno::errno().0), (-1, libc::EPIPE));
+        drop(guard);
+        // discarded, i.e. it isn't raised after the signal is unblocked
+        assert!(!sigpipe_pending());
+    }
+
+    #[test]
+    fn test_self_pipe() {
+        static PIPE: OnceLock<SelfPipe> = OnceLock::new();
+        let pipe = SelfPipe::get(&PIPE).unwrap();
+        assert!(std::ptr::eq(pipe, SelfPipe::get(&PIPE).unwrap()));
+        assert!(pipe.acquire());
+        assert!(!pipe.acquire());
+        pipe.notify();
+        // returns because of the notification
+        pipe.wait();
+        pipe.release();
+        assert!(pipe.acquire());
+        pipe.release();
+    }
+}
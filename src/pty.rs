@@ -0,0 +1,414 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Execution of a command chain inside a pseudo terminal (pty), similar to
+//! what the `script` command does to record a terminal session.
+//! See https://man7.org/linux/man-pages/man7/pty.7.html
+
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread::JoinHandle;
+use crate::pipe::SelfPipe;
+use crate::wait::{deadline_of, Watchdog};
+use crate::{ensure_foreground, spawn_piped_cmd_chain, ChainHandle, ChainIo, CmdChain, ExecOptions,
+            PiperError, ProcessState, PtyMode};
+
+/// Runs a command chain with a single pseudo terminal as its terminal and
+/// records everything that gets written to it. Returns the process states
+/// of the finished processes and the recorded bytes.
+///
+/// With the default `PtyMode::AllStages` the slave side of the pty becomes
+///  * STDIN of the first command,
+///  * STDOUT of the last command, and
+///  * STDERR of every command.
+///
+/// The pipes between the commands stay pipes, hence only what a user
+/// would see on the terminal gets recorded. The parent reads the master
+/// side until all processes closed the slave side. If multiple commands
+/// write to the terminal at the same time their output gets interleaved in
+/// the order the kernel receives the `write()`s; there is no ordering
+/// between commands. Like on a real terminal, the line discipline converts
+/// `\n` into `\r\n`.
+///
+/// This function always waits for the processes, even if the chain
+/// is marked as background (but fails if it's required to run in
+/// background). Nothing writes to the master side, hence
+/// a command that reads from its STDIN (the terminal) blocks forever.
+///
+/// The processes stay in the session of the parent, i.e. the pty isn't
+/// their controlling terminal: programs that open `/dev/tty` don't find
+/// it and the kernel sends no job control signals like `SIGINT` for
+/// `^C` written to the master side.
+pub fn execute_piped_cmd_chain_in_pty(cmds: &CmdChain) -> Result<(Vec<ProcessState>, Vec<u8>), PiperError> {
+    ensure_foreground(cmds)?;
+    let (master, slave) = open_pty()?;
+
+    let io = pty_io(cmds.pty_mode(), slave);
+    let deadline = deadline_of(cmds);
+    let states = spawn_piped_cmd_chain(cmds, &io, &ExecOptions::default());
+
+    // Close the slave in the parent. Otherwise reading from the master
+    // never ends because there is always someone who could write.
+    unsafe { libc::close(slave) };
+
+    let mut master = unsafe { File::from_raw_fd(master) };
+    let mut states = states?;
+    let watchdog = Watchdog::start(&states, deadline);
+    let mut recorded = vec![];
+    let mut buf = [0_u8; 4096];
+    loop {
+        match master.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => recorded.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // Linux reports EIO after the last slave FD was closed
+            Err(_) => break,
+        }
+    }
+
+    drop(master);
+    watchdog.wait(&mut states)?;
+    Ok((states, recorded))
+}
+
+/// Spawns a command chain with a new pseudo terminal, attached according
+/// to `CmdChain::pty_mode()`, and returns a handle for the processes and
+/// the master side of the pty. The caller writes the input of the
+/// terminal (e.g. key strokes) into the master side and reads what the
+/// processes write to the terminal from it, including the echo of the
+/// input. Like in `execute_piped_cmd_chain_in_pty()` the line discipline
+/// converts `\n` into `\r\n`; reading fails with `EIO` instead of EOF
+/// after all processes closed the terminal.
+///
+/// The pty isn't the controlling terminal of the processes, hence
+/// programs that open `/dev/tty` don't find it. Otherwise like
+/// `spawn_piped_cmd_chain_handle()`.
+pub fn spawn_piped_cmd_chain_pty(cmds: &CmdChain) -> Result<(ChainHandle, File), PiperError> {
+    let (master, slave) = open_pty()?;
+    let master = unsafe { File::from_raw_fd(master) };
+    let states = spawn_piped_cmd_chain(cmds, &pty_io(cmds.pty_mode(), slave), &ExecOptions::default());
+    unsafe { libc::close(slave) };
+    Ok((ChainHandle::new(states?, cmds), master))
+}
+
+/// The FDs of the chain that are connected to the slave side of a pty.
+fn pty_io(mode: PtyMode, slave: libc::c_int) -> ChainIo {
+    let all = mode == PtyMode::AllStages;
+    ChainIo {
+        stdin: Some(slave).filter(|_| all || mode == PtyMode::FirstStage),
+        stdout: Some(slave).filter(|_| all || mode == PtyMode::LastStage),
+        stderr: Some(slave).filter(|_| all),
+    }
+}
+
+/// Copies the window size (rows and columns) of the terminal `from` to
+/// the terminal `to`, e.g. from the terminal of the parent to the master
+/// side of a pty. Fails with `PiperError::TerminalSizeFailed`, e.g. with
+/// `ENOTTY` if `from` isn't a terminal.
+pub fn copy_terminal_size(from: libc::c_int, to: libc::c_int) -> Result<(), PiperError> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(from, libc::TIOCGWINSZ, &mut size) } == -1
+        || unsafe { libc::ioctl(to, libc::TIOCSWINSZ, &size) } == -1 {
+        return Err(PiperError::TerminalSizeFailed(errno::errno().0));
+    }
+    Ok(())
+}
+
+/// The self-pipe of the forwarder thread.
+static WINCH_PIPE: OnceLock<SelfPipe> = OnceLock::new();
+
+/// The `SIGWINCH` handler: wakes up the forwarder thread, like the
+/// `SIGCHLD` handler of the `Reaper`.
+extern "C" fn on_sigwinch(_signal: libc::c_int) {
+    if let Some(pipe) = WINCH_PIPE.get() {
+        pipe.notify();
+    }
+}
+
+/// Forwards size changes of the terminal of the parent to a pty, so that
+/// full-screen programs in the chain render correctly. A `SIGWINCH`
+/// handler wakes up a thread of its own that copies the size (see
+/// `copy_terminal_size()`) and sends `SIGWINCH` to the processes of the
+/// chain; the kernel doesn't do the latter because the pty isn't their
+/// controlling terminal.
+///
+/// Only one forwarder can run at a time because the signal handler is
+/// process-wide; dropping the forwarder restores the previous handler.
+/// It should be dropped when the chain is finished, otherwise a signal
+/// may hit an unrelated process that reused a pid.
+pub struct WinsizeForwarder {
+    /// Wakes up the thread.
+    pipe: &'static SelfPipe,
+    /// Stops the thread at its next wake-up.
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    /// The `SIGWINCH` handler before the forwarder started.
+    old_action: libc::sigaction,
+}
+
+impl WinsizeForwarder {
+    /// Copies the size of `terminal` (e.g. `STDIN_FILENO` of an
+    /// interactive parent) to `master` right away and then after every
+    /// `SIGWINCH`, which is also forwarded to `pids`. Fails with
+    /// `PiperError::TerminalSizeFailed(EBUSY)` if another forwarder runs.
+    pub fn start(terminal: libc::c_int, master: &File, pids: Vec<libc::pid_t>) -> Result<Self, PiperError> {
+        // commands that run in the parent have no process (pid 0)
+        let pids: Vec<libc::pid_t> = pids.into_iter().filter(|pid| *pid > 0).collect();
+        copy_terminal_size(terminal, master.as_raw_fd())?;
+        let master = master.try_clone().map_err(|e| PiperError::TerminalSizeFailed(e.raw_os_error().unwrap_or(libc::EIO)))?;
+        let pipe = SelfPipe::get(&WINCH_PIPE)?;
+        if !pipe.acquire() {
+            return Err(PiperError::TerminalSizeFailed(libc::EBUSY));
+        }
+
+        let mut old_action: libc::sigaction = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sigwinch as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGWINCH, &action, &mut old_action)
+        };
+        if res == -1 {
+            let errno = errno::errno().0;
+            pipe.release();
+            return Err(PiperError::TerminalSizeFailed(errno));
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || loop {
+                pipe.wait();
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                // the parent may have lost its terminal meanwhile
+                if copy_terminal_size(terminal, master.as_raw_fd()).is_ok() {
+                    for pid in &pids {
+                        unsafe { libc::kill(*pid, libc::SIGWINCH) };
+                    }
+                }
+            })
+        };
+        Ok(Self { pipe, stop, thread: Some(thread), old_action })
+    }
+}
+
+impl fmt::Debug for WinsizeForwarder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WinsizeForwarder").field("stopped", &self.stop.load(Ordering::SeqCst)).finish()
+    }
+}
+
+impl Drop for WinsizeForwarder {
+    fn drop(&mut self) {
+        unsafe { libc::sigaction(libc::SIGWINCH, &self.old_action, std::ptr::null_mut()) };
+        self.stop.store(true, Ordering::SeqCst);
+        self.pipe.notify();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // the pipe stays open, a handler may still be running
+        self.pipe.release();
+    }
+}
+
+/// Gives the pty the size of the terminal of the parent, if it has one.
+fn inherit_terminal_size(master: libc::c_int) {
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].iter() {
+        if copy_terminal_size(*fd, master).is_ok() {
+            break;
+        }
+    }
+}
+
+/// Opens a new pty. Returns the `(master, slave)` FDs. Both have the
+/// close-on-exec flag from the start, so that they don't leak into
+/// programs that another thread executes meanwhile. The pty has the size
+/// of the terminal of the parent, if it has one. Neither side becomes the
+/// controlling terminal of the parent.
+#[cfg(target_os = "linux")]
+fn open_pty() -> Result<(libc::c_int, libc::c_int), PiperError> {
+    let flags = libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC;
+    let master = unsafe { libc::posix_openpt(flags) };
+    if master == -1 {
+        return Err(PiperError::PtyOpenFailed(errno::errno().0));
+    }
+    let mut name = [0 as libc::c_char; 64];
+    let slave = unsafe {
+        if libc::grantpt(master) == -1
+            || libc::unlockpt(master) == -1
+            || libc::ptsname_r(master, name.as_mut_ptr(), name.len()) != 0 {
+            -1
+        } else {
+            libc::open(name.as_ptr(), flags)
+        }
+    };
+    if slave == -1 {
+        let err = errno::errno().0;
+        unsafe { libc::close(master) };
+        return Err(PiperError::PtyOpenFailed(err));
+    }
+    inherit_terminal_size(master);
+    Ok((master, slave))
+}
+
+/// Like the Linux version, but `openpty()` can't set the close-on-exec
+/// flag: a program that another thread executes between `openpty()` and
+/// `fcntl()` inherits both FDs, which keeps the pty open.
+#[cfg(not(target_os = "linux"))]
+fn open_pty() -> Result<(libc::c_int, libc::c_int), PiperError> {
+    let mut master: libc::c_int = -1;
+    let mut slave: libc::c_int = -1;
+    let res = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if res == -1 {
+        return Err(PiperError::PtyOpenFailed(errno::errno().0));
+    }
+    for fd in [master, slave].iter() {
+        unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    inherit_terminal_size(master);
+    Ok((master, slave))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BasicCmdBuilder, Builder, CmdChainBuilder};
+
+    #[test]
+    fn test_execute_in_pty_records_output() {
+        // 'echo hello | tr a-z A-Z'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("echo")
+                    .add_arg("echo")
+                    .add_arg("hello")
+            ).add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("tr")
+                    .add_arg("tr")
+                    .add_arg("a-z")
+                    .add_arg("A-Z")
+            ).build();
+
+        let (states, recorded) = execute_piped_cmd_chain_in_pty(&cmd_chain).unwrap();
+        assert!(states.iter().all(|s| s.finished()));
+        // line discipline translates "\n" to "\r\n"
+        assert_eq!(String::from_utf8_lossy(&recorded), "HELLO\r\n");
+    }
+
+    #[test]
+    fn test_spawn_in_pty() {
+        use std::io::Write;
+
+        let sh = |script: &str| BasicCmdBuilder::new().set_executable("sh").add_arg("sh").add_arg("-c").add_arg(script);
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(sh("test -t 0"))
+            .set_pty_mode(PtyMode::FirstStage)
+            .build();
+        let (mut handle, _master) = spawn_piped_cmd_chain_pty(&cmd_chain).unwrap();
+        assert_eq!(handle.wait().unwrap()[0].exit_code(), 0);
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(sh("test -t 1 && test ! -t 0").set_input_redirect_path("/dev/null"))
+            .set_pty_mode(PtyMode::LastStage)
+            .build();
+        let (mut handle, _master) = spawn_piped_cmd_chain_pty(&cmd_chain).unwrap();
+        assert_eq!(handle.wait().unwrap()[0].exit_code(), 0);
+
+        // 'head -n 1' reads a line that is typed into the terminal
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("head").add_arg("head").add_arg("-n").add_arg("1"))
+            .build();
+        let (mut handle, mut master) = spawn_piped_cmd_chain_pty(&cmd_chain).unwrap();
+        master.write_all(b"typed\n").unwrap();
+        let mut recorded = vec![];
+        let mut buf = [0_u8; 64];
+        while let Ok(n) = master.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            recorded.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(handle.wait().unwrap()[0].exit_code(), 0);
+        // the echo of the input and the output of 'head'
+        assert_eq!(String::from_utf8_lossy(&recorded), "typed\r\ntyped\r\n");
+    }
+
+    #[test]
+    fn test_winsize_forwarder() {
+        let size = |fd: libc::c_int| {
+            let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+            unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) };
+            (size.ws_row, size.ws_col)
+        };
+        let set_size = |fd: libc::c_int, rows: u16, cols: u16| {
+            let size = libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 };
+            unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &size) };
+        };
+        // another pty plays the terminal of the parent
+        let (terminal_master, terminal) = open_pty().unwrap();
+        set_size(terminal_master, 24, 80);
+
+        let cmd_chain = crate::parse_cmd_chain("sleep 5").unwrap();
+        let (mut handle, master) = spawn_piped_cmd_chain_pty(&cmd_chain).unwrap();
+        let forwarder = WinsizeForwarder::start(terminal, &master, handle.pids()).unwrap();
+        assert_eq!(size(master.as_raw_fd()), (24, 80));
+        assert_eq!(WinsizeForwarder::start(terminal, &master, vec![]).unwrap_err(), PiperError::TerminalSizeFailed(libc::EBUSY));
+
+        set_size(terminal_master, 50, 132);
+        unsafe { libc::raise(libc::SIGWINCH) };
+        let start = std::time::Instant::now();
+        while size(master.as_raw_fd()) != (50, 132) && start.elapsed().as_secs() < 5 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(size(master.as_raw_fd()), (50, 132));
+        // 'sleep' ignores SIGWINCH
+        assert!(handle.try_wait().unwrap().is_none());
+
+        drop(forwarder);
+        handle.kill().unwrap();
+        handle.wait().unwrap();
+        assert_eq!(copy_terminal_size(master.as_raw_fd(), -1).unwrap_err(), PiperError::TerminalSizeFailed(libc::EBADF));
+        unsafe {
+            libc::close(terminal_master);
+            libc::close(terminal);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Reaping of background chains driven by `SIGCHLD` instead of polling
+//! `update_process_states()` in a loop.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use crate::pipe::SelfPipe;
+use crate::{try_update_process_states, PiperError, ProcessState};
+
+/// The self-pipe of the reaper thread.
+static WAKE_PIPE: OnceLock<SelfPipe> = OnceLock::new();
+
+/// The `SIGCHLD` handler: wakes up the reaper thread. Only async-signal-safe
+/// calls, i.e. no locks and no allocations.
+extern "C" fn on_sigchld(_signal: libc::c_int) {
+    if let Some(pipe) = WAKE_PIPE.get() {
+        pipe.notify();
+    }
+}
+
+/// Wakes up the running reaper, if any, like `SIGCHLD` does: for commands
+/// that run on a thread of the parent, which don't raise `SIGCHLD` when
+/// they finish.
+pub(crate) fn wake() {
+    on_sigchld(libc::SIGCHLD);
+}
+
+/// A chain whose processes are all finished: its ID from
+/// `Reaper::register()` and the states.
+pub type ReapedChain = (usize, Vec<ProcessState>);
+
+/// The chains whose processes aren't all finished yet, by ID.
+#[derive(Debug, Default)]
+struct Registry {
+    next_id: usize,
+    chains: HashMap<usize, Vec<ProcessState>>,
+}
+
+/// Waits for the processes of background chains in a thread of its own.
+/// A `SIGCHLD` handler wakes the thread up, which then updates the states
+/// of the registered chains with `WNOHANG`, i.e. it never waits for
+/// children that weren't registered. As soon as all processes of a chain
+/// are finished, the chain is sent to the receiver of `start()`, see
+/// `ReapedChain`.
+///
+/// Only one reaper can run at a time because the signal handler is
+/// process-wide; dropping the reaper restores the previous handler.
+/// Chains whose processes are waited for elsewhere (`ECHILD`) are never
+/// reported.
+pub struct Reaper {
+    registry: Arc<Mutex<Registry>>,
+    /// Wakes up the thread.
+    pipe: &'static SelfPipe,
+    /// Stops the thread at its next wake-up.
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    /// The `SIGCHLD` handler before the reaper started.
+    old_action: libc::sigaction,
+}
+
+impl Reaper {
+    /// Installs the `SIGCHLD` handler and starts the reaper thread. Fails
+    /// with `PiperError::ReaperFailed(EBUSY)` if another reaper runs.
+    pub fn start() -> Result<(Self, Receiver<ReapedChain>), PiperError> {
+        let pipe = SelfPipe::get(&WAKE_PIPE)?;
+        if !pipe.acquire() {
+            return Err(PiperError::ReaperFailed(libc::EBUSY));
+        }
+
+        let mut old_action: libc::sigaction = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sigchld as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGCHLD, &action, &mut old_action)
+        };
+        if res == -1 {
+            let errno = errno::errno().0;
+            pipe.release();
+            return Err(PiperError::ReaperFailed(errno));
+        }
+
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+        let thread = {
+            let (registry, stop) = (registry.clone(), stop.clone());
+            std::thread::spawn(move || reap_loop(pipe, &stop, &registry, &sender))
+        };
+        Ok((Self { registry, pipe, stop, thread: Some(thread), old_action }, receiver))
+    }
+
+    /// Hands the processes of a chain, e.g. of a background chain from
+    /// `execute_piped_cmd_chain()`, over to the reaper. Returns the ID the
+    /// chain is reported with.
+    pub fn register(&self, states: Vec<ProcessState>) -> usize {
+        let id = {
+            let mut registry = self.registry.lock().unwrap();
+            let id = registry.next_id;
+            registry.next_id += 1;
+            registry.chains.insert(id, states);
+            id
+        };
+        // the processes may have finished before the registration
+        self.wake();
+        id
+    }
+
+    /// Whether the chain with this ID has processes that aren't finished.
+    pub fn is_running(&self, id: usize) -> bool {
+        self.registry.lock().unwrap().chains.contains_key(&id)
+    }
+
+    /// The IDs of all chains that have processes that aren't finished.
+    pub fn running(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.registry.lock().unwrap().chains.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Lets the thread check the registered chains.
+    fn wake(&self) {
+        self.pipe.notify();
+    }
+}
+
+impl fmt::Debug for Reaper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reaper").field("running", &self.running()).finish()
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        unsafe { libc::sigaction(libc::SIGCHLD, &self.old_action, std::ptr::null_mut()) };
+        self.stop.store(true, Ordering::SeqCst);
+        self.wake();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // the pipe stays open, a handler may still be running
+        self.pipe.release();
+    }
+}
+
+/// The reaper thread: updates the registered chains after each wake-up
+/// until the reaper is dropped.
+fn reap_loop(pipe: &SelfPipe, stop: &AtomicBool, registry: &Mutex<Registry>, sender: &Sender<ReapedChain>) {
+    loop {
+        // EINTR (SIGCHLD itself) is a wake-up as well
+        pipe.wait();
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let mut registry = registry.lock().unwrap();
+        let finished: Vec<usize> = registry.chains.iter_mut()
+            .filter_map(|(id, states)| if try_update_process_states(states, true) == Ok(true) { Some(*id) } else { None })
+            .collect();
+        for id in finished {
+            let states = registry.chains.remove(&id).unwrap();
+            // the receiver may be gone; the chain is reaped anyway
+            let _ = sender.send((id, states));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::{execute_piped_cmd_chain, parse_cmd_chain};
+
+    #[test]
+    fn test_reaper() {
+        let (reaper, receiver) = Reaper::start().unwrap();
+        assert_eq!(Reaper::start().unwrap_err(), PiperError::ReaperFailed(libc::EBUSY));
+
+        let slow = reaper.register(execute_piped_cmd_chain(&parse_cmd_chain("sleep 0.3 | cat &").unwrap()).unwrap());
+        let fast = reaper.register(execute_piped_cmd_chain(&parse_cmd_chain("false &").unwrap()).unwrap());
+        assert!(reaper.is_running(slow));
+
+        let (id, states) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(id, fast);
+        assert_eq!(states[0].exit_code(), 1);
+        let (id, states) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(id, slow);
+        assert!(states.iter().all(|s| s.finished() && s.exit_code() == 0));
+        assert!(reaper.running().is_empty());
+
+        drop(reaper);
+        let (_reaper, _) = Reaper::start().unwrap();
+    }
+}
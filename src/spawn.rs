@@ -0,0 +1,461 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Pluggable creation of the processes of a chain, see
+//! `ExecOptions::set_spawn_backend()`. The executor creates the pipes and
+//! gets a `SpawnRequest` per command; a `SpawnBackend` starts the process.
+//! `ForkExecBackend` forks and sets up the child in Rust code and is the
+//! only backend that supports every feature of a chain.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use crate::libc_util::CStringArray;
+use crate::pipe::Pipe;
+use crate::{BasicCmd, ChainIo, CmdChain, ExecOptions, ExecPlan, PiperError};
+
+/// What the new process does with its file descriptors before `exec()`.
+/// The actions are applied in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileAction {
+    /// Opens `path` with `flags` and `mode` as FD `fd`, e.g. STDOUT for
+    /// an output redirect.
+    Open {
+        fd: libc::c_int,
+        path: CString,
+        flags: libc::c_int,
+        mode: libc::mode_t,
+    },
+    /// `dup2(from, to)`: FD `to` refers to the same file or pipe as
+    /// `from` afterwards.
+    Dup2 {
+        from: libc::c_int,
+        to: libc::c_int,
+    },
+}
+
+/// Everything a `SpawnBackend` needs to start the process of a command.
+/// All strings are already converted, i.e. nothing has to be allocated
+/// in the new process.
+#[derive(Debug)]
+pub struct SpawnRequest<'a> {
+    /// The executable of the command, for error messages.
+    pub(crate) executable: String,
+    pub(crate) cmds: &'a CmdChain,
+    pub(crate) plan: &'a ExecPlan,
+    pub(crate) io: &'a ChainIo,
+    pub(crate) opts: &'a ExecOptions,
+    /// The pipes of the chain that exist so far.
+    pub(crate) pipes: &'a [Pipe],
+    /// The pid of the first process of the chain; 0 for the first one.
+    pub(crate) leader: libc::pid_t,
+}
+
+impl SpawnRequest<'_> {
+    /// Getter for executable.
+    pub fn executable(&self) -> &str {
+        &self.executable
+    }
+    /// The command whose process is requested.
+    pub fn cmd(&self) -> &BasicCmd {
+        &self.cmds.cmds()[self.plan.index]
+    }
+    /// The executables that are tried in order, as long as they can't be
+    /// found (`ENOENT`): the one of the command and then its fallbacks.
+    /// Each entry has the paths of the `$PATH` lookup (see `execvp()`) and
+    /// the argv for this executable.
+    pub fn candidates(&self) -> &[(Vec<CString>, CStringArray)] {
+        &self.plan.candidates
+    }
+    /// The environment of the process.
+    pub fn envp(&self) -> &CStringArray {
+        &self.plan.envp
+    }
+    /// The working directory of the process, if it differs from the one
+    /// of the parent.
+    pub fn work_dir(&self) -> Option<&CStr> {
+        self.plan.work_dir.as_deref()
+    }
+    /// The FD setup of the process: the pipes, redirects and
+    /// duplications of the command.
+    pub fn actions(&self) -> Vec<FileAction> {
+        crate::file_actions(self.cmds, self.plan, self.io, self.pipes)
+    }
+    /// The process group the process joins (0 for a new group with the
+    /// pid of the process as pgid), see `ExecOptions::set_process_group()`.
+    pub fn pgid(&self) -> Option<libc::pid_t> {
+        if self.opts.process_group() {
+            Some(self.leader)
+        } else {
+            None
+        }
+    }
+}
+
+/// Why `SpawnBackend::spawn()` failed. If the process was created but
+/// failed before `exec()`, it holds its pid: the process exits on its own
+/// with 127, 126 or 1 (see `ProcessState::spawn_error()`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpawnFailure {
+    pub(crate) error: PiperError,
+    pub(crate) pid: Option<libc::pid_t>,
+}
+
+impl SpawnFailure {
+    /// Constructor for a failure of a process that was created.
+    pub fn with_pid(error: PiperError, pid: libc::pid_t) -> Self {
+        Self { error, pid: Some(pid) }
+    }
+    /// Getter for error.
+    pub fn error(&self) -> &PiperError {
+        &self.error
+    }
+    /// Getter for pid.
+    pub fn pid(&self) -> Option<libc::pid_t> {
+        self.pid
+    }
+}
+
+impl From<PiperError> for SpawnFailure {
+    fn from(error: PiperError) -> Self {
+        Self { error, pid: None }
+    }
+}
+
+/// Creates the process of a single command of a chain. The executor
+/// creates the pipes, calls `spawn()` for every command in order and
+/// waits for the processes afterwards.
+pub trait SpawnBackend: fmt::Debug + Send + Sync {
+    /// Starts the process described by `request` and returns its pid.
+    fn spawn(&self, request: &SpawnRequest) -> Result<libc::pid_t, SpawnFailure>;
+
+    /// The first feature of the chain the backend doesn't support; the
+    /// chain fails with `PiperError::Unsupported` before any process
+    /// exists then. Supports everything by default.
+    fn unsupported_feature(&self, _cmds: &CmdChain, _opts: &ExecOptions) -> Option<&'static str> {
+        None
+    }
+}
+
+/// The default backend: `fork()` and the setup of the child in Rust code
+/// with only async-signal-safe calls before `exec()`. Supports every
+/// feature of a chain and reports failures of the child (e.g. of a
+/// redirect) with the matching `PiperError`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ForkExecBackend;
+
+impl SpawnBackend for ForkExecBackend {
+    fn spawn(&self, request: &SpawnRequest) -> Result<libc::pid_t, SpawnFailure> {
+        crate::fork_exec(request)
+    }
+}
+
+/// Backend that uses `posix_spawn()`. The C library can create the process
+/// without copying the page tables of the parent (`vfork()`-like), which is
+/// much faster for big parents and long pipelines, and no code of the
+/// parent runs in the new process.
+///
+/// `posix_spawn()` only knows file actions and process groups, therefore
+/// chains with a function or builtin, a working directory, another
+/// user or groups, a new mount namespace, a terminal or
+/// `CmdChainBuilder::set_close_fds()` fail with `PiperError::Unsupported`.
+/// It also can't tell which step failed, hence errors are reported as
+/// `PiperError::SpawnFailed`, e.g. `ENOENT` if either the executable or the
+/// file of an input redirect doesn't exist.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PosixSpawnBackend;
+
+impl SpawnBackend for PosixSpawnBackend {
+    fn spawn(&self, request: &SpawnRequest) -> Result<libc::pid_t, SpawnFailure> {
+        unsafe {
+            let mut actions: libc::posix_spawn_file_actions_t = std::mem::zeroed();
+            let mut attr: libc::posix_spawnattr_t = std::mem::zeroed();
+            libc::posix_spawn_file_actions_init(&mut actions);
+            libc::posix_spawnattr_init(&mut attr);
+            let res = prepare(request, &mut actions, &mut attr)
+                .and_then(|_| spawn_candidates(request, &actions, &attr));
+            libc::posix_spawn_file_actions_destroy(&mut actions);
+            libc::posix_spawnattr_destroy(&mut attr);
+            Ok(res?)
+        }
+    }
+
+    fn unsupported_feature(&self, cmds: &CmdChain, opts: &ExecOptions) -> Option<&'static str> {
+        crate::fork_only_feature(cmds, opts)
+    }
+}
+
+/// Translates the request into file actions and attributes.
+unsafe fn prepare(request: &SpawnRequest,
+                  actions: &mut libc::posix_spawn_file_actions_t,
+                  attr: &mut libc::posix_spawnattr_t) -> Result<(), PiperError> {
+    let spawn_failed = |errno| PiperError::SpawnFailed { executable: request.executable().to_owned(), errno };
+    for action in &request.actions() {
+        let res = match action {
+            FileAction::Open { fd, path, flags, mode } => {
+                libc::posix_spawn_file_actions_addopen(actions, *fd, path.as_ptr(), *flags, *mode)
+            }
+            FileAction::Dup2 { from, to } => libc::posix_spawn_file_actions_adddup2(actions, *from, *to),
+        };
+        if res != 0 {
+            return Err(spawn_failed(res));
+        }
+    }
+    if let Some(pgid) = request.pgid() {
+        let res = libc::posix_spawnattr_setflags(attr, libc::POSIX_SPAWN_SETPGROUP as libc::c_short);
+        let res = if res == 0 { libc::posix_spawnattr_setpgroup(attr, pgid) } else { res };
+        if res != 0 {
+            return Err(spawn_failed(res));
+        }
+    }
+    Ok(())
+}
+
+/// Like the exec() loop of a forked child: the candidates as long as they
+/// can't be found and for each the paths of the `$PATH` lookup.
+unsafe fn spawn_candidates(request: &SpawnRequest,
+                           actions: &libc::posix_spawn_file_actions_t,
+                           attr: &libc::posix_spawnattr_t) -> Result<libc::pid_t, PiperError> {
+    let mut errno = libc::ENOENT;
+    for (paths, argv) in request.candidates() {
+        errno = libc::ENOENT;
+        for path in paths {
+            let mut pid: libc::pid_t = -1;
+            let res = libc::posix_spawn(
+                &mut pid,
+                path.as_ptr(),
+                actions,
+                attr,
+                argv.as_ptr() as *const *mut libc::c_char,
+                request.envp().as_ptr() as *const *mut libc::c_char,
+            );
+            match res {
+                0 => return Ok(pid),
+                libc::ENOENT | libc::ENOTDIR => {}
+                libc::EACCES => errno = libc::EACCES,
+                other => {
+                    errno = other;
+                    break;
+                }
+            }
+        }
+        if errno != libc::ENOENT {
+            break;
+        }
+    }
+    Err(PiperError::SpawnFailed { executable: request.executable().to_owned(), errno })
+}
+
+/// Portable backend on top of `std::process::Command`, for users who want
+/// the data model of this crate but no raw `fork()`. Supports working
+/// directories, users and process groups.
+///
+/// `Command` only sets up STDIN, STDOUT and STDERR, hence the file actions
+/// are resolved in the parent (redirects are opened there) and chains with
+/// other FD duplications, groups, a new mount namespace, a terminal or
+/// `CmdChainBuilder::set_close_fds()` fail with `PiperError::Unsupported`.
+/// Errors are reported as `PiperError::SpawnFailed`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StdCommandBackend;
+
+impl SpawnBackend for StdCommandBackend {
+    fn spawn(&self, request: &SpawnRequest) -> Result<libc::pid_t, SpawnFailure> {
+        let spawn_failed = |errno| PiperError::SpawnFailed { executable: request.executable().to_owned(), errno };
+        // the files of the redirects stay open until the process exists
+        let mut opened = vec![];
+        // the FD of the parent for STDIN, STDOUT and STDERR; `None` inherits it
+        let mut stdio: [Option<libc::c_int>; 3] = [None; 3];
+        for action in request.actions() {
+            match action {
+                FileAction::Open { fd, path, flags, mode } => {
+                    let file = unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC, mode as libc::c_uint) };
+                    if file == -1 {
+                        return Err(spawn_failed(errno::errno().0).into());
+                    }
+                    opened.push(unsafe { File::from_raw_fd(file) });
+                    stdio[fd as usize] = Some(file);
+                }
+                FileAction::Dup2 { from, to } => {
+                    let source = if from <= libc::STDERR_FILENO { stdio[from as usize] } else { None };
+                    stdio[to as usize] = source.or(Some(from));
+                }
+            }
+        }
+
+        let mut errno = libc::ENOENT;
+        for (paths, argv) in request.candidates() {
+            errno = libc::ENOENT;
+            for path in paths {
+                match std_command(request, path, argv, &stdio).and_then(|mut command| command.spawn()) {
+                    // not waited for by `Child`; the executor reaps the pid
+                    Ok(child) => return Ok(child.id() as libc::pid_t),
+                    Err(e) => match e.raw_os_error().unwrap_or(libc::EIO) {
+                        libc::ENOENT | libc::ENOTDIR => {}
+                        libc::EACCES => errno = libc::EACCES,
+                        other => {
+                            errno = other;
+                            break;
+                        }
+                    },
+                }
+            }
+            if errno != libc::ENOENT {
+                break;
+            }
+        }
+        Err(spawn_failed(errno).into())
+    }
+
+    fn unsupported_feature(&self, cmds: &CmdChain, opts: &ExecOptions) -> Option<&'static str> {
+        let cmd_feature = |cmd: &BasicCmd| {
+            if cmd.groups().is_some() {
+                Some("groups")
+            } else if cmd.fd_dups().iter().any(|dup| dup.from() > libc::STDERR_FILENO) {
+                Some("FD duplication")
+            } else {
+                None
+            }
+        };
+        match crate::fork_only_feature(cmds, opts) {
+            Some("working directory") | Some("user or groups") | None => cmds.cmds().iter().find_map(cmd_feature),
+            feature => feature,
+        }
+    }
+}
+
+/// The `Command` for one path of the `$PATH` lookup.
+fn std_command(request: &SpawnRequest,
+               path: &CStr,
+               argv: &CStringArray,
+               stdio: &[Option<libc::c_int>; 3]) -> io::Result<Command> {
+    let os_str = |string: &CStr| OsStr::from_bytes(string.to_bytes()).to_owned();
+    let mut command = Command::new(os_str(path));
+    if let Some((argv0, args)) = argv.strings().split_first() {
+        command.arg0(os_str(argv0)).args(args.iter().map(|arg| os_str(arg)));
+    }
+    command.env_clear();
+    for entry in request.envp().strings() {
+        let entry = entry.to_bytes();
+        if let Some(pos) = entry.iter().position(|b| *b == b'=') {
+            command.env(OsStr::from_bytes(&entry[..pos]), OsStr::from_bytes(&entry[pos + 1..]));
+        }
+    }
+    if let Some(work_dir) = request.work_dir() {
+        command.current_dir(os_str(work_dir));
+    }
+    if let Some(uid) = request.cmd().uid() {
+        command.uid(uid);
+    }
+    if let Some(gid) = request.cmd().gid() {
+        command.gid(gid);
+    }
+    if let Some(pgid) = request.pgid() {
+        command.process_group(pgid);
+    }
+    // `Stdio` owns its FD, hence a duplicate of the FD of the parent
+    let to_stdio = |fd: Option<libc::c_int>| -> io::Result<Stdio> {
+        match fd {
+            Some(fd) => match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+                -1 => Err(io::Error::last_os_error()),
+                dup => Ok(Stdio::from(unsafe { File::from_raw_fd(dup) })),
+            },
+            None => Ok(Stdio::inherit()),
+        }
+    };
+    command.stdin(to_stdio(stdio[0])?).stdout(to_stdio(stdio[1])?).stderr(to_stdio(stdio[2])?);
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute_piped_cmd_chain_with_options, parse_cmd_chain, BasicCmdBuilder, Builder, CmdChainBuilder, ExecOptions};
+
+    #[test]
+    fn test_posix_spawn() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_posix_spawn_{}.txt", std::process::id()));
+        let opts = ExecOptions::new().set_spawn_backend(PosixSpawnBackend).set_process_group(true);
+
+        let cmd_chain = parse_cmd_chain(&format!("echo hello | tr a-z A-Z | cat > {}", out_file.display())).unwrap();
+        let states = execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+        assert!(states.iter().all(|s| s.finished() && s.exit_code() == 0));
+        assert_eq!(states[1].pgid(), Some(states[0].pid()));
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "HELLO\n");
+
+        let cmd_chain = parse_cmd_chain("does-not-exist | cat").unwrap();
+        assert_eq!(
+            execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap_err(),
+            PiperError::SpawnFailed { executable: "does-not-exist".to_owned(), errno: libc::ENOENT }
+        );
+
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("pwd").add_arg("pwd").set_working_dir("/"))
+            .build();
+        assert_eq!(
+            execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap_err(),
+            PiperError::Unsupported("working directory")
+        );
+
+        std::fs::remove_file(&out_file).unwrap();
+    }
+
+    #[test]
+    fn test_std_command_backend() {
+        let out_file = std::env::temp_dir().join(format!("unix_exec_piper_std_command_{}.txt", std::process::id()));
+        let opts = ExecOptions::new().set_spawn_backend(StdCommandBackend).set_process_group(true);
+
+        // 'cd / && pwd | tr / R > out_file 2>&1' where tr is a fallback
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("pwd").add_arg("pwd").set_working_dir("/"))
+            .add_cmd(BasicCmdBuilder::new()
+                .set_executable("does-not-exist")
+                .add_fallback("tr")
+                .add_arg("does-not-exist")
+                .add_arg("/")
+                .add_arg("R")
+                .set_output_redirect_path(&out_file)
+                .merge_stderr_into_stdout(true))
+            .build();
+        let states = execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+        assert!(states.iter().all(|s| s.finished() && s.exit_code() == 0));
+        assert_eq!(states[1].pgid(), Some(states[0].pid()));
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "R\n");
+        std::fs::remove_file(&out_file).unwrap();
+
+        let cmd_chain = parse_cmd_chain("true 3>&1").unwrap();
+        assert_eq!(
+            execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap_err(),
+            PiperError::Unsupported("FD duplication")
+        );
+        // the default backend
+        let opts = ExecOptions::new().set_spawn_backend(ForkExecBackend);
+        let states = execute_piped_cmd_chain_with_options(&cmd_chain, &opts).unwrap();
+        assert!(states[0].finished());
+    }
+}
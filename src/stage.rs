@@ -0,0 +1,246 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Commands of a chain that run on a thread of the parent instead of in a
+//! process of their own: subshells and functions (see
+//! `BasicCmdBuilder::set_subshell()` and `BasicCmdBuilder::set_function()`).
+//! In a forked child they would run code of this crate between `fork()` and
+//! `exec()`, which may deadlock if another thread of the parent held a lock
+//! of the allocator meanwhile, see `ExecPlan`.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use crate::pipe::new_cloexec_pipe;
+use crate::spawn::FileAction;
+use crate::{file_actions, list, BasicCmd, ChainIo, CmdChain, ExecOptions, ExecPlan, Pipe, PipeEnd, PiperError};
+
+/// Exit code of a stage whose thread panicked, like the one of a Rust
+/// program.
+const PANIC_EXIT_CODE: libc::c_int = 101;
+
+/// The thread of a command that runs in the parent. It's shared between the
+/// thread and the `ProcessState` of the command, whose pid is 0.
+#[derive(Debug)]
+pub(crate) struct StageThread {
+    /// The exit code, as soon as the thread is finished.
+    exit_code: Mutex<Option<libc::c_int>>,
+    /// Read end of a pipe whose write end the thread closes when it's
+    /// finished, i.e. it becomes readable (EOF) like a pidfd.
+    done: File,
+    /// The processes the stage started that are still running, e.g. the
+    /// ones of a subshell; signals to the stage are forwarded to them.
+    pids: Mutex<Vec<libc::pid_t>>,
+    /// The first signal to the stage that terminates a process by default;
+    /// like a shell process the stage doesn't start anything afterwards.
+    terminated_by: Mutex<Option<libc::c_int>>,
+}
+
+impl StageThread {
+    /// The exit code if the thread is finished. With `block` it waits
+    /// until the thread is finished.
+    pub(crate) fn exit_code(&self, block: bool) -> Option<libc::c_int> {
+        if block {
+            // EINTR is retried; EOF as soon as the thread is finished
+            while !matches!((&self.done).read(&mut [0_u8; 1]), Ok(0)) {}
+        }
+        *self.exit_code.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// An FD that becomes readable when the thread is finished, e.g.
+    /// for `poll()`.
+    pub(crate) fn done_fd(&self) -> libc::c_int {
+        self.done.as_raw_fd()
+    }
+
+    /// Sends the signal to the running processes of the stage. All are
+    /// tried even if sending to one fails; the first error is returned.
+    pub(crate) fn send_signal(&self, signal: libc::c_int) -> Result<(), PiperError> {
+        let ignored_or_stops = [libc::SIGCHLD, libc::SIGCONT, libc::SIGSTOP, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU,
+                                libc::SIGURG, libc::SIGWINCH];
+        if signal != 0 && !ignored_or_stops.contains(&signal) {
+            self.terminated_by.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert(signal);
+        }
+        let mut res = Ok(());
+        for pid in self.pids.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            if unsafe { libc::kill(*pid, signal) } == -1 {
+                res = res.and(Err(PiperError::SignalFailed { pid: *pid, errno: errno::errno().0 }));
+            }
+        }
+        res
+    }
+
+    /// The first signal that terminated the stage, see `send_signal()`.
+    pub(crate) fn terminated_by(&self) -> Option<libc::c_int> {
+        *self.terminated_by.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Registers processes the stage started; unregister them before they
+    /// are reaped. If the stage was already terminated, they get the signal
+    /// right away.
+    pub(crate) fn register(&self, pids: &[libc::pid_t]) {
+        let mut registered = self.pids.lock().unwrap_or_else(|e| e.into_inner());
+        for pid in pids.iter().filter(|pid| **pid > 0) {
+            if let Some(signal) = self.terminated_by() {
+                unsafe { libc::kill(*pid, signal) };
+            }
+            registered.push(*pid);
+        }
+    }
+
+    /// Counterpart of `register()`.
+    pub(crate) fn unregister(&self, pids: &[libc::pid_t]) {
+        self.pids.lock().unwrap_or_else(|e| e.into_inner()).retain(|pid| !pids.contains(pid));
+    }
+}
+
+/// Whether the command runs on a thread of the parent, see `spawn_stage()`.
+pub(crate) fn runs_on_thread(cmd: &BasicCmd) -> bool {
+    thread_feature(cmd).is_some()
+}
+
+/// What kind of command runs on a thread of the parent, for
+/// `PiperError::Unsupported`. `None` for a normal command.
+pub(crate) fn thread_feature(cmd: &BasicCmd) -> Option<&'static str> {
+    if cmd.subshell().is_some() {
+        Some("subshells")
+    } else if cmd.is_function() {
+        Some("functions")
+    } else {
+        None
+    }
+}
+
+/// Starts the thread of command `plan.index` of the chain with the STDIN,
+/// STDOUT and STDERR the process of a normal command would get: the ends
+/// of the neighboring pipes, the redirects and the FDs of `io`. The thread
+/// owns duplicates of them, i.e. the caller closes the pipes as usual.
+pub(crate) fn spawn_stage(cmds: &CmdChain,
+                          plan: &ExecPlan,
+                          io: &ChainIo,
+                          opts: &ExecOptions,
+                          pipes: &[Pipe]) -> Result<Arc<StageThread>, PiperError> {
+    let cmd = &cmds.cmds()[plan.index];
+    if cmd.work_dir().is_some() {
+        return Err(PiperError::Unsupported("working directory"));
+    }
+    if cmd.uid().is_some() || cmd.gid().is_some() || cmd.groups().is_some() {
+        return Err(PiperError::Unsupported("user or groups"));
+    }
+    let [stdin, stdout, stderr] = open_stdio(cmds, plan, io, pipes)?;
+    let fds = new_cloexec_pipe()?;
+    let (done, done_write) = unsafe {
+        (File::from_raw_fd(fds[PipeEnd::Read as usize]), File::from_raw_fd(fds[PipeEnd::Write as usize]))
+    };
+    let stage = Arc::new(StageThread { exit_code: Mutex::new(None), done, pids: Mutex::new(vec![]), terminated_by: Mutex::new(None) });
+
+    let thread_stage = stage.clone();
+    let cmd = cmd.clone();
+    let opts = opts.clone();
+    std::thread::Builder::new()
+        .spawn(move || {
+            let exit_code = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                run_stage(&cmd, &opts, stdin, stdout, stderr, &thread_stage)
+            })).unwrap_or(PANIC_EXIT_CODE);
+            thread_stage.exit_code.lock().unwrap_or_else(|e| e.into_inner()).replace(exit_code);
+            // after the exit code is set
+            drop(done_write);
+            crate::reaper::wake();
+        })
+        .map_err(|e| PiperError::ThreadSpawnFailed(e.raw_os_error().unwrap_or(libc::EAGAIN)))?;
+    Ok(stage)
+}
+
+/// Thread code: runs the command. The FDs are closed when it returns, i.e.
+/// the next command sees EOF.
+fn run_stage(cmd: &BasicCmd,
+             opts: &ExecOptions,
+             stdin: File,
+             stdout: File,
+             stderr: File,
+             stage: &StageThread) -> libc::c_int {
+    if let Some(function) = cmd.function() {
+        let mut stdout = BufWriter::new(stdout);
+        let exit_code = function(&mut &stdin, &mut stdout);
+        // e.g. EPIPE if the next command doesn't read everything
+        let _ = stdout.flush();
+        return exit_code;
+    }
+    let io = ChainIo {
+        stdin: Some(stdin.as_raw_fd()),
+        stdout: Some(stdout.as_raw_fd()),
+        stderr: Some(stderr.as_raw_fd()),
+    };
+    // like a shell, a command of the list that can't be started only fails
+    // its chain; the processes of the list aren't in the group of the chain
+    let opts = opts.clone()
+        .set_spawn_failures_as_exit_codes(true)
+        .set_process_group(false)
+        .with_subshell_env(cmd.clear_env(), cmd.env());
+    list::run_in_stage(cmd.subshell().unwrap(), &io, &opts, stage)
+}
+
+/// Opens STDIN, STDOUT and STDERR of a command that runs on a thread: the
+/// file actions of the process it would have (see `file_actions()`) are
+/// applied in the parent. FD duplications other than to STDIN, STDOUT or
+/// STDERR aren't supported.
+fn open_stdio(cmds: &CmdChain, plan: &ExecPlan, io: &ChainIo, pipes: &[Pipe]) -> Result<[File; 3], PiperError> {
+    // the files of the redirects stay open until they are duplicated
+    let mut opened = vec![];
+    // the FD of the parent that becomes STDIN, STDOUT or STDERR
+    let mut stdio = [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO];
+    for action in file_actions(cmds, plan, io, pipes) {
+        match action {
+            FileAction::Open { fd, path, flags, mode } => {
+                let file = unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC, mode as libc::c_uint) };
+                if file == -1 {
+                    return Err(PiperError::RedirectOpenFailed {
+                        path: path.to_string_lossy().into_owned(),
+                        errno: errno::errno().0,
+                    });
+                }
+                opened.push(unsafe { File::from_raw_fd(file) });
+                stdio[fd as usize] = file;
+            }
+            FileAction::Dup2 { to, .. } if to > libc::STDERR_FILENO => {
+                return Err(PiperError::Unsupported("FD duplication"));
+            }
+            FileAction::Dup2 { from, to } => {
+                stdio[to as usize] = if from <= libc::STDERR_FILENO { stdio[from as usize] } else { from };
+            }
+        }
+    }
+    let dup = |fd: libc::c_int| match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+        // e.g. STDIN of the parent is closed
+        -1 if errno::errno().0 == libc::EBADF && fd <= libc::STDERR_FILENO => {
+            std::fs::OpenOptions::new().read(true).write(true).open("/dev/null")
+                .map_err(|e| PiperError::DupFailed(e.raw_os_error().unwrap_or(libc::EBADF)))
+        }
+        -1 => Err(PiperError::DupFailed(errno::errno().0)),
+        dup => Ok(unsafe { File::from_raw_fd(dup) }),
+    };
+    Ok([dup(stdio[0])?, dup(stdio[1])?, dup(stdio[2])?])
+}
@@ -0,0 +1,139 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! A read-only snapshot of a `CmdChain`, e.g. to display a chain in a UI
+//! without depending on the internals of the data model.
+
+use crate::{CmdChain, OutputRedirectMode};
+
+/// Snapshot of a command chain, see `CmdChain::summary()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainSummary {
+    /// One entry per command in the order of the chain.
+    stages: Vec<StageSummary>,
+    /// Whether the chain runs in background.
+    background: bool,
+    /// Number of pipes between the commands.
+    pipe_count: usize,
+}
+
+impl ChainSummary {
+    /// Getter for stages.
+    pub fn stages(&self) -> &Vec<StageSummary> {
+        &self.stages
+    }
+    /// Getter for background.
+    pub fn background(&self) -> bool {
+        self.background
+    }
+    /// Getter for pipe_count.
+    pub fn pipe_count(&self) -> usize {
+        self.pipe_count
+    }
+}
+
+/// Snapshot of a single command of a chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageSummary {
+    /// The executable, e.g. `grep`.
+    name: String,
+    /// The args without args[0].
+    args: Vec<String>,
+    /// Path of the `<` redirect.
+    input_redirect: Option<String>,
+    /// Path and mode of the `>`, `>>` or `>|` redirect.
+    output_redirect: Option<(String, OutputRedirectMode)>,
+    /// Path of the `2>` redirect.
+    error_redirect: Option<String>,
+}
+
+impl StageSummary {
+    /// Getter for name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Getter for args.
+    pub fn args(&self) -> &Vec<String> {
+        &self.args
+    }
+    /// Getter for input_redirect.
+    pub fn input_redirect(&self) -> Option<&str> {
+        self.input_redirect.as_deref()
+    }
+    /// Getter for output_redirect.
+    pub fn output_redirect(&self) -> Option<(&str, OutputRedirectMode)> {
+        self.output_redirect.as_ref().map(|(path, mode)| (path.as_str(), *mode))
+    }
+    /// Getter for error_redirect.
+    pub fn error_redirect(&self) -> Option<&str> {
+        self.error_redirect.as_deref()
+    }
+}
+
+impl CmdChain {
+    /// Returns an owned snapshot of the chain: the commands, their
+    /// redirects, the background flag and the number of pipes. Bytes that
+    /// aren't valid UTF-8 are replaced with `U+FFFD`.
+    pub fn summary(&self) -> ChainSummary {
+        let stages = self.cmds().iter()
+            .map(|cmd| StageSummary {
+                name: cmd.executable().to_string_lossy().into_owned(),
+                args: cmd.args().iter().skip(1).map(|arg| arg.to_string_lossy().into_owned()).collect(),
+                input_redirect: cmd.in_red_path().as_ref().map(|path| path.to_string_lossy().into_owned()),
+                output_redirect: cmd.out_red_path().as_ref()
+                    .map(|path| (path.to_string_lossy().into_owned(), cmd.out_red_mode())),
+                error_redirect: cmd.err_red_path().as_ref().map(|path| path.to_string_lossy().into_owned()),
+            })
+            .collect();
+        ChainSummary {
+            stages,
+            background: self.background(),
+            pipe_count: self.length().saturating_sub(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_cmd_chain;
+
+    #[test]
+    fn test_summary() {
+        let cmd_chain = parse_cmd_chain("cat < in.txt | grep -i abc | wc -l >> out.txt &").unwrap();
+        let summary = cmd_chain.summary();
+        assert!(summary.background());
+        assert_eq!(summary.pipe_count(), 2);
+        assert_eq!(summary.stages().len(), 3);
+
+        let stages = summary.stages();
+        assert_eq!(stages[0].name(), "cat");
+        assert_eq!(stages[0].input_redirect(), Some("in.txt"));
+        assert_eq!(stages[1].args(), &vec!["-i".to_string(), "abc".to_string()]);
+        assert_eq!(stages[1].input_redirect(), None);
+        assert_eq!(stages[1].output_redirect(), None);
+        assert_eq!(stages[2].output_redirect(), Some(("out.txt", OutputRedirectMode::Append)));
+        assert_eq!(stages[2].error_redirect(), None);
+    }
+}
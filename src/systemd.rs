@@ -0,0 +1,327 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Execution of a command chain inside a transient systemd scope (Linux),
+//! so that the processes are tracked as a unit with cgroup accounting.
+//! The chain is started via `systemd-run --scope` which runs the
+//! reconstructed command line with `sh -c`.
+//! See https://www.freedesktop.org/software/systemd/man/systemd-run.html
+
+use std::ffi::OsStr;
+use crate::{execute_piped_cmd_chain, BasicCmd, BasicCmdBuilder, BuildError, Builder, CmdChain, CmdChainBuilder, PiperError,
+            ProcessState};
+
+/// Settings of the transient scope unit.
+#[derive(Debug, Default, Clone)]
+pub struct SystemdScope {
+    /// Optional name of the unit (`--unit`). systemd generates one otherwise.
+    unit: Option<String>,
+    /// Whether the scope is created in the user's service manager (`--user`).
+    user: bool,
+    /// Properties of the unit like `MemoryMax=1G` (`--property`).
+    properties: Vec<String>,
+}
+
+impl SystemdScope {
+    /// Constructor. Same as `SystemdScope::default()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the name of the unit.
+    pub fn set_unit(mut self, unit: &str) -> Self {
+        self.unit.replace(unit.to_string());
+        self
+    }
+    /// Creates the scope in the user's service manager instead of the system one.
+    pub fn set_user(mut self, user: bool) -> Self {
+        self.user = user;
+        self
+    }
+    /// Adds a property of the unit, e.g. `MemoryMax=1G`.
+    pub fn add_property(mut self, property: &str) -> Self {
+        self.properties.push(property.to_string());
+        self
+    }
+
+    /// Getter for unit.
+    pub fn unit(&self) -> Option<&str> {
+        self.unit.as_deref()
+    }
+    /// Getter for user.
+    pub fn user(&self) -> bool {
+        self.user
+    }
+    /// Getter for properties.
+    pub fn properties(&self) -> &Vec<String> {
+        &self.properties
+    }
+
+    /// Constructs the `systemd-run` invocation (including argv[0]) that
+    /// runs the chain inside the scope, e.g.
+    /// `systemd-run --scope --quiet -- sh -c 'cat < in.txt | wc -l'`.
+    /// Whether the chain runs in background is up to the caller; it's
+    /// not part of the command line.
+    ///
+    /// A working directory, UID or GID becomes `--working-directory`,
+    /// `--uid` or `--gid` and must therefore be the same for all commands.
+    /// Fails with `PiperError::Unsupported` if the chain uses something
+    /// that can't be expressed on the command line (functions, stdin
+    /// contents, externally managed pipes, supplementary groups,
+    /// fallbacks, environments of subshells, builtins other than the ones
+    /// of sh), and with
+    /// `BuildError::InvalidEnvVar` if an environment variable isn't a
+    /// valid shell name.
+    pub fn systemd_run_args(&self, cmds: &CmdChain) -> Result<Vec<String>, PiperError> {
+        for cmd in cmds.cmds() {
+            check_expressible(cmd, false)?;
+        }
+        let mut args = vec!["systemd-run".to_owned(), "--scope".to_owned(), "--quiet".to_owned()];
+        if self.user {
+            args.push("--user".to_owned());
+        }
+        if let Some(unit) = &self.unit {
+            args.push(format!("--unit={}", unit));
+        }
+        for property in &self.properties {
+            args.push(format!("--property={}", property));
+        }
+        if let Some(dir) = common(cmds, "per command working directories", |cmd| cmd.work_dir().clone())? {
+            args.push(format!("--working-directory={}", dir.to_string_lossy()));
+        }
+        if let Some(uid) = common(cmds, "per command UIDs", BasicCmd::uid)? {
+            args.push(format!("--uid={}", uid));
+        }
+        if let Some(gid) = common(cmds, "per command GIDs", BasicCmd::gid)? {
+            args.push(format!("--gid={}", gid));
+        }
+
+        let mut cmd_line = String::new();
+        if cmds.pipefail() {
+            // a shell without pipefail fails instead of silently ignoring it
+            cmd_line.push_str("set -o pipefail; ");
+        }
+        if cmds.negate_status() {
+            cmd_line.push_str("! ");
+        }
+        cmd_line.push_str(&cmds.cmds().iter()
+            .map(|cmd| cmd.to_string())
+            .collect::<Vec<_>>()
+            .join(" | "));
+        args.extend(vec!["--".to_owned(), "sh".to_owned(), "-c".to_owned(), cmd_line]);
+        Ok(args)
+    }
+}
+
+/// Fails if the command (or a command of its subshell) uses something that
+/// the reconstructed command line doesn't express. The working directory
+/// and the credentials are only expressible for the top level commands.
+fn check_expressible(cmd: &BasicCmd, nested: bool) -> Result<(), PiperError> {
+    if cmd.is_function() {
+        return Err(PiperError::Unsupported("functions"));
+    }
+    if cmd.stdin_contents().is_some() {
+        return Err(PiperError::Unsupported("stdin contents"));
+    }
+    if cmd.stdin_pipe_fd().is_some() || cmd.stdout_pipe_fd().is_some() {
+        return Err(PiperError::Unsupported("externally managed pipes"));
+    }
+    if cmd.groups().is_some() {
+        return Err(PiperError::Unsupported("supplementary groups"));
+    }
+    if !cmd.fallbacks().is_empty() {
+        return Err(PiperError::Unsupported("fallbacks"));
+    }
+    if let Some(builtin) = cmd.builtin() {
+        let ty = format!("{:?}", builtin);
+        if !SH_BUILTINS.iter().any(|(builtin, name)| *builtin == ty && cmd.executable() == OsStr::new(name)) {
+            return Err(PiperError::Unsupported("builtins that sh doesn't provide"));
+        }
+    }
+    // 'FOO=1 (…)' is a syntax error and 'env -i (…)' runs no subshell
+    if cmd.subshell().is_some() && (!cmd.env().is_empty() || cmd.clear_env()) {
+        return Err(PiperError::Unsupported("environments of subshells"));
+    }
+    if nested && (cmd.work_dir().is_some() || cmd.uid().is_some() || cmd.gid().is_some()) {
+        return Err(PiperError::Unsupported("working directories or credentials in subshells"));
+    }
+    // the key isn't quoted on the command line
+    if let Some((key, _)) = cmd.env().iter().find(|(key, _)| !is_shell_name(key)) {
+        return Err(PiperError::InvalidChain(BuildError::InvalidEnvVar { key: key.clone() }));
+    }
+    if let Some(list) = cmd.subshell() {
+        for (_, chain) in list.items() {
+            if chain.pipefail() {
+                return Err(PiperError::Unsupported("pipefail in subshells"));
+            }
+            for cmd in chain.cmds() {
+                check_expressible(cmd, true)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The builtins of the crate that sh provides as well: the `Debug` output
+/// of the type and the name that sh knows them by.
+const SH_BUILTINS: [(&str, &str); 4] = [("Cd", "cd"), ("Export", "export"), ("Umask", "umask"), ("Exit", "exit")];
+
+/// The value that all commands of the chain share. Fails if they differ.
+fn common<T: PartialEq>(cmds: &CmdChain, what: &'static str, value: impl Fn(&BasicCmd) -> Option<T>) -> Result<Option<T>, PiperError> {
+    let mut values = cmds.cmds().iter().map(value);
+    let first = values.next().flatten();
+    if values.all(|other| other == first) {
+        Ok(first)
+    } else {
+        Err(PiperError::Unsupported(what))
+    }
+}
+
+/// Whether the key of an environment variable can be used in an
+/// assignment (`KEY=value cmd`) without quoting.
+fn is_shell_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Runs a command chain inside a transient systemd scope. The returned
+/// state belongs to the `systemd-run` process, which becomes the shell
+/// that runs the chain and exits with its exit code. The chain is waited
+/// for unless it is marked as background.
+///
+/// Redirects are part of the command line and are handled by the shell.
+/// Fails if the chain can't be expressed on a command line, see
+/// `SystemdScope::systemd_run_args()`.
+pub fn execute_piped_cmd_chain_in_systemd_scope(cmds: &CmdChain, scope: &SystemdScope) -> Result<Vec<ProcessState>, PiperError> {
+    let args = scope.systemd_run_args(cmds)?;
+    let mut cmd = BasicCmdBuilder::new().set_executable(&args[0]);
+    for arg in &args {
+        cmd = cmd.add_arg(arg);
+    }
+    let mut wrapped = CmdChainBuilder::new()
+        .add_cmd(cmd)
+        .set_background(cmds.background())
+        .set_close_fds(cmds.close_fds());
+    if let Some(timeout) = cmds.timeout() {
+        wrapped = wrapped.set_timeout(timeout);
+    }
+    execute_piped_cmd_chain(&wrapped.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_cmd_chain;
+
+    #[test]
+    fn test_systemd_run_args() {
+        let cmd_chain = parse_cmd_chain("cat < in.txt | grep -i 'a b' &").unwrap();
+        let scope = SystemdScope::new()
+            .set_unit("piper-test")
+            .set_user(true)
+            .add_property("MemoryMax=1G");
+
+        assert_eq!(
+            scope.systemd_run_args(&cmd_chain).unwrap(),
+            vec![
+                "systemd-run", "--scope", "--quiet", "--user", "--unit=piper-test", "--property=MemoryMax=1G",
+                "--", "sh", "-c", "cat < in.txt | grep -i 'a b'",
+            ]
+        );
+
+        // '! true | false' with pipefail, in /tmp as UID 0
+        let cmd = |exe: &str| BasicCmdBuilder::new().set_executable(exe).add_arg(exe).set_working_dir("/tmp").set_uid(0);
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(cmd("true"))
+            .add_cmd(cmd("false"))
+            .set_pipefail(true)
+            .set_negate_status(true)
+            .build();
+        assert_eq!(
+            SystemdScope::new().systemd_run_args(&cmd_chain).unwrap(),
+            vec![
+                "systemd-run", "--scope", "--quiet", "--working-directory=/tmp", "--uid=0",
+                "--", "sh", "-c", "set -o pipefail; ! true | false",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_systemd_run_args_unsupported() {
+        let scope = SystemdScope::new();
+        let cmd = |exe: &str| BasicCmdBuilder::new().set_executable(exe).add_arg(exe);
+        let chain = |cmds: Vec<BasicCmdBuilder>| {
+            cmds.into_iter().fold(CmdChainBuilder::new(), |chain, cmd| chain.add_cmd(cmd)).build()
+        };
+
+        assert_eq!(
+            scope.systemd_run_args(&chain(vec![cmd("cat").set_stdin_contents(b"x")])),
+            Err(PiperError::Unsupported("stdin contents"))
+        );
+        assert_eq!(
+            scope.systemd_run_args(&chain(vec![cmd("true").set_working_dir("/tmp"), cmd("true")])),
+            Err(PiperError::Unsupported("per command working directories"))
+        );
+        assert_eq!(
+            scope.systemd_run_args(&chain(vec![cmd("env").add_env("A;rm", "x")])),
+            Err(PiperError::InvalidChain(BuildError::InvalidEnvVar { key: "A;rm".to_owned() }))
+        );
+        assert_eq!(
+            scope.systemd_run_args(&chain(vec![cmd("vim").add_fallback("vi")])),
+            Err(PiperError::Unsupported("fallbacks"))
+        );
+
+        let subshell = || BasicCmdBuilder::new().set_subshell(crate::parse_cmd_list("echo a").unwrap());
+        assert!(scope.systemd_run_args(&chain(vec![subshell()])).is_ok());
+        assert_eq!(
+            scope.systemd_run_args(&chain(vec![subshell().add_env("FOO", "1")])),
+            Err(PiperError::Unsupported("environments of subshells"))
+        );
+        assert_eq!(
+            scope.systemd_run_args(&chain(vec![subshell().clear_env()])),
+            Err(PiperError::Unsupported("environments of subshells"))
+        );
+
+        #[derive(Debug)]
+        struct Custom;
+        impl crate::Builtin for Custom {
+            fn run(&self, _: &[std::ffi::OsString], _: &mut dyn std::io::Read, _: &mut dyn std::io::Write, _: &mut dyn std::io::Write) -> i32 {
+                0
+            }
+        }
+        let builtin = |builtin: BasicCmdBuilder, name: &str| builtin.add_arg(name).add_arg("x");
+        let args = scope.systemd_run_args(&chain(vec![builtin(BasicCmdBuilder::new().set_builtin(crate::Umask), "umask")])).unwrap();
+        assert_eq!(args.last().unwrap(), "umask x");
+        assert_eq!(
+            scope.systemd_run_args(&chain(vec![builtin(BasicCmdBuilder::new().set_builtin(Custom), "custom")])),
+            Err(PiperError::Unsupported("builtins that sh doesn't provide"))
+        );
+        // the name decides what sh runs
+        assert_eq!(
+            scope.systemd_run_args(&chain(vec![builtin(BasicCmdBuilder::new().set_builtin(crate::Cd), "umask")])),
+            Err(PiperError::Unsupported("builtins that sh doesn't provide"))
+        );
+    }
+}
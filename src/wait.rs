@@ -0,0 +1,511 @@
+/*
+    MIT License
+
+    Copyright (c) 2020 Philipp Schuster
+
+    Permission is hereby granted, free of charge, to any person obtaining a copy
+    of this software and associated documentation files (the "Software"), to deal
+    in the Software without restriction, including without limitation the rights
+    to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+    copies of the Software, and to permit persons to whom the Software is
+    furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in all
+    copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+    OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+    SOFTWARE.
+*/
+
+//! Helpers for waiting on the processes of a (background) command chain
+//! on top of `update_process_states()`.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::stage::StageThread;
+use crate::{signal_group, try_update_process_states, update_process_states, CmdChain, PiperError, ProcessState};
+
+/// Time between `SIGTERM` and `SIGKILL` if a chain runs into its timeout,
+/// see `CmdChainBuilder::set_timeout()`.
+pub const TIMEOUT_KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// Waits until all processes are finished by polling them with
+/// `update_process_states()` (non-blocking). Between two polls the thread
+/// sleeps; the sleep time starts with `initial` and doubles after each
+/// unsuccessful poll until it reaches `max`. This doesn't burn CPU like
+/// a busy loop and doesn't require signal handling. Both times are at
+/// least one millisecond.
+pub fn wait_background_poll(states: &mut [ProcessState], initial: Duration, max: Duration) {
+    let max = max.max(Duration::from_millis(1));
+    let mut sleep_time = initial.max(Duration::from_millis(1)).min(max);
+    while !update_process_states(states, true) {
+        std::thread::sleep(sleep_time);
+        sleep_time = sleep_time.saturating_mul(2).min(max);
+    }
+}
+
+/// Waits up to `timeout` until any of the unfinished processes finishes
+/// and updates the states. Returns whether all processes are finished.
+/// Unlike `update_process_states()` this neither returns immediately
+/// (non-blocking) nor blocks forever, which bounds the latency of e.g. an
+/// UI event loop.
+///
+/// On Linux >= 5.3 the processes are observed via pidfds and `poll()`,
+/// i.e. the call returns as soon as something finished. Otherwise it
+/// falls back to polling with a short sleep in between.
+///
+/// Panics if `waitpid()` fails, see `try_update_process_states_timeout()`.
+pub fn update_process_states_timeout(states: &mut [ProcessState], timeout: Duration) -> bool {
+    try_update_process_states_timeout(states, timeout).unwrap_or_else(|e| panic!("Failure during waitpid! {}", e))
+}
+
+/// Like `update_process_states_timeout()` but returns an error if
+/// `waitpid()` fails, see `try_update_process_states()`.
+pub fn try_update_process_states_timeout(states: &mut [ProcessState], timeout: Duration) -> Result<bool, PiperError> {
+    if try_update_process_states(states, true)? {
+        return Ok(true);
+    }
+    // a timeout that doesn't fit into an `Instant` means no deadline
+    let deadline = Instant::now().checked_add(timeout);
+
+    let unfinished: Vec<&ProcessState> = states.iter()
+        .filter(|state| !state.finished())
+        .collect();
+    // the pidfds of the states (`ExecOptions::set_pidfds()`) and the FDs
+    // of commands that run in the parent are reused
+    let opened: Vec<libc::c_int> = unfinished.iter()
+        .filter(|state| state.wait_fd().is_none())
+        .filter_map(|state| pidfd_open(state.pid()))
+        .collect();
+    let pidfds: Vec<libc::c_int> = unfinished.iter()
+        .filter_map(|state| state.wait_fd())
+        .chain(opened.iter().copied())
+        .collect();
+
+    if pidfds.len() == unfinished.len() {
+        let mut poll_fds: Vec<libc::pollfd> = pidfds.iter()
+            .map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 })
+            .collect();
+        loop {
+            // round up, otherwise a sub millisecond timeout would spin
+            let remaining_ms = deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .map_or(-1, |remaining| remaining.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int);
+            let res = unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, remaining_ms) };
+            if res == -1 && errno::errno().0 == libc::EINTR {
+                continue;
+            }
+            break;
+        }
+    } else {
+        let mut sleep_time = Duration::from_millis(1);
+        loop {
+            let remaining = deadline.map_or(sleep_time, |deadline| deadline.saturating_duration_since(Instant::now()));
+            if remaining == Duration::from_secs(0) || states.iter().any(|state| !state.finished() && has_finished(state)) {
+                break;
+            }
+            std::thread::sleep(sleep_time.min(remaining));
+            sleep_time = (sleep_time * 2).min(Duration::from_millis(50));
+        }
+    }
+
+    for fd in opened {
+        unsafe { libc::close(fd) };
+    }
+    try_update_process_states(states, true)
+}
+
+/// Waits until all processes are finished, but gives up after `timeout`.
+/// Returns whether all processes are finished. On timeout the processes
+/// that are still running keep `finished() == false`, i.e. the caller can
+/// wait again or kill them. Doesn't burn CPU, see
+/// `update_process_states_timeout()`.
+///
+/// Panics if `waitpid()` fails, see `try_wait_all_timeout()`.
+pub fn wait_all_timeout(states: &mut [ProcessState], timeout: Duration) -> bool {
+    try_wait_all_timeout(states, timeout).unwrap_or_else(|e| panic!("Failure during waitpid! {}", e))
+}
+
+/// Like `wait_all_timeout()` but returns an error if `waitpid()` fails.
+pub fn try_wait_all_timeout(states: &mut [ProcessState], timeout: Duration) -> Result<bool, PiperError> {
+    let deadline = match Instant::now().checked_add(timeout) {
+        Some(deadline) => deadline,
+        // too far in the future to ever be reached
+        None => return try_update_process_states(states, false),
+    };
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if try_update_process_states_timeout(states, remaining)? {
+            return Ok(true);
+        }
+        if remaining == Duration::from_secs(0) {
+            return Ok(false);
+        }
+    }
+}
+
+/// Waits for the processes of a chain with a timeout. On timeout the
+/// processes get `SIGTERM`, then `SIGKILL` after `TIMEOUT_KILL_GRACE` (see
+/// `signal_chain()`), and all states are marked as timed out. Returns
+/// whether it timed out.
+pub(crate) fn wait_or_kill(states: &mut [ProcessState], timeout: Duration) -> bool {
+    if wait_all_timeout(states, timeout) {
+        return false;
+    }
+    // errors only mean that a process exited meanwhile
+    let _ = signal_chain(states, libc::SIGTERM);
+    if !wait_all_timeout(states, TIMEOUT_KILL_GRACE) {
+        let _ = signal_chain(states, libc::SIGKILL);
+        update_process_states(states, false);
+    }
+    for state in states.iter_mut() {
+        state.set_timed_out();
+    }
+    true
+}
+
+/// The deadline of a chain that starts now, see
+/// `CmdChainBuilder::set_timeout()`. `None` without a timeout, or if the
+/// timeout is too big to be represented, i.e. it never expires.
+pub(crate) fn deadline_of(cmds: &CmdChain) -> Option<Instant> {
+    cmds.timeout().and_then(|timeout| Instant::now().checked_add(timeout))
+}
+
+/// Sends the signal to the processes of a chain that aren't finished: to
+/// the whole process group if the chain has one (see
+/// `ExecOptions::set_process_group()`), so that children of the commands
+/// get it as well, otherwise to each process. Commands that run in the
+/// parent forward it to their processes.
+pub(crate) fn signal_chain(states: &[ProcessState], signal: libc::c_int) -> Result<(), PiperError> {
+    ChainTargets::of(states).signal(signal)
+}
+
+/// Who gets a signal for a chain, see `signal_chain()`.
+#[derive(Debug, Clone)]
+struct ChainTargets {
+    pgid: Option<libc::pid_t>,
+    pids: Vec<libc::pid_t>,
+    stages: Vec<Arc<StageThread>>,
+}
+
+impl ChainTargets {
+    /// The targets for the unfinished processes of a chain.
+    fn of(states: &[ProcessState]) -> Self {
+        let running = || states.iter().filter(|state| !state.finished());
+        ChainTargets {
+            pgid: states.iter().find_map(|state| state.pgid()),
+            pids: running().map(|state| state.pid()).filter(|pid| *pid > 0).collect(),
+            stages: running().filter_map(|state| state.stage().cloned()).collect(),
+        }
+    }
+
+    /// Sends the signal to all targets, also if sending to one fails; the
+    /// first error is returned.
+    fn signal(&self, signal: libc::c_int) -> Result<(), PiperError> {
+        let mut res = match self.pgid {
+            Some(pgid) if !self.pids.is_empty() => signal_group(pgid, signal),
+            Some(_) => Ok(()),
+            None => {
+                let mut res = Ok(());
+                // last command first; otherwise it may see EOF and exit
+                // normally before it gets the signal
+                for pid in self.pids.iter().rev() {
+                    if unsafe { libc::kill(*pid, signal) } == -1 && res.is_ok() {
+                        res = Err(PiperError::SignalFailed { pid: *pid, errno: errno::errno().0 });
+                    }
+                }
+                res
+            }
+        };
+        for stage in &self.stages {
+            res = res.and(stage.send_signal(signal));
+        }
+        res
+    }
+}
+
+/// For executors that poll the processes of a chain with a deadline: sends
+/// the signal that is due now, `SIGTERM` from the deadline on and `SIGKILL`
+/// after `TIMEOUT_KILL_GRACE`, each once (see `signal_chain()`). `sent` is
+/// the last signal that was sent, i.e. `Some` if the chain timed out.
+pub(crate) fn signal_due(states: &[ProcessState], deadline: Option<Instant>, sent: &mut Option<libc::c_int>) {
+    match next_signal_at(deadline, *sent) {
+        Some(at) if Instant::now() >= at => {}
+        _ => return,
+    }
+    let due = if sent.is_none() { libc::SIGTERM } else { libc::SIGKILL };
+    // errors only mean that a process exited meanwhile
+    let _ = signal_chain(states, due);
+    sent.replace(due);
+}
+
+/// When the next signal is due, see `signal_due()`; `None` if none is.
+pub(crate) fn next_signal_at(deadline: Option<Instant>, sent: Option<libc::c_int>) -> Option<Instant> {
+    let deadline = deadline?;
+    match sent {
+        None => Some(deadline),
+        Some(libc::SIGTERM) => Some(deadline.checked_add(TIMEOUT_KILL_GRACE).unwrap_or(deadline)),
+        Some(_) => None,
+    }
+}
+
+/// Enforces the deadline of a chain (see `CmdChainBuilder::set_timeout()`)
+/// while the executor is busy with the IO of the chain, e.g. blocked in
+/// `read()` of its output or `write()` of its input: at the deadline a
+/// thread sends `SIGTERM` to the chain and `SIGKILL` after
+/// `TIMEOUT_KILL_GRACE`, which ends the IO. It never signals after
+/// `wait()` or `kill()` started, i.e. before the processes are reaped, so
+/// that the pids can't be reused meanwhile.
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    deadline: Option<Instant>,
+    /// Whether the watchdog was cancelled; wakes up the thread.
+    cancelled: Arc<(Mutex<bool>, Condvar)>,
+    /// Returns whether it sent a signal; `None` without a deadline.
+    thread: Option<JoinHandle<bool>>,
+}
+
+impl Watchdog {
+    /// Starts the watchdog for the just spawned processes of a chain. A
+    /// no-op without a deadline, see `deadline_of()`.
+    pub(crate) fn start(states: &[ProcessState], deadline: Option<Instant>) -> Self {
+        let cancelled = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = deadline.map(|deadline| {
+            let targets = ChainTargets::of(states);
+            let cancelled = cancelled.clone();
+            std::thread::spawn(move || {
+                let (lock, condvar) = &*cancelled;
+                let mut cancelled = lock.lock().unwrap_or_else(|e| e.into_inner());
+                let mut fired = false;
+                let kill_at = deadline.checked_add(TIMEOUT_KILL_GRACE).unwrap_or(deadline);
+                for (at, signal) in [(deadline, libc::SIGTERM), (kill_at, libc::SIGKILL)] {
+                    loop {
+                        if *cancelled {
+                            return fired;
+                        }
+                        let now = Instant::now();
+                        if now >= at {
+                            break;
+                        }
+                        cancelled = condvar.wait_timeout(cancelled, at - now).unwrap_or_else(|e| e.into_inner()).0;
+                    }
+                    // errors only mean that a process exited meanwhile
+                    let _ = targets.signal(signal);
+                    fired = true;
+                }
+                fired
+            })
+        });
+        Watchdog { deadline, cancelled, thread }
+    }
+
+    /// Stops the thread. Returns whether it sent a signal.
+    fn cancel(&mut self) -> bool {
+        let (lock, condvar) = &*self.cancelled;
+        *lock.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        condvar.notify_all();
+        self.thread.take().is_some_and(|thread| thread.join().unwrap_or(true))
+    }
+
+    /// Waits for the processes after the IO of the chain is done: up to
+    /// the deadline, then like `wait_or_kill()`. All states are marked as
+    /// timed out if the deadline was reached.
+    pub(crate) fn wait(mut self, states: &mut [ProcessState]) -> Result<(), PiperError> {
+        let fired = self.cancel();
+        match self.deadline {
+            Some(deadline) => {
+                let timed_out = wait_or_kill(states, deadline.saturating_duration_since(Instant::now()));
+                if fired && !timed_out {
+                    states.iter_mut().for_each(|state| state.set_timed_out());
+                }
+                Ok(())
+            }
+            None => try_update_process_states(states, false).map(|_| ()),
+        }
+    }
+
+    /// Kills the processes with `SIGKILL` (see `signal_chain()`) and
+    /// reaps them, e.g. if the executor gives up on the IO.
+    pub(crate) fn kill(mut self, states: &mut [ProcessState]) -> Result<(), PiperError> {
+        self.cancel();
+        // errors only mean that a process exited meanwhile
+        let _ = signal_chain(states, libc::SIGKILL);
+        try_update_process_states(states, false).map(|_| ())
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Opens a pidfd for the process. `None` if the kernel doesn't support it.
+/// The pidfd has the close-on-exec flag.
+#[cfg(target_os = "linux")]
+pub(crate) fn pidfd_open(pid: libc::pid_t) -> Option<libc::c_int> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0 as libc::c_uint) };
+    if fd < 0 { None } else { Some(fd as libc::c_int) }
+}
+
+/// pidfds only exist on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pidfd_open(_pid: libc::pid_t) -> Option<libc::c_int> {
+    None
+}
+
+/// Sends the signal to the process of the pidfd. Unlike `kill()` it can't
+/// hit another process that reused the pid. Returns the errno on failure.
+#[cfg(target_os = "linux")]
+pub(crate) fn pidfd_send_signal(pidfd: libc::c_int, signal: libc::c_int) -> Result<(), libc::c_int> {
+    let res = unsafe {
+        libc::syscall(libc::SYS_pidfd_send_signal, pidfd, signal, std::ptr::null::<libc::siginfo_t>(), 0 as libc::c_uint)
+    };
+    if res == -1 { Err(errno::errno().0) } else { Ok(()) }
+}
+
+/// pidfds only exist on Linux, i.e. there never is one.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pidfd_send_signal(_pidfd: libc::c_int, _signal: libc::c_int) -> Result<(), libc::c_int> {
+    Err(libc::ENOSYS)
+}
+
+/// Checks whether the process (or the thread of a command that runs in the
+/// parent) finished without reaping it.
+fn has_finished(state: &ProcessState) -> bool {
+    match state.stage() {
+        Some(stage) => stage.exit_code(false).is_some(),
+        None => has_exited(state.pid()),
+    }
+}
+
+/// Checks whether the child exited without reaping it.
+fn has_exited(pid: libc::pid_t) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WEXITED | libc::WNOHANG | libc::WNOWAIT) };
+    // si_pid is 0 if the child is still running
+    res == 0 && unsafe { info.si_pid() } != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute_piped_cmd_chain, BasicCmdBuilder, Builder, CmdChainBuilder, ProcessOutcome};
+
+    #[test]
+    fn test_wait_background_poll() {
+        // 'sleep 0.2 &'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sleep")
+                    .add_arg("sleep")
+                    .add_arg("0.2")
+            )
+            .set_background(true)
+            .build();
+
+        let begin = Instant::now();
+        let mut states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert!(!states[0].finished());
+        wait_background_poll(&mut states, Duration::from_millis(1), Duration::from_millis(50));
+        assert!(states[0].finished());
+        assert_eq!(states[0].exit_code(), 0);
+        assert_eq!(states[0].outcome(), ProcessOutcome::Exited(0));
+        assert_eq!(states[0].termination_reason(), Some(crate::TerminationReason::Exited(0)));
+        assert!(begin.elapsed() >= Duration::from_millis(200));
+
+        // a zero initial time doesn't spin, a huge max doesn't overflow
+        let mut states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        wait_background_poll(&mut states, Duration::ZERO, Duration::MAX);
+        assert!(states[0].finished());
+    }
+
+    #[test]
+    fn test_update_process_states_timeout() {
+        // 'sleep 2 &'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(
+                BasicCmdBuilder::new()
+                    .set_executable("sleep")
+                    .add_arg("sleep")
+                    .add_arg("2")
+            )
+            .set_background(true)
+            .build();
+        let mut states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+
+        // nothing finishes within the timeout
+        let begin = Instant::now();
+        assert!(!update_process_states_timeout(&mut states, Duration::from_millis(100)));
+        let elapsed = begin.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_millis(1000));
+
+        unsafe { libc::kill(states[0].pid(), libc::SIGKILL) };
+        assert!(update_process_states_timeout(&mut states, Duration::from_secs(5)));
+        assert!(states[0].finished());
+        assert_eq!(states[0].term_signal(), Some(libc::SIGKILL));
+        assert_eq!(states[0].outcome(), ProcessOutcome::Signaled(libc::SIGKILL));
+        assert_eq!(states[0].exit_code(), 128 + libc::SIGKILL);
+    }
+
+    #[test]
+    fn test_wait_all_timeout() {
+        let sleep = |secs: &str| {
+            CmdChainBuilder::new()
+                .add_cmd(BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg(secs))
+                .set_background(true)
+                .build()
+        };
+
+        // 'sleep 0.1 &' and 'sleep 0.3 &': waits for both, not just the first
+        let begin = Instant::now();
+        let mut states = execute_piped_cmd_chain(&sleep("0.1")).unwrap();
+        states.extend(execute_piped_cmd_chain(&sleep("0.3")).unwrap());
+        assert!(wait_all_timeout(&mut states, Duration::from_secs(5)));
+        assert!(begin.elapsed() >= Duration::from_millis(300));
+        assert!(states.iter().all(|state| state.finished()));
+
+        // 'sleep 2 &' doesn't finish within the timeout
+        let mut states = execute_piped_cmd_chain(&sleep("2")).unwrap();
+        assert!(!wait_all_timeout(&mut states, Duration::from_millis(100)));
+        assert!(!states[0].finished());
+        unsafe { libc::kill(states[0].pid(), libc::SIGKILL) };
+        assert!(wait_all_timeout(&mut states, Duration::from_secs(5)));
+
+        // a timeout that overflows `Instant` means no deadline
+        let mut states = execute_piped_cmd_chain(&sleep("0.1")).unwrap();
+        assert!(update_process_states_timeout(&mut states, Duration::MAX));
+        let mut states = execute_piped_cmd_chain(&sleep("0.1")).unwrap();
+        assert!(wait_all_timeout(&mut states, Duration::MAX));
+    }
+
+    #[test]
+    fn test_try_wait_all_timeout() {
+        // 'sleep 2 &'
+        let cmd_chain = CmdChainBuilder::new()
+            .add_cmd(BasicCmdBuilder::new().set_executable("sleep").add_arg("sleep").add_arg("2"))
+            .set_background(true)
+            .build();
+        let mut states = execute_piped_cmd_chain(&cmd_chain).unwrap();
+        assert_eq!(try_wait_all_timeout(&mut states, Duration::from_millis(50)), Ok(false));
+
+        // reaped elsewhere, e.g. by a SIGCHLD handler
+        unsafe {
+            libc::kill(states[0].pid(), libc::SIGKILL);
+            libc::waitpid(states[0].pid(), std::ptr::null_mut(), 0);
+        }
+        assert_eq!(
+            try_update_process_states_timeout(&mut states, Duration::from_millis(50)),
+            Err(PiperError::WaitFailed(libc::ECHILD))
+        );
+        assert!(!states[0].finished());
+    }
+}